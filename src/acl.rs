@@ -0,0 +1,129 @@
+use std::net::{IpAddr, SocketAddr};
+use anyhow::Result;
+
+use crate::config::AclSettings;
+
+/// `::ffff:1.2.3.4` from a dual-stack socket becomes a plain IPv4
+pub fn normalize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+pub fn normalize_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(normalize_ip(addr.ip()), addr.port())
+}
+
+/// A network in CIDR notation ("10.0.0.0/8", "2001:db8::/32"); an address without a mask is a single host
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Result<Self> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+
+        let network: IpAddr = addr.trim().parse()
+            .map_err(|_| anyhow::anyhow!("Invalid CIDR address: {}", value))?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>()
+                .ok()
+                .filter(|&p| p <= max_prefix)
+                .ok_or_else(|| anyhow::anyhow!("Invalid CIDR prefix: {}", value))?,
+            None => max_prefix,
+        };
+
+        Ok(Self { network, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Client access rules built from the config
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    deny_clients: Vec<Cidr>,
+}
+
+impl AccessList {
+    /// Invalid rules are skipped with a warning
+    pub fn from_settings(settings: &AclSettings) -> Self {
+        let deny_clients = settings.deny_clients
+            .iter()
+            .filter_map(|rule| match Cidr::parse(rule) {
+                Ok(cidr) => Some(cidr),
+                Err(e) => {
+                    log::warn!("Ignoring ACL rule: {}", e);
+                    None
+                }
+            })
+            .collect();
+
+        Self { deny_clients }
+    }
+
+    pub fn is_client_denied(&self, ip: IpAddr) -> bool {
+        let ip = normalize_ip(ip);
+        self.deny_clients.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr_matching() {
+        let v4 = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(v4.contains("10.20.30.40".parse().unwrap()));
+        assert!(!v4.contains("11.0.0.1".parse().unwrap()));
+        assert!(!v4.contains("::1".parse().unwrap()));
+
+        let host = Cidr::parse("192.168.1.5").unwrap();
+        assert!(host.contains("192.168.1.5".parse().unwrap()));
+        assert!(!host.contains("192.168.1.6".parse().unwrap()));
+
+        let any = Cidr::parse("0.0.0.0/0").unwrap();
+        assert!(any.contains("8.8.8.8".parse().unwrap()));
+
+        let v6 = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(v6.contains("2001:db8:1::1".parse().unwrap()));
+
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("not-an-ip/8").is_err());
+    }
+
+    #[test]
+    fn test_ipv4_mapped_client_matches_ipv4_rule() {
+        let acl = AccessList::from_settings(&AclSettings {
+            deny_clients: vec!["10.0.0.0/8".to_string()],
+            ..Default::default()
+        });
+
+        assert!(acl.is_client_denied("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!acl.is_client_denied("::ffff:192.168.0.1".parse().unwrap()));
+        assert!(!acl.is_client_denied("2001:db8::1".parse().unwrap()));
+
+        let addr: SocketAddr = "[::ffff:10.0.0.1]:5555".parse().unwrap();
+        assert_eq!(normalize_addr(addr).to_string(), "10.0.0.1:5555");
+    }
+}
@@ -1,9 +1,13 @@
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::time::{SystemTime, UNIX_EPOCH};
 use cookie::Cookie;
+use parking_lot::RwLock;
 
 const MAX_REDIRECTS: u32 = 10;
 const CHALLENGE_TIMEOUT: u64 = 300; // 5 minutes
+const CHALLENGE_SHARDS: usize = 16;
 
 pub struct ChallengeHandler {
     pending_challenges: HashMap<String, ChallengeState>,
@@ -114,35 +118,11 @@ impl ChallengeHandler {
     }
 
     pub fn detect_challenge(&self, response_body: &str, headers: &HashMap<String, String>) -> bool {
-        if response_body.contains("cf-browser-verification") ||
-           response_body.contains("__cf_chl_jschl_tk__") ||
-           response_body.contains("cf-challenge-form") ||
-           response_body.contains("jschl-answer") ||
-           response_body.contains("cf-captcha-container") {
-            return true;
-        }
-
-        if let Some(server) = headers.get("server") {
-            if server.contains("cloudflare") {
-                if let Some(_status) = headers.get("cf-ray") {
-                    if headers.get("cf-mitigated").is_some() {
-                        return true;
-                    }
-                }
-            }
-        }
-
-        if let Some(location) = headers.get("location") {
-            if location.contains("__cf_chl_jschl_tk__") || location.contains("cdn-cgi/challenge") {
-                return true;
-            }
-        }
-
-        false
+        detect_challenge_markers(response_body, headers)
     }
 
     pub fn is_redirect(&self, status_code: u16) -> bool {
-        matches!(status_code, 301 | 302 | 303 | 307 | 308)
+        is_redirect_status(status_code)
     }
 
     pub fn start_redirect_chain(&mut self, original_url: String) {
@@ -181,6 +161,10 @@ impl ChallengeHandler {
             .unwrap_or_default()
     }
 
+    pub fn has_redirect_chain(&self, original_url: &str) -> bool {
+        self.redirect_chains.contains_key(original_url)
+    }
+
     pub fn finish_redirect_chain(&mut self, original_url: &str) -> Option<String> {
         self.redirect_chains.remove(original_url).and_then(|chain| chain.get_final_url())
     }
@@ -265,6 +249,12 @@ impl ChallengeHandler {
             .unwrap_or(false)
     }
 
+    pub fn get_redirect_chain_final_url(&self, original_url: &str) -> Option<String> {
+        self.redirect_chains
+            .get(original_url)
+            .and_then(|chain| chain.get_final_url())
+    }
+
     pub fn get_redirect_chain_length(&self, original_url: &str) -> usize {
         self.redirect_chains
             .get(original_url)
@@ -273,6 +263,127 @@ impl ChallengeHandler {
     }
 }
 
+fn detect_challenge_markers(response_body: &str, headers: &HashMap<String, String>) -> bool {
+    if response_body.contains("cf-browser-verification") ||
+       response_body.contains("__cf_chl_jschl_tk__") ||
+       response_body.contains("cf-challenge-form") ||
+       response_body.contains("jschl-answer") ||
+       response_body.contains("cf-captcha-container") {
+        return true;
+    }
+
+    if let Some(server) = headers.get("server") {
+        if server.contains("cloudflare") {
+            if let Some(_status) = headers.get("cf-ray") {
+                if headers.get("cf-mitigated").is_some() {
+                    return true;
+                }
+            }
+        }
+    }
+
+    if let Some(location) = headers.get("location") {
+        if location.contains("__cf_chl_jschl_tk__") || location.contains("cdn-cgi/challenge") {
+            return true;
+        }
+    }
+
+    false
+}
+
+fn is_challenge_cookie(name: &str) -> bool {
+    name.starts_with("__cf") || name.starts_with("cf_")
+}
+
+/// name=value pairs of the challenge cookies from Set-Cookie values. Empty
+/// when cf_clearance is not among them, i.e. the challenge is not passed yet.
+pub fn clearance_cookies(set_cookies: &[String]) -> Vec<String> {
+    let cookies: Vec<Cookie> = set_cookies
+        .iter()
+        .filter_map(|value| Cookie::parse(value.trim().to_string()).ok())
+        .filter(|cookie| is_challenge_cookie(cookie.name()))
+        .collect();
+
+    if !cookies.iter().any(|cookie| cookie.name() == "cf_clearance") {
+        return Vec::new();
+    }
+
+    cookies
+        .iter()
+        .map(|cookie| format!("{}={}", cookie.name(), cookie.value()))
+        .collect()
+}
+
+pub fn is_redirect_status(status_code: u16) -> bool {
+    matches!(status_code, 301 | 302 | 303 | 307 | 308)
+}
+
+/// ChallengeHandler sharded by host: a write to one shard does not block
+/// responses for other hosts, and detection takes no lock at all.
+pub struct ShardedChallengeHandler {
+    shards: Vec<RwLock<ChallengeHandler>>,
+}
+
+impl ShardedChallengeHandler {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..CHALLENGE_SHARDS)
+                .map(|_| RwLock::new(ChallengeHandler::new()))
+                .collect(),
+        }
+    }
+
+    pub fn shard(&self, url: &str) -> &RwLock<ChallengeHandler> {
+        let mut hasher = DefaultHasher::new();
+        url.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    pub fn detect_challenge(&self, response_body: &str, headers: &HashMap<String, String>) -> bool {
+        detect_challenge_markers(response_body, headers)
+    }
+
+    /// The caller parses everything (cookies, location) up front, so the
+    /// write lock is held only for the map insert.
+    pub fn record_challenge(&self, url: &str, cookies: Vec<String>, redirect: Option<(u16, String)>) {
+        let mut handler = self.shard(url).write();
+        handler.register_challenge(url.to_string(), cookies);
+
+        if let Some((status_code, location)) = redirect {
+            if !handler.has_redirect_chain(url) {
+                handler.start_redirect_chain(url.to_string());
+            }
+            let from_url = handler
+                .get_redirect_chain_final_url(url)
+                .unwrap_or_else(|| url.to_string());
+            let _ = handler.add_redirect(url, from_url, location, status_code);
+        }
+    }
+
+    /// Closes the redirect chain as soon as the final (non-3xx) response
+    /// arrives, without waiting for cleanup_expired. Returns the chain's final URL.
+    pub fn complete_response(&self, url: &str, status_code: u16) -> Option<String> {
+        if is_redirect_status(status_code) {
+            return None;
+        }
+
+        let shard = self.shard(url);
+        if !shard.read().has_redirect_chain(url) {
+            return None;
+        }
+
+        let final_url = shard.write().finish_redirect_chain(url);
+        log::debug!("Redirect chain for {} finished at {:?}", url, final_url);
+        final_url
+    }
+
+    pub fn cleanup_expired(&self) {
+        for shard in &self.shards {
+            shard.write().cleanup_expired();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -362,4 +473,71 @@ mod tests {
         assert_eq!(cookies.len(), 1);
         assert!(cookies[0].contains("cf_clearance"));
     }
+
+    #[test]
+    fn test_sharded_handler_does_not_serialize_hosts() {
+        let handler = std::sync::Arc::new(ShardedChallengeHandler::new());
+        let busy = "busy.example.com:80";
+        let other = (0..100)
+            .map(|i| format!("host{}.example.com:80", i))
+            .find(|url| !std::ptr::eq(handler.shard(url), handler.shard(busy)))
+            .unwrap();
+
+        // Hold one host's shard write lock as if a long write were in progress
+        let _guard = handler.shard(busy).write();
+
+        let worker = {
+            let handler = handler.clone();
+            let other = other.clone();
+            std::thread::spawn(move || {
+                let started = std::time::Instant::now();
+                let body = "<div id=\"cf-browser-verification\">";
+                assert!(handler.detect_challenge(body, &HashMap::new()));
+                handler.record_challenge(
+                    &other,
+                    vec!["cf_clearance=1".to_string()],
+                    Some((302, "https://host/next".to_string())),
+                );
+                started.elapsed()
+            })
+        };
+
+        let elapsed = worker.join().unwrap();
+        assert!(elapsed < std::time::Duration::from_secs(1));
+        assert!(handler.shard(&other).read().should_passthrough(&other));
+        assert_eq!(handler.shard(&other).read().get_redirect_chain_length(&other), 1);
+    }
+
+    #[test]
+    fn test_clearance_cookies() {
+        let set_cookies = vec![
+            "__cf_bm=abc; Path=/; HttpOnly".to_string(),
+            "session=xyz".to_string(),
+        ];
+        assert!(clearance_cookies(&set_cookies).is_empty());
+
+        let mut with_clearance = set_cookies.clone();
+        with_clearance.push("cf_clearance=token; Path=/; Secure".to_string());
+        assert_eq!(
+            clearance_cookies(&with_clearance),
+            vec!["__cf_bm=abc".to_string(), "cf_clearance=token".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_redirect_chain_finished_on_final_response() {
+        let handler = ShardedChallengeHandler::new();
+        let url = "example.com:80";
+
+        handler.record_challenge(url, Vec::new(), Some((302, "https://example.com/a".to_string())));
+        handler.record_challenge(url, Vec::new(), Some((301, "https://example.com/b".to_string())));
+        assert_eq!(handler.shard(url).read().get_redirect_chain_length(url), 2);
+
+        assert_eq!(handler.complete_response(url, 302), None);
+        assert!(handler.shard(url).read().has_redirect_chain(url));
+
+        let final_url = handler.complete_response(url, 200);
+        assert_eq!(final_url, Some("https://example.com/b".to_string()));
+        assert!(!handler.shard(url).read().has_redirect_chain(url));
+    }
 }
\ No newline at end of file
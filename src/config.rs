@@ -1,6 +1,28 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
+use std::time::Duration;
 use anyhow::Result;
+use once_cell::sync::Lazy;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use crate::tls::{
+    TlsClientHello, ClientHelloOptions, GreaseMode, GreasePositions, parse_version_name, cipher_suite_id, extension_id,
+};
+
+/// Smallest MSS that TCP_MAXSEG accepts on Linux
+const MIN_MSS: u16 = 88;
+
+/// Profile the legacy name "ios_safari" points to
+pub const LATEST_IOS_PROFILE: &str = "ios_17_safari";
+
+/// Built-in profile library; available by name even when the profile
+/// is not listed in the config
+static BUILTIN_PROFILES: Lazy<Vec<FingerprintProfile>> = Lazy::new(|| vec![
+    Config::ios_16_safari_profile(),
+    Config::ios_17_safari_profile(),
+]);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -8,15 +30,468 @@ pub struct Config {
     pub default_profile: String,
     #[serde(default)]
     pub proxy_settings: ProxySettings,
+    #[serde(default)]
+    pub tcp_settings: TcpSettings,
+    #[serde(default)]
+    pub tls_settings: TlsSettings,
+    #[serde(default)]
+    pub challenge_settings: ChallengeSettings,
+    #[serde(default)]
+    pub http2_settings: Http2ProxySettings,
+    #[serde(default)]
+    pub runtime_settings: RuntimeSettings,
+    #[serde(default)]
+    pub acl_settings: AclSettings,
+    #[serde(default)]
+    pub udp_settings: UdpSettings,
+    #[serde(default)]
+    pub pool_settings: PoolSettings,
+    #[serde(default)]
+    pub timing_settings: TimingSettings,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimingSettings {
+    /// Delays between forwards based on learned intervals (false - no delays,
+    /// when only the TLS fingerprint matters)
+    #[serde(default = "default_timing_enabled")]
+    pub enabled: bool,
+    /// Delay jitter as a percentage of the learned interval
+    #[serde(default = "default_jitter_percent")]
+    pub jitter_percent: f64,
+    /// Pause before sending the ClientHello, like a real client (0 - no pause)
+    #[serde(default)]
+    pub tls_handshake_delay_ms: u64,
+    /// Pause after the HTTP/2 preface before the first request (0 - no pause)
+    #[serde(default)]
+    pub http2_settings_delay_ms: u64,
+}
+
+fn default_timing_enabled() -> bool {
+    true
+}
+
+fn default_jitter_percent() -> f64 {
+    5.0
+}
+
+impl Default for TimingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_timing_enabled(),
+            jitter_percent: default_jitter_percent(),
+            tls_handshake_delay_ms: 0,
+            http2_settings_delay_ms: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolSettings {
+    /// Idle upstream connections to keep per target (0 - pool disabled)
+    #[serde(default = "default_pool_max_idle_per_target")]
+    pub max_idle_per_target: usize,
+    /// Total limit of idle connections
+    #[serde(default = "default_pool_max_idle_total")]
+    pub max_idle_total: usize,
+    /// Idle milliseconds after which a connection is closed
+    #[serde(default = "default_pool_idle_timeout_ms")]
+    pub idle_timeout_ms: u64,
+}
+
+fn default_pool_max_idle_per_target() -> usize {
+    4
+}
+
+fn default_pool_max_idle_total() -> usize {
+    64
+}
+
+fn default_pool_idle_timeout_ms() -> u64 {
+    30000
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        Self {
+            max_idle_per_target: default_pool_max_idle_per_target(),
+            max_idle_total: default_pool_max_idle_total(),
+            idle_timeout_ms: default_pool_idle_timeout_ms(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UdpSettings {
+    /// UDP forwarder address (QUIC, WebRTC), e.g. "0.0.0.0:8443" (None - no UDP)
+    #[serde(default)]
+    pub listen_addr: Option<String>,
+    /// Fixed target for all datagrams (None - original destination via TPROXY,
+    /// only together with `transparent`)
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Receive via TPROXY (IP_TRANSPARENT, needs CAP_NET_ADMIN); without it an explicit `target` is required
+    #[serde(default)]
+    pub transparent: bool,
+    /// Targets by destination port, e.g. {"443": "10.0.0.5:443", "3478": "10.0.0.6:3478"};
+    /// take precedence over `target` (port comes from TPROXY, otherwise the forwarder's port)
+    #[serde(default)]
+    pub port_targets: HashMap<u16, String>,
+    /// Resolver for intercepted DNS queries to port 53, e.g. "1.1.1.1:53"
+    #[serde(default)]
+    pub dns_upstream: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AclSettings {
+    /// Clients denied access (CIDR, e.g. "10.0.0.0/8")
+    #[serde(default)]
+    pub deny_clients: Vec<String>,
+    /// Close rejected connections with RST (SO_LINGER = 0) instead of FIN
+    #[serde(default)]
+    pub reject_with_rst: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuntimeSettings {
+    /// Number of tokio worker threads (None - one per CPU)
+    #[serde(default)]
+    pub worker_threads: Option<usize>,
+    /// Limit of threads for blocking tasks (None - tokio default)
+    #[serde(default)]
+    pub max_blocking_threads: Option<usize>,
+    /// CPUs the runtime threads are pinned to round-robin (empty - no pinning)
+    #[serde(default)]
+    pub cpu_affinity: Vec<usize>,
+    /// Seconds to wait for connections to finish on shutdown before closing them forcibly
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    /// How often to log the number of remaining connections during shutdown
+    #[serde(default = "default_shutdown_progress_interval_secs")]
+    pub shutdown_progress_interval_secs: u64,
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+fn default_shutdown_progress_interval_secs() -> u64 {
+    5
+}
+
+impl Default for RuntimeSettings {
+    fn default() -> Self {
+        Self {
+            worker_threads: None,
+            max_blocking_threads: None,
+            cpu_affinity: Vec::new(),
+            shutdown_timeout_secs: default_shutdown_timeout_secs(),
+            shutdown_progress_interval_secs: default_shutdown_progress_interval_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Http2ProxySettings {
+    /// Client h2 is translated to HTTP/1.1 for upstreams without h2 support
+    #[serde(default)]
+    pub h2_downgrade: bool,
+    /// Strip Alt-Svc (HTTP/3) from responses so the client stays on h2
+    #[serde(default)]
+    pub strip_alt_svc: bool,
+    /// PING the upstream after this many ms of idle time, like a browser does (0 - disabled)
+    #[serde(default)]
+    pub keepalive_interval_ms: u64,
+    /// How long to wait for a PING ACK before closing the connection
+    #[serde(default = "default_keepalive_timeout_ms")]
+    pub keepalive_timeout_ms: u64,
+    /// Limit on an upstream response including headers with h2_downgrade:
+    /// the response is buffered whole before being re-encoded as h2
+    #[serde(default = "default_h2_downgrade_max_response")]
+    pub h2_downgrade_max_response: usize,
+}
+
+fn default_keepalive_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_h2_downgrade_max_response() -> usize {
+    16 * 1024 * 1024
+}
+
+impl Default for Http2ProxySettings {
+    fn default() -> Self {
+        Self {
+            h2_downgrade: false,
+            strip_alt_svc: false,
+            keepalive_interval_ms: 0,
+            keepalive_timeout_ms: default_keepalive_timeout_ms(),
+            h2_downgrade_max_response: default_h2_downgrade_max_response(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChallengeSettings {
+    /// Look for challenges in HTTP/1.1 responses (false - responses go straight to the client)
+    #[serde(default = "default_enable_challenge_handling")]
+    pub enable_challenge_handling: bool,
+    /// HTTP statuses treated as a challenge (e.g. 403, 503)
+    #[serde(default)]
+    pub challenge_status_codes: Vec<u16>,
+    /// If not empty, a status counts as a challenge only when the Server header
+    /// contains one of the markers (case-insensitive)
+    #[serde(default)]
+    pub challenge_server_markers: Vec<String>,
+    /// Follow challenge redirects on the same host without the client
+    #[serde(default)]
+    pub follow_redirects: bool,
+    /// Total upstream requests a single client connection may produce
+    /// while following redirects; past that the client gets the last response
+    #[serde(default = "default_max_upstream_requests_per_connection")]
+    pub max_upstream_requests_per_connection: u32,
+}
+
+fn default_max_upstream_requests_per_connection() -> u32 {
+    10
+}
+
+fn default_enable_challenge_handling() -> bool {
+    true
+}
+
+impl Default for ChallengeSettings {
+    fn default() -> Self {
+        Self {
+            enable_challenge_handling: default_enable_challenge_handling(),
+            challenge_status_codes: Vec::new(),
+            challenge_server_markers: Vec::new(),
+            follow_redirects: false,
+            max_upstream_requests_per_connection: default_max_upstream_requests_per_connection(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsSettings {
+    /// Warn if ClientHello fingerprint generation takes longer (microseconds, 0 - don't check)
+    #[serde(default = "default_fingerprint_warn_us")]
+    pub fingerprint_warn_us: u64,
+    /// How long to wait for the full ClientHello after the TLS record header (0 - no limit)
+    #[serde(default = "default_client_hello_timeout_ms")]
+    pub client_hello_timeout_ms: u64,
+    /// Consecutive resets after a rewritten ClientHello that disable the fingerprint for an SNI (0 - never)
+    #[serde(default = "default_fingerprint_failure_threshold")]
+    pub fingerprint_failure_threshold: u32,
+    /// Seconds before the fingerprint is tried again for a disabled target
+    #[serde(default = "default_fingerprint_failure_cooldown_secs")]
+    pub fingerprint_failure_cooldown_secs: u64,
+    /// Log JA3/JA4 of the client and the generated ClientHello
+    #[serde(default)]
+    pub log_ja4: bool,
+    /// Re-check the generated ClientHello with the parser and drop it if broken
+    /// (always enabled in debug builds)
+    #[serde(default)]
+    pub verify_generated_client_hello: bool,
+}
+
+fn default_fingerprint_failure_threshold() -> u32 {
+    3
+}
+
+fn default_fingerprint_failure_cooldown_secs() -> u64 {
+    600
+}
+
+fn default_fingerprint_warn_us() -> u64 {
+    5000
+}
+
+fn default_client_hello_timeout_ms() -> u64 {
+    5000
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        Self {
+            fingerprint_warn_us: default_fingerprint_warn_us(),
+            client_hello_timeout_ms: default_client_hello_timeout_ms(),
+            fingerprint_failure_threshold: default_fingerprint_failure_threshold(),
+            fingerprint_failure_cooldown_secs: default_fingerprint_failure_cooldown_secs(),
+            log_ja4: false,
+            verify_generated_client_hello: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TcpSettings {
+    /// Measure and log the RTT to the upstream (handshake + TCP_INFO)
+    #[serde(default)]
+    pub measure_rtt: bool,
+    /// How long to wait for the first client data (0 - no limit)
+    #[serde(default)]
+    pub empty_connection_timeout_ms: u64,
+    /// How long to wait for the first upstream reply to an HTTP/1.1 request before
+    /// the client gets 504 Gateway Timeout (0 - no limit)
+    #[serde(default)]
+    pub first_response_timeout_ms: u64,
+    /// Maximum concurrent client connections; extra ones are closed right
+    /// after accept (0 - no limit)
+    #[serde(default)]
+    pub max_connections: usize,
+    /// Log level for connections without data ("error", "warn", "info", "debug", "trace")
+    #[serde(default = "default_empty_connection_log_level")]
+    pub empty_connection_log_level: String,
+    /// Content-Length from which an HTTP/1.1 response body, once the headers
+    /// are checked, goes through splice bypassing the buffer (0 - always buffered).
+    /// Responses with Transfer-Encoding are always buffered
+    #[serde(default)]
+    pub zero_copy_threshold: u64,
+    /// How often to tune SO_RCVBUF/SO_SNDBUF of the upstream socket to the
+    /// measured RTT and throughput (0 - don't tune, the default).
+    /// Buffers only grow beyond the size the kernel picked
+    #[serde(default)]
+    pub adaptive_window_interval_ms: u64,
+    /// TCP Fast Open for direct connections to targets: first data goes in the SYN.
+    /// Linux only; without kernel or target support - a regular handshake
+    #[serde(default)]
+    pub tcp_fastopen: bool,
+    /// Read buffer from the client (client -> server)
+    #[serde(default = "default_relay_buffer_size")]
+    pub client_buffer_size: usize,
+    /// Read buffer from the server (server -> client); worth increasing for downloads
+    #[serde(default = "default_relay_buffer_size")]
+    pub server_buffer_size: usize,
+    /// Tunnels without traffic inspection and timing delays go through splice,
+    /// bypassing userspace copies (the buffers above are unused then)
+    #[serde(default = "default_splice_relay")]
+    pub splice_relay: bool,
+    /// After this many bytes from the server the download buffer moves to an anonymous mmap
+    /// with MADV_SEQUENTIAL (Linux only), 0 - always a regular buffer
+    #[serde(default)]
+    pub mmap_buffer_threshold: u64,
+    /// Forward TCP urgent data (MSG_OOB, e.g. telnet) as urgent
+    /// instead of losing it; disables the splice relay
+    #[serde(default)]
+    pub forward_urgent_data: bool,
+    /// TCP_NODELAY on client and upstream sockets: true for interactive
+    /// traffic, false enables Nagle for bulk transfers. A profile can override it
+    #[serde(default = "default_tcp_nodelay")]
+    pub tcp_nodelay: bool,
+    /// Congestion control algorithm for client and upstream sockets ("cubic", "bbr").
+    /// If the kernel doesn't support it, the system default stays
+    #[serde(default = "default_congestion_control")]
+    pub congestion_control: String,
+    /// TCP keepalive with the parameters below on client and upstream
+    /// sockets, so tunnels don't die behind NAT
+    #[serde(default)]
+    pub keepalive: bool,
+    /// Idle seconds before the first keepalive probe
+    #[serde(default = "default_keepalive_idle_secs")]
+    pub keepalive_idle_secs: u64,
+    /// Seconds between probes
+    #[serde(default = "default_keepalive_interval_secs")]
+    pub keepalive_interval_secs: u64,
+    /// Unanswered probes before the connection is dropped
+    #[serde(default = "default_keepalive_count")]
+    pub keepalive_count: u32,
+}
+
+fn default_empty_connection_log_level() -> String {
+    "debug".to_string()
+}
+
+fn default_relay_buffer_size() -> usize {
+    65536
+}
+
+fn default_splice_relay() -> bool {
+    true
+}
+
+fn default_tcp_nodelay() -> bool {
+    true
+}
+
+fn default_congestion_control() -> String {
+    "cubic".to_string()
+}
+
+fn default_keepalive_idle_secs() -> u64 {
+    60
+}
+
+fn default_keepalive_interval_secs() -> u64 {
+    10
+}
+
+fn default_keepalive_count() -> u32 {
+    6
+}
+
+impl Default for TcpSettings {
+    fn default() -> Self {
+        Self {
+            measure_rtt: false,
+            empty_connection_timeout_ms: 0,
+            first_response_timeout_ms: 0,
+            max_connections: 0,
+            empty_connection_log_level: default_empty_connection_log_level(),
+            zero_copy_threshold: 0,
+            adaptive_window_interval_ms: 0,
+            tcp_fastopen: false,
+            client_buffer_size: default_relay_buffer_size(),
+            server_buffer_size: default_relay_buffer_size(),
+            splice_relay: default_splice_relay(),
+            mmap_buffer_threshold: 0,
+            forward_urgent_data: false,
+            tcp_nodelay: default_tcp_nodelay(),
+            congestion_control: default_congestion_control(),
+            keepalive: false,
+            keepalive_idle_secs: default_keepalive_idle_secs(),
+            keepalive_interval_secs: default_keepalive_interval_secs(),
+            keepalive_count: default_keepalive_count(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProxySettings {
     pub proxy_host: String,
     pub proxy_port: u16,
-    pub proxy_type: String, // "socks5", "http", "https", "direct"
+    pub proxy_type: String, // "socks5", "socks4", "http", "https", "direct"
     pub username: Option<String>,
     pub password: Option<String>,
+    /// Pass the host name to the upstream proxy instead of a locally resolved IP
+    #[serde(default = "default_remote_dns")]
+    pub remote_dns: bool,
+    /// Limit on connecting to the upstream, proxy handshake included (0 - no limit)
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    /// SOCKS5: fail if the proxy doesn't require authentication although username/password are set
+    #[serde(default)]
+    pub require_auth: bool,
+    /// Hop-by-hop headers stripped from requests when converting to origin-form (direct);
+    /// with "Connection" the headers it lists are stripped too. Transfer-Encoding
+    /// isn't needed here: the body is forwarded as is and its framing must be kept
+    #[serde(default = "default_hop_by_hop_headers")]
+    pub hop_by_hop_headers: Vec<String>,
+}
+
+fn default_remote_dns() -> bool {
+    true
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_hop_by_hop_headers() -> Vec<String> {
+    ["Proxy-Connection", "Proxy-Authorization", "Connection", "Keep-Alive", "TE", "Trailer", "Upgrade"]
+        .iter()
+        .map(|name| name.to_string())
+        .collect()
 }
 
 impl Default for ProxySettings {
@@ -27,11 +502,19 @@ impl Default for ProxySettings {
             proxy_type: "socks5".to_string(),
             username: None,
             password: None,
+            remote_dns: default_remote_dns(),
+            connect_timeout_ms: default_connect_timeout_ms(),
+            require_auth: false,
+            hop_by_hop_headers: default_hop_by_hop_headers(),
         }
     }
 }
 
 impl ProxySettings {
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        (self.connect_timeout_ms > 0).then(|| Duration::from_millis(self.connect_timeout_ms))
+    }
+
     pub fn is_direct(&self) -> bool {
         self.proxy_type.to_lowercase() == "direct"
     }
@@ -48,14 +531,67 @@ pub struct FingerprintProfile {
     pub key_share_groups: Vec<String>,
     pub psk_key_exchange_modes: Vec<String>,
     pub compress_certificate: Vec<String>,
+    /// Size of the first TLS record when fragmenting the ClientHello (None - one record)
+    #[serde(default)]
+    pub record_fragment_size: Option<usize>,
+    /// GREASE values are derived from the connection id instead of random
+    #[serde(default)]
+    pub grease_per_connection: bool,
+    /// GREASE as the first cipher suite (None - yes, like iOS Safari)
+    #[serde(default)]
+    pub grease_cipher_first: Option<bool>,
+    /// GREASE as the first extension (None - yes)
+    #[serde(default)]
+    pub grease_extension_first: Option<bool>,
+    /// GREASE as the last extension, before pre_shared_key (None - yes)
+    #[serde(default)]
+    pub grease_extension_last: Option<bool>,
+    /// ClientHello alignment boundary via the padding extension, 512 for Safari
+    /// (None or 0 - no padding)
+    #[serde(default)]
+    pub padding_boundary: Option<usize>,
+    /// Add GREASE ECH if the client didn't send encrypted_client_hello
+    #[serde(default)]
+    pub ech_grease: bool,
+    /// HTTP/2 SETTINGS in the order the browser sends them
+    #[serde(default)]
+    pub http2_settings: Vec<Http2SettingEntry>,
+    /// IP TTL (hop limit for IPv6) of upstream connections (None - 64, like iOS)
+    #[serde(default)]
+    pub ttl: Option<u8>,
+    /// MSS in the SYN of upstream connections (None - 1460)
+    #[serde(default)]
+    pub mss: Option<u16>,
+    /// TCP timestamps in the SYN under packet interception (None - as the kernel does; they can only be removed, not added)
+    #[serde(default)]
+    pub tcp_timestamps: Option<bool>,
+    /// TCP_NODELAY for connections with this profile (None - tcp_settings.tcp_nodelay)
+    #[serde(default)]
+    pub tcp_nodelay: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Http2SettingEntry {
+    /// Name without the SETTINGS_ prefix, e.g. "INITIAL_WINDOW_SIZE"
+    pub name: String,
+    pub value: u32,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            profiles: vec![Self::default_ios_safari_profile()],
-            default_profile: "ios_safari".to_string(),
+            profiles: Self::builtin_profiles().to_vec(),
+            default_profile: LATEST_IOS_PROFILE.to_string(),
             proxy_settings: ProxySettings::default(),
+            tcp_settings: TcpSettings::default(),
+            tls_settings: TlsSettings::default(),
+            challenge_settings: ChallengeSettings::default(),
+            http2_settings: Http2ProxySettings::default(),
+            runtime_settings: RuntimeSettings::default(),
+            acl_settings: AclSettings::default(),
+            udp_settings: UdpSettings::default(),
+            pool_settings: PoolSettings::default(),
+            timing_settings: TimingSettings::default(),
         }
     }
 }
@@ -64,26 +600,137 @@ impl Config {
     pub fn load(path: &str) -> Result<Self> {
         let content = fs::read_to_string(path)?;
         let config: Config = serde_json::from_str(&content)?;
+        config.validate()?;
         Ok(config)
     }
 
+    /// Values the proxy can't work with are rejected at load time,
+    /// not on the first connection
+    pub fn validate(&self) -> Result<()> {
+        let online = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+        let cpu_limit = if online > 0 {
+            (online as usize).min(libc::CPU_SETSIZE as usize)
+        } else {
+            libc::CPU_SETSIZE as usize
+        };
+        if let Some(cpu) = self.runtime_settings.cpu_affinity.iter().find(|&&cpu| cpu >= cpu_limit) {
+            return Err(anyhow::anyhow!(
+                "runtime_settings.cpu_affinity: CPU {} is out of range (0..{})", cpu, cpu_limit
+            ));
+        }
+
+        // The kernel rejects TTL 0 and MSS below 88 - otherwise every upstream socket would fail
+        for profile in &self.profiles {
+            if profile.ttl == Some(0) {
+                return Err(anyhow::anyhow!("profile {}: ttl must be 1..=255", profile.name));
+            }
+            if let Some(mss) = profile.mss.filter(|&mss| mss < MIN_MSS) {
+                return Err(anyhow::anyhow!("profile {}: mss {} is below {}", profile.name, mss, MIN_MSS));
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn save(&self, path: &str) -> Result<()> {
         let content = serde_json::to_string_pretty(self)?;
         fs::write(path, content)?;
         Ok(())
     }
 
+    /// Profile from the config, else the built-in one with the same name
     pub fn get_profile(&self, name: &str) -> Option<&FingerprintProfile> {
         self.profiles.iter().find(|p| p.name == name)
+            .or_else(|| Self::builtin_profile(name))
+    }
+
+    pub fn builtin_profiles() -> &'static [FingerprintProfile] {
+        &BUILTIN_PROFILES
+    }
+
+    pub fn builtin_profile(name: &str) -> Option<&'static FingerprintProfile> {
+        let name = if name == "ios_safari" { LATEST_IOS_PROFILE } else { name };
+        BUILTIN_PROFILES.iter().find(|p| p.name == name)
     }
 
     pub fn get_default_profile(&self) -> Option<&FingerprintProfile> {
         self.get_profile(&self.default_profile)
     }
 
-    fn default_ios_safari_profile() -> FingerprintProfile {
+    /// iOS 16: no compress_certificate, with ECDHE_RSA suites, SETTINGS without
+    /// ENABLE_PUSH and NO_RFC7540_PRIORITIES
+    fn ios_16_safari_profile() -> FingerprintProfile {
         FingerprintProfile {
-            name: "ios_safari".to_string(),
+            name: "ios_16_safari".to_string(),
+            cipher_suites: vec![
+                "TLS_AES_128_GCM_SHA256".to_string(),
+                "TLS_AES_256_GCM_SHA384".to_string(),
+                "TLS_CHACHA20_POLY1305_SHA256".to_string(),
+                "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384".to_string(),
+                "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256".to_string(),
+                "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256".to_string(),
+                "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384".to_string(),
+                "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_string(),
+            ],
+            extensions: vec![
+                "server_name".to_string(),
+                "status_request".to_string(),
+                "supported_groups".to_string(),
+                "ec_point_formats".to_string(),
+                "signature_algorithms".to_string(),
+                "application_layer_protocol_negotiation".to_string(),
+                "signed_certificate_timestamp".to_string(),
+                "key_share".to_string(),
+                "psk_key_exchange_modes".to_string(),
+                "supported_versions".to_string(),
+                "session_ticket".to_string(),
+            ],
+            supported_versions: vec![
+                "TLS 1.3".to_string(),
+                "TLS 1.2".to_string(),
+            ],
+            alpn: vec![
+                "h2".to_string(),
+                "http/1.1".to_string(),
+            ],
+            signature_algorithms: vec![
+                "ecdsa_secp256r1_sha256".to_string(),
+                "rsa_pss_rsae_sha256".to_string(),
+                "rsa_pkcs1_sha256".to_string(),
+                "ecdsa_secp384r1_sha384".to_string(),
+                "ecdsa_sha1".to_string(),
+                "rsa_pkcs1_sha1".to_string(),
+            ],
+            key_share_groups: vec![
+                "x25519".to_string(),
+                "secp256r1".to_string(),
+            ],
+            psk_key_exchange_modes: vec![
+                "psk_dhe_ke".to_string(),
+            ],
+            compress_certificate: vec![],
+            record_fragment_size: None,
+            grease_per_connection: false,
+            grease_cipher_first: None,
+            grease_extension_first: None,
+            grease_extension_last: None,
+            padding_boundary: None,
+            ech_grease: false,
+            http2_settings: vec![
+                Http2SettingEntry { name: "MAX_CONCURRENT_STREAMS".to_string(), value: 100 },
+                Http2SettingEntry { name: "INITIAL_WINDOW_SIZE".to_string(), value: 4194304 },
+            ],
+            ttl: None,
+            mss: None,
+            tcp_timestamps: None,
+            tcp_nodelay: None,
+        }
+    }
+
+    /// iOS 17: brotli compress_certificate, ENABLE_PUSH=0 and NO_RFC7540_PRIORITIES
+    fn ios_17_safari_profile() -> FingerprintProfile {
+        FingerprintProfile {
+            name: "ios_17_safari".to_string(),
             cipher_suites: vec![
                 "TLS_AES_128_GCM_SHA256".to_string(),
                 "TLS_AES_256_GCM_SHA384".to_string(),
@@ -131,19 +778,109 @@ impl Config {
             compress_certificate: vec![
                 "brotli".to_string(),
             ],
+            record_fragment_size: None,
+            grease_per_connection: false,
+            grease_cipher_first: None,
+            grease_extension_first: None,
+            grease_extension_last: None,
+            padding_boundary: None,
+            ech_grease: false,
+            http2_settings: vec![
+                Http2SettingEntry { name: "ENABLE_PUSH".to_string(), value: 0 },
+                Http2SettingEntry { name: "MAX_CONCURRENT_STREAMS".to_string(), value: 100 },
+                Http2SettingEntry { name: "INITIAL_WINDOW_SIZE".to_string(), value: 2097152 },
+                Http2SettingEntry { name: "NO_RFC7540_PRIORITIES".to_string(), value: 1 },
+            ],
+            ttl: None,
+            mss: None,
+            tcp_timestamps: None,
+            tcp_nodelay: None,
         }
     }
 }
 
+impl FingerprintProfile {
+    /// JA3 string (before MD5) of the ClientHello built from the profile
+    pub fn ja3_string(&self) -> Result<String> {
+        let record = self.build_client_hello("example.com", &mut StdRng::seed_from_u64(0))?;
+        Ok(TlsClientHello::parse(&record)?.ja3_string())
+    }
+
+    /// ClientHello generation parameters from the profile; `grease` is used
+    /// unless the profile requires GREASE from the connection id
+    pub fn client_hello_options(&self, grease: GreaseMode, conn_id: u64) -> ClientHelloOptions {
+        let mut options = ClientHelloOptions {
+            grease: if self.grease_per_connection { GreaseMode::PerConnection(conn_id) } else { grease },
+            grease_positions: GreasePositions {
+                cipher_first: self.grease_cipher_first.unwrap_or(true),
+                extension_first: self.grease_extension_first.unwrap_or(true),
+                extension_last: self.grease_extension_last.unwrap_or(true),
+            },
+            ech_grease: self.ech_grease,
+            cipher_suites: self.cipher_suites.iter().filter_map(|name| cipher_suite_id(name)).collect(),
+            extension_order: self.extensions.iter().filter_map(|name| extension_id(name)).collect(),
+            ..ClientHelloOptions::default()
+        };
+        if let Some(boundary) = self.padding_boundary {
+            options.padding_boundary = boundary;
+        }
+
+        let version_order: Vec<u16> = self.supported_versions.iter()
+            .filter_map(|name| parse_version_name(name))
+            .collect();
+        if !version_order.is_empty() {
+            options.version_order = version_order;
+        }
+
+        options
+    }
+
+    /// Complete TLS record with a ClientHello from the profile alone, without a client
+    /// ClientHello (synthetic tests, fingerprint dumps)
+    pub fn build_client_hello<R: Rng>(&self, sni: &str, rng: &mut R) -> Result<Vec<u8>> {
+        let hello = TlsClientHello::from_profile(self, sni, rng)?;
+        let options = self.client_hello_options(GreaseMode::PerConnection(rng.random()), 0);
+        hello.to_ios_safari(sni, &options)
+    }
+
+    /// SETTINGS in send order, as in the Akamai h2 fingerprint ("NAME:value;...")
+    pub fn http2_fingerprint(&self) -> String {
+        self.http2_settings.iter()
+            .map(|entry| format!("{}:{}", entry.name, entry.value))
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_validate_rejects_unusable_values() {
+        let mut config = Config::default();
+        config.validate().unwrap();
+
+        config.runtime_settings.cpu_affinity = vec![0];
+        config.validate().unwrap();
+        config.runtime_settings.cpu_affinity = vec![0, libc::CPU_SETSIZE as usize];
+        assert!(config.validate().is_err());
+        config.runtime_settings.cpu_affinity.clear();
+
+        config.profiles[0].mss = Some(MIN_MSS);
+        config.validate().unwrap();
+        config.profiles[0].mss = Some(MIN_MSS - 1);
+        assert!(config.validate().is_err());
+        config.profiles[0].mss = None;
+        config.profiles[0].ttl = Some(0);
+        assert!(config.validate().is_err());
+    }
+
     #[test]
     fn test_default_config() {
         let config = Config::default();
-        assert_eq!(config.default_profile, "ios_safari");
-        assert_eq!(config.profiles.len(), 1);
+        assert_eq!(config.default_profile, LATEST_IOS_PROFILE);
+        assert_eq!(config.profiles.len(), Config::builtin_profiles().len());
         assert_eq!(config.proxy_settings.proxy_host, "127.0.0.1");
     }
 
@@ -152,7 +889,66 @@ mod tests {
         let config = Config::default();
         let profile = config.get_profile("ios_safari");
         assert!(profile.is_some());
-        assert_eq!(profile.unwrap().name, "ios_safari");
+        assert_eq!(profile.unwrap().name, LATEST_IOS_PROFILE);
+
+        // A built-in profile is picked by name even when it's not in the config
+        let config = Config { profiles: Vec::new(), ..Config::default() };
+        assert_eq!(config.get_profile("ios_16_safari").unwrap().name, "ios_16_safari");
+        assert!(config.get_profile("android_chrome").is_none());
+    }
+
+    #[test]
+    fn test_builtin_profiles_distinct() {
+        let profiles = Config::builtin_profiles();
+        assert!(profiles.len() >= 2);
+
+        for profile in profiles {
+            assert!(profile.supported_versions.iter()
+                .all(|v| crate::tls::parse_version_name(v).is_some()));
+            assert!(profile.http2_settings.iter()
+                .all(|e| crate::http2_advanced::setting_id(&e.name).is_some()));
+            assert_eq!(profile.ja3_string().unwrap(), profile.clone().ja3_string().unwrap());
+            assert_eq!(profile.extensions.contains(&"compress_certificate".to_string()),
+                !profile.compress_certificate.is_empty());
+        }
+
+        for (i, a) in profiles.iter().enumerate() {
+            for b in &profiles[i + 1..] {
+                assert_ne!(a.name, b.name);
+                assert_ne!(a.ja3_string().unwrap(), b.ja3_string().unwrap());
+                assert_ne!(a.http2_fingerprint(), b.http2_fingerprint());
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_client_hello_from_profile() {
+        use crate::tls::{cipher_suite_id, extension_id, is_grease};
+        use rand::SeedableRng;
+
+        let profile = Config::builtin_profile("ios_17_safari").unwrap();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let record = profile.build_client_hello("example.net", &mut rng).unwrap();
+
+        let hello = TlsClientHello::verify_generated(&record, "example.net").unwrap();
+        assert_eq!(hello.server_name().as_deref(), Some("example.net"));
+        assert_eq!(hello.session_id.len(), 32);
+
+        let ciphers: Vec<u16> = hello.cipher_suites.iter().copied().filter(|c| !is_grease(*c)).collect();
+        let expected: Vec<u16> = profile.cipher_suites.iter().filter_map(|name| cipher_suite_id(name)).collect();
+        assert_eq!(ciphers, expected);
+
+        let extensions: Vec<u16> = hello.extensions.iter()
+            .map(|ext| ext.extension_type)
+            .filter(|t| !is_grease(*t) && *t != 21)
+            .collect();
+        let expected: Vec<u16> = profile.extensions.iter().filter_map(|name| extension_id(name)).collect();
+        assert_eq!(extensions, expected);
+        assert!(hello.ja4_string().split('_').next().unwrap().ends_with("h2"));
+
+        let mut broken = profile.clone();
+        broken.cipher_suites.push("TLS_NOT_A_CIPHER".to_string());
+        assert!(broken.build_client_hello("example.net", &mut rng).is_err());
     }
 
     #[test]
@@ -1,6 +1,8 @@
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 use tokio::sync::{RwLock, Notify};
+use tokio::signal::unix::{signal, Signal, SignalKind};
 use tokio::time::{sleep, timeout};
 use std::collections::HashMap;
 use anyhow::Result;
@@ -8,13 +10,16 @@ use anyhow::Result;
 const MAX_RETRIES: u32 = 3;
 const RETRY_BACKOFF_MS: u64 = 100;
 const SHUTDOWN_TIMEOUT_SEC: u64 = 30;
+const DRAIN_PROGRESS_INTERVAL_SEC: u64 = 5;
 const CONNECTION_TIMEOUT_SEC: u64 = 60;
 
 #[derive(Clone, Debug)]
 pub struct ConnectionState {
     pub id: u64,
     pub established_at: Instant,
-    pub last_activity: Instant,
+    /// Milliseconds after established_at, so relay loops can bump it
+    /// under the shared read lock
+    last_activity_ms: Arc<AtomicU64>,
     pub retry_count: u32,
     pub is_closing: bool,
 }
@@ -25,18 +30,23 @@ impl ConnectionState {
         Self {
             id,
             established_at: now,
-            last_activity: now,
+            last_activity_ms: Arc::new(AtomicU64::new(0)),
             retry_count: 0,
             is_closing: false,
         }
     }
 
-    pub fn mark_activity(&mut self) {
-        self.last_activity = Instant::now();
+    pub fn mark_activity(&self) {
+        let elapsed = self.established_at.elapsed().as_millis() as u64;
+        self.last_activity_ms.store(elapsed, Ordering::Relaxed);
+    }
+
+    pub fn last_activity(&self) -> Instant {
+        self.established_at + Duration::from_millis(self.last_activity_ms.load(Ordering::Relaxed))
     }
 
     pub fn is_idle(&self, timeout: Duration) -> bool {
-        self.last_activity.elapsed() > timeout
+        self.last_activity().elapsed() > timeout
     }
 
     pub fn should_retry(&self) -> bool {
@@ -51,7 +61,10 @@ impl ConnectionState {
 pub struct GracefulShutdown {
     connections: Arc<RwLock<HashMap<u64, ConnectionState>>>,
     shutdown_notify: Arc<Notify>,
-    is_shutting_down: Arc<RwLock<bool>>,
+    /// Checked on every proxy loop iteration, so an atomic rather than a lock
+    is_shutting_down: Arc<AtomicBool>,
+    shutdown_timeout: Duration,
+    progress_interval: Duration,
 }
 
 impl GracefulShutdown {
@@ -59,10 +72,20 @@ impl GracefulShutdown {
         Self {
             connections: Arc::new(RwLock::new(HashMap::new())),
             shutdown_notify: Arc::new(Notify::new()),
-            is_shutting_down: Arc::new(RwLock::new(false)),
+            is_shutting_down: Arc::new(AtomicBool::new(false)),
+            shutdown_timeout: Duration::from_secs(SHUTDOWN_TIMEOUT_SEC),
+            progress_interval: Duration::from_secs(DRAIN_PROGRESS_INTERVAL_SEC),
         }
     }
 
+    /// How long graceful_close_all drains before force-closing, and how often
+    /// it logs the remaining connection count meanwhile
+    pub fn with_shutdown_timeout(mut self, timeout: Duration, progress_interval: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self.progress_interval = progress_interval;
+        self
+    }
+
     pub async fn register_connection(&self, id: u64) {
         let state = ConnectionState::new(id);
         self.connections.write().await.insert(id, state);
@@ -72,29 +95,46 @@ impl GracefulShutdown {
         self.connections.write().await.remove(&id);
     }
 
+    /// Called for every relayed chunk, so only the read lock is taken
     pub async fn mark_activity(&self, id: u64) {
-        if let Some(state) = self.connections.write().await.get_mut(&id) {
+        if let Some(state) = self.connections.read().await.get(&id) {
             state.mark_activity();
         }
     }
 
     pub async fn initiate_shutdown(&self) {
-        *self.is_shutting_down.write().await = true;
-        self.shutdown_notify.notify_waiters();
+        self.is_shutting_down.store(true, Ordering::Release);
+        self.shutdown_notify.notify_one();
     }
 
-    pub async fn is_shutting_down(&self) -> bool {
-        *self.is_shutting_down.read().await
+    pub fn is_shutting_down(&self) -> bool {
+        self.is_shutting_down.load(Ordering::Acquire)
     }
 
+    /// Resolves once shutdown has started, also when it started before the call.
+    /// The waiter is registered before the flag is checked, and each woken
+    /// waiter hands the notification on to the next one
     pub async fn wait_for_shutdown(&self) {
-        self.shutdown_notify.notified().await;
+        let notified = self.shutdown_notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if self.is_shutting_down() {
+            return;
+        }
+
+        notified.await;
+        self.shutdown_notify.notify_one();
+    }
+
+    /// Upper bound for draining in-flight work after shutdown starts
+    pub fn shutdown_timeout(&self) -> Duration {
+        self.shutdown_timeout
     }
 
     pub async fn graceful_close_all(&self) -> Result<()> {
-        let timeout_duration = Duration::from_secs(SHUTDOWN_TIMEOUT_SEC);
+        let started = Instant::now();
         
-        let result = timeout(timeout_duration, async {
+        let result = timeout(self.shutdown_timeout, async {
             let mut connections = self.connections.write().await;
             for state in connections.values_mut() {
                 state.is_closing = true;
@@ -102,11 +142,16 @@ impl GracefulShutdown {
             
             drop(connections);
             
+            let mut last_report = Instant::now();
             loop {
                 let count = self.connections.read().await.len();
                 if count == 0 {
                     break;
                 }
+                if last_report.elapsed() >= self.progress_interval {
+                    log::info!("Draining: {} connections remaining after {:?}", count, started.elapsed());
+                    last_report = Instant::now();
+                }
                 sleep(Duration::from_millis(100)).await;
             }
         }).await;
@@ -151,6 +196,31 @@ impl GracefulShutdown {
     }
 }
 
+/// SIGINT from a terminal and SIGTERM from systemd/Kubernetes both start a
+/// graceful shutdown. Handlers are installed in new(), so a signal arriving
+/// before recv() is polled is not lost
+pub struct ShutdownSignals {
+    interrupt: Signal,
+    terminate: Signal,
+}
+
+impl ShutdownSignals {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+        })
+    }
+
+    /// Waits for the first shutdown signal and returns its name
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.interrupt.recv() => "SIGINT",
+            _ = self.terminate.recv() => "SIGTERM",
+        }
+    }
+}
+
 pub struct ConnectionRecovery {
     max_retries: u32,
     backoff_ms: u64,
@@ -164,10 +234,21 @@ impl ConnectionRecovery {
         }
     }
 
-    pub async fn retry_with_backoff<F, Fut, T>(&self, mut operation: F) -> Result<T>
+    pub async fn retry_with_backoff<F, Fut, T>(&self, operation: F) -> Result<T>
     where
         F: FnMut() -> Fut,
         Fut: std::future::Future<Output = Result<T>>,
+    {
+        self.retry_with_backoff_if(operation, |_| true).await
+    }
+
+    /// Like `retry_with_backoff`, but gives up at once on errors that
+    /// `should_retry` classifies as permanent
+    pub async fn retry_with_backoff_if<F, Fut, T, R>(&self, mut operation: F, should_retry: R) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Result<T>>,
+        R: Fn(&anyhow::Error) -> bool,
     {
         let mut last_error = None;
         
@@ -175,6 +256,10 @@ impl ConnectionRecovery {
             match operation().await {
                 Ok(result) => return Ok(result),
                 Err(e) => {
+                    if !should_retry(&e) {
+                        log::debug!("Not retrying permanent error: {}", e);
+                        return Err(e);
+                    }
                     last_error = Some(e);
                     
                     if attempt < self.max_retries - 1 {
@@ -270,6 +355,117 @@ mod tests {
         assert_eq!(gs.get_active_connections().await, 1);
     }
 
+    #[tokio::test]
+    async fn test_wait_for_shutdown_wakes_every_waiter() {
+        let gs = Arc::new(GracefulShutdown::new());
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let gs = gs.clone();
+                tokio::spawn(async move { gs.wait_for_shutdown().await })
+            })
+            .collect();
+        tokio::task::yield_now().await;
+
+        gs.initiate_shutdown().await;
+        for waiter in waiters {
+            timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+        }
+
+        // Started before the call, still resolves
+        timeout(Duration::from_secs(1), gs.wait_for_shutdown()).await.unwrap();
+    }
+
+    static LOGGED: parking_lot::Mutex<Vec<String>> = parking_lot::Mutex::new(Vec::new());
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOGGED.lock().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLogger = CaptureLogger;
+
+    #[tokio::test]
+    async fn test_shutdown_force_closes_after_timeout() {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Info);
+
+        let gs = GracefulShutdown::new()
+            .with_shutdown_timeout(Duration::from_millis(350), Duration::from_millis(100));
+        gs.register_connection(1).await;
+        gs.register_connection(2).await;
+
+        let started = Instant::now();
+        gs.graceful_close_all().await.unwrap();
+        let elapsed = started.elapsed();
+
+        assert!(elapsed >= Duration::from_millis(350));
+        assert!(elapsed < Duration::from_secs(5));
+        assert_eq!(gs.get_active_connections().await, 0);
+
+        let logged = LOGGED.lock();
+        assert!(logged.iter().any(|line| line.starts_with("Draining: 2 connections remaining")));
+        assert!(logged.iter().any(|line| line == "Shutdown timeout: 2 connections remaining"));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_flag_observed_without_runtime() {
+        let gs = Arc::new(GracefulShutdown::new());
+        assert!(!gs.is_shutting_down());
+
+        // A plain thread can only poll the flag if checking it never awaits
+        let watcher = {
+            let gs = gs.clone();
+            std::thread::spawn(move || {
+                let started = Instant::now();
+                while !gs.is_shutting_down() {
+                    assert!(started.elapsed() < Duration::from_secs(1));
+                    std::hint::spin_loop();
+                }
+            })
+        };
+
+        gs.initiate_shutdown().await;
+        assert!(gs.is_shutting_down());
+        watcher.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sigterm_drains_connections() {
+        let mut signals = ShutdownSignals::new().unwrap();
+        let gs = Arc::new(GracefulShutdown::new()
+            .with_shutdown_timeout(Duration::from_secs(5), Duration::from_secs(1)));
+        gs.register_connection(1).await;
+
+        // The connection finishes on its own once shutdown starts
+        let conn = {
+            let gs = gs.clone();
+            tokio::spawn(async move {
+                while !gs.is_shutting_down() {
+                    sleep(Duration::from_millis(10)).await;
+                }
+                gs.unregister_connection(1).await;
+            })
+        };
+
+        unsafe { libc::raise(libc::SIGTERM) };
+        let name = timeout(Duration::from_secs(1), signals.recv()).await.unwrap();
+        assert_eq!(name, "SIGTERM");
+
+        gs.initiate_shutdown().await;
+        gs.graceful_close_all().await.unwrap();
+        assert_eq!(gs.get_active_connections().await, 0);
+        conn.await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_connection_recovery() {
         let recovery = ConnectionRecovery::new();
@@ -288,12 +484,31 @@ mod tests {
         assert_eq!(attempt, 3);
     }
 
+    #[tokio::test]
+    async fn test_permanent_error_not_retried() {
+        let recovery = ConnectionRecovery::new();
+        let mut attempt = 0;
+
+        let result: Result<()> = recovery.retry_with_backoff_if(|| {
+            attempt += 1;
+            async { Err(anyhow::anyhow!("Host unreachable")) }
+        }, |e| !e.to_string().contains("unreachable")).await;
+
+        assert!(result.is_err());
+        assert_eq!(attempt, 1);
+    }
+
     #[test]
     fn test_connection_state() {
         let mut state = ConnectionState::new(1);
         
         assert!(!state.is_idle(Duration::from_secs(10)));
         assert!(state.should_retry());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(state.is_idle(Duration::from_millis(20)));
+        state.mark_activity();
+        assert!(!state.is_idle(Duration::from_millis(20)));
         
         state.increment_retry();
         state.increment_retry();
@@ -0,0 +1,883 @@
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+
+/// Per-entry overhead from RFC 7541 section 4.1.
+const ENTRY_OVERHEAD: usize = 32;
+
+/// Default SETTINGS_HEADER_TABLE_SIZE before the peer says otherwise.
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+const EOS: u16 = 256;
+
+/// RFC 7541 Appendix A.
+pub const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// RFC 7541 Appendix B: (code, bit length) for every symbol including EOS.
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28),
+    (0xfffffe4, 28), (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28),
+    (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28),
+    (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28),
+    (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28),
+    (0xffffff8, 28), (0xffffff9, 28), (0xffffffa, 28), (0xffffffb, 28),
+    (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11),
+    (0x3fa, 10), (0x3fb, 10), (0xf9, 8), (0x7fb, 11),
+    (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6),
+    (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6),
+    (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10),
+    (0x1ffa, 13), (0x21, 6), (0x5d, 7), (0x5e, 7),
+    (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7),
+    (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7),
+    (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7),
+    (0xfc, 8), (0x73, 7), (0xfd, 8), (0x1ffb, 13),
+    (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5),
+    (0x24, 6), (0x5, 5), (0x25, 6), (0x26, 6),
+    (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5),
+    (0x2b, 6), (0x76, 7), (0x2c, 6), (0x8, 5),
+    (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15),
+    (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28),
+    (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23),
+    (0x3fffd6, 22), (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23),
+    (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23),
+    (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23),
+    (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24),
+    (0x3fffda, 22), (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22),
+    (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24),
+    (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23),
+    (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23),
+    (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22),
+    (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19),
+    (0x3fffe7, 22), (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25),
+    (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25),
+    (0x7fff2, 19), (0x1fffe3, 21), (0x3ffffe6, 26), (0x7ffffe0, 27),
+    (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26),
+    (0xffffffd, 28), (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27),
+    (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23),
+    (0x3fffea, 22), (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25),
+    (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26),
+    (0x7ffffe7, 27), (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27),
+    (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+/// Binary decoding tree built from `HUFFMAN_CODES`: each node holds its two
+/// children (index into the vec) or a leaf symbol.
+#[derive(Clone, Copy)]
+enum HuffmanNode {
+    Branch([usize; 2]),
+    Leaf(u16),
+}
+
+static HUFFMAN_TREE: Lazy<Vec<HuffmanNode>> = Lazy::new(|| {
+    let mut nodes = vec![HuffmanNode::Branch([0, 0])];
+
+    for (symbol, &(code, bits)) in HUFFMAN_CODES.iter().enumerate() {
+        let mut current = 0;
+        for i in (0..bits).rev() {
+            let bit = ((code >> i) & 1) as usize;
+            let next = match nodes[current] {
+                HuffmanNode::Branch(children) => children[bit],
+                HuffmanNode::Leaf(_) => unreachable!("huffman code is a prefix of another"),
+            };
+
+            if next != 0 {
+                current = next;
+                continue;
+            }
+
+            let node = if i == 0 {
+                HuffmanNode::Leaf(symbol as u16)
+            } else {
+                HuffmanNode::Branch([0, 0])
+            };
+            nodes.push(node);
+            let created = nodes.len() - 1;
+            if let HuffmanNode::Branch(children) = &mut nodes[current] {
+                children[bit] = created;
+            }
+            current = created;
+        }
+    }
+
+    nodes
+});
+
+pub fn huffman_encoded_len(data: &[u8]) -> usize {
+    let bits: usize = data.iter().map(|&b| HUFFMAN_CODES[b as usize].1 as usize).sum();
+    bits.div_ceil(8)
+}
+
+pub fn huffman_encode(data: &[u8], out: &mut Vec<u8>) {
+    let mut acc: u64 = 0;
+    let mut acc_bits = 0u32;
+
+    for &byte in data {
+        let (code, bits) = HUFFMAN_CODES[byte as usize];
+        acc = (acc << bits) | code as u64;
+        acc_bits += bits as u32;
+
+        while acc_bits >= 8 {
+            acc_bits -= 8;
+            out.push((acc >> acc_bits) as u8);
+        }
+    }
+
+    if acc_bits > 0 {
+        // Pad with the most significant bits of EOS (all ones)
+        let pad = 8 - acc_bits;
+        out.push(((acc << pad) as u8) | ((1u8 << pad) - 1));
+    }
+}
+
+pub fn huffman_decode(data: &[u8]) -> Result<Vec<u8>> {
+    let tree = &*HUFFMAN_TREE;
+    let mut out = Vec::with_capacity(data.len() * 8 / 5);
+    let mut current = 0;
+    let mut depth = 0u32;
+    let mut padding_ones = true;
+
+    for &byte in data {
+        for i in (0..8).rev() {
+            let bit = ((byte >> i) & 1) as usize;
+            padding_ones &= bit == 1;
+            depth += 1;
+
+            let next = match tree[current] {
+                HuffmanNode::Branch(children) => children[bit],
+                HuffmanNode::Leaf(_) => unreachable!(),
+            };
+            if next == 0 {
+                return Err(anyhow::anyhow!("Invalid huffman code"));
+            }
+
+            match tree[next] {
+                HuffmanNode::Leaf(EOS) => {
+                    return Err(anyhow::anyhow!("EOS in huffman string"));
+                }
+                HuffmanNode::Leaf(symbol) => {
+                    out.push(symbol as u8);
+                    current = 0;
+                    depth = 0;
+                    padding_ones = true;
+                }
+                HuffmanNode::Branch(_) => current = next,
+            }
+        }
+    }
+
+    if depth > 7 || !padding_ones {
+        return Err(anyhow::anyhow!("Invalid huffman padding"));
+    }
+
+    Ok(out)
+}
+
+/// Integer representation from RFC 7541 section 5.1. `flags` carries the
+/// bits above the prefix in the first octet.
+pub fn encode_integer(value: usize, prefix_bits: u8, flags: u8, out: &mut Vec<u8>) {
+    let max_prefix = (1usize << prefix_bits) - 1;
+
+    if value < max_prefix {
+        out.push(flags | value as u8);
+        return;
+    }
+
+    out.push(flags | max_prefix as u8);
+    let mut rest = value - max_prefix;
+    while rest >= 128 {
+        out.push((rest % 128) as u8 | 0x80);
+        rest /= 128;
+    }
+    out.push(rest as u8);
+}
+
+/// Returns the decoded integer and the number of bytes consumed.
+pub fn decode_integer(data: &[u8], prefix_bits: u8) -> Result<(usize, usize)> {
+    let first = *data.first().ok_or_else(|| anyhow::anyhow!("Truncated integer"))?;
+    let max_prefix = (1usize << prefix_bits) - 1;
+    let mut value = first as usize & max_prefix;
+
+    if value < max_prefix {
+        return Ok((value, 1));
+    }
+
+    let mut shift = 0u32;
+    for (i, &byte) in data[1..].iter().enumerate() {
+        if shift > 28 {
+            return Err(anyhow::anyhow!("Integer overflow"));
+        }
+        value += ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 2));
+        }
+    }
+
+    Err(anyhow::anyhow!("Truncated integer"))
+}
+
+/// String literal from RFC 7541 section 5.2. Huffman is used only when it
+/// actually makes the string shorter, like browsers do.
+pub fn encode_string(data: &[u8], huffman: bool, out: &mut Vec<u8>) {
+    if huffman {
+        let huffman_len = huffman_encoded_len(data);
+        if huffman_len < data.len() {
+            encode_integer(huffman_len, 7, 0x80, out);
+            huffman_encode(data, out);
+            return;
+        }
+    }
+
+    encode_integer(data.len(), 7, 0x00, out);
+    out.extend_from_slice(data);
+}
+
+/// Returns the decoded string and the number of bytes consumed.
+pub fn decode_string(data: &[u8]) -> Result<(Vec<u8>, usize)> {
+    let huffman = data.first().map(|b| b & 0x80 != 0).unwrap_or(false);
+    let (len, consumed) = decode_integer(data, 7)?;
+    let end = consumed + len;
+
+    if data.len() < end {
+        return Err(anyhow::anyhow!("Truncated string literal"));
+    }
+
+    let raw = &data[consumed..end];
+    let value = if huffman { huffman_decode(raw)? } else { raw.to_vec() };
+
+    Ok((value, end))
+}
+
+#[derive(Debug, Clone)]
+pub struct DynamicTable {
+    entries: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    evictions: u64,
+}
+
+impl DynamicTable {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            size: 0,
+            max_size,
+            evictions: 0,
+        }
+    }
+
+    pub fn entry_size(name: &str, value: &str) -> usize {
+        name.len() + value.len() + ENTRY_OVERHEAD
+    }
+
+    pub fn insert(&mut self, name: String, value: String) {
+        let entry_size = Self::entry_size(&name, &value);
+
+        // An entry larger than the table just empties it (section 4.4)
+        if entry_size > self.max_size {
+            self.evictions += self.entries.len() as u64;
+            self.entries.clear();
+            self.size = 0;
+            return;
+        }
+
+        self.size += entry_size;
+        self.entries.push_front((name, value));
+        self.evict();
+    }
+
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            match self.entries.pop_back() {
+                Some((name, value)) => {
+                    self.size -= Self::entry_size(&name, &value);
+                    self.evictions += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Zero-based position within the dynamic table (newest first).
+    pub fn get(&self, index: usize) -> Option<&(String, String)> {
+        self.entries.get(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Entries dropped so far to stay within max_size
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(String, String)> {
+        self.entries.iter()
+    }
+}
+
+enum TableMatch {
+    Full(usize),
+    Name(usize),
+    None,
+}
+
+pub struct HpackEncoder {
+    table: DynamicTable,
+    /// Upper bound for the table, taken from our local header_table_size
+    max_allowed: usize,
+    pending_size_update: Option<usize>,
+    huffman: bool,
+}
+
+impl HpackEncoder {
+    pub fn new(max_allowed: usize) -> Self {
+        Self {
+            table: DynamicTable::new(DEFAULT_TABLE_SIZE.min(max_allowed)),
+            max_allowed,
+            pending_size_update: None,
+            huffman: true,
+        }
+    }
+
+    pub fn with_huffman(mut self, huffman: bool) -> Self {
+        self.huffman = huffman;
+        self
+    }
+
+    /// Applies the peer's SETTINGS_HEADER_TABLE_SIZE. The change is
+    /// announced with a size update at the start of the next header block.
+    pub fn set_max_table_size(&mut self, size: usize) {
+        let size = size.min(self.max_allowed);
+        if size != self.table.max_size() {
+            self.table.set_max_size(size);
+            self.pending_size_update = Some(size);
+        }
+    }
+
+    pub fn table(&self) -> &DynamicTable {
+        &self.table
+    }
+
+    pub fn encode(&mut self, headers: &[(String, String)]) -> Vec<u8> {
+        let mut block = Vec::new();
+
+        if let Some(size) = self.pending_size_update.take() {
+            encode_integer(size, 5, 0x20, &mut block);
+        }
+
+        for (name, value) in headers {
+            self.encode_field(name, value, &mut block);
+        }
+
+        block
+    }
+
+    fn encode_field(&mut self, name: &str, value: &str, out: &mut Vec<u8>) {
+        let sensitive = is_sensitive(name);
+
+        let name_index = match self.find(name, value) {
+            TableMatch::Full(index) if !sensitive => {
+                encode_integer(index, 7, 0x80, out);
+                return;
+            }
+            TableMatch::Full(index) | TableMatch::Name(index) => Some(index),
+            TableMatch::None => None,
+        };
+
+        if sensitive {
+            // Literal never indexed, so intermediaries don't cache credentials
+            encode_integer(name_index.unwrap_or(0), 4, 0x10, out);
+        } else {
+            encode_integer(name_index.unwrap_or(0), 6, 0x40, out);
+        }
+
+        if name_index.is_none() {
+            encode_string(name.as_bytes(), self.huffman, out);
+        }
+        encode_string(value.as_bytes(), self.huffman, out);
+
+        if !sensitive {
+            self.table.insert(name.to_string(), value.to_string());
+        }
+    }
+
+    fn find(&self, name: &str, value: &str) -> TableMatch {
+        let mut name_match = None;
+
+        for (i, (n, v)) in STATIC_TABLE.iter().enumerate() {
+            if *n == name {
+                if *v == value {
+                    return TableMatch::Full(i + 1);
+                }
+                name_match.get_or_insert(i + 1);
+            }
+        }
+
+        for (i, (n, v)) in self.table.iter().enumerate() {
+            if n == name {
+                let index = STATIC_TABLE.len() + i + 1;
+                if v == value {
+                    return TableMatch::Full(index);
+                }
+                name_match.get_or_insert(index);
+            }
+        }
+
+        match name_match {
+            Some(index) => TableMatch::Name(index),
+            None => TableMatch::None,
+        }
+    }
+}
+
+pub struct HpackDecoder {
+    table: DynamicTable,
+    /// SETTINGS_HEADER_TABLE_SIZE we advertised; size updates above it are errors
+    max_allowed: usize,
+}
+
+impl HpackDecoder {
+    pub fn new(max_allowed: usize) -> Self {
+        Self {
+            table: DynamicTable::new(max_allowed),
+            max_allowed,
+        }
+    }
+
+    pub fn table(&self) -> &DynamicTable {
+        &self.table
+    }
+
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<(String, String)>> {
+        let mut headers = Vec::new();
+        let mut offset = 0;
+
+        while offset < block.len() {
+            let data = &block[offset..];
+            let first = data[0];
+
+            if first & 0x80 != 0 {
+                // Indexed header field
+                let (index, consumed) = decode_integer(data, 7)?;
+                headers.push(self.lookup(index)?);
+                offset += consumed;
+            } else if first & 0x40 != 0 {
+                // Literal with incremental indexing
+                let (name, value, consumed) = self.decode_literal(data, 6)?;
+                self.table.insert(name.clone(), value.clone());
+                headers.push((name, value));
+                offset += consumed;
+            } else if first & 0x20 != 0 {
+                // Dynamic table size update, only allowed before the first field (section 4.2)
+                if !headers.is_empty() {
+                    return Err(anyhow::anyhow!("Table size update after a header field"));
+                }
+                let (size, consumed) = decode_integer(data, 5)?;
+                if size > self.max_allowed {
+                    return Err(anyhow::anyhow!(
+                        "Table size update {} exceeds limit {}", size, self.max_allowed
+                    ));
+                }
+                self.table.set_max_size(size);
+                offset += consumed;
+            } else {
+                // Literal without indexing / never indexed
+                let (name, value, consumed) = self.decode_literal(data, 4)?;
+                headers.push((name, value));
+                offset += consumed;
+            }
+        }
+
+        Ok(headers)
+    }
+
+    fn decode_literal(&self, data: &[u8], prefix_bits: u8) -> Result<(String, String, usize)> {
+        let (index, mut consumed) = decode_integer(data, prefix_bits)?;
+
+        let name = if index == 0 {
+            let (name, len) = decode_string(&data[consumed..])?;
+            consumed += len;
+            String::from_utf8_lossy(&name).into_owned()
+        } else {
+            self.lookup(index)?.0
+        };
+
+        let (value, len) = decode_string(&data[consumed..])?;
+        consumed += len;
+
+        Ok((name, String::from_utf8_lossy(&value).into_owned(), consumed))
+    }
+
+    fn lookup(&self, index: usize) -> Result<(String, String)> {
+        if index == 0 {
+            return Err(anyhow::anyhow!("Header index 0"));
+        }
+
+        if index <= STATIC_TABLE.len() {
+            let (name, value) = STATIC_TABLE[index - 1];
+            return Ok((name.to_string(), value.to_string()));
+        }
+
+        self.table
+            .get(index - STATIC_TABLE.len() - 1)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Header index {} out of range", index))
+    }
+}
+
+fn is_sensitive(name: &str) -> bool {
+    matches!(name, "authorization" | "proxy-authorization")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn headers(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_integer_encoding_rfc_examples() {
+        // C.1.1 - C.1.3
+        let mut out = Vec::new();
+        encode_integer(10, 5, 0, &mut out);
+        assert_eq!(out, vec![0x0a]);
+
+        out.clear();
+        encode_integer(1337, 5, 0, &mut out);
+        assert_eq!(out, vec![0x1f, 0x9a, 0x0a]);
+        assert_eq!(decode_integer(&out, 5).unwrap(), (1337, 3));
+
+        out.clear();
+        encode_integer(42, 8, 0, &mut out);
+        assert_eq!(out, vec![0x2a]);
+    }
+
+    #[test]
+    fn test_huffman_round_trip() {
+        let mut out = Vec::new();
+        huffman_encode(b"www.example.com", &mut out);
+        assert_eq!(out, hex("f1e3 c2e5 f23a 6ba0 ab90 f4ff"));
+        assert_eq!(huffman_decode(&out).unwrap(), b"www.example.com");
+
+        let all: Vec<u8> = (0..=255).collect();
+        out.clear();
+        huffman_encode(&all, &mut out);
+        assert_eq!(huffman_decode(&out).unwrap(), all);
+    }
+
+    #[test]
+    fn test_encoder_rfc_c3_without_huffman() {
+        let mut encoder = HpackEncoder::new(DEFAULT_TABLE_SIZE).with_huffman(false);
+
+        let first = encoder.encode(&headers(&[
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+        ]));
+        assert_eq!(first, hex("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d"));
+
+        let second = encoder.encode(&headers(&[
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+            ("cache-control", "no-cache"),
+        ]));
+        assert_eq!(second, hex("8286 84be 5808 6e6f 2d63 6163 6865"));
+
+        let third = encoder.encode(&headers(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/index.html"),
+            (":authority", "www.example.com"),
+            ("custom-key", "custom-value"),
+        ]));
+        assert_eq!(
+            third,
+            hex("8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65")
+        );
+        assert_eq!(encoder.table().size(), 164);
+    }
+
+    #[test]
+    fn test_encoder_rfc_c4_with_huffman() {
+        let mut encoder = HpackEncoder::new(DEFAULT_TABLE_SIZE);
+
+        let first = encoder.encode(&headers(&[
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+        ]));
+        assert_eq!(first, hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"));
+
+        let second = encoder.encode(&headers(&[
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+            ("cache-control", "no-cache"),
+        ]));
+        assert_eq!(second, hex("8286 84be 5886 a8eb 1064 9cbf"));
+
+        let third = encoder.encode(&headers(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/index.html"),
+            (":authority", "www.example.com"),
+            ("custom-key", "custom-value"),
+        ]));
+        assert_eq!(
+            third,
+            hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf")
+        );
+    }
+
+    #[test]
+    fn test_long_value_and_table_size_update() {
+        let mut encoder = HpackEncoder::new(DEFAULT_TABLE_SIZE).with_huffman(false);
+        let long = "a".repeat(300);
+
+        let block = encoder.encode(&headers(&[("user-agent", &long)]));
+        // Literal with incremental indexing, static name index 58
+        assert_eq!(block[0], 0x40 | 58);
+        assert_eq!(&block[1..4], &[0x7f, 0xad, 0x01]);
+        assert_eq!(decode_string(&block[1..]).unwrap().0, long.as_bytes());
+
+        encoder.set_max_table_size(0);
+        assert_eq!(encoder.table().len(), 0);
+        let block = encoder.encode(&headers(&[(":method", "GET")]));
+        assert_eq!(block, vec![0x20, 0x82]);
+    }
+
+    #[test]
+    fn test_decoder_rfc_c3_and_c4_requests() {
+        let blocks = [
+            ("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+             "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"),
+            ("8286 84be 5808 6e6f 2d63 6163 6865",
+             "8286 84be 5886 a8eb 1064 9cbf"),
+            ("8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+             "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf"),
+        ];
+        let expected = [
+            headers(&[(":method", "GET"), (":scheme", "http"), (":path", "/"),
+                (":authority", "www.example.com")]),
+            headers(&[(":method", "GET"), (":scheme", "http"), (":path", "/"),
+                (":authority", "www.example.com"), ("cache-control", "no-cache")]),
+            headers(&[(":method", "GET"), (":scheme", "https"), (":path", "/index.html"),
+                (":authority", "www.example.com"), ("custom-key", "custom-value")]),
+        ];
+
+        let mut plain = HpackDecoder::new(DEFAULT_TABLE_SIZE);
+        let mut huffman = HpackDecoder::new(DEFAULT_TABLE_SIZE);
+        for ((raw, huff), want) in blocks.iter().zip(expected.iter()) {
+            assert_eq!(&plain.decode(&hex(raw)).unwrap(), want);
+            assert_eq!(&huffman.decode(&hex(huff)).unwrap(), want);
+        }
+        assert_eq!(plain.table().size(), 164);
+        assert_eq!(huffman.table().size(), 164);
+    }
+
+    #[test]
+    fn test_decoder_rfc_c5_responses_with_eviction() {
+        let mut decoder = HpackDecoder::new(256);
+
+        let first = decoder.decode(&hex(
+            "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 \
+             3230 3133 2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 \
+             7777 2e65 7861 6d70 6c65 2e63 6f6d",
+        )).unwrap();
+        assert_eq!(first, headers(&[
+            (":status", "302"),
+            ("cache-control", "private"),
+            ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+            ("location", "https://www.example.com"),
+        ]));
+        assert_eq!(decoder.table().size(), 222);
+
+        let second = decoder.decode(&hex("4803 3330 37c1 c0bf")).unwrap();
+        assert_eq!(second[0], (":status".to_string(), "307".to_string()));
+        assert_eq!(second[3], ("location".to_string(), "https://www.example.com".to_string()));
+        assert_eq!(decoder.table().size(), 222);
+
+        let third = decoder.decode(&hex(
+            "88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 \
+             3220 474d 54c0 5a04 677a 6970 7738 666f 6f3d 4153 444a 4b48 514b 425a \
+             584f 5157 454f 5049 5541 5851 5745 4f49 553b 206d 6178 2d61 6765 3d33 \
+             3630 303b 2076 6572 7369 6f6e 3d31",
+        )).unwrap();
+        assert_eq!(third, headers(&[
+            (":status", "200"),
+            ("cache-control", "private"),
+            ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+            ("location", "https://www.example.com"),
+            ("content-encoding", "gzip"),
+            ("set-cookie", "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1"),
+        ]));
+        assert_eq!(decoder.table().len(), 3);
+        assert_eq!(decoder.table().size(), 215);
+    }
+
+    #[test]
+    fn test_dynamic_table_bounded_with_eviction_count() {
+        let mut decoder = HpackDecoder::new(100);
+
+        // Literal with incremental indexing, new name: 32 + 4 + 10 = 46 bytes each
+        let mut block = Vec::new();
+        for i in 0..5 {
+            block.push(0x40);
+            encode_string(format!("x-h{}", i).as_bytes(), false, &mut block);
+            encode_string(b"0123456789", false, &mut block);
+        }
+        assert_eq!(decoder.decode(&block).unwrap().len(), 5);
+
+        let table = decoder.table();
+        assert_eq!((table.len(), table.size(), table.evictions()), (2, 92, 3));
+        assert_eq!(table.get(0).unwrap().0, "x-h4");
+        assert_eq!(table.get(1).unwrap().0, "x-h3");
+
+        // An entry larger than the table flushes it
+        let mut huge = vec![0x40];
+        encode_string(b"x-big", false, &mut huge);
+        encode_string(&[b'v'; 100], false, &mut huge);
+        decoder.decode(&huge).unwrap();
+        assert_eq!((decoder.table().len(), decoder.table().size(), decoder.table().evictions()), (0, 0, 5));
+
+        // Shrinking evicts too, and a size update must lead the block
+        decoder.decode(&block[..block.len() / 5 * 2]).unwrap();
+        assert_eq!(decoder.decode(&[0x20]).unwrap(), Vec::new());
+        assert_eq!((decoder.table().size(), decoder.table().evictions()), (0, 7));
+        assert!(decoder.decode(&[0x82, 0x20]).is_err());
+    }
+
+    #[test]
+    fn test_decoder_rejects_bad_input() {
+        let mut decoder = HpackDecoder::new(DEFAULT_TABLE_SIZE);
+        assert!(decoder.decode(&[0x80]).is_err());
+        assert!(decoder.decode(&[0xbe]).is_err());
+        assert!(decoder.decode(&[0x3f, 0xe2, 0x1f]).is_err());
+        assert!(decoder.decode(&[0x41, 0x05, b'a']).is_err());
+    }
+
+    #[test]
+    fn test_sensitive_headers_never_indexed() {
+        let mut encoder = HpackEncoder::new(DEFAULT_TABLE_SIZE).with_huffman(false);
+        let block = encoder.encode(&headers(&[("authorization", "secret")]));
+
+        assert_eq!(block[0], 0x10 | 0x0f);
+        assert_eq!(block[1], 23 - 15);
+        assert_eq!(encoder.table().len(), 0);
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::VecDeque;
+
+/// Limit for headers and chunked lines; anything longer makes the stream unparsable
+const MAX_LINE_LEN: usize = 65536;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Head,
+    Body(u64),
+    ChunkSize,
+    ChunkData(u64),
+    ChunkEnd,
+    Trailers,
+    /// Body until the connection closes: a response without a length or after 101
+    UntilClose,
+    Invalid,
+}
+
+/// Streaming message boundary parser for one direction of HTTP/1.1.
+/// Bodies are not buffered, only counted
+#[derive(Debug)]
+struct MessageFramer {
+    stage: Stage,
+    line: Vec<u8>,
+}
+
+impl Default for MessageFramer {
+    fn default() -> Self {
+        Self { stage: Stage::Head, line: Vec::new() }
+    }
+}
+
+impl MessageFramer {
+    fn at_boundary(&self) -> bool {
+        self.stage == Stage::Head && self.line.is_empty()
+    }
+
+    /// Parses the next chunk of the stream and passes its bytes to emit; a message's
+    /// headers go as one piece once they're complete (flag true).
+    /// on_head decides from the headers what follows; Stage::Head means a message
+    /// without a body or an interim 1xx response
+    fn feed(
+        &mut self,
+        mut data: &[u8],
+        mut on_head: impl FnMut(&str) -> Stage,
+        emit: &mut dyn FnMut(&[u8], bool),
+    ) {
+        while !data.is_empty() {
+            match self.stage {
+                Stage::Head | Stage::ChunkSize | Stage::ChunkEnd | Stage::Trailers => {
+                    let head = self.stage == Stage::Head;
+                    self.line.push(data[0]);
+                    if !head {
+                        emit(&data[..1], false);
+                    }
+                    data = &data[1..];
+
+                    let terminator: &[u8] = if head { b"\r\n\r\n" } else { b"\r\n" };
+                    if self.line.len() > MAX_LINE_LEN {
+                        if head {
+                            emit(&self.line, false);
+                        }
+                        self.stage = Stage::Invalid;
+                        continue;
+                    }
+                    if !self.line.ends_with(terminator) {
+                        continue;
+                    }
+
+                    let line = std::mem::take(&mut self.line);
+                    self.stage = match self.stage {
+                        Stage::Head => {
+                            emit(&line, true);
+                            on_head(&String::from_utf8_lossy(&line))
+                        }
+                        Stage::ChunkSize => match parse_chunk_size(&line) {
+                            Some(0) => Stage::Trailers,
+                            Some(size) => Stage::ChunkData(size),
+                            None => Stage::Invalid,
+                        },
+                        Stage::ChunkEnd if line == b"\r\n" => Stage::ChunkSize,
+                        Stage::ChunkEnd => Stage::Invalid,
+                        _ if line == b"\r\n" => Stage::Head,
+                        _ => Stage::Trailers,
+                    };
+                }
+                Stage::Body(left) | Stage::ChunkData(left) => {
+                    let n = (data.len() as u64).min(left);
+                    emit(&data[..n as usize], false);
+                    data = &data[n as usize..];
+                    self.advance_body(n);
+                }
+                Stage::UntilClose | Stage::Invalid => {
+                    emit(data, false);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Counts n body bytes that went past the parser
+    fn advance_body(&mut self, n: u64) {
+        let (left, chunked) = match self.stage {
+            Stage::Body(left) => (left, false),
+            Stage::ChunkData(left) => (left, true),
+            _ => {
+                self.stage = Stage::Invalid;
+                return;
+            }
+        };
+        if n > left {
+            self.stage = Stage::Invalid;
+            return;
+        }
+
+        self.stage = match (chunked, left - n) {
+            (false, 0) => Stage::Head,
+            (false, left) => Stage::Body(left),
+            (true, 0) => Stage::ChunkEnd,
+            (true, left) => Stage::ChunkData(left),
+        };
+    }
+}
+
+fn parse_chunk_size(line: &[u8]) -> Option<u64> {
+    let line = std::str::from_utf8(line).ok()?;
+    let size = line.trim_end().split(';').next()?.trim();
+    u64::from_str_radix(size, 16).ok()
+}
+
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+fn has_token(head: &str, name: &str, token: &str) -> bool {
+    header(head, name).is_some_and(|value| {
+        value.split(',').any(|item| item.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+/// Body length from the headers (RFC 9112, 6.3); None - no length given
+fn body_stage(head: &str) -> Option<Stage> {
+    if header(head, "Transfer-Encoding").is_some() {
+        return Some(if has_token(head, "Transfer-Encoding", "chunked") { Stage::ChunkSize } else { Stage::Invalid });
+    }
+
+    let length = header(head, "Content-Length")?;
+    Some(match length.parse::<u64>() {
+        Ok(0) => Stage::Head,
+        Ok(length) => Stage::Body(length),
+        Err(_) => Stage::Invalid,
+    })
+}
+
+/// Requests and responses of one HTTP/1.1 upstream connection: which requests
+/// still await a response and whether the connection can go to the next client
+#[derive(Debug, Default)]
+pub struct Http1Exchange {
+    requests: MessageFramer,
+    responses: MessageFramer,
+    /// Requests without a final response: true for HEAD, whose response has no body
+    awaiting: VecDeque<bool>,
+    /// One of the sides asked for Connection: close
+    closing: bool,
+}
+
+impl Http1Exchange {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes the client sent upstream
+    pub fn on_request(&mut self, data: &[u8]) {
+        let Self { requests, awaiting, closing, .. } = self;
+        requests.feed(data, |head| {
+            awaiting.push_back(head.starts_with("HEAD "));
+            *closing |= has_token(head, "Connection", "close");
+            body_stage(head).unwrap_or(Stage::Head)
+        }, &mut |_, _| {});
+    }
+
+    /// Upstream response bytes passed to the client
+    pub fn on_response(&mut self, data: &[u8]) {
+        self.feed_response(data, &mut |_, _| {});
+    }
+
+    /// Like on_response, but returns the bytes for the client, with the headers
+    /// of every response (1xx included) passed through rewrite_head.
+    /// Incomplete headers are held back until the next chunk
+    pub fn relay_response(&mut self, data: &[u8], rewrite_head: impl Fn(&[u8]) -> Vec<u8>) -> Vec<u8> {
+        let mut forward = Vec::with_capacity(data.len());
+        self.feed_response(data, &mut |bytes, head| {
+            if head {
+                forward.extend_from_slice(&rewrite_head(bytes));
+            } else {
+                forward.extend_from_slice(bytes);
+            }
+        });
+        forward
+    }
+
+    fn feed_response(&mut self, data: &[u8], emit: &mut dyn FnMut(&[u8], bool)) {
+        let Self { responses, awaiting, closing, .. } = self;
+        responses.feed(data, |head| {
+            let status = head.split(' ').nth(1).and_then(|code| code.parse::<u16>().ok());
+            let Some(status) = status else {
+                return Stage::Invalid;
+            };
+            if (100..200).contains(&status) && status != 101 {
+                return Stage::Head;
+            }
+
+            let Some(head_request) = awaiting.pop_front() else {
+                return Stage::Invalid;
+            };
+            *closing |= has_token(head, "Connection", "close");
+            if status == 101 {
+                Stage::UntilClose
+            } else if head_request || status == 204 || status == 304 {
+                Stage::Head
+            } else {
+                body_stage(head).unwrap_or(Stage::UntilClose)
+            }
+        }, emit);
+    }
+
+    /// Response body passed around userspace (splice)
+    pub fn on_response_body(&mut self, len: u64) {
+        self.responses.advance_body(len);
+    }
+
+    /// Every sent request got a complete response
+    pub fn is_idle(&self) -> bool {
+        self.awaiting.is_empty() && self.requests.at_boundary() && self.responses.at_boundary()
+    }
+
+    /// A response has started, but its headers or known-length body aren't fully read
+    pub fn is_response_incomplete(&self) -> bool {
+        !matches!(self.responses.stage, Stage::UntilClose | Stage::Invalid) && !self.responses.at_boundary()
+    }
+
+    /// There's a sent request that hasn't received a single response byte
+    pub fn is_unanswered(&self) -> bool {
+        !self.awaiting.is_empty() && self.responses.at_boundary()
+    }
+
+    /// The connection is at a response boundary without Connection: close - it can go back to the pool
+    pub fn is_reusable(&self) -> bool {
+        self.is_idle() && !self.closing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exchange_idle_only_after_framed_response() {
+        let mut exchange = Http1Exchange::new();
+        exchange.on_request(b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nab");
+        assert!(!exchange.is_idle());
+        exchange.on_request(b"c");
+        exchange.on_response(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\no");
+        assert!(!exchange.is_idle());
+        exchange.on_response(b"k");
+        assert!(exchange.is_reusable());
+
+        // Pipeline: HEAD without a body and chunked with a trailer
+        exchange.on_request(b"HEAD / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n");
+        exchange.on_response(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n");
+        exchange.on_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n");
+        assert!(!exchange.is_idle());
+        exchange.on_response(b"X-Sum: 1\r\n\r\n");
+        assert!(exchange.is_reusable());
+
+        // A body until close has no boundary
+        exchange.on_request(b"GET / HTTP/1.1\r\n\r\n");
+        exchange.on_response(b"HTTP/1.1 200 OK\r\n\r\nbody");
+        assert!(!exchange.is_idle());
+
+        let mut spliced = Http1Exchange::new();
+        spliced.on_request(b"GET / HTTP/1.1\r\n\r\n");
+        spliced.on_response(b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 5\r\n\r\nab");
+        spliced.on_response_body(3);
+        assert!(spliced.is_idle());
+        assert!(!spliced.is_reusable());
+    }
+}
@@ -1,10 +1,13 @@
 use anyhow::Result;
-use std::collections::HashMap;
+use bytes::{Buf, BytesMut};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 use crate::http2_advanced::{
     Http2Settings, FlowController, PriorityTree, HeaderOrderPreserver,
-    StreamPriority,
+    StreamPriority, SETTINGS_HEADER_TABLE_SIZE,
 };
+use crate::hpack::{HpackEncoder, HpackDecoder, DynamicTable, DEFAULT_TABLE_SIZE};
 
 const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
 
@@ -19,6 +22,7 @@ const FRAME_PING: u8 = 0x06;
 const FRAME_GOAWAY: u8 = 0x07;
 const FRAME_WINDOW_UPDATE: u8 = 0x08;
 const FRAME_CONTINUATION: u8 = 0x09;
+const FRAME_ALTSVC: u8 = 0x0a;
 
 // Frame flags
 const FLAG_END_STREAM: u8 = 0x01;
@@ -27,6 +31,15 @@ const FLAG_PADDED: u8 = 0x08;
 const FLAG_PRIORITY: u8 = 0x20;
 const FLAG_ACK: u8 = 0x01;
 
+// Error codes
+pub const ERROR_NO_ERROR: u32 = 0x00;
+const ERROR_PROTOCOL: u32 = 0x01;
+
+// Connection-specific headers that must not cross between HTTP/1.1 and HTTP/2
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade", "te",
+];
+
 #[derive(Debug, Clone)]
 pub struct Http2Frame {
     pub length: u32,
@@ -83,13 +96,182 @@ impl Http2Frame {
     }
 }
 
+/// HTTP/1.1 response from an upstream, parsed for re-encoding as HTTP/2.
+#[derive(Debug, Clone)]
+pub struct H1Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl H1Response {
+    /// Returns the response and the bytes it took, or None while it is
+    /// still incomplete. `eof` means the upstream closed, which ends a body
+    /// that has neither Content-Length nor chunked encoding.
+    pub fn parse(data: &[u8], eof: bool, head_request: bool) -> Result<Option<(Self, usize)>> {
+        let Some(header_end) = find_subslice(data, b"\r\n\r\n") else {
+            return Ok(None);
+        };
+
+        let head = String::from_utf8_lossy(&data[..header_end]);
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| anyhow::anyhow!("Invalid HTTP/1.1 status line"))?;
+
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+            .collect();
+
+        let body_start = header_end + 4;
+        let rest = &data[body_start..];
+        let chunked = headers.iter().any(|(name, value)| {
+            name == "transfer-encoding" && value.to_lowercase().contains("chunked")
+        });
+        let content_length = headers
+            .iter()
+            .find(|(name, _)| name == "content-length")
+            .and_then(|(_, value)| value.parse::<usize>().ok());
+
+        let (body, consumed) = if head_request || status / 100 == 1 || status == 204 || status == 304 {
+            (Vec::new(), 0)
+        } else if chunked {
+            match decode_chunked(rest)? {
+                Some(decoded) => decoded,
+                None => return Ok(None),
+            }
+        } else if let Some(length) = content_length {
+            if rest.len() < length {
+                return Ok(None);
+            }
+            (rest[..length].to_vec(), length)
+        } else if eof {
+            (rest.to_vec(), rest.len())
+        } else {
+            return Ok(None);
+        };
+
+        Ok(Some((Self { status, headers, body }, body_start + consumed)))
+    }
+}
+
+fn find_subslice(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|w| w == needle)
+}
+
+fn decode_chunked(data: &[u8]) -> Result<Option<(Vec<u8>, usize)>> {
+    let mut body = Vec::new();
+    let mut pos = 0;
+
+    loop {
+        let Some(line_end) = find_subslice(&data[pos..], b"\r\n") else {
+            return Ok(None);
+        };
+        let size_line = String::from_utf8_lossy(&data[pos..pos + line_end]);
+        let size_hex = size_line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_hex, 16)
+            .map_err(|_| anyhow::anyhow!("Invalid chunk size: {}", size_hex))?;
+        pos += line_end + 2;
+
+        if size == 0 {
+            // Optional trailers end with an empty line
+            if data[pos..].starts_with(b"\r\n") {
+                return Ok(Some((body, pos + 2)));
+            }
+            return Ok(find_subslice(&data[pos..], b"\r\n\r\n").map(|end| (body, pos + end + 4)));
+        }
+
+        if data.len() < pos + size + 2 {
+            return Ok(None);
+        }
+        body.extend_from_slice(&data[pos..pos + size]);
+        pos += size + 2;
+    }
+}
+
+/// Accumulates raw socket reads and yields complete frames; a partial
+/// frame stays buffered until the rest of it arrives.
+#[derive(Debug, Default)]
+pub struct Http2FrameBuffer {
+    buffer: BytesMut,
+}
+
+impl Http2FrameBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Consumes the client connection preface if it is at the head of the
+    /// buffer. Returns None while only a prefix of it has arrived.
+    pub fn strip_preface(&mut self) -> Option<bool> {
+        if self.buffer.starts_with(PREFACE) {
+            self.buffer.advance(PREFACE.len());
+            return Some(true);
+        }
+        if PREFACE.starts_with(&self.buffer) {
+            return None;
+        }
+        Some(false)
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<Http2Frame>> {
+        if self.buffer.len() < 9 {
+            return Ok(None);
+        }
+
+        let length = u32::from_be_bytes([0, self.buffer[0], self.buffer[1], self.buffer[2]]) as usize;
+        if self.buffer.len() < 9 + length {
+            return Ok(None);
+        }
+
+        let frame_bytes = self.buffer.split_to(9 + length);
+        Http2Frame::parse(&frame_bytes).map(Some)
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+
 pub struct Http2Handler {
     settings: Http2Settings,
     flow_controller: FlowController,
     priority_tree: PriorityTree,
     header_preserver: HeaderOrderPreserver,
+    hpack_encoder: HpackEncoder,
+    hpack_decoder: HpackDecoder,
+    decoded_headers: HashMap<u32, Vec<(String, String)>>,
+    pending_header_block: Option<(u32, Vec<u8>)>,
+    frame_buffer: Http2FrameBuffer,
+    outgoing_buffer: Http2FrameBuffer,
+    h1_downgrade: bool,
+    request_bodies: HashMap<u32, Vec<u8>>,
+    completed_requests: Vec<u32>,
+    pending_data: HashMap<u32, Vec<u8>>,
+    stripped_headers: Vec<String>,
+    relay_encoder: HpackEncoder,
+    relay_end_stream: HashMap<u32, bool>,
     remote_settings: Option<Http2Settings>,
+    keepalive: Option<(Duration, Duration)>,
+    pending_ping: Option<([u8; 8], Instant)>,
+    /// Round trip of the last answered keepalive PING
+    ping_rtt: Option<Duration>,
     next_stream_id: u32,
+    /// Highest stream the client has opened, reported in GOAWAY on drain
+    last_client_stream_id: u32,
+    /// Client streams the server has not finished answering yet
+    open_client_streams: HashSet<u32>,
     stream_states: HashMap<u32, StreamState>,
     preface_sent: bool,
     preface_received: bool,
@@ -106,18 +288,42 @@ enum StreamState {
 
 impl Http2Handler {
     pub fn new_ios_safari() -> Self {
-        let settings = Http2Settings::ios_safari();
+        Self::with_settings(Http2Settings::ios_safari())
+    }
+
+    /// iOS Safari behaviour (priorities, header order) with the given SETTINGS.
+    pub fn with_settings(settings: Http2Settings) -> Self {
         let flow_controller = FlowController::new(settings.initial_window_size);
         let priority_tree = PriorityTree::ios_safari_defaults();
         let header_preserver = HeaderOrderPreserver::ios_safari();
+        let hpack_encoder = HpackEncoder::new(settings.header_table_size as usize);
+        let hpack_decoder = HpackDecoder::new(settings.header_table_size as usize);
 
         Self {
             settings,
             flow_controller,
             priority_tree,
             header_preserver,
+            hpack_encoder,
+            hpack_decoder,
+            decoded_headers: HashMap::new(),
+            pending_header_block: None,
+            frame_buffer: Http2FrameBuffer::new(),
+            outgoing_buffer: Http2FrameBuffer::new(),
+            h1_downgrade: false,
+            request_bodies: HashMap::new(),
+            completed_requests: Vec::new(),
+            pending_data: HashMap::new(),
+            stripped_headers: Vec::new(),
+            relay_encoder: HpackEncoder::new(DEFAULT_TABLE_SIZE),
+            relay_end_stream: HashMap::new(),
             remote_settings: None,
+            keepalive: None,
+            pending_ping: None,
+            ping_rtt: None,
             next_stream_id: 1,
+            last_client_stream_id: 0,
+            open_client_streams: HashSet::new(),
             stream_states: HashMap::new(),
             preface_sent: false,
             preface_received: false,
@@ -125,21 +331,9 @@ impl Http2Handler {
     }
 
     pub fn new_custom(settings: Http2Settings) -> Self {
-        let flow_controller = FlowController::new(settings.initial_window_size);
-        let priority_tree = PriorityTree::new();
-        let header_preserver = HeaderOrderPreserver::ios_safari();
-
-        Self {
-            settings,
-            flow_controller,
-            priority_tree,
-            header_preserver,
-            remote_settings: None,
-            next_stream_id: 1,
-            stream_states: HashMap::new(),
-            preface_sent: false,
-            preface_received: false,
-        }
+        let mut handler = Self::with_settings(settings);
+        handler.priority_tree = PriorityTree::new();
+        handler
     }
 
     pub fn build_connection_preface(&mut self) -> Vec<u8> {
@@ -153,16 +347,243 @@ impl Http2Handler {
         preface
     }
 
+    /// Headers (lowercase) removed by `relay_frames`. Stripping ALTSVC
+    /// frames comes with "alt-svc".
+    pub fn strip_headers(&mut self, names: &[&str]) {
+        self.stripped_headers = names.iter().map(|name| name.to_lowercase()).collect();
+    }
+
+    /// Processes peer bytes like `handle_incoming_frame` and also returns the
+    /// frames to forward to the other side. Header blocks are decoded,
+    /// filtered and re-encoded with a separate HPACK context, since dropping
+    /// a field would otherwise desync the other side's dynamic table.
+    /// Returns (frames to forward, our replies to the peer).
+    pub fn relay_frames(&mut self, data: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        self.frame_buffer.push(data);
+        let mut forward = Vec::new();
+        let mut replies = Vec::new();
+
+        while let Some(frame) = self.frame_buffer.next_frame()? {
+            if frame.frame_type == FRAME_RST_STREAM {
+                // Pass the reset on with the peer's error code before our state is torn down
+                forward.extend_from_slice(&frame.serialize());
+            }
+
+            // The ACK for our own keepalive PING means nothing to the client
+            let own_ping_ack = self.is_keepalive_ack(&frame);
+
+            replies.extend_from_slice(&self.process_frame(&frame)?);
+
+            match frame.frame_type {
+                FRAME_RST_STREAM => {}
+                FRAME_PING if own_ping_ack => {}
+                FRAME_ALTSVC if self.stripped_headers.iter().any(|h| h == "alt-svc") => {}
+                FRAME_HEADERS | FRAME_CONTINUATION => {
+                    if frame.frame_type == FRAME_HEADERS {
+                        self.relay_end_stream.insert(frame.stream_id, frame.is_end_stream());
+                    }
+                    if !frame.is_end_headers() {
+                        continue;
+                    }
+
+                    let Some(mut headers) = self.decoded_headers.remove(&frame.stream_id) else {
+                        continue;
+                    };
+                    headers.retain(|(name, _)| !self.stripped_headers.contains(name));
+
+                    let end_stream = self.relay_end_stream.remove(&frame.stream_id).unwrap_or(false);
+                    let block = self.relay_encoder.encode(&headers);
+                    forward.extend_from_slice(&self.header_block_frames(frame.stream_id, block, end_stream));
+                }
+                _ => forward.extend_from_slice(&frame.serialize()),
+            }
+        }
+
+        Ok((forward, replies))
+    }
+
+    /// Send a PING after `interval` without traffic; the connection is
+    /// considered dead if its ACK does not arrive within `timeout`.
+    pub fn set_keepalive(&mut self, interval: Duration, timeout: Duration) {
+        self.keepalive = Some((interval, timeout));
+    }
+
+    /// When `poll_keepalive` next has something to do, if keepalive is enabled.
+    pub fn keepalive_deadline(&self, last_activity: Instant) -> Option<Instant> {
+        let (interval, timeout) = self.keepalive?;
+        Some(match self.pending_ping {
+            Some((_, sent)) => sent + timeout,
+            None => last_activity + interval,
+        })
+    }
+
+    /// Returns a PING to send once the connection has been idle for the
+    /// interval, or an error if the previous PING went unanswered.
+    pub fn poll_keepalive(&mut self, now: Instant, last_activity: Instant) -> Result<Option<Vec<u8>>> {
+        let Some((interval, timeout)) = self.keepalive else {
+            return Ok(None);
+        };
+
+        if let Some((_, sent)) = self.pending_ping {
+            if now.duration_since(sent) >= timeout {
+                return Err(anyhow::anyhow!("No PING ACK within {:?}, peer is gone", timeout));
+            }
+            return Ok(None);
+        }
+
+        if now.duration_since(last_activity) < interval {
+            return Ok(None);
+        }
+
+        let opaque: [u8; 8] = rand::random();
+        self.pending_ping = Some((opaque, now));
+        Ok(Some(self.build_ping_frame(&opaque)))
+    }
+
+    /// RTT measured by the last keepalive PING ACK, if any arrived yet.
+    pub fn ping_rtt(&self) -> Option<Duration> {
+        self.ping_rtt
+    }
+
+    fn is_keepalive_ack(&self, frame: &Http2Frame) -> bool {
+        frame.frame_type == FRAME_PING
+            && (frame.flags & FLAG_ACK) != 0
+            && matches!(self.pending_ping, Some((opaque, _)) if frame.payload == opaque)
+    }
+
+    /// Server-side preface for downgrade mode: just our SETTINGS.
+    pub fn build_server_preface(&mut self) -> Vec<u8> {
+        self.preface_sent = true;
+        self.settings.to_frame()
+    }
+
+    /// Client requests are collected and handed out as HTTP/1.1 instead of
+    /// being relayed as frames (for upstreams without h2 support).
+    pub fn enable_h1_downgrade(&mut self) {
+        self.h1_downgrade = true;
+    }
+
+    /// Requests whose headers and body are complete, serialized as HTTP/1.1.
+    pub fn take_h1_requests(&mut self) -> Vec<(u32, Vec<u8>)> {
+        let mut requests = Vec::new();
+        let completed = std::mem::take(&mut self.completed_requests);
+
+        for stream_id in completed {
+            match self.decoded_headers.remove(&stream_id) {
+                Some(headers) => {
+                    let body = self.request_bodies.remove(&stream_id).unwrap_or_default();
+                    requests.push((stream_id, build_h1_request(&headers, &body)));
+                }
+                // Header block still waiting for CONTINUATION
+                None => self.completed_requests.push(stream_id),
+            }
+        }
+
+        requests
+    }
+
+    /// Encodes an HTTP/1.1 response as HEADERS + DATA frames on the stream.
+    /// Body data beyond the current send window stays queued for
+    /// `drain_pending_data`.
+    pub fn encode_h1_response(&mut self, stream_id: u32, response: H1Response) -> Vec<u8> {
+        let mut headers = vec![(":status".to_string(), response.status.to_string())];
+        headers.extend(
+            response.headers
+                .into_iter()
+                .filter(|(name, _)| !HOP_BY_HOP_HEADERS.contains(&name.as_str())),
+        );
+
+        let end_stream = response.body.is_empty();
+        let mut frames = self.encode_header_block(stream_id, &headers, end_stream);
+
+        if end_stream {
+            self.close_local(stream_id);
+        } else {
+            self.pending_data.insert(stream_id, response.body);
+            frames.extend_from_slice(&self.drain_pending_data());
+        }
+
+        frames
+    }
+
+    /// Emits queued response DATA as far as flow control allows.
+    pub fn drain_pending_data(&mut self) -> Vec<u8> {
+        let max_frame = self.remote_max_frame_size();
+        let mut frames = Vec::new();
+        let mut finished = Vec::new();
+
+        for (&stream_id, data) in self.pending_data.iter_mut() {
+            while !data.is_empty() {
+                let chunk = data.len().min(max_frame);
+                if !self.flow_controller.consume_window(stream_id, chunk as u32).unwrap_or(false) {
+                    break;
+                }
+
+                let end_stream = chunk == data.len();
+                let frame = Http2Frame {
+                    length: chunk as u32,
+                    frame_type: FRAME_DATA,
+                    flags: if end_stream { FLAG_END_STREAM } else { 0 },
+                    stream_id,
+                    payload: data.drain(..chunk).collect(),
+                };
+                frames.extend_from_slice(&frame.serialize());
+            }
+
+            if data.is_empty() {
+                finished.push(stream_id);
+            }
+        }
+
+        for stream_id in finished {
+            self.pending_data.remove(&stream_id);
+            self.close_local(stream_id);
+        }
+
+        frames
+    }
+
+    fn close_local(&mut self, stream_id: u32) {
+        if let Some(state) = self.stream_states.get_mut(&stream_id) {
+            *state = match state {
+                StreamState::HalfClosedRemote => StreamState::Closed,
+                _ => StreamState::HalfClosedLocal,
+            };
+        }
+    }
+
     pub fn create_stream(&mut self, stream_id: u32) -> Result<()> {
         self.flow_controller.create_stream(stream_id, self.settings.initial_window_size);
         self.stream_states.insert(stream_id, StreamState::Open);
         Ok(())
     }
 
-    pub fn get_next_stream_id(&mut self) -> u32 {
+    /// Opens the next locally initiated stream. Fails while the peer's
+    /// MAX_CONCURRENT_STREAMS is reached; closed or reset streams free a slot.
+    pub fn get_next_stream_id(&mut self) -> Result<u32> {
+        let limit = self.remote_settings
+            .as_ref()
+            .map(|s| s.max_concurrent_streams)
+            .unwrap_or(self.settings.max_concurrent_streams) as usize;
+
+        if self.active_local_streams() >= limit {
+            return Err(anyhow::anyhow!("Peer MAX_CONCURRENT_STREAMS ({}) reached", limit));
+        }
+
         let id = self.next_stream_id;
         self.next_stream_id += 2;
-        id
+        self.create_stream(id)?;
+        Ok(id)
+    }
+
+    fn active_local_streams(&self) -> usize {
+        self.stream_states
+            .iter()
+            .filter(|(&id, state)| {
+                id % 2 == self.next_stream_id % 2
+                    && !matches!(state, StreamState::Idle | StreamState::Closed)
+            })
+            .count()
     }
 
     pub fn can_send_data(&mut self, stream_id: u32, bytes: u32) -> Result<bool> {
@@ -206,53 +627,81 @@ impl Http2Handler {
         end_stream: bool,
     ) -> Vec<u8> {
         self.header_preserver.sort_headers(&mut headers);
+        self.encode_header_block(stream_id, &headers, end_stream)
+    }
 
-        let mut header_block = Vec::new();
-        for (name, value) in headers {
-            header_block.extend_from_slice(&self.encode_header(&name, &value));
-        }
+    fn encode_header_block(&mut self, stream_id: u32, headers: &[(String, String)], end_stream: bool) -> Vec<u8> {
+        let header_block = self.hpack_encoder.encode(headers);
+        self.header_block_frames(stream_id, header_block, end_stream)
+    }
 
-        let flags = if end_stream {
-            FLAG_END_STREAM | FLAG_END_HEADERS
-        } else {
-            FLAG_END_HEADERS
-        };
+    fn header_block_frames(&self, stream_id: u32, header_block: Vec<u8>, end_stream: bool) -> Vec<u8> {
+        let max_frame = self.remote_max_frame_size();
 
-        let frame = Http2Frame {
-            length: header_block.len() as u32,
-            frame_type: FRAME_HEADERS,
-            flags,
-            stream_id,
-            payload: header_block,
-        };
+        // Blocks larger than a frame go out as HEADERS + CONTINUATION
+        let mut chunks = header_block.chunks(max_frame.max(1)).peekable();
+        let mut frames = Vec::new();
+        let mut frame_type = FRAME_HEADERS;
 
-        frame.serialize()
+        while let Some(chunk) = chunks.next() {
+            let mut flags = 0;
+            if chunks.peek().is_none() {
+                flags |= FLAG_END_HEADERS;
+            }
+            if end_stream && frame_type == FRAME_HEADERS {
+                flags |= FLAG_END_STREAM;
+            }
+
+            let frame = Http2Frame {
+                length: chunk.len() as u32,
+                frame_type,
+                flags,
+                stream_id,
+                payload: chunk.to_vec(),
+            };
+            frames.extend_from_slice(&frame.serialize());
+            frame_type = FRAME_CONTINUATION;
+        }
+
+        frames
     }
 
-    fn encode_header(&self, name: &str, value: &str) -> Vec<u8> {
-        let mut encoded = Vec::new();
-        
-        encoded.push(0x40);
-        encoded.push(name.len() as u8);
-        encoded.extend_from_slice(name.as_bytes());
-        encoded.push(value.len() as u8);
-        encoded.extend_from_slice(value.as_bytes());
-        
-        encoded
+    /// Largest frame payload the peer accepts (our own limit until its SETTINGS arrive).
+    fn remote_max_frame_size(&self) -> usize {
+        self.remote_settings
+            .as_ref()
+            .map(|s| s.max_frame_size)
+            .unwrap_or(self.settings.max_frame_size)
+            .max(1) as usize
     }
 
+    /// Splits the payload into DATA frames no larger than the peer's
+    /// max_frame_size; only the last one carries END_STREAM.
     pub fn build_data_frame(&self, stream_id: u32, data: &[u8], end_stream: bool) -> Vec<u8> {
-        let flags = if end_stream { FLAG_END_STREAM } else { 0 };
+        let max_frame = self.remote_max_frame_size();
+        let mut frames = Vec::new();
+        let mut offset = 0;
 
-        let frame = Http2Frame {
-            length: data.len() as u32,
-            frame_type: FRAME_DATA,
-            flags,
-            stream_id,
-            payload: data.to_vec(),
-        };
+        loop {
+            let chunk = (data.len() - offset).min(max_frame);
+            let last = offset + chunk == data.len();
 
-        frame.serialize()
+            let frame = Http2Frame {
+                length: chunk as u32,
+                frame_type: FRAME_DATA,
+                flags: if end_stream && last { FLAG_END_STREAM } else { 0 },
+                stream_id,
+                payload: data[offset..offset + chunk].to_vec(),
+            };
+            frames.extend_from_slice(&frame.serialize());
+
+            offset += chunk;
+            if last {
+                break;
+            }
+        }
+
+        frames
     }
 
     pub fn build_priority_frame(&self, stream_id: u32) -> Option<Vec<u8>> {
@@ -263,22 +712,40 @@ impl Http2Handler {
         self.priority_tree.update_priority(stream_id, priority);
     }
 
+    /// Accepts an arbitrary chunk of the byte stream: processes every frame
+    /// completed by it and keeps any trailing partial frame for the next call.
     pub fn handle_incoming_frame(&mut self, data: &[u8]) -> Result<Vec<u8>> {
-        if !self.preface_received && data.starts_with(PREFACE) {
-            self.preface_received = true;
-            if data.len() > PREFACE.len() {
-                return self.handle_incoming_frame(&data[PREFACE.len()..]);
+        self.frame_buffer.push(data);
+
+        if !self.preface_received {
+            match self.frame_buffer.strip_preface() {
+                Some(true) => self.preface_received = true,
+                Some(false) => {}
+                None => return Ok(Vec::new()),
             }
-            return Ok(Vec::new());
         }
 
-        let frame = Http2Frame::parse(data)?;
-        let response = self.process_frame(&frame)?;
+        let mut response = Vec::new();
+        while let Some(frame) = self.frame_buffer.next_frame()? {
+            response.extend_from_slice(&self.process_frame(&frame)?);
+        }
 
         Ok(response)
     }
 
     fn process_frame(&mut self, frame: &Http2Frame) -> Result<Vec<u8>> {
+        if let Some((stream_id, _)) = &self.pending_header_block {
+            if frame.frame_type != FRAME_CONTINUATION || frame.stream_id != *stream_id {
+                let stream_id = *stream_id;
+                log::warn!("Expected CONTINUATION on stream {}, got frame type {}", stream_id, frame.frame_type);
+                return Ok(self.reject_stream(stream_id));
+            }
+        }
+
+        if matches!(frame.frame_type, FRAME_DATA | FRAME_HEADERS) && frame.is_end_stream() {
+            self.open_client_streams.remove(&frame.stream_id);
+        }
+
         match frame.frame_type {
             FRAME_DATA => self.handle_data_frame(frame),
             FRAME_HEADERS => self.handle_headers_frame(frame),
@@ -298,7 +765,15 @@ impl Http2Handler {
     }
 
     fn handle_data_frame(&mut self, frame: &Http2Frame) -> Result<Vec<u8>> {
-        self.flow_controller.update_window(frame.stream_id, frame.length);
+        self.flow_controller.record_received(frame.stream_id, frame.length);
+
+        if self.h1_downgrade {
+            let data = Self::padded_payload(frame)?;
+            self.request_bodies.entry(frame.stream_id).or_default().extend_from_slice(data);
+            if frame.is_end_stream() {
+                self.completed_requests.push(frame.stream_id);
+            }
+        }
 
         if frame.is_end_stream() {
             if let Some(state) = self.stream_states.get_mut(&frame.stream_id) {
@@ -318,15 +793,140 @@ impl Http2Handler {
             self.create_stream(frame.stream_id)?;
         }
 
+        let block = Self::header_block_fragment(frame)?;
+        if frame.is_end_headers() {
+            self.finish_header_block(frame.stream_id, block)?;
+        } else {
+            self.pending_header_block = Some((frame.stream_id, block.to_vec()));
+        }
+
         if frame.is_end_stream() {
             if let Some(state) = self.stream_states.get_mut(&frame.stream_id) {
                 *state = StreamState::HalfClosedRemote;
             }
+            if self.h1_downgrade {
+                self.completed_requests.push(frame.stream_id);
+            }
         }
 
         Ok(Vec::new())
     }
 
+    fn finish_header_block(&mut self, stream_id: u32, block: &[u8]) -> Result<()> {
+        let headers = self.hpack_decoder.decode(block)?;
+        self.decoded_headers.insert(stream_id, headers);
+        Ok(())
+    }
+
+    fn reject_stream(&mut self, stream_id: u32) -> Vec<u8> {
+        self.pending_header_block = None;
+        self.teardown_stream(stream_id);
+
+        self.build_rst_stream_frame(stream_id, ERROR_PROTOCOL)
+    }
+
+    fn teardown_stream(&mut self, stream_id: u32) {
+        self.open_client_streams.remove(&stream_id);
+        if let Some(state) = self.stream_states.get_mut(&stream_id) {
+            *state = StreamState::Closed;
+        }
+        if matches!(self.pending_header_block, Some((id, _)) if id == stream_id) {
+            self.pending_header_block = None;
+        }
+        self.flow_controller.remove_stream(stream_id);
+        self.decoded_headers.remove(&stream_id);
+        self.request_bodies.remove(&stream_id);
+        self.pending_data.remove(&stream_id);
+        self.relay_end_stream.remove(&stream_id);
+        self.completed_requests.retain(|&id| id != stream_id);
+    }
+
+    /// Watches frames we relay from the other side without rewriting them, so
+    /// a RST_STREAM sent the other way still tears down our stream state and
+    /// that side's SETTINGS_HEADER_TABLE_SIZE bounds the relay encoder.
+    pub fn observe_outgoing(&mut self, data: &[u8]) -> Result<()> {
+        self.outgoing_buffer.push(data);
+        if self.outgoing_buffer.strip_preface().is_none() {
+            return Ok(());
+        }
+
+        while let Some(frame) = self.outgoing_buffer.next_frame()? {
+            match frame.frame_type {
+                FRAME_RST_STREAM => self.teardown_stream(frame.stream_id),
+                FRAME_HEADERS => {
+                    self.last_client_stream_id = self.last_client_stream_id.max(frame.stream_id);
+                    if frame.stream_id % 2 == 1 {
+                        self.open_client_streams.insert(frame.stream_id);
+                    }
+                }
+                // Re-encoded header blocks go to this side, so its table size bounds relay_encoder
+                FRAME_SETTINGS if (frame.flags & FLAG_ACK) == 0 => {
+                    for setting in frame.payload.chunks_exact(6) {
+                        if u16::from_be_bytes([setting[0], setting[1]]) == SETTINGS_HEADER_TABLE_SIZE {
+                            let size = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                            self.relay_encoder.set_max_table_size(size as usize);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn last_client_stream_id(&self) -> u32 {
+        self.last_client_stream_id
+    }
+
+    /// A client request is still in flight, so a GOAWAY drain must wait
+    pub fn has_open_client_streams(&self) -> bool {
+        !self.open_client_streams.is_empty()
+    }
+
+    /// Peer-controlled HPACK table, bounded by our SETTINGS_HEADER_TABLE_SIZE
+    pub fn decoder_table(&self) -> &DynamicTable {
+        self.hpack_decoder.table()
+    }
+
+    fn padded_payload(frame: &Http2Frame) -> Result<&[u8]> {
+        if (frame.flags & FLAG_PADDED) == 0 {
+            return Ok(&frame.payload);
+        }
+
+        let pad_len = *frame.payload.first().ok_or_else(|| anyhow::anyhow!("Missing pad length"))? as usize;
+        if 1 + pad_len > frame.payload.len() {
+            return Err(anyhow::anyhow!("DATA padding exceeds frame length"));
+        }
+
+        Ok(&frame.payload[1..frame.payload.len() - pad_len])
+    }
+
+    fn header_block_fragment(frame: &Http2Frame) -> Result<&[u8]> {
+        let payload = &frame.payload[..];
+        let mut start = 0;
+        let mut pad_len = 0;
+
+        if (frame.flags & FLAG_PADDED) != 0 {
+            pad_len = *payload.first().ok_or_else(|| anyhow::anyhow!("Missing pad length"))? as usize;
+            start += 1;
+        }
+        if (frame.flags & FLAG_PRIORITY) != 0 {
+            start += 5;
+        }
+
+        if start + pad_len > payload.len() {
+            return Err(anyhow::anyhow!("HEADERS padding exceeds frame length"));
+        }
+
+        Ok(&payload[start..payload.len() - pad_len])
+    }
+
+    /// Headers decoded from the most recent complete header block on the stream
+    pub fn take_decoded_headers(&mut self, stream_id: u32) -> Option<Vec<(String, String)>> {
+        self.decoded_headers.remove(&stream_id)
+    }
+
     fn handle_priority_frame(&mut self, frame: &Http2Frame) -> Result<Vec<u8>> {
         if frame.payload.len() >= 5 {
             let depends_on = u32::from_be_bytes([
@@ -351,10 +951,7 @@ impl Http2Handler {
     }
 
     fn handle_rst_stream_frame(&mut self, frame: &Http2Frame) -> Result<Vec<u8>> {
-        if let Some(state) = self.stream_states.get_mut(&frame.stream_id) {
-            *state = StreamState::Closed;
-        }
-        self.flow_controller.remove_stream(frame.stream_id);
+        self.teardown_stream(frame.stream_id);
 
         Ok(Vec::new())
     }
@@ -377,7 +974,10 @@ impl Http2Handler {
             ]);
 
             match id {
-                0x01 => settings.header_table_size = value,
+                0x01 => {
+                    settings.header_table_size = value;
+                    self.hpack_encoder.set_max_table_size(value as usize);
+                }
                 0x02 => settings.enable_push = value != 0,
                 0x03 => settings.max_concurrent_streams = value,
                 0x04 => settings.initial_window_size = value,
@@ -400,6 +1000,13 @@ impl Http2Handler {
 
     fn handle_ping_frame(&mut self, frame: &Http2Frame) -> Result<Vec<u8>> {
         if (frame.flags & FLAG_ACK) != 0 {
+            if self.is_keepalive_ack(frame) {
+                if let Some((_, sent)) = self.pending_ping.take() {
+                    let rtt = Instant::now().saturating_duration_since(sent);
+                    log::debug!("HTTP/2 keepalive PING ACK after {:?}", rtt);
+                    self.ping_rtt = Some(rtt);
+                }
+            }
             return Ok(Vec::new());
         }
 
@@ -432,10 +1039,34 @@ impl Http2Handler {
         Ok(Vec::new())
     }
 
-    fn handle_continuation_frame(&mut self, _frame: &Http2Frame) -> Result<Vec<u8>> {
+    fn handle_continuation_frame(&mut self, frame: &Http2Frame) -> Result<Vec<u8>> {
+        let Some((stream_id, mut block)) = self.pending_header_block.take() else {
+            log::warn!("Unexpected CONTINUATION on stream {}", frame.stream_id);
+            return Ok(self.reject_stream(frame.stream_id));
+        };
+
+        block.extend_from_slice(&frame.payload);
+
+        if frame.is_end_headers() {
+            self.finish_header_block(stream_id, &block)?;
+        } else {
+            self.pending_header_block = Some((stream_id, block));
+        }
+
         Ok(Vec::new())
     }
 
+    pub fn build_rst_stream_frame(&self, stream_id: u32, error_code: u32) -> Vec<u8> {
+        let frame = Http2Frame {
+            length: 4,
+            frame_type: FRAME_RST_STREAM,
+            flags: 0,
+            stream_id,
+            payload: error_code.to_be_bytes().to_vec(),
+        };
+        frame.serialize()
+    }
+
     pub fn build_settings_ack(&self) -> Vec<u8> {
         let frame = Http2Frame {
             length: 0,
@@ -493,9 +1124,50 @@ impl Http2Handler {
     }
 }
 
+fn build_h1_request(headers: &[(String, String)], body: &[u8]) -> Vec<u8> {
+    let pseudo = |name: &str| {
+        headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    };
+    let method = pseudo(":method").unwrap_or("GET");
+    let path = pseudo(":path").unwrap_or("/");
+
+    let mut request = format!("{} {} HTTP/1.1\r\n", method, path);
+    if let Some(authority) = pseudo(":authority") {
+        if !headers.iter().any(|(n, _)| n == "host") {
+            request.push_str(&format!("Host: {}\r\n", authority));
+        }
+    }
+
+    // HTTP/2 may split cookies into separate fields; HTTP/1.1 wants one
+    let cookies: Vec<&str> = headers.iter()
+        .filter(|(n, _)| n == "cookie")
+        .map(|(_, v)| v.as_str())
+        .collect();
+
+    for (name, value) in headers {
+        if name.starts_with(':') || name == "cookie" || HOP_BY_HOP_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    if !cookies.is_empty() {
+        request.push_str(&format!("cookie: {}\r\n", cookies.join("; ")));
+    }
+    if !body.is_empty() && !headers.iter().any(|(n, _)| n == "content-length") {
+        request.push_str(&format!("content-length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+
+    let mut request = request.into_bytes();
+    request.extend_from_slice(body);
+    request
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::http2_advanced::{SETTINGS_MAX_FRAME_SIZE, SETTINGS_MAX_CONCURRENT_STREAMS};
 
     #[test]
     fn test_frame_parse() {
@@ -507,10 +1179,381 @@ mod tests {
         assert_eq!(frame.stream_id, 0);
     }
 
+    #[test]
+    fn test_frame_fed_byte_by_byte() {
+        let mut handler = Http2Handler::new_ios_safari();
+        handler.create_stream(1).unwrap();
+        let data = vec![0x5a; 20 * 1024];
+        let mut stream = handler.build_data_frame(1, &data, false);
+        stream.extend_from_slice(&handler.build_ping_frame(&[7; 8]));
+
+        let mut response = Vec::new();
+        for byte in &stream {
+            response.extend_from_slice(&handler.handle_incoming_frame(&[*byte]).unwrap());
+        }
+
+        // Only the PING completes an exchange; the DATA frame must not be mangled on the way
+        let ack = Http2Frame::parse(&response).unwrap();
+        assert_eq!(ack.frame_type, FRAME_PING);
+        assert_eq!(ack.flags, FLAG_ACK);
+        assert_eq!(ack.payload, vec![7; 8]);
+        assert!(handler.frame_buffer.is_empty());
+    }
+
+    #[test]
+    fn test_frame_buffer_splits_and_retains() {
+        let handler = Http2Handler::new_ios_safari();
+        let mut bytes = handler.build_ping_frame(&[1; 8]);
+        bytes.extend_from_slice(&handler.build_data_frame(1, &[2; 100], true));
+
+        let mut buffer = Http2FrameBuffer::new();
+        buffer.push(&bytes[..40]);
+
+        let ping = buffer.next_frame().unwrap().unwrap();
+        assert_eq!(ping.frame_type, FRAME_PING);
+        assert!(buffer.next_frame().unwrap().is_none());
+        assert_eq!(buffer.len(), 40 - 17);
+
+        buffer.push(&bytes[40..]);
+        let data = buffer.next_frame().unwrap().unwrap();
+        assert_eq!(data.frame_type, FRAME_DATA);
+        assert_eq!(data.payload, vec![2; 100]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_h1_downgrade_round_trip() {
+        let mut client = Http2Handler::new_ios_safari();
+        let mut server = Http2Handler::new_ios_safari();
+        server.enable_h1_downgrade();
+
+        let mut bytes = client.build_connection_preface();
+        bytes.extend_from_slice(&client.build_headers_frame(1, vec![
+            (":method".to_string(), "POST".to_string()),
+            (":scheme".to_string(), "https".to_string()),
+            (":path".to_string(), "/submit".to_string()),
+            (":authority".to_string(), "example.com".to_string()),
+            ("cookie".to_string(), "a=1".to_string()),
+            ("cookie".to_string(), "b=2".to_string()),
+        ], false));
+        bytes.extend_from_slice(&client.build_data_frame(1, b"hello", true));
+
+        server.handle_incoming_frame(&bytes).unwrap();
+        let requests = server.take_h1_requests();
+        assert_eq!(requests.len(), 1);
+        let request = String::from_utf8(requests[0].1.clone()).unwrap();
+        assert!(request.starts_with("POST /submit HTTP/1.1\r\nHost: example.com\r\n"));
+        assert!(request.contains("cookie: a=1; b=2\r\n"));
+        assert!(request.ends_with("content-length: 5\r\n\r\nhello"));
+
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        assert!(H1Response::parse(&raw[..raw.len() - 3], false, false).unwrap().is_none());
+        let (response, consumed) = H1Response::parse(raw, false, false).unwrap().unwrap();
+        assert_eq!(consumed, raw.len());
+
+        let frames = server.encode_h1_response(1, response);
+        client.handle_incoming_frame(&frames).unwrap();
+        let headers = client.take_decoded_headers(1).unwrap();
+        assert_eq!(headers[0], (":status".to_string(), "200".to_string()));
+        assert!(headers.contains(&("content-type".to_string(), "text/plain".to_string())));
+        assert!(!headers.iter().any(|(n, _)| n == "transfer-encoding"));
+
+        let mut buffer = Http2FrameBuffer::new();
+        buffer.push(&frames);
+        buffer.next_frame().unwrap();
+        let data = buffer.next_frame().unwrap().unwrap();
+        assert_eq!(data.frame_type, FRAME_DATA);
+        assert_eq!(data.payload, b"hello world");
+        assert!(data.is_end_stream());
+    }
+
+    #[test]
+    fn test_relay_strips_alt_svc() {
+        let mut server = Http2Handler::new_ios_safari();
+        let mut relay = Http2Handler::new_ios_safari();
+        let mut client = Http2Handler::new_ios_safari();
+        relay.strip_headers(&["alt-svc"]);
+
+        let mut bytes = Vec::new();
+        for stream_id in [1, 3] {
+            let mut frames = server.encode_header_block(stream_id, &[
+                (":status".to_string(), "200".to_string()),
+                ("alt-svc".to_string(), "h3=\":443\"; ma=86400".to_string()),
+                ("content-type".to_string(), "text/html".to_string()),
+            ], false);
+            frames.extend_from_slice(&server.build_data_frame(stream_id, b"body", true));
+            bytes.extend_from_slice(&frames);
+        }
+        let altsvc = Http2Frame {
+            length: 2,
+            frame_type: FRAME_ALTSVC,
+            flags: 0,
+            stream_id: 0,
+            payload: vec![0, 0],
+        };
+        bytes.extend_from_slice(&altsvc.serialize());
+
+        let (forward, _) = relay.relay_frames(&bytes).unwrap();
+
+        let mut buffer = Http2FrameBuffer::new();
+        buffer.push(&forward);
+        let mut types = Vec::new();
+        while let Some(frame) = buffer.next_frame().unwrap() {
+            types.push(frame.frame_type);
+        }
+        assert_eq!(types, vec![FRAME_HEADERS, FRAME_DATA, FRAME_HEADERS, FRAME_DATA]);
+
+        client.handle_incoming_frame(&forward).unwrap();
+        for stream_id in [1, 3] {
+            let headers = client.take_decoded_headers(stream_id).unwrap();
+            assert_eq!(headers, vec![
+                (":status".to_string(), "200".to_string()),
+                ("content-type".to_string(), "text/html".to_string()),
+            ]);
+        }
+    }
+
+    #[test]
+    fn test_relay_encoder_follows_client_table_size() {
+        let mut server = Http2Handler::new_ios_safari();
+        let mut relay = Http2Handler::new_ios_safari();
+        relay.strip_headers(&["alt-svc"]);
+
+        let mut client_bytes = PREFACE.to_vec();
+        client_bytes.extend_from_slice(&Http2Settings::from_entries(&[(SETTINGS_HEADER_TABLE_SIZE, 0)]).to_frame());
+        relay.observe_outgoing(&client_bytes).unwrap();
+        assert_eq!(relay.relay_encoder.table().max_size(), 0);
+
+        let headers = vec![(":status".to_string(), "200".to_string())];
+        let (forward, _) = relay.relay_frames(&server.encode_header_block(1, &headers, true)).unwrap();
+        let mut buffer = Http2FrameBuffer::new();
+        buffer.push(&forward);
+        let frame = buffer.next_frame().unwrap().unwrap();
+        // Dynamic table size update to 0 opens the first re-encoded block
+        assert_eq!(frame.payload[0], 0x20);
+    }
+
+    #[test]
+    fn test_rst_stream_forwarded_both_ways() {
+        let mut server = Http2Handler::new_ios_safari();
+        let mut relay = Http2Handler::new_ios_safari();
+        relay.strip_headers(&["alt-svc"]);
+
+        let headers = vec![(":status".to_string(), "200".to_string())];
+        let mut bytes = server.encode_header_block(5, &headers, false);
+        bytes.extend_from_slice(&server.build_rst_stream_frame(5, 0x08));
+
+        let (forward, _) = relay.relay_frames(&bytes).unwrap();
+        let mut buffer = Http2FrameBuffer::new();
+        buffer.push(&forward);
+        buffer.next_frame().unwrap();
+        let rst = buffer.next_frame().unwrap().unwrap();
+        assert_eq!(rst.frame_type, FRAME_RST_STREAM);
+        assert_eq!(rst.stream_id, 5);
+        assert_eq!(rst.payload, 0x08u32.to_be_bytes().to_vec());
+        assert_eq!(relay.stream_states.get(&5), Some(&StreamState::Closed));
+
+        // Client-side reset of a stream the server opened
+        relay.handle_incoming_frame(&server.encode_header_block(7, &headers, false)).unwrap();
+        let mut client_bytes = PREFACE.to_vec();
+        client_bytes.extend_from_slice(&server.build_rst_stream_frame(7, 0x08));
+        relay.observe_outgoing(&client_bytes).unwrap();
+        assert_eq!(relay.stream_states.get(&7), Some(&StreamState::Closed));
+    }
+
     #[test]
     fn test_http2_handler_creation() {
         let handler = Http2Handler::new_ios_safari();
-        assert_eq!(handler.settings.initial_window_size, 1048576);
+        assert_eq!(handler.settings.initial_window_size, 2097152);
         assert_eq!(handler.settings.max_frame_size, 16384);
     }
+
+    #[test]
+    fn test_headers_frame_uses_hpack() {
+        let mut handler = Http2Handler::new_ios_safari();
+        let headers = vec![
+            (":method".to_string(), "GET".to_string()),
+            (":scheme".to_string(), "https".to_string()),
+            (":path".to_string(), "/".to_string()),
+        ];
+
+        let first = Http2Frame::parse(&handler.build_headers_frame(1, headers.clone(), true)).unwrap();
+        assert_eq!(first.payload, vec![0x82, 0x87, 0x84]);
+
+        let settings = Http2Frame {
+            length: 6,
+            frame_type: FRAME_SETTINGS,
+            flags: 0,
+            stream_id: 0,
+            payload: vec![0x00, 0x01, 0x00, 0x00, 0x00, 0x00],
+        };
+        handler.handle_incoming_frame(&settings.serialize()).unwrap();
+
+        let second = Http2Frame::parse(&handler.build_headers_frame(3, headers, true)).unwrap();
+        assert_eq!(second.payload[0], 0x20);
+    }
+
+    fn split_headers(handler: &mut Http2Handler, stream_id: u32) -> (Http2Frame, Http2Frame) {
+        let headers = vec![
+            (":status".to_string(), "200".to_string()),
+            ("set-cookie".to_string(), "a".repeat(200)),
+        ];
+        let full = Http2Frame::parse(&handler.build_headers_frame(stream_id, headers, false)).unwrap();
+        let (first, rest) = full.payload.split_at(full.payload.len() / 2);
+
+        let headers = Http2Frame {
+            length: first.len() as u32,
+            frame_type: FRAME_HEADERS,
+            flags: 0,
+            stream_id,
+            payload: first.to_vec(),
+        };
+        let continuation = Http2Frame {
+            length: rest.len() as u32,
+            frame_type: FRAME_CONTINUATION,
+            flags: FLAG_END_HEADERS,
+            stream_id,
+            payload: rest.to_vec(),
+        };
+        (headers, continuation)
+    }
+
+    #[test]
+    fn test_headers_with_continuation() {
+        let mut sender = Http2Handler::new_ios_safari();
+        let mut receiver = Http2Handler::new_ios_safari();
+        let (headers, continuation) = split_headers(&mut sender, 1);
+
+        receiver.handle_incoming_frame(&headers.serialize()).unwrap();
+        assert!(receiver.take_decoded_headers(1).is_none());
+
+        receiver.handle_incoming_frame(&continuation.serialize()).unwrap();
+        let decoded = receiver.take_decoded_headers(1).unwrap();
+        assert!(decoded.contains(&("set-cookie".to_string(), "a".repeat(200))));
+
+        // Any other frame mid-sequence resets the stream with PROTOCOL_ERROR
+        let (headers, _) = split_headers(&mut sender, 3);
+        receiver.handle_incoming_frame(&headers.serialize()).unwrap();
+        let ping = receiver.build_ping_frame(&[0; 8]);
+        let response = Http2Frame::parse(&receiver.handle_incoming_frame(&ping).unwrap()).unwrap();
+        assert_eq!(response.frame_type, FRAME_RST_STREAM);
+        assert_eq!(response.stream_id, 3);
+        assert_eq!(response.payload, ERROR_PROTOCOL.to_be_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_data_frame_split_by_max_frame_size() {
+        let mut handler = Http2Handler::new_ios_safari();
+        let peer = Http2Settings::from_entries(&[(SETTINGS_MAX_FRAME_SIZE, 16384)]);
+        handler.handle_incoming_frame(&peer.to_frame()).unwrap();
+
+        let bytes = handler.build_data_frame(1, &vec![0x42; 40 * 1024], true);
+        let mut buffer = Http2FrameBuffer::new();
+        buffer.push(&bytes);
+
+        let mut frames = Vec::new();
+        while let Some(frame) = buffer.next_frame().unwrap() {
+            frames.push(frame);
+        }
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames.iter().map(|f| f.length).collect::<Vec<_>>(), vec![16384, 16384, 8192]);
+        assert!(frames[..2].iter().all(|f| f.flags & FLAG_END_STREAM == 0));
+        assert_eq!(frames[2].flags & FLAG_END_STREAM, FLAG_END_STREAM);
+    }
+
+    #[test]
+    fn test_keepalive_ping() {
+        let mut handler = Http2Handler::new_ios_safari();
+        let idle_since = Instant::now();
+        assert!(handler.poll_keepalive(idle_since, idle_since).unwrap().is_none());
+
+        handler.set_keepalive(Duration::from_secs(30), Duration::from_secs(5));
+        assert!(handler.poll_keepalive(idle_since + Duration::from_secs(10), idle_since).unwrap().is_none());
+
+        let sent_at = idle_since + Duration::from_secs(30);
+        let ping = handler.poll_keepalive(sent_at, idle_since).unwrap().unwrap();
+        let ping = Http2Frame::parse(&ping).unwrap();
+        assert_eq!(ping.frame_type, FRAME_PING);
+        assert_eq!(ping.flags, 0);
+        assert_eq!(handler.keepalive_deadline(idle_since), Some(sent_at + Duration::from_secs(5)));
+
+        // Our ACK is consumed, not relayed to the client
+        let mut opaque = [0u8; 8];
+        opaque.copy_from_slice(&ping.payload);
+        let (forward, _) = handler.relay_frames(&handler.build_ping_ack(&opaque)).unwrap();
+        assert!(forward.is_empty());
+        assert!(handler.pending_ping.is_none());
+
+        // An unanswered PING tears the connection down
+        let sent_at = sent_at + Duration::from_secs(30);
+        handler.poll_keepalive(sent_at, sent_at - Duration::from_secs(30)).unwrap().unwrap();
+        assert!(handler.poll_keepalive(sent_at + Duration::from_secs(1), sent_at).unwrap().is_none());
+        assert!(handler.poll_keepalive(sent_at + Duration::from_secs(5), sent_at).is_err());
+    }
+
+    #[test]
+    fn test_keepalive_ping_rtt() {
+        let mut handler = Http2Handler::new_ios_safari();
+        handler.set_keepalive(Duration::from_millis(10), Duration::from_secs(5));
+        assert_eq!(handler.ping_rtt(), None);
+
+        let now = Instant::now();
+        let first = handler.poll_keepalive(now, now - Duration::from_millis(10)).unwrap().unwrap();
+        let first = Http2Frame::parse(&first).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        // An ACK with foreign opaque data is not ours
+        handler.relay_frames(&handler.build_ping_ack(&[0u8; 8])).unwrap();
+        assert_eq!(handler.ping_rtt(), None);
+
+        let mut opaque = [0u8; 8];
+        opaque.copy_from_slice(&first.payload);
+        handler.relay_frames(&handler.build_ping_ack(&opaque)).unwrap();
+        let rtt = handler.ping_rtt().unwrap();
+        assert!(rtt >= Duration::from_millis(20) && rtt < Duration::from_secs(5));
+
+        // Each PING carries fresh opaque data
+        let now = Instant::now();
+        let second = handler.poll_keepalive(now, now - Duration::from_millis(10)).unwrap().unwrap();
+        assert_ne!(Http2Frame::parse(&second).unwrap().payload, first.payload);
+    }
+
+    #[test]
+    fn test_max_concurrent_streams_enforced() {
+        let mut handler = Http2Handler::new_ios_safari();
+        let peer = Http2Settings::from_entries(&[(SETTINGS_MAX_CONCURRENT_STREAMS, 2)]);
+        handler.handle_incoming_frame(&peer.to_frame()).unwrap();
+
+        assert_eq!(handler.get_next_stream_id().unwrap(), 1);
+        assert_eq!(handler.get_next_stream_id().unwrap(), 3);
+        assert!(handler.get_next_stream_id().is_err());
+
+        // A reset from the peer frees the slot
+        handler.handle_incoming_frame(&handler.build_rst_stream_frame(1, ERROR_PROTOCOL)).unwrap();
+        assert_eq!(handler.get_next_stream_id().unwrap(), 5);
+        assert!(handler.get_next_stream_id().is_err());
+    }
+
+    #[test]
+    fn test_incoming_headers_decoded() {
+        let mut sender = Http2Handler::new_ios_safari();
+        let mut receiver = Http2Handler::new_ios_safari();
+        let headers = vec![
+            (":status".to_string(), "200".to_string()),
+            ("content-type".to_string(), "text/html".to_string()),
+            ("set-cookie".to_string(), "__cf_bm=abc".to_string()),
+        ];
+
+        for stream_id in [1, 3] {
+            let frame = sender.build_headers_frame(stream_id, headers.clone(), false);
+            receiver.handle_incoming_frame(&frame).unwrap();
+
+            let decoded = receiver.take_decoded_headers(stream_id).unwrap();
+            assert_eq!(decoded.len(), headers.len());
+            for header in &headers {
+                assert!(decoded.contains(header));
+            }
+        }
+    }
 }
\ No newline at end of file
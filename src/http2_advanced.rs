@@ -7,7 +7,35 @@ const WINDOW_UPDATE_THRESHOLD: u32 = 524288;
 const MAX_FRAME_SIZE: u32 = 16384;
 const HEADER_TABLE_SIZE: u32 = 65536;
 
-#[derive(Debug, Clone, Copy)]
+// SETTINGS identifiers
+pub const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x01;
+pub const SETTINGS_ENABLE_PUSH: u16 = 0x02;
+pub const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x03;
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x04;
+pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x05;
+pub const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x06;
+pub const SETTINGS_ENABLE_CONNECT_PROTOCOL: u16 = 0x08;
+pub const SETTINGS_NO_RFC7540_PRIORITIES: u16 = 0x09;
+
+// HPACK default from RFC 7540, used when HEADER_TABLE_SIZE isn't sent
+const DEFAULT_HEADER_TABLE_SIZE: u32 = 4096;
+
+pub fn setting_id(name: &str) -> Option<u16> {
+    let id = match name.to_uppercase().trim_start_matches("SETTINGS_") {
+        "HEADER_TABLE_SIZE" => SETTINGS_HEADER_TABLE_SIZE,
+        "ENABLE_PUSH" => SETTINGS_ENABLE_PUSH,
+        "MAX_CONCURRENT_STREAMS" => SETTINGS_MAX_CONCURRENT_STREAMS,
+        "INITIAL_WINDOW_SIZE" => SETTINGS_INITIAL_WINDOW_SIZE,
+        "MAX_FRAME_SIZE" => SETTINGS_MAX_FRAME_SIZE,
+        "MAX_HEADER_LIST_SIZE" => SETTINGS_MAX_HEADER_LIST_SIZE,
+        "ENABLE_CONNECT_PROTOCOL" => SETTINGS_ENABLE_CONNECT_PROTOCOL,
+        "NO_RFC7540_PRIORITIES" => SETTINGS_NO_RFC7540_PRIORITIES,
+        _ => return None,
+    };
+    Some(id)
+}
+
+#[derive(Debug, Clone)]
 pub struct Http2Settings {
     pub header_table_size: u32,
     pub enable_push: bool,
@@ -15,6 +43,9 @@ pub struct Http2Settings {
     pub initial_window_size: u32,
     pub max_frame_size: u32,
     pub max_header_list_size: u32,
+    /// Exactly what goes into our SETTINGS frame, in order; the order and
+    /// the set of ids are part of the browser fingerprint.
+    pub frame_entries: Vec<(u16, u32)>,
 }
 
 impl Default for Http2Settings {
@@ -26,20 +57,52 @@ impl Default for Http2Settings {
             initial_window_size: INITIAL_WINDOW_SIZE,
             max_frame_size: MAX_FRAME_SIZE,
             max_header_list_size: 0,
+            frame_entries: vec![
+                (SETTINGS_HEADER_TABLE_SIZE, HEADER_TABLE_SIZE),
+                (SETTINGS_INITIAL_WINDOW_SIZE, INITIAL_WINDOW_SIZE),
+                (SETTINGS_MAX_FRAME_SIZE, MAX_FRAME_SIZE),
+            ],
         }
     }
 }
 
 impl Http2Settings {
+    /// iOS Safari 17: ENABLE_PUSH, MAX_CONCURRENT_STREAMS, INITIAL_WINDOW_SIZE,
+    /// NO_RFC7540_PRIORITIES
     pub fn ios_safari() -> Self {
-        Self {
-            header_table_size: 65536,
-            enable_push: false,
+        Self::from_entries(&[
+            (SETTINGS_ENABLE_PUSH, 0),
+            (SETTINGS_MAX_CONCURRENT_STREAMS, 100),
+            (SETTINGS_INITIAL_WINDOW_SIZE, 2097152),
+            (SETTINGS_NO_RFC7540_PRIORITIES, 1),
+        ])
+    }
+
+    /// Settings that are not listed keep their protocol defaults.
+    pub fn from_entries(entries: &[(u16, u32)]) -> Self {
+        let mut settings = Self {
+            header_table_size: DEFAULT_HEADER_TABLE_SIZE,
+            enable_push: true,
             max_concurrent_streams: 100,
-            initial_window_size: 1048576,
-            max_frame_size: 16384,
+            initial_window_size: 65535,
+            max_frame_size: MAX_FRAME_SIZE,
             max_header_list_size: 0,
+            frame_entries: entries.to_vec(),
+        };
+
+        for &(id, value) in entries {
+            match id {
+                SETTINGS_HEADER_TABLE_SIZE => settings.header_table_size = value,
+                SETTINGS_ENABLE_PUSH => settings.enable_push = value != 0,
+                SETTINGS_MAX_CONCURRENT_STREAMS => settings.max_concurrent_streams = value,
+                SETTINGS_INITIAL_WINDOW_SIZE => settings.initial_window_size = value,
+                SETTINGS_MAX_FRAME_SIZE => settings.max_frame_size = value,
+                SETTINGS_MAX_HEADER_LIST_SIZE => settings.max_header_list_size = value,
+                _ => {}
+            }
         }
+
+        settings
     }
 
     pub fn to_frame(&self) -> Vec<u8> {
@@ -52,14 +115,10 @@ impl Http2Settings {
         
         let mut settings = Vec::new();
         
-        settings.extend_from_slice(&self.header_table_size.to_be_bytes()[2..4]);
-        settings.extend_from_slice(&self.header_table_size.to_be_bytes());
-        
-        settings.extend_from_slice(&[0x00, 0x04]);
-        settings.extend_from_slice(&self.initial_window_size.to_be_bytes());
-        
-        settings.extend_from_slice(&[0x00, 0x05]);
-        settings.extend_from_slice(&self.max_frame_size.to_be_bytes());
+        for (id, value) in &self.frame_entries {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&value.to_be_bytes());
+        }
         
         let length = settings.len() as u32;
         frame[0..3].copy_from_slice(&length.to_be_bytes()[1..4]);
@@ -73,6 +132,7 @@ impl Http2Settings {
 pub struct StreamState {
     pub id: u32,
     pub window_size: u32,
+    pub recv_window: u32,
     pub bytes_sent: u64,
     pub bytes_received: u64,
     pub last_update: Instant,
@@ -101,6 +161,7 @@ impl StreamState {
         Self {
             id,
             window_size: initial_window,
+            recv_window: initial_window,
             bytes_sent: 0,
             bytes_received: 0,
             last_update: Instant::now(),
@@ -123,17 +184,23 @@ impl StreamState {
     }
 
     pub fn should_send_window_update(&self) -> bool {
-        self.window_size < WINDOW_UPDATE_THRESHOLD
+        self.recv_window < WINDOW_UPDATE_THRESHOLD
     }
 
     pub fn update_received(&mut self, bytes: u32) {
+        self.recv_window = self.recv_window.saturating_sub(bytes);
         self.bytes_received += bytes as u64;
         self.last_update = Instant::now();
     }
 }
 
+/// Tracks both directions separately: `connection_window` and the stream
+/// `window_size` are what the peer lets us send, `connection_recv_window`
+/// and the stream `recv_window` are what we granted the peer.
 pub struct FlowController {
     connection_window: u32,
+    connection_recv_window: u32,
+    initial_window: u32,
     streams: HashMap<u32, StreamState>,
     window_updates: VecDeque<(u32, u32)>,
     last_update_time: Instant,
@@ -143,6 +210,8 @@ impl FlowController {
     pub fn new(initial_window: u32) -> Self {
         Self {
             connection_window: initial_window,
+            connection_recv_window: initial_window,
+            initial_window,
             streams: HashMap::new(),
             window_updates: VecDeque::new(),
             last_update_time: Instant::now(),
@@ -173,6 +242,7 @@ impl FlowController {
         Ok(false)
     }
 
+    /// Peer sent WINDOW_UPDATE: more send window for us.
     pub fn update_window(&mut self, stream_id: u32, increment: u32) {
         if stream_id == 0 {
             self.connection_window = self.connection_window.saturating_add(increment);
@@ -181,21 +251,37 @@ impl FlowController {
         }
     }
 
+    /// Peer sent DATA: it used up part of the receive window we granted.
+    pub fn record_received(&mut self, stream_id: u32, bytes: u32) {
+        self.connection_recv_window = self.connection_recv_window.saturating_sub(bytes);
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.update_received(bytes);
+        }
+    }
+
+    pub fn send_window(&self) -> u32 {
+        self.connection_window
+    }
+
+    pub fn recv_window(&self) -> u32 {
+        self.connection_recv_window
+    }
+
     pub fn check_and_queue_updates(&mut self) {
         let now = Instant::now();
         
-        if self.connection_window < WINDOW_UPDATE_THRESHOLD {
-            let increment = INITIAL_WINDOW_SIZE - self.connection_window;
+        if self.connection_recv_window < WINDOW_UPDATE_THRESHOLD {
+            let increment = self.initial_window - self.connection_recv_window;
             self.window_updates.push_back((0, increment));
-            self.connection_window = INITIAL_WINDOW_SIZE;
+            self.connection_recv_window = self.initial_window;
         }
 
         let mut stream_updates = Vec::new();
         for (id, stream) in self.streams.iter_mut() {
             if stream.should_send_window_update() {
-                let increment = INITIAL_WINDOW_SIZE - stream.window_size;
+                let increment = self.initial_window - stream.recv_window;
                 stream_updates.push((*id, increment));
-                stream.add_window(increment);
+                stream.recv_window = self.initial_window;
             }
         }
 
@@ -337,6 +423,28 @@ mod tests {
         assert!(frame.len() > 9);
     }
 
+    #[test]
+    fn test_ios_safari_settings_frame_layout() {
+        let frame = Http2Settings::ios_safari().to_frame();
+
+        // SETTINGS as sent by iOS Safari 17: 2:0;3:100;4:2097152;9:1
+        let captured: [u8; 33] = [
+            0x00, 0x00, 0x18, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x02, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x03, 0x00, 0x00, 0x00, 0x64,
+            0x00, 0x04, 0x00, 0x20, 0x00, 0x00,
+            0x00, 0x09, 0x00, 0x00, 0x00, 0x01,
+        ];
+        assert_eq!(frame, captured);
+
+        let custom = Http2Settings::from_entries(&[
+            (setting_id("MAX_CONCURRENT_STREAMS").unwrap(), 10),
+            (setting_id("SETTINGS_HEADER_TABLE_SIZE").unwrap(), 8192),
+        ]);
+        assert_eq!(custom.header_table_size, 8192);
+        assert_eq!(&custom.to_frame()[9..], &[0, 3, 0, 0, 0, 10, 0, 1, 0, 0, 0x20, 0]);
+    }
+
     #[test]
     fn test_flow_controller() {
         let mut fc = FlowController::new(INITIAL_WINDOW_SIZE);
@@ -346,6 +454,29 @@ mod tests {
         assert_eq!(fc.connection_window, INITIAL_WINDOW_SIZE - 1000);
     }
 
+    #[test]
+    fn test_receive_window_updates() {
+        let mut fc = FlowController::new(INITIAL_WINDOW_SIZE);
+        fc.create_stream(1, INITIAL_WINDOW_SIZE);
+
+        let total = 2 * 1024 * 1024;
+        let mut granted = HashMap::new();
+        for _ in 0..total / MAX_FRAME_SIZE {
+            fc.record_received(1, MAX_FRAME_SIZE);
+            fc.check_and_queue_updates();
+            while let Some((id, increment)) = fc.pop_window_update() {
+                *granted.entry(id).or_insert(0u32) += increment;
+            }
+        }
+
+        // Everything received is either granted back or still outstanding
+        assert_eq!(granted[&0] + (INITIAL_WINDOW_SIZE - fc.recv_window()), total);
+        let stream_outstanding = INITIAL_WINDOW_SIZE - fc.streams[&1].recv_window;
+        assert_eq!(granted[&1] + stream_outstanding, total);
+        assert_eq!(fc.send_window(), INITIAL_WINDOW_SIZE);
+        assert_eq!(fc.streams[&1].window_size, INITIAL_WINDOW_SIZE);
+    }
+
     #[test]
     fn test_priority_tree() {
         let tree = PriorityTree::ios_safari_defaults();
@@ -1,7 +1,6 @@
 use tokio::net::TcpListener;
 use std::sync::Arc;
 use anyhow::Result;
-use tokio::signal;
 
 mod config;
 mod proxy;
@@ -9,6 +8,7 @@ mod tls;
 mod tcp;
 mod udp;
 mod http2;
+mod hpack;
 mod packet;
 mod state;
 mod challenge;
@@ -19,12 +19,15 @@ mod graceful;
 mod http2_advanced;
 mod tcp_advanced;
 mod socks5;
+mod runtime;
+mod acl;
+mod pool;
+mod http1;
 
 use config::Config;
 use proxy::ProxyHandler;
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     env_logger::init();
 
     let args: Vec<String> = std::env::args().collect();
@@ -34,11 +37,20 @@ async fn main() -> Result<()> {
         "config.json"
     };
 
-    let config = Config::load(config_path).unwrap_or_else(|e| {
-        log::warn!("Failed to load {}: {}, using defaults", config_path, e);
+    // No file means defaults; a broken or invalid config fails startup
+    let config = if std::path::Path::new(config_path).exists() {
+        Config::load(config_path)
+            .map_err(|e| anyhow::anyhow!("Failed to load {}: {}", config_path, e))?
+    } else {
+        log::warn!("{} not found, using defaults", config_path);
         Config::default()
-    });
-    
+    };
+
+    let runtime = runtime::build_runtime(&config.runtime_settings)?;
+    runtime.block_on(run(config, config_path))
+}
+
+async fn run(config: Config, config_path: &str) -> Result<()> {
     log::info!("=================================================");
     log::info!("TPROXY v2.0 - Transparent Proxy with Fingerprinting");
     log::info!("=================================================");
@@ -59,6 +71,18 @@ async fn main() -> Result<()> {
     }
     log::info!("=================================================");
 
+    match udp::UdpForwarder::from_config(&config) {
+        Ok(Some(forwarder)) => {
+            tokio::spawn(async move {
+                if let Err(e) = forwarder.run().await {
+                    log::error!("UDP forwarder stopped: {}", e);
+                }
+            });
+        }
+        Ok(None) => log::info!("UDP: disabled"),
+        Err(e) => log::error!("UDP forwarder not started: {}", e),
+    }
+
     let proxy_handler = Arc::new(ProxyHandler::new(config));
 
     // Cleanup task
@@ -67,19 +91,14 @@ async fn main() -> Result<()> {
         cleanup_handler.cleanup_task().await;
     });
 
-    // Graceful shutdown handler
-    let shutdown_handler = proxy_handler.clone();
-    tokio::spawn(async move {
-        match signal::ctrl_c().await {
-            Ok(()) => {
-                log::info!("Received SIGINT, initiating graceful shutdown...");
-                // Можно добавить логику shutdown
-            }
-            Err(err) => {
-                log::error!("Failed to listen for SIGINT: {}", err);
-            }
+    // Graceful shutdown: a signal stops accepting, then open connections drain
+    let mut signals = match graceful::ShutdownSignals::new() {
+        Ok(signals) => Some(signals),
+        Err(err) => {
+            log::error!("Failed to listen for SIGINT/SIGTERM: {}", err);
+            None
         }
-    });
+    };
 
     let listen_addr = "127.0.0.1:8080";
     let listener = TcpListener::bind(listen_addr).await?;
@@ -87,8 +106,22 @@ async fn main() -> Result<()> {
     log::info!("Ready to accept connections");
 
     loop {
-        match listener.accept().await {
+        let accepted = tokio::select! {
+            name = async {
+                match signals.as_mut() {
+                    Some(signals) => signals.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                log::info!("Received {}, initiating graceful shutdown...", name);
+                break;
+            }
+            accepted = listener.accept() => accepted,
+        };
+
+        match accepted {
             Ok((stream, addr)) => {
+                let addr = acl::normalize_addr(addr);
                 log::debug!("New connection from {}", addr);
                 
                 let handler = proxy_handler.clone();
@@ -106,4 +139,9 @@ async fn main() -> Result<()> {
             }
         }
     }
-}
\ No newline at end of file
+
+    drop(listener);
+    proxy_handler.shutdown().await;
+    log::info!("Shutdown complete");
+    Ok(())
+}
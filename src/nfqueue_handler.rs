@@ -15,7 +15,7 @@ impl PacketProcessor {
     }
     
     pub fn modify_packet(&self, _data: &[u8]) -> Option<Vec<u8>> {
-        // Заглушка - в реальной реализации здесь будет модификация пакетов
+        // Stub - a real implementation would modify packets here
         None
     }
 }
@@ -42,11 +42,11 @@ impl NfqueueHandler {
     }
 
     fn run_queue_blocking(queue_num: u16) -> Result<()> {
-        // Заглушка для nfqueue - требует libnetfilter_queue
-        // В продакшене нужна полная реализация
+        // nfqueue stub - needs libnetfilter_queue
+        // Production needs a full implementation
         info!("NFQUEUE handler would run on queue {} (not implemented in this build)", queue_num);
         
-        // Примерная структура реализации:
+        // Rough shape of the implementation:
         // let mut queue = Queue::open()?;
         // queue.bind(queue_num)?;
         // 
@@ -4,6 +4,63 @@ use pnet::packet::tcp::TcpPacket;
 use pnet::packet::ipv4::Ipv4Packet;
 use log::debug;
 
+use crate::config::FingerprintProfile;
+use crate::tcp::TcpOptionsExact;
+
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_FLAG_ACK: u8 = 0x10;
+const TCP_OPT_EOL: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+const TCP_OPT_WINDOW_SCALE: u8 = 3;
+const TCP_OPT_SACK_PERMITTED: u8 = 4;
+const TCP_OPT_TIMESTAMP: u8 = 8;
+
+/// SYN TCP options set by the profile. MSS and window scale stay as the
+/// kernel chose them: it sizes the windows and segments of the whole connection by them
+#[derive(Debug, Clone, PartialEq)]
+pub struct SynOptions {
+    pub timestamps: bool,
+    pub sack_permitted: bool,
+}
+
+impl SynOptions {
+    pub fn from_profile(profile: &FingerprintProfile) -> Self {
+        Self {
+            timestamps: profile.tcp_timestamps.unwrap_or(true),
+            sack_permitted: true,
+        }
+    }
+
+    /// Options in Apple's order: MSS, NOP, WS, NOP, NOP, TS, SACK_PERM, EOL.
+    /// Values come from the original SYN; the profile can only remove
+    /// timestamps and SACK, not add them if the kernel didn't enable them
+    fn encode(&self, original: &TcpOptionsExact) -> Vec<u8> {
+        let mut options = Vec::new();
+        if let Some(mss) = original.mss {
+            options.extend_from_slice(&[TCP_OPT_MSS, 4]);
+            options.extend_from_slice(&mss.to_be_bytes());
+        }
+        if let Some(shift) = original.window_scale {
+            options.extend_from_slice(&[TCP_OPT_NOP, TCP_OPT_WINDOW_SCALE, 3, shift]);
+        }
+        if let (true, Some(value)) = (self.timestamps, original.timestamp_value) {
+            options.extend_from_slice(&[TCP_OPT_NOP, TCP_OPT_NOP, TCP_OPT_TIMESTAMP, 10]);
+            options.extend_from_slice(&value.to_be_bytes());
+            options.extend_from_slice(&original.timestamp_echo.unwrap_or(0).to_be_bytes());
+        }
+        if self.sack_permitted && original.sack_permitted {
+            options.extend_from_slice(&[TCP_OPT_SACK_PERMITTED, 2]);
+        }
+
+        // The header length is a multiple of 4 bytes
+        while options.len() % 4 != 0 {
+            options.push(TCP_OPT_EOL);
+        }
+        options
+    }
+}
+
 pub struct PacketModifier {
 }
 
@@ -171,6 +228,51 @@ impl PacketModifier {
         }
     }
 
+    /// Reorders the options of an outgoing SYN into the profile's order: rebuilds the TCP
+    /// header, data offset, total length and both checksums. SYN-ACKs and
+    /// packets without SYN are left untouched
+    pub fn rewrite_syn_options(&self, packet: &mut Vec<u8>, options: &SynOptions) -> bool {
+        let Some(ip_header_len) = self.get_ip_header_length(packet) else {
+            return false;
+        };
+        if packet.len() < ip_header_len + 20
+            || packet[ip_header_len + 13] & (TCP_FLAG_SYN | TCP_FLAG_ACK) != TCP_FLAG_SYN
+        {
+            return false;
+        }
+
+        let tcp_header_len = ((packet[ip_header_len + 12] >> 4) as usize) * 4;
+        if tcp_header_len < 20 || packet.len() < ip_header_len + tcp_header_len {
+            return false;
+        }
+
+        let Some(original) = TcpPacket::new(&packet[ip_header_len..]).map(|tcp| TcpOptionsExact::from_packet(&tcp)) else {
+            return false;
+        };
+        let new_options = options.encode(&original);
+
+        let mut rebuilt = packet[..ip_header_len + 20].to_vec();
+        rebuilt.extend_from_slice(&new_options);
+        rebuilt.extend_from_slice(&packet[ip_header_len + tcp_header_len..]);
+
+        let data_offset = (20 + new_options.len()) / 4;
+        rebuilt[ip_header_len + 12] = (data_offset as u8) << 4 | (rebuilt[ip_header_len + 12] & 0x0F);
+        let total_len = rebuilt.len() as u16;
+        rebuilt[2..4].copy_from_slice(&total_len.to_be_bytes());
+
+        Self::recalculate_ip_checksum(&mut rebuilt, ip_header_len);
+        self.recalculate_tcp_checksum(&mut rebuilt, ip_header_len, 20 + new_options.len());
+        *packet = rebuilt;
+        true
+    }
+
+    fn recalculate_ip_checksum(packet: &mut [u8], ip_header_len: usize) {
+        packet[10] = 0;
+        packet[11] = 0;
+        let checksum = !fold_checksum(sum_words(&packet[..ip_header_len]));
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+
     fn recalculate_tcp_checksum(&self, packet: &mut [u8], ip_header_len: usize, _tcp_header_len: usize) {
         if packet.len() < ip_header_len + 20 {
             return;
@@ -195,14 +297,8 @@ impl PacketModifier {
 
         let tcp_length = packet.len() - ip_header_len;
 
-        let mut sum: u32 = 0;
-
-        for byte in src_ip.octets() {
-            sum += byte as u32;
-        }
-        for byte in dst_ip.octets() {
-            sum += byte as u32;
-        }
+        // Pseudo-header: addresses are summed as 16-bit words
+        let mut sum = sum_words(&src_ip.octets()) + sum_words(&dst_ip.octets());
 
         sum += 6;
         sum += tcp_length as u32;
@@ -227,6 +323,20 @@ impl PacketModifier {
     }
 }
 
+/// Sum of 16-bit words (an odd tail is padded with zero)
+fn sum_words(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum()
+}
+
+fn fold_checksum(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -236,4 +346,77 @@ mod tests {
         let modifier = PacketModifier::new();
         assert!(true);
     }
-}
\ No newline at end of file
+
+    /// IPv4 + TCP SYN with Linux options: MSS, SACK_PERM, TS, NOP, WS 7
+    fn linux_syn(payload: &[u8]) -> Vec<u8> {
+        let mut tcp = vec![0x9c, 0x40, 0x01, 0xbb, 0, 0, 0, 1, 0, 0, 0, 0, 0xa0, TCP_FLAG_SYN, 0xfa, 0xf0, 0, 0, 0, 0];
+        tcp.extend_from_slice(&[TCP_OPT_MSS, 4, 0x05, 0xb4, TCP_OPT_SACK_PERMITTED, 2, TCP_OPT_TIMESTAMP, 10]);
+        tcp.extend_from_slice(&0x1234_5678u32.to_be_bytes());
+        tcp.extend_from_slice(&0u32.to_be_bytes());
+        tcp.extend_from_slice(&[TCP_OPT_NOP, TCP_OPT_WINDOW_SCALE, 3, 7]);
+        tcp.extend_from_slice(payload);
+
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 6, 0, 0, 10, 0, 0, 2, 93, 184, 216, 34];
+        packet.extend_from_slice(&tcp);
+        let total_len = packet.len() as u16;
+        packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_syn_options_rewritten_to_profile() {
+        let modifier = PacketModifier::new();
+        let profile = crate::config::Config::default().get_default_profile().unwrap().clone();
+        let options = SynOptions::from_profile(&profile);
+
+        let mut packet = linux_syn(b"");
+        assert!(modifier.rewrite_syn_options(&mut packet, &options));
+
+        let ip = Ipv4Packet::new(&packet).unwrap();
+        assert_eq!(ip.get_total_length() as usize, packet.len());
+        assert_eq!(ip.get_checksum(), pnet::packet::ipv4::checksum(&ip));
+
+        let tcp = TcpPacket::new(&packet[20..]).unwrap();
+        assert_eq!(tcp.get_data_offset(), 11);
+        assert_eq!(tcp.get_checksum(), pnet::packet::tcp::ipv4_checksum(&tcp, &ip.get_source(), &ip.get_destination()));
+
+        // The kernel's MSS and window scale are kept, only the order changes
+        let exact = crate::tcp::TcpOptionsExact::from_packet(&tcp);
+        assert_eq!(exact.mss, Some(1460));
+        assert_eq!(exact.window_scale, Some(7));
+        assert!(exact.sack_permitted);
+        assert_eq!(exact.timestamp_value, Some(0x1234_5678));
+        assert_eq!(&exact.options_raw[..8], &[TCP_OPT_MSS, 4, 0x05, 0xb4, TCP_OPT_NOP, TCP_OPT_WINDOW_SCALE, 3, 7]);
+
+        // Without timestamps in the profile the option is removed, the payload stays in place
+        let mut no_ts = profile.clone();
+        no_ts.tcp_timestamps = Some(false);
+        let mut packet = linux_syn(b"data");
+        assert!(modifier.rewrite_syn_options(&mut packet, &SynOptions::from_profile(&no_ts)));
+        let tcp = TcpPacket::new(&packet[20..]).unwrap();
+        let exact = crate::tcp::TcpOptionsExact::from_packet(&tcp);
+        assert_eq!((exact.mss, exact.timestamp_value), (Some(1460), None));
+        assert!(packet.ends_with(b"data"));
+
+        // Timestamps that weren't in the SYN are not added
+        let mut packet = linux_syn(b"");
+        assert!(modifier.rewrite_syn_options(&mut packet, &SynOptions::from_profile(&no_ts)));
+        assert!(modifier.rewrite_syn_options(&mut packet, &options));
+        let tcp = TcpPacket::new(&packet[20..]).unwrap();
+        assert_eq!(crate::tcp::TcpOptionsExact::from_packet(&tcp).timestamp_value, None);
+
+        // SYN-ACK - unchanged
+        let mut syn_ack = linux_syn(b"");
+        syn_ack[20 + 13] = TCP_FLAG_SYN | TCP_FLAG_ACK;
+        let original = syn_ack.clone();
+        assert!(!modifier.rewrite_syn_options(&mut syn_ack, &options));
+        assert_eq!(syn_ack, original);
+
+        // Not a SYN - unchanged
+        let mut ack = linux_syn(b"");
+        ack[20 + 13] = TCP_FLAG_ACK;
+        let original = ack.clone();
+        assert!(!modifier.rewrite_syn_options(&mut ack, &options));
+        assert_eq!(ack, original);
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use tokio::net::TcpStream;
+use nix::sys::socket::{recv, MsgFlags};
+use nix::errno::Errno;
+
+use crate::config::PoolSettings;
+
+/// Pool key: which proxy ("direct" for direct connections) and which target
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    pub proxy_addr: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl PoolKey {
+    pub fn new(proxy_addr: &str, host: &str, port: u16) -> Self {
+        Self {
+            proxy_addr: proxy_addr.to_string(),
+            host: host.to_string(),
+            port,
+        }
+    }
+}
+
+struct IdleStream {
+    stream: TcpStream,
+    returned_at: Instant,
+}
+
+/// Idle upstream connections ready for reuse.
+/// Only connections at a response boundary may be returned (HTTP/1.1 keep-alive)
+pub struct UpstreamPool {
+    max_idle_per_target: usize,
+    max_idle_total: usize,
+    idle_timeout: Duration,
+    idle: Mutex<HashMap<PoolKey, VecDeque<IdleStream>>>,
+}
+
+impl UpstreamPool {
+    pub fn new(max_idle_per_target: usize, max_idle_total: usize, idle_timeout: Duration) -> Self {
+        Self {
+            max_idle_per_target,
+            max_idle_total,
+            idle_timeout,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_settings(settings: &PoolSettings) -> Self {
+        Self::new(
+            settings.max_idle_per_target,
+            settings.max_idle_total,
+            Duration::from_millis(settings.idle_timeout_ms),
+        )
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_idle_per_target > 0 && self.max_idle_total > 0
+    }
+
+    /// Freshest live connection to the target; stale ones and those the upstream closed are dropped
+    pub fn checkout(&self, key: &PoolKey) -> Option<TcpStream> {
+        let mut idle = self.idle.lock();
+        let queue = idle.get_mut(key)?;
+
+        let mut found = None;
+        while let Some(entry) = queue.pop_back() {
+            if entry.returned_at.elapsed() < self.idle_timeout && is_reusable(&entry.stream) {
+                found = Some(entry.stream);
+                break;
+            }
+        }
+
+        if queue.is_empty() {
+            idle.remove(key);
+        }
+        found
+    }
+
+    /// Returns a connection to the pool; false if it's closed or the limits are reached
+    pub fn checkin(&self, key: PoolKey, stream: TcpStream) -> bool {
+        if !self.is_enabled() || !is_reusable(&stream) {
+            return false;
+        }
+
+        let mut idle = self.idle.lock();
+        let total: usize = idle.values().map(VecDeque::len).sum();
+        let queue = idle.entry(key).or_default();
+        if queue.len() >= self.max_idle_per_target || total >= self.max_idle_total {
+            return false;
+        }
+
+        queue.push_back(IdleStream { stream, returned_at: Instant::now() });
+        true
+    }
+
+    /// Closes connections idle for longer than idle_timeout
+    pub fn evict_idle(&self) -> usize {
+        let mut idle = self.idle.lock();
+        let mut evicted = 0;
+
+        idle.retain(|_, queue| {
+            let before = queue.len();
+            queue.retain(|entry| entry.returned_at.elapsed() < self.idle_timeout);
+            evicted += before - queue.len();
+            !queue.is_empty()
+        });
+
+        evicted
+    }
+
+    pub fn idle_count(&self) -> usize {
+        self.idle.lock().values().map(VecDeque::len).sum()
+    }
+}
+
+/// The connection is open and holds no unread data (the tail of a previous response)
+fn is_reusable(stream: &TcpStream) -> bool {
+    let mut byte = [0u8; 1];
+    matches!(
+        recv(stream.as_raw_fd(), &mut byte, MsgFlags::MSG_PEEK | MsgFlags::MSG_DONTWAIT),
+        Err(Errno::EAGAIN)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    async fn connected_pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_checkout_and_return() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = UpstreamPool::new(1, 8, Duration::from_secs(30));
+        let key = PoolKey::new("direct", "example.com", 80);
+
+        assert!(pool.checkout(&key).is_none());
+
+        let (first, _first_peer) = connected_pair(&listener).await;
+        let first_port = first.local_addr().unwrap().port();
+        assert!(pool.checkin(key.clone(), first));
+
+        // Per-target limit
+        let (second, _second_peer) = connected_pair(&listener).await;
+        assert!(!pool.checkin(key.clone(), second));
+        assert_eq!(pool.idle_count(), 1);
+
+        let reused = pool.checkout(&key).unwrap();
+        assert_eq!(reused.local_addr().unwrap().port(), first_port);
+        assert!(pool.checkout(&key).is_none());
+        assert!(pool.checkout(&PoolKey::new("direct", "other.com", 80)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_closed_and_dirty_streams_not_reused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = UpstreamPool::new(4, 8, Duration::from_secs(30));
+        let key = PoolKey::new("direct", "example.com", 80);
+
+        let (stream, mut peer) = connected_pair(&listener).await;
+        assert!(pool.checkin(key.clone(), stream));
+        peer.write_all(b"stale").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(pool.checkout(&key).is_none());
+
+        let (stream, peer) = connected_pair(&listener).await;
+        assert!(pool.checkin(key.clone(), stream));
+        drop(peer);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(pool.checkout(&key).is_none());
+        assert_eq!(pool.idle_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_idle_eviction() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = UpstreamPool::new(4, 8, Duration::from_millis(30));
+        let key = PoolKey::new("proxy:8080", "example.com", 443);
+
+        let (stream, _peer) = connected_pair(&listener).await;
+        assert!(pool.checkin(key.clone(), stream));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(pool.evict_idle(), 1);
+        assert_eq!(pool.idle_count(), 0);
+        assert!(pool.checkout(&key).is_none());
+    }
+}
@@ -1,73 +1,234 @@
 use std::sync::Arc;
-use tokio::net::TcpStream;
+use std::borrow::Cow;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::Semaphore;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use anyhow::Result;
 use std::os::unix::io::AsRawFd;
-
-use crate::config::Config;
-use crate::tls::{TlsClientHello, SessionTicketCache};
-use crate::challenge::ChallengeHandler;
-use crate::http2::Http2Handler;
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::{Config, FingerprintProfile};
+use crate::acl::{AccessList, normalize_addr};
+use crate::tls::{
+    TlsClientHello, ClientHelloOptions, GreaseMode, FingerprintFailures,
+    parse_version_name, fragment_record, is_handshake_rejection,
+};
+use crate::challenge::{ShardedChallengeHandler, is_redirect_status, clearance_cookies};
+use crate::http2::{Http2Handler, H1Response, ERROR_NO_ERROR};
+use crate::http2_advanced::{Http2Settings, setting_id};
 use crate::state::ConnectionStateManager;
 use crate::graceful::{GracefulShutdown, ConnectionRecovery};
-use crate::tcp_advanced::{configure_tcp_socket, apply_tcp_options};
-use crate::timing::TimingPreserver;
-use crate::socks5::{Socks5Connector, HttpsProxyConnector};
+use crate::tcp_advanced::{configure_tcp_socket, configure_keepalive, apply_tcp_options, apply_ip_fingerprint, read_tcp_rtt, peer_closed, set_linger_zero, enable_fastopen_connect, send_urgent, TcpWindowManager, UrgentWatcher};
+use crate::timing::{TimingPreserver, SpecializedTimers};
+use crate::zerocopy::{SplicePipe, RelayBuffer, is_splice_unsupported};
+use crate::http1::Http1Exchange;
+use crate::pool::{UpstreamPool, PoolKey};
+use crate::socks5::{ConnectTimeout, Socks5Connector, Socks4Connector, HttpsProxyConnector, is_retryable_connect_error};
 
 const BUFFER_SIZE: usize = 65536;
+/// Initial upstream socket window before the first measurements
+const INITIAL_UPSTREAM_WINDOW: u32 = 65535;
+/// How many response bytes are read whole to inspect challenges and redirects
+const MAX_BUFFERED_RESPONSE: usize = 1024 * 1024;
+/// How many bytes of unanswered keep-alive requests are kept for a retry
+const MAX_REPLAY_LEN: usize = 65536;
+const GATEWAY_TIMEOUT_RESPONSE: &[u8] =
+    b"HTTP/1.1 504 Gateway Timeout\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
 
 pub struct ProxyHandler {
     config: Arc<Config>,
-    session_cache: Arc<SessionTicketCache>,
-    challenge_handler: Arc<parking_lot::RwLock<ChallengeHandler>>,
+    challenge_handler: Arc<ShardedChallengeHandler>,
     state_manager: Arc<ConnectionStateManager>,
     graceful_shutdown: Arc<GracefulShutdown>,
+    access_list: AccessList,
+    empty_connections: AtomicU64,
+    /// Slots for client connections per tcp_settings.max_connections
+    /// (None - no limit); the permit is held until handling finishes
+    connection_slots: Option<Semaphore>,
+    rejected_connections: AtomicU64,
+    incomplete_client_hellos: AtomicU64,
+    zero_copy_bytes: AtomicU64,
+    mmap_relays: AtomicU64,
+    fingerprint_stats: FingerprintStats,
+    resumption_stats: ResumptionStats,
+    fingerprint_failures: FingerprintFailures,
+    upstream_pool: UpstreamPool,
+    timers: SpecializedTimers,
+}
+
+/// CPU cost of generating ClientHello fingerprints
+#[derive(Default)]
+pub struct FingerprintStats {
+    count: AtomicU64,
+    total_us: AtomicU64,
+    max_us: AtomicU64,
+}
+
+impl FingerprintStats {
+    fn record(&self, elapsed: Duration) {
+        let us = elapsed.as_micros() as u64;
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(us, Ordering::Relaxed);
+        self.max_us.fetch_max(us, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn average(&self) -> Duration {
+        let count = self.count();
+        if count == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_micros(self.total_us.load(Ordering::Relaxed) / count)
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max_us.load(Ordering::Relaxed))
+    }
+}
+
+/// How many ClientHellos carried the client's own ticket (resumption) and how
+/// many were full handshakes without one
+#[derive(Default)]
+pub struct ResumptionStats {
+    resumptions: AtomicU64,
+    full_handshakes: AtomicU64,
+}
+
+impl ResumptionStats {
+    fn record(&self, resumed: bool) {
+        let counter = if resumed { &self.resumptions } else { &self.full_handshakes };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn resumptions(&self) -> u64 {
+        self.resumptions.load(Ordering::Relaxed)
+    }
+
+    pub fn full_handshakes(&self) -> u64 {
+        self.full_handshakes.load(Ordering::Relaxed)
+    }
 }
 
 impl ProxyHandler {
     pub fn new(config: Config) -> Self {
+        let access_list = AccessList::from_settings(&config.acl_settings);
+        let fingerprint_failures = FingerprintFailures::new(
+            config.tls_settings.fingerprint_failure_threshold,
+            Duration::from_secs(config.tls_settings.fingerprint_failure_cooldown_secs),
+        );
+
+        let upstream_pool = UpstreamPool::from_settings(&config.pool_settings);
+        let connection_slots = (config.tcp_settings.max_connections > 0)
+            .then(|| Semaphore::new(config.tcp_settings.max_connections));
+        let timing = &config.timing_settings;
+        let timers = if timing.enabled {
+            SpecializedTimers::new(
+                Duration::from_millis(timing.tls_handshake_delay_ms),
+                Duration::from_millis(timing.http2_settings_delay_ms),
+            )
+        } else {
+            SpecializedTimers::default()
+        };
+        let graceful_shutdown = GracefulShutdown::new().with_shutdown_timeout(
+            Duration::from_secs(config.runtime_settings.shutdown_timeout_secs),
+            Duration::from_secs(config.runtime_settings.shutdown_progress_interval_secs),
+        );
+
         Self {
             config: Arc::new(config),
-            session_cache: Arc::new(SessionTicketCache::new()),
-            challenge_handler: Arc::new(parking_lot::RwLock::new(ChallengeHandler::new())),
+            challenge_handler: Arc::new(ShardedChallengeHandler::new()),
             state_manager: Arc::new(ConnectionStateManager::new()),
-            graceful_shutdown: Arc::new(GracefulShutdown::new()),
+            graceful_shutdown: Arc::new(graceful_shutdown),
+            access_list,
+            empty_connections: AtomicU64::new(0),
+            connection_slots,
+            rejected_connections: AtomicU64::new(0),
+            incomplete_client_hellos: AtomicU64::new(0),
+            zero_copy_bytes: AtomicU64::new(0),
+            mmap_relays: AtomicU64::new(0),
+            fingerprint_stats: FingerprintStats::default(),
+            resumption_stats: ResumptionStats::default(),
+            fingerprint_failures,
+            upstream_pool,
+            timers,
         }
     }
 
     pub async fn handle_connection(&self, mut client_stream: TcpStream) -> Result<()> {
+        if let Ok(peer) = client_stream.peer_addr().map(normalize_addr) {
+            if self.access_list.is_client_denied(peer.ip()) {
+                log::info!("Connection from {} denied by ACL", peer);
+                self.reject_connection(&client_stream);
+                return Ok(());
+            }
+        }
+
+        let _slot = match self.connection_slots.as_ref().map(Semaphore::try_acquire) {
+            Some(Err(_)) => {
+                let total = self.rejected_connections.fetch_add(1, Ordering::Relaxed) + 1;
+                log::warn!("Connection limit {} reached, closing new connection ({} rejected total)",
+                    self.config.tcp_settings.max_connections, total);
+                self.reject_connection(&client_stream);
+                return Ok(());
+            }
+            slot => slot,
+        };
+
         let conn_id = self.state_manager.create_connection();
+        self.record_connection_profile(conn_id);
         self.graceful_shutdown.register_connection(conn_id).await;
 
         let result = self.process_connection(&mut client_stream, conn_id).await;
 
         self.graceful_shutdown.unregister_connection(conn_id).await;
+        if let Some(info) = self.state_manager.get_connection(conn_id) {
+            log::info!(target: "access", "{}", info.summary());
+        }
         self.state_manager.remove_connection(conn_id);
 
         result
     }
 
+    /// Prepares a rejected connection for closing: with reject_with_rst
+    /// the client gets a RST, otherwise a regular FIN on drop
+    fn reject_connection(&self, stream: &TcpStream) {
+        if self.config.acl_settings.reject_with_rst {
+            if let Err(e) = set_linger_zero(stream) {
+                log::warn!("Failed to set SO_LINGER for rejected connection: {}", e);
+            }
+        }
+    }
+
     async fn process_connection(&self, client_stream: &mut TcpStream, conn_id: u64) -> Result<()> {
-        configure_tcp_socket(client_stream)?;
+        configure_tcp_socket(client_stream, self.tcp_nodelay(conn_id))?;
         
         // Apply iOS Safari TCP options
-        if let Err(e) = apply_tcp_options(client_stream, true) {
+        if let Err(e) = apply_tcp_options(client_stream, true, &self.config.tcp_settings.congestion_control) {
             log::warn!("Failed to apply TCP options: {}", e);
         }
+        self.apply_keepalive(client_stream);
 
         let mut buffer = vec![0u8; BUFFER_SIZE];
-        let n = client_stream.read(&mut buffer).await?;
+        let n = self.read_first_packet(client_stream, &mut buffer, conn_id).await?;
 
         if n == 0 {
             return Ok(());
         }
 
-        let request_data = &buffer[..n];
+        buffer.truncate(n);
+        let request_data = buffer.as_slice();
 
         if self.is_connect_method(request_data) {
             self.handle_connect_method(client_stream, request_data, conn_id).await
         } else if self.is_tls_handshake(request_data) {
-            self.handle_tls_connection(client_stream, request_data, conn_id).await
+            let Some(client_hello) = self.read_client_hello(client_stream, buffer, conn_id).await? else {
+                return Ok(());
+            };
+            self.handle_tls_connection(client_stream, &client_hello, conn_id).await
         } else if self.is_http_request(request_data) {
             self.handle_http_connection(client_stream, request_data, conn_id).await
         } else {
@@ -75,6 +236,104 @@ impl ProxyHandler {
         }
     }
 
+    /// First read from the client; a timeout or EOF counts as an empty connection
+    async fn read_first_packet(
+        &self,
+        client_stream: &mut TcpStream,
+        buffer: &mut [u8],
+        conn_id: u64,
+    ) -> Result<usize> {
+        let settings = &self.config.tcp_settings;
+
+        let n = if settings.empty_connection_timeout_ms > 0 {
+            let limit = Duration::from_millis(settings.empty_connection_timeout_ms);
+            match tokio::time::timeout(limit, client_stream.read(buffer)).await {
+                Ok(result) => result?,
+                Err(_) => 0,
+            }
+        } else {
+            client_stream.read(buffer).await?
+        };
+
+        if n == 0 {
+            let total = self.empty_connections.fetch_add(1, Ordering::Relaxed) + 1;
+            let level = settings.empty_connection_log_level.parse().unwrap_or(log::Level::Debug);
+            log::log!(level, "Connection {} sent no data ({} empty connections total)", conn_id, total);
+        }
+
+        Ok(n)
+    }
+
+    pub fn empty_connection_count(&self) -> u64 {
+        self.empty_connections.load(Ordering::Relaxed)
+    }
+
+    /// Limit of concurrent connections (0 - no limit)
+    pub fn max_connections(&self) -> usize {
+        self.config.tcp_settings.max_connections
+    }
+
+    pub fn active_connection_count(&self) -> usize {
+        self.state_manager.get_active_count()
+    }
+
+    /// How many connections were closed because of max_connections
+    pub fn rejected_connection_count(&self) -> u64 {
+        self.rejected_connections.load(Ordering::Relaxed)
+    }
+
+    /// Reads the TLS record with the ClientHello in full. A client that sent
+    /// a header without a body gets the connection closed (None) after
+    /// client_hello_timeout_ms instead of an endless wait
+    async fn read_client_hello(
+        &self,
+        client_stream: &mut TcpStream,
+        mut data: Vec<u8>,
+        conn_id: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        let timeout_ms = self.config.tls_settings.client_hello_timeout_ms;
+        let deadline = (timeout_ms > 0)
+            .then(|| tokio::time::Instant::now() + Duration::from_millis(timeout_ms));
+
+        let mut chunk = vec![0u8; BUFFER_SIZE];
+        while data.len() < 5 || data.len() < 5 + tls_record_len(&data) {
+            if data.len() >= 5 && tls_record_len(&data) > MAX_TLS_RECORD_LEN {
+                self.record_incomplete_client_hello(conn_id, data.len(), "oversized record");
+                return Ok(None);
+            }
+
+            let read = client_stream.read(&mut chunk);
+            let n = match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, read).await {
+                    Ok(result) => result?,
+                    Err(_) => {
+                        self.record_incomplete_client_hello(conn_id, data.len(), "timeout");
+                        return Ok(None);
+                    }
+                },
+                None => read.await?,
+            };
+
+            if n == 0 {
+                self.record_incomplete_client_hello(conn_id, data.len(), "EOF");
+                return Ok(None);
+            }
+            data.extend_from_slice(&chunk[..n]);
+        }
+
+        Ok(Some(data))
+    }
+
+    fn record_incomplete_client_hello(&self, conn_id: u64, received: usize, reason: &str) {
+        let total = self.incomplete_client_hellos.fetch_add(1, Ordering::Relaxed) + 1;
+        log::warn!("Connection {} sent TLS record header without full ClientHello ({} bytes, {}; {} total)",
+            conn_id, received, reason, total);
+    }
+
+    pub fn incomplete_client_hello_count(&self) -> u64 {
+        self.incomplete_client_hellos.load(Ordering::Relaxed)
+    }
+
     async fn handle_connect_method(
         &self,
         client_stream: &mut TcpStream,
@@ -86,10 +345,10 @@ impl ProxyHandler {
         
         log::debug!("CONNECT method to: {}", target);
 
-        let mut server_stream = self.connect_to_target(&target).await?;
+        let mut server_stream = self.connect_to_target(&target, conn_id).await?;
         
         // Apply TCP options to server connection
-        if let Err(e) = apply_tcp_options(&server_stream, false) {
+        if let Err(e) = self.apply_upstream_tcp_options(&server_stream, conn_id) {
             log::warn!("Failed to apply server TCP options: {}", e);
         }
 
@@ -104,30 +363,51 @@ impl ProxyHandler {
             return Ok(());
         }
 
-        let first_packet = &first_packet[..n];
+        first_packet.truncate(n);
+        if self.is_tls_handshake(&first_packet) {
+            match self.read_client_hello(client_stream, first_packet, conn_id).await? {
+                Some(client_hello) => first_packet = client_hello,
+                None => return Ok(()),
+            }
+        }
+        let first_packet = first_packet.as_slice();
+        let mut fingerprinted = None;
 
         if self.is_tls_handshake(first_packet) {
-            log::debug!("Detected TLS ClientHello, applying iOS Safari fingerprint");
+            let domain = split_host_port(&target, 443).0.to_string();
+            let sni = self.fingerprint_target(first_packet, &domain);
 
-            let domain = target.split(':').next().unwrap_or(&target).to_string();
+            if !self.fingerprint_failures.should_fingerprint(&sni) {
+                log::info!("Fingerprint disabled for {} after upstream resets, passing through", sni);
+                server_stream.write_all(first_packet).await?;
+            } else {
+                log::debug!("Detected TLS ClientHello, applying iOS Safari fingerprint");
 
-            match TlsClientHello::parse(first_packet) {
-                Ok(client_hello) => {
-                    match client_hello.to_ios_safari(Some(&self.session_cache), &domain) {
-                        Ok(modified_hello) => {
-                            log::info!("✓ TLS fingerprint applied: {} ({}→{} bytes)", 
-                                domain, first_packet.len(), modified_hello.len());
-                            server_stream.write_all(&modified_hello).await?;
-                        }
-                        Err(e) => {
-                            log::warn!("Failed to generate iOS ClientHello: {}, using original", e);
-                            server_stream.write_all(first_packet).await?;
+                match TlsClientHello::parse(first_packet) {
+                    Ok(client_hello) if client_hello.offers_psk() => {
+                        log::debug!("Connection {}: PSK/early data offered for {}, passing through", conn_id, sni);
+                        server_stream.write_all(first_packet).await?;
+                    }
+                    Ok(client_hello) => {
+                        match self.generate_fingerprint(&client_hello, &domain, conn_id) {
+                            Ok(modified_hello) => {
+                                log::info!("✓ TLS fingerprint applied: {} ({}→{} bytes)", 
+                                    domain, first_packet.len(), modified_hello.len());
+                                self.timers.apply_tls_delay().await;
+                                server_stream.write_all(&modified_hello).await?;
+                                server_stream.write_all(after_first_record(first_packet)).await?;
+                                fingerprinted = Some(sni);
+                            }
+                            Err(e) => {
+                                log::warn!("Failed to generate iOS ClientHello: {}, using original", e);
+                                server_stream.write_all(first_packet).await?;
+                            }
                         }
                     }
-                }
-                Err(e) => {
-                    log::warn!("Failed to parse ClientHello: {}, using original", e);
-                    server_stream.write_all(first_packet).await?;
+                    Err(e) => {
+                        log::warn!("Failed to parse ClientHello: {}, using original", e);
+                        server_stream.write_all(first_packet).await?;
+                    }
                 }
             }
         } else {
@@ -135,7 +415,42 @@ impl ProxyHandler {
             server_stream.write_all(first_packet).await?;
         }
 
-        self.proxy_bidirectional(client_stream, &mut server_stream, conn_id).await
+        self.proxy_bidirectional(client_stream, &mut server_stream, conn_id, fingerprinted.as_deref()).await
+    }
+
+    fn client_hello_options(&self, conn_id: u64) -> ClientHelloOptions {
+        match self.connection_profile(conn_id) {
+            Some(profile) => profile.client_hello_options(GreaseMode::default(), conn_id),
+            None => ClientHelloOptions::default(),
+        }
+    }
+
+    /// HTTP/2 SETTINGS from the profile; without them - the iOS Safari defaults
+    fn http2_settings(&self, conn_id: u64) -> Http2Settings {
+        let entries: Vec<(u16, u32)> = self.connection_profile(conn_id)
+            .map(|profile| profile.http2_settings.iter()
+                .filter_map(|entry| match setting_id(&entry.name) {
+                    Some(id) => Some((id, entry.value)),
+                    None => {
+                        log::warn!("Unknown HTTP/2 setting in profile: {}", entry.name);
+                        None
+                    }
+                })
+                .collect())
+            .unwrap_or_default();
+
+        if entries.is_empty() {
+            Http2Settings::ios_safari()
+        } else {
+            Http2Settings::from_entries(&entries)
+        }
+    }
+
+    fn fragment_client_hello(&self, hello: Vec<u8>, conn_id: u64) -> Vec<u8> {
+        match self.connection_profile(conn_id).and_then(|p| p.record_fragment_size) {
+            Some(size) => fragment_record(&hello, size),
+            None => hello,
+        }
     }
 
     fn extract_connect_target(&self, request: &str) -> Result<String> {
@@ -166,16 +481,90 @@ impl ProxyHandler {
         data.starts_with(b"DELETE ")
     }
 
+    /// ClientHello with the profile fingerprint (with record fragmentation). Generation time
+    /// goes into the stats, overly slow generation is logged
+    fn generate_fingerprint(&self, client_hello: &TlsClientHello, domain: &str, conn_id: u64) -> Result<Vec<u8>> {
+        let started = Instant::now();
+
+        // Only the client itself can resume a session with its own ticket: it has
+        // no secret for a ticket from the cache
+        let ticket = client_hello.extract_session_ticket();
+        match &ticket {
+            Some(ticket) => log::debug!("Connection {}: client resumes {} with {}-byte ticket", conn_id, domain, ticket.len()),
+            None => log::trace!("Connection {}: no client ticket for {}, full handshake", conn_id, domain),
+        }
+        self.resumption_stats.record(ticket.is_some());
+
+        let options = self.client_hello_options(conn_id);
+        let modified_hello = client_hello.to_ios_safari(domain, &options)?;
+        if cfg!(debug_assertions) || self.config.tls_settings.verify_generated_client_hello {
+            if let Err(e) = TlsClientHello::verify_generated(&modified_hello, domain) {
+                log::error!("[{}] Generated ClientHello for {} is malformed: {}", conn_id, domain, e);
+                return Err(e);
+            }
+        }
+        if self.config.tls_settings.log_ja4 {
+            self.log_ja4(client_hello, &modified_hello, domain);
+        }
+        let modified_hello = self.fragment_client_hello(modified_hello, conn_id);
+
+        let elapsed = started.elapsed();
+        self.fingerprint_stats.record(elapsed);
+
+        let threshold = self.config.tls_settings.fingerprint_warn_us;
+        if threshold > 0 && elapsed > Duration::from_micros(threshold) {
+            log::warn!("Fingerprint generation for {} took {:?} (threshold {}us, avg {:?})",
+                domain, elapsed, threshold, self.fingerprint_stats.average());
+        }
+
+        Ok(modified_hello)
+    }
+
+    /// Client and generated ClientHello JA3/JA4 for comparison with the reference
+    fn log_ja4(&self, original: &TlsClientHello, modified_hello: &[u8], domain: &str) {
+        match TlsClientHello::parse(modified_hello) {
+            Ok(generated) => {
+                let (client_ja4, generated_ja4) = (original.ja4_string(), generated.ja4_string());
+                log::info!("JA4 {}: client={} generated={}{}", domain, client_ja4, generated_ja4,
+                    if client_ja4 == generated_ja4 { " (unchanged)" } else { "" });
+                log::info!("JA3 {}: client={} generated={}", domain, original.ja3_string(), generated.ja3_string());
+            }
+            Err(e) => log::warn!("Failed to parse generated ClientHello for JA4: {}", e),
+        }
+    }
+
+    pub fn resumption_stats(&self) -> &ResumptionStats {
+        &self.resumption_stats
+    }
+
+    pub fn fingerprint_stats(&self) -> &FingerprintStats {
+        &self.fingerprint_stats
+    }
+
     async fn handle_tls_connection(
         &self,
         client_stream: &mut TcpStream,
         initial_data: &[u8],
         conn_id: u64,
     ) -> Result<()> {
-        let domain = self.extract_sni(initial_data).unwrap_or_default();
-
-        let client_hello = TlsClientHello::parse(initial_data)?;
-        let modified_hello = client_hello.to_ios_safari(Some(&self.session_cache), &domain)?;
+        let domain = self.fingerprint_target(initial_data, "");
+
+        let mut fingerprint = self.fingerprint_failures.should_fingerprint(&domain);
+        let hello = if fingerprint {
+            let client_hello = TlsClientHello::parse(initial_data)?;
+            if client_hello.offers_psk() {
+                log::debug!("Connection {}: PSK/early data offered for {}, passing through", conn_id, domain);
+                fingerprint = false;
+                initial_data.to_vec()
+            } else {
+                let mut hello = self.generate_fingerprint(&client_hello, &domain, conn_id)?;
+                hello.extend_from_slice(after_first_record(initial_data));
+                hello
+            }
+        } else {
+            log::info!("Fingerprint disabled for {} after upstream resets, passing through", domain);
+            initial_data.to_vec()
+        };
 
         let target = if !domain.is_empty() {
             format!("{}:443", domain)
@@ -183,12 +572,16 @@ impl ProxyHandler {
             "unknown:443".to_string()
         };
 
-        let mut server_stream = self.connect_to_target(&target).await?;
-        apply_tcp_options(&server_stream, false)?;
+        let mut server_stream = self.connect_to_target(&target, conn_id).await?;
+        self.apply_upstream_tcp_options(&server_stream, conn_id)?;
 
-        server_stream.write_all(&modified_hello).await?;
+        if fingerprint {
+            self.timers.apply_tls_delay().await;
+        }
+        server_stream.write_all(&hello).await?;
 
-        self.proxy_bidirectional(client_stream, &mut server_stream, conn_id).await
+        let fingerprinted = (fingerprint && !domain.is_empty()).then_some(domain.as_str());
+        self.proxy_bidirectional(client_stream, &mut server_stream, conn_id, fingerprinted).await
     }
 
     async fn handle_http_connection(
@@ -203,8 +596,13 @@ impl ProxyHandler {
         let target_host = self.extract_http_host(&request);
         log::debug!("Extracted target host: {}", target_host);
 
-        let mut server_stream = self.connect_to_target(&target_host).await?;
-        apply_tcp_options(&server_stream, false)?;
+        // An h2 connection lives alongside the client one; only HTTP/1.1 uses the pool
+        let pooled = if is_http2 { None } else { self.checkout_upstream(&target_host, conn_id) };
+        let reused = pooled.is_some();
+        let mut server_stream = match pooled {
+            Some(stream) => stream,
+            None => self.reconnect_http1(&target_host, conn_id).await?,
+        };
 
         let modified_request = if self.config.proxy_settings.is_direct() {
             self.rewrite_http_request(&request)
@@ -212,33 +610,58 @@ impl ProxyHandler {
             initial_data.to_vec()
         };
 
-        if is_http2 {
+        if is_http2 && self.config.http2_settings.h2_downgrade {
+            self.handle_h2_downgrade(client_stream, &mut server_stream, initial_data, conn_id).await
+        } else if is_http2 {
             self.handle_http2_connection(client_stream, &mut server_stream, &modified_request, conn_id).await
         } else {
-            server_stream.write_all(&modified_request).await?;
-            
             // Read response and check for challenges
-            let mut response_buffer = vec![0u8; BUFFER_SIZE];
-            let n = server_stream.read(&mut response_buffer).await?;
+            let read = self.send_first_request(client_stream, &mut server_stream, reused, &modified_request, &target_host, conn_id);
+            let timeout_ms = self.config.tcp_settings.first_response_timeout_ms;
+            let (mut response_buffer, mut exchange) = if timeout_ms > 0 {
+                match tokio::time::timeout(Duration::from_millis(timeout_ms), read).await {
+                    Ok(result) => result?,
+                    Err(_) => {
+                        log::warn!("Connection {}: no response from {} within {}ms", conn_id, target_host, timeout_ms);
+                        client_stream.write_all(GATEWAY_TIMEOUT_RESPONSE).await?;
+                        return Ok(());
+                    }
+                }
+            } else {
+                read.await?
+            };
             
-            if n > 0 {
-                let response_data = &response_buffer[..n];
+            let challenge_handling = self.config.challenge_settings.enable_challenge_handling;
+            if challenge_handling && self.may_be_challenge(&response_buffer) {
+                self.read_full_response(&mut server_stream, &mut exchange, &mut response_buffer).await?;
+            }
+
+            if !response_buffer.is_empty() {
+                let response_data = &response_buffer[..];
                 let response_str = String::from_utf8_lossy(response_data);
                 
                 // Check for challenge/redirect
-                if self.detect_challenge_in_response(&response_str) {
+                if challenge_handling && self.detect_challenge_in_response(&response_str) {
                     log::info!("Challenge detected, handling...");
                     self.handle_challenge_response(
                         client_stream, 
                         &mut server_stream, 
                         response_data, 
+                        &modified_request,
                         &target_host,
                         conn_id
                     ).await?;
                 } else {
                     // Normal response
+                    if let Some(status_code) = parse_status_code(&response_str).filter(|_| challenge_handling) {
+                        self.challenge_handler.complete_response(&target_host, status_code);
+                    }
                     client_stream.write_all(response_data).await?;
-                    self.proxy_bidirectional(client_stream, &mut server_stream, conn_id).await?;
+                    if let Some(remaining) = self.zero_copy_body_len(&modified_request, response_data) {
+                        self.splice_response_body(&mut server_stream, client_stream, remaining, conn_id).await?;
+                        exchange.on_response_body(remaining);
+                    }
+                    self.proxy_http1_keepalive(client_stream, server_stream, exchange, &target_host, conn_id).await?;
                 }
             }
             
@@ -246,187 +669,896 @@ impl ProxyHandler {
         }
     }
 
-    fn detect_challenge_in_response(&self, response: &str) -> bool {
-        let mut headers = std::collections::HashMap::new();
-        
-        for line in response.lines() {
-            if let Some(pos) = line.find(':') {
-                let key = line[..pos].trim().to_lowercase();
-                let value = line[pos + 1..].trim().to_string();
-                headers.insert(key, value);
-            }
-        }
-
-        let handler = self.challenge_handler.read();
-        handler.detect_challenge(response, &headers)
+    /// Statuses challenge pages and redirects come with: such responses are
+    /// inspected whole, the rest go to the client without buffering the body
+    fn may_be_challenge(&self, response: &[u8]) -> bool {
+        let Some(status) = parse_status_code(&String::from_utf8_lossy(response)) else {
+            return false;
+        };
+        is_redirect_status(status)
+            || matches!(status, 403 | 429 | 503)
+            || self.config.challenge_settings.challenge_status_codes.contains(&status)
     }
 
-    async fn handle_challenge_response(
+    /// Reads a started response up to the end of a body with known length, but no more
+    /// than MAX_BUFFERED_RESPONSE: Set-Cookie and challenge markers may not be in the first chunk
+    async fn read_full_response(
         &self,
-        client_stream: &mut TcpStream,
         server_stream: &mut TcpStream,
-        response_data: &[u8],
-        url: &str,
-        conn_id: u64,
+        exchange: &mut Http1Exchange,
+        response: &mut Vec<u8>,
     ) -> Result<()> {
-        let response_str = String::from_utf8_lossy(response_data);
-        
-        // Extract status code
-        let status_code = response_str.lines()
-            .next()
-            .and_then(|line| line.split_whitespace().nth(1))
-            .and_then(|code| code.parse::<u16>().ok())
-            .unwrap_or(200);
-
-        // Extract cookies
-        let mut cookies = Vec::new();
-        for line in response_str.lines() {
-            if line.to_lowercase().starts_with("set-cookie:") {
-                if let Some(cookie) = line.split(':').nth(1) {
-                    cookies.push(cookie.trim().to_string());
-                }
-            }
-        }
-
-        // Store challenge state
-        {
-            let mut handler = self.challenge_handler.write();
-            handler.register_challenge(url.to_string(), cookies.clone());
-            
-            if handler.is_redirect(status_code) {
-                handler.start_redirect_chain(url.to_string());
-                
-                // Extract redirect location
-                for line in response_str.lines() {
-                    if line.to_lowercase().starts_with("location:") {
-                        if let Some(location) = line.split(':').nth(1) {
-                            let _ = handler.add_redirect(
-                                url,
-                                url.to_string(),
-                                location.trim().to_string(),
-                                status_code,
-                            );
-                        }
-                    }
-                }
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        while exchange.is_response_incomplete() && response.len() < MAX_BUFFERED_RESPONSE {
+            let n = server_stream.read(&mut buffer).await?;
+            if n == 0 {
+                break;
             }
+            response.extend_from_slice(&self.relay_http1_response(exchange, &buffer[..n]));
         }
-
-        // Pass response to client (important: don't modify challenge responses)
-        client_stream.write_all(response_data).await?;
-        
-        // Continue proxying
-        self.proxy_bidirectional(client_stream, server_stream, conn_id).await
+        Ok(())
     }
 
-    fn rewrite_http_request(&self, request: &str) -> Vec<u8> {
-        let parts: Vec<&str> = request.split("\r\n\r\n").collect();
-        let headers_part = parts[0];
-        let body = if parts.len() > 1 { parts[1] } else { "" };
-        
-        let lines: Vec<&str> = headers_part.split("\r\n").collect();
-        
-        if lines.is_empty() {
-            return request.as_bytes().to_vec();
-        }
-
-        let first_line = lines[0];
-        let parts: Vec<&str> = first_line.split_whitespace().collect();
-        
-        if parts.len() >= 2 {
-            let method = parts[0];
-            let url = parts[1];
-            let version = if parts.len() >= 3 { parts[2] } else { "HTTP/1.1" };
-            
-            let path = if url.starts_with("http://") {
-                if let Some(host_end) = url[7..].find('/') {
-                    &url[7 + host_end..]
-                } else {
-                    "/"
-                }
-            } else {
-                url
-            };
-            
-            let new_first_line = format!("{} {} {}", method, path, version);
-            let mut new_lines = vec![new_first_line];
-            
-            for line in &lines[1..] {
-                if !line.is_empty() && !line.to_lowercase().starts_with("proxy-connection:") {
-                    new_lines.push(line.to_string());
-                }
+    /// Sends the client's first request and waits for the final response. The upstream
+    /// may have closed a pooled connection just now: then the request is sent once more on a new
+    /// connection if it didn't reach the server, or is idempotent and got no response
+    async fn send_first_request(
+        &self,
+        client_stream: &mut TcpStream,
+        server_stream: &mut TcpStream,
+        reused: bool,
+        request: &[u8],
+        target_host: &str,
+        conn_id: u64,
+    ) -> Result<(Vec<u8>, Http1Exchange)> {
+        let expects_continue = header_value(&String::from_utf8_lossy(request), "Expect")
+            .is_some_and(|value| value.eq_ignore_ascii_case("100-continue"));
+        let mut exchange = Http1Exchange::new();
+        exchange.on_request(request);
+
+        let result = match server_stream.write_all(request).await {
+            Ok(()) => self.read_final_response(client_stream, server_stream, &mut exchange, expects_continue, conn_id).await,
+            Err(e) if reused => {
+                log::info!("Pooled upstream {} of connection {} failed ({}), retrying on a new connection", target_host, conn_id, e);
+                return self.retry_first_request(client_stream, server_stream, request, expects_continue, target_host, conn_id).await;
             }
-            
-            let rewritten = if body.is_empty() {
-                format!("{}\r\n\r\n", new_lines.join("\r\n"))
-            } else {
-                format!("{}\r\n\r\n{}", new_lines.join("\r\n"), body)
-            };
-            
-            return rewritten.as_bytes().to_vec();
+            Err(e) => return Err(e.into()),
+        };
+
+        let closed_unanswered = match &result {
+            Ok(response) => response.is_empty(),
+            Err(_) => true,
+        } && exchange.is_unanswered();
+        if reused && closed_unanswered && is_replayable(request) {
+            log::info!("Pooled upstream {} closed connection {} before responding, retrying on a new connection", target_host, conn_id);
+            return self.retry_first_request(client_stream, server_stream, request, expects_continue, target_host, conn_id).await;
         }
-        
-        request.as_bytes().to_vec()
+        Ok((result?, exchange))
     }
 
-    async fn handle_http2_connection(
+    async fn retry_first_request(
         &self,
         client_stream: &mut TcpStream,
         server_stream: &mut TcpStream,
-        initial_data: &[u8],
+        request: &[u8],
+        expects_continue: bool,
+        target_host: &str,
         conn_id: u64,
-    ) -> Result<()> {
-        let mut http2_handler = Http2Handler::new_ios_safari();
-
-        let preface = http2_handler.build_connection_preface();
-        server_stream.write_all(&preface).await?;
-
-        server_stream.write_all(initial_data).await?;
-
-        self.proxy_http2_bidirectional(
-            client_stream,
-            server_stream,
-            &mut http2_handler,
-            conn_id,
-        ).await
+    ) -> Result<(Vec<u8>, Http1Exchange)> {
+        *server_stream = self.reconnect_http1(target_host, conn_id).await?;
+        let mut exchange = Http1Exchange::new();
+        exchange.on_request(request);
+        server_stream.write_all(request).await?;
+        let response = self.read_final_response(client_stream, server_stream, &mut exchange, expects_continue, conn_id).await?;
+        Ok((response, exchange))
     }
 
-    async fn proxy_http2_bidirectional(
+    /// Waits for the final upstream response, forwarding interim 1xx to the client.
+    /// With Expect: 100-continue the request body the client sends after
+    /// 100 Continue goes to the server meanwhile. An empty result means the upstream closed
+    async fn read_final_response(
         &self,
         client_stream: &mut TcpStream,
         server_stream: &mut TcpStream,
-        http2_handler: &mut Http2Handler,
+        exchange: &mut Http1Exchange,
+        expects_continue: bool,
         conn_id: u64,
-    ) -> Result<()> {
-        let mut client_buffer = vec![0u8; BUFFER_SIZE];
-        let mut server_buffer = vec![0u8; BUFFER_SIZE];
-        let mut timing = TimingPreserver::new(0.05);
+    ) -> Result<Vec<u8>> {
+        let mut response = Vec::new();
+        let (mut client_buffer, mut server_buffer) = self.relay_buffers();
+        let mut client_open = expects_continue;
 
         loop {
-            if self.graceful_shutdown.is_shutting_down().await {
-                break;
+            while is_interim_response(&response) == Some(true) {
+                let Some(end) = response.windows(4).position(|w| w == b"\r\n\r\n") else {
+                    break;
+                };
+                log::debug!("Forwarding interim {} response for connection {}",
+                    String::from_utf8_lossy(&response[9..12]), conn_id);
+                client_stream.write_all(&response[..end + 4]).await?;
+                response.drain(..end + 4);
+            }
+
+            if is_interim_response(&response) == Some(false) {
+                return Ok(response);
             }
 
             tokio::select! {
-                result = client_stream.read(&mut client_buffer) => {
+                result = server_stream.read(&mut server_buffer) => {
                     let n = result?;
                     if n == 0 {
-                        break;
+                        return Ok(response);
                     }
-
-                    timing.wait_natural_delay().await;
-                    server_stream.write_all(&client_buffer[..n]).await?;
-                    timing.record_send();
-                    self.graceful_shutdown.mark_activity(conn_id).await;
+                    response.extend_from_slice(&self.relay_http1_response(exchange, &server_buffer[..n]));
                 }
-                result = server_stream.read(&mut server_buffer) => {
+                result = client_stream.read(&mut client_buffer), if client_open => {
+                    let n = result?;
+                    if n == 0 {
+                        client_open = false;
+                    } else {
+                        exchange.on_request(&client_buffer[..n]);
+                        server_stream.write_all(&client_buffer[..n]).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// HTTP/1.1 keep-alive proxying. If the upstream closes the connection
+    /// while a client request is still unanswered, an idempotent request goes
+    /// to a new connection instead of cutting the client off. TLS tunnels can't do this - the session is the client's.
+    async fn proxy_http1_keepalive(
+        &self,
+        client_stream: &mut TcpStream,
+        mut server_stream: TcpStream,
+        mut exchange: Http1Exchange,
+        target_host: &str,
+        conn_id: u64,
+    ) -> Result<()> {
+        let (mut client_buffer, server_buffer) = self.relay_buffers();
+        let mut server_buffer = RelayBuffer::Heap(server_buffer);
+        let mut mmap_threshold = self.config.tcp_settings.mmap_buffer_threshold;
+        let mut server_bytes = 0u64;
+        let mut timing = self.timing_preserver();
+        let window_interval = Duration::from_millis(self.config.tcp_settings.adaptive_window_interval_ms);
+        let mut window = TcpWindowManager::new(INITIAL_UPSTREAM_WINDOW);
+        let mut window_bytes = 0u64;
+        let mut window_started = Instant::now();
+        // Requests since the last response boundary that the upstream hasn't started answering:
+        // only those can be retried. None - no retry until the next boundary
+        let mut unanswered = exchange.is_idle().then(Vec::new);
+        let mut replayed = false;
+        let mut server_open = true;
+
+        loop {
+            if self.graceful_shutdown.is_shutting_down() {
+                break;
+            }
+
+            tokio::select! {
+                result = client_stream.read(&mut client_buffer) => {
+                    let n = result?;
+                    if n == 0 {
+                        log::debug!("Client closed connection {}", conn_id);
+                        break;
+                    }
+
+                    if exchange.is_idle() && peer_closed(&server_stream) {
+                        log::info!("Upstream {} closed keep-alive connection {}, reconnecting", target_host, conn_id);
+                        server_stream = self.reconnect_http1(target_host, conn_id).await?;
+                        exchange = Http1Exchange::new();
+                        unanswered = Some(Vec::new());
+                    }
+
+                    timing.wait_natural_delay().await;
+
+                    exchange.on_request(&client_buffer[..n]);
+                    unanswered = unanswered
+                        .map(|mut requests| {
+                            requests.extend_from_slice(&client_buffer[..n]);
+                            requests
+                        })
+                        .filter(|requests| requests.len() <= MAX_REPLAY_LEN);
+                    if let Err(e) = server_stream.write_all(&client_buffer[..n]).await {
+                        server_stream = self.replay_unanswered(target_host, &unanswered, &mut replayed, e.into(), conn_id).await?;
+                    }
+
+                    timing.record_send();
+                    self.graceful_shutdown.mark_activity(conn_id).await;
+                }
+                result = server_stream.read(&mut server_buffer) => {
+                    let pending = unanswered.as_ref().is_some_and(|requests| !requests.is_empty());
+                    let n = match result {
+                        Ok(0) if pending => {
+                            let closed = anyhow::anyhow!("Upstream {} closed before responding", target_host);
+                            server_stream = self.replay_unanswered(target_host, &unanswered, &mut replayed, closed, conn_id).await?;
+                            continue;
+                        }
+                        Ok(0) => {
+                            // Idle or end of a body-until-close - the client learns about it via EOF
+                            log::debug!("Upstream closed connection {}", conn_id);
+                            server_open = false;
+                            break;
+                        }
+                        Err(e) if pending => {
+                            server_stream = self.replay_unanswered(target_host, &unanswered, &mut replayed, e.into(), conn_id).await?;
+                            continue;
+                        }
+                        Err(e) => return Err(e.into()),
+                        Ok(n) => n,
+                    };
+
+                    // A started response can't be retried: the request could execute twice
+                    let forward = self.relay_http1_response(&mut exchange, &server_buffer[..n]);
+                    unanswered = exchange.is_idle().then(Vec::new);
+                    replayed = false;
+
+                    window_bytes += n as u64;
+                    if !window_interval.is_zero() && window_started.elapsed() >= window_interval {
+                        adapt_upstream_window(&mut window, &server_stream, window_bytes, window_started.elapsed(), conn_id);
+                        window_bytes = 0;
+                        window_started = Instant::now();
+                    }
+
+                    timing.wait_natural_delay().await;
+                    client_stream.write_all(&forward).await?;
+
+                    server_bytes += n as u64;
+                    self.promote_download_buffer(&mut server_buffer, server_bytes, &mut mmap_threshold, conn_id);
+                    timing.record_send();
+                    self.graceful_shutdown.mark_activity(conn_id).await;
+                }
+            }
+        }
+
+        // The client left and all responses were read to a boundary: the upstream can serve the next one
+        if server_open && exchange.is_reusable() {
+            if let Some(key) = self.pool_key(target_host) {
+                if self.upstream_pool.checkin(key, server_stream) {
+                    log::debug!("Returned upstream {} of connection {} to pool", target_host, conn_id);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// HTTP/1.1 response bytes for the client; with strip_alt_svc Alt-Svc is removed
+    /// from the headers of every response, not just the first
+    fn relay_http1_response<'a>(&self, exchange: &mut Http1Exchange, data: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.config.http2_settings.strip_alt_svc {
+            Cow::Owned(exchange.relay_response(data, strip_alt_svc))
+        } else {
+            exchange.on_response(data);
+            Cow::Borrowed(data)
+        }
+    }
+
+    /// Retries the unanswered requests on a new connection - once,
+    /// and only if they're all idempotent; otherwise returns the original error
+    async fn replay_unanswered(
+        &self,
+        target_host: &str,
+        unanswered: &Option<Vec<u8>>,
+        replayed: &mut bool,
+        error: anyhow::Error,
+        conn_id: u64,
+    ) -> Result<TcpStream> {
+        let requests = match unanswered {
+            Some(requests) if !*replayed && is_replayable(requests) => requests,
+            _ => return Err(error),
+        };
+
+        log::info!("Upstream {} failed on connection {} ({}), replaying request on a new connection",
+            target_host, conn_id, error);
+        let mut server_stream = self.reconnect_http1(target_host, conn_id).await?;
+        server_stream.write_all(requests).await?;
+        *replayed = true;
+        Ok(server_stream)
+    }
+
+    /// Pool key for the target; SOCKS tunnels are not reused
+    fn pool_key(&self, target: &str) -> Option<PoolKey> {
+        if !self.upstream_pool.is_enabled() {
+            return None;
+        }
+
+        let proxy = &self.config.proxy_settings;
+        let (host, port) = split_host_port(target, 443);
+        match proxy.proxy_type.to_lowercase().as_str() {
+            "direct" => Some(PoolKey::new("direct", host, port)),
+            "http" | "https" => {
+                let proxy_addr = format!("{}:{}", proxy.proxy_host, proxy.proxy_port);
+                Some(PoolKey::new(&proxy_addr, host, port))
+            }
+            _ => None,
+        }
+    }
+
+    fn checkout_upstream(&self, target: &str, conn_id: u64) -> Option<TcpStream> {
+        let stream = self.upstream_pool.checkout(&self.pool_key(target)?)?;
+        log::debug!("Reusing pooled upstream {} for connection {}", target, conn_id);
+        self.record_upstream(conn_id, target);
+        Some(stream)
+    }
+
+    /// How many body bytes are left to pass through splice: only for responses
+    /// with Content-Length at or above the threshold whose headers were already checked.
+    /// With Transfer-Encoding it sets the length, not Content-Length (RFC 9112, 6.3)
+    fn zero_copy_body_len(&self, request: &[u8], response: &[u8]) -> Option<u64> {
+        let threshold = self.config.tcp_settings.zero_copy_threshold;
+        if threshold == 0 || request.starts_with(b"HEAD ") {
+            return None;
+        }
+
+        let header_end = response.windows(4).position(|w| w == b"\r\n\r\n")?;
+        let headers = String::from_utf8_lossy(&response[..header_end]);
+        if matches!(parse_status_code(&headers), Some(100..=199 | 204 | 304) | None) {
+            return None;
+        }
+
+        let mut fields = headers.lines().skip(1).filter_map(|line| line.split_once(':'));
+        if fields.clone().any(|(name, _)| name.trim().eq_ignore_ascii_case("transfer-encoding")) {
+            return None;
+        }
+        let content_length: u64 = fields
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse().ok())?;
+        if content_length < threshold {
+            return None;
+        }
+
+        let body_seen = (response.len() - header_end - 4) as u64;
+        Some(content_length.saturating_sub(body_seen)).filter(|remaining| *remaining > 0)
+    }
+
+    /// Passes the rest of the response body upstream -> client through a pipe and splice.
+    /// Without splice support - a regular buffered copy
+    async fn splice_response_body(
+        &self,
+        server_stream: &mut TcpStream,
+        client_stream: &mut TcpStream,
+        mut remaining: u64,
+        conn_id: u64,
+    ) -> Result<()> {
+        use tokio::io::Interest;
+
+        log::debug!("Connection {}: switching to zero-copy for {} body bytes", conn_id, remaining);
+
+        let pipe = match SplicePipe::new() {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                log::debug!("Failed to create splice pipe ({}), copying body", e);
+                return self.copy_response_body(server_stream, client_stream, remaining).await;
+            }
+        };
+
+        while remaining > 0 {
+            if self.graceful_shutdown.is_shutting_down() {
+                return Err(anyhow::anyhow!("Shutdown with {} body bytes left", remaining));
+            }
+
+            let len = remaining.min(BUFFER_SIZE as u64) as usize;
+            let result = tokio::select! {
+                result = server_stream.async_io(Interest::READABLE, || {
+                    pipe.splice_in(server_stream.as_raw_fd(), len)
+                }) => result,
+                _ = self.graceful_shutdown.wait_for_shutdown() => continue,
+            };
+
+            let n = match result {
+                Ok(0) => return Err(anyhow::anyhow!("Upstream closed with {} body bytes left", remaining)),
+                Ok(n) => n,
+                // The pipe is empty: a regular copy can take over
+                Err(e) if is_splice_unsupported(&e) => {
+                    log::debug!("splice unsupported ({}), copying body", e);
+                    return self.copy_response_body(server_stream, client_stream, remaining).await;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let mut pending = n;
+            while pending > 0 {
+                pending -= client_stream.async_io(Interest::WRITABLE, || {
+                    pipe.splice_out(client_stream.as_raw_fd(), pending)
+                }).await?;
+            }
+
+            remaining -= n as u64;
+            self.zero_copy_bytes.fetch_add(n as u64, Ordering::Relaxed);
+        }
+
+        self.graceful_shutdown.mark_activity(conn_id).await;
+        Ok(())
+    }
+
+    async fn copy_response_body(
+        &self,
+        server_stream: &mut TcpStream,
+        client_stream: &mut TcpStream,
+        remaining: u64,
+    ) -> Result<()> {
+        let copied = tokio::io::copy(&mut (&mut *server_stream).take(remaining), client_stream).await?;
+        if copied < remaining {
+            return Err(anyhow::anyhow!("Upstream closed with {} body bytes left", remaining - copied));
+        }
+        Ok(())
+    }
+
+    pub fn zero_copy_bytes(&self) -> u64 {
+        self.zero_copy_bytes.load(Ordering::Relaxed)
+    }
+
+    /// How many downloads switched to an mmap buffer (tcp_settings.mmap_buffer_threshold)
+    pub fn mmap_relay_count(&self) -> u64 {
+        self.mmap_relays.load(Ordering::Relaxed)
+    }
+
+    async fn reconnect_http1(&self, target_host: &str, conn_id: u64) -> Result<TcpStream> {
+        let stream = self.connect_to_target(target_host, conn_id).await?;
+        self.apply_upstream_tcp_options(&stream, conn_id)?;
+        Ok(stream)
+    }
+
+    fn detect_challenge_in_response(&self, response: &str) -> bool {
+        let mut headers = std::collections::HashMap::new();
+        
+        for line in response.lines() {
+            if let Some(pos) = line.find(':') {
+                let key = line[..pos].trim().to_lowercase();
+                let value = line[pos + 1..].trim().to_string();
+                headers.insert(key, value);
+            }
+        }
+
+        if self.is_challenge_status(response, &headers) {
+            return true;
+        }
+
+        self.challenge_handler.detect_challenge(response, &headers)
+    }
+
+    fn is_challenge_status(&self, response: &str, headers: &std::collections::HashMap<String, String>) -> bool {
+        let settings = &self.config.challenge_settings;
+        let status_matches = parse_status_code(response)
+            .map(|code| settings.challenge_status_codes.contains(&code))
+            .unwrap_or(false);
+
+        if !status_matches {
+            return false;
+        }
+
+        if settings.challenge_server_markers.is_empty() {
+            return true;
+        }
+
+        let server = headers.get("server").map(|s| s.to_lowercase()).unwrap_or_default();
+        settings.challenge_server_markers
+            .iter()
+            .any(|marker| server.contains(&marker.to_lowercase()))
+    }
+
+    async fn handle_challenge_response(
+        &self,
+        client_stream: &mut TcpStream,
+        server_stream: &mut TcpStream,
+        response_data: &[u8],
+        original_request: &[u8],
+        url: &str,
+        conn_id: u64,
+    ) -> Result<()> {
+        let response_str = String::from_utf8_lossy(response_data);
+        
+        let status_code = parse_status_code(&response_str).unwrap_or(200);
+
+        // Extract cookies and redirect target before touching the handler
+        let mut cookies = Vec::new();
+        let mut location = None;
+        for line in response_str.lines() {
+            if let Some((name, value)) = line.split_once(':') {
+                match name.trim().to_lowercase().as_str() {
+                    "set-cookie" => cookies.push(value.trim().to_string()),
+                    "location" => location = Some(value.trim().to_string()),
+                    _ => {}
+                }
+            }
+        }
+
+        let redirect = location
+            .filter(|_| is_redirect_status(status_code))
+            .map(|location| (status_code, location));
+
+        let clearance = clearance_cookies(&cookies);
+        // The retry carries all response cookies, not just the challenge ones: the site session is needed too
+        let replay_cookies: Vec<String> = cookies.iter()
+            .filter_map(|value| value.split(';').next().map(|pair| pair.trim().to_string()))
+            .collect();
+
+        let follow = self.config.challenge_settings.follow_redirects && redirect.is_some();
+
+        // Store challenge state
+        self.challenge_handler.record_challenge(url, cookies, redirect);
+        self.challenge_handler.complete_response(url, status_code);
+
+        if follow {
+            return self.follow_redirects(client_stream, server_stream, response_data, original_request, url, conn_id).await;
+        }
+
+        // The challenge is already passed - retry the original request with the clearance cookie
+        // and give the client its response instead of the challenge page
+        if !clearance.is_empty() {
+            log::info!("Clearance cookie obtained for {}, replaying original request", url);
+
+            let replayed = inject_cookies(original_request, &replay_cookies);
+            let mut replay_stream = self.connect_to_target(url, conn_id).await?;
+            self.apply_upstream_tcp_options(&replay_stream, conn_id)?;
+            replay_stream.write_all(&replayed).await?;
+
+            return self.proxy_bidirectional(client_stream, &mut replay_stream, conn_id, None).await;
+        }
+
+        // Pass response to client (important: don't modify challenge responses)
+        client_stream.write_all(response_data).await?;
+        
+        // Continue proxying
+        self.proxy_bidirectional(client_stream, server_stream, conn_id, None).await
+    }
+
+    /// Follows a redirect chain on the same host, at most
+    /// max_upstream_requests_per_connection upstream requests (the original included).
+    /// The client gets the last response received
+    async fn follow_redirects(
+        &self,
+        client_stream: &mut TcpStream,
+        server_stream: &mut TcpStream,
+        first_response: &[u8],
+        original_request: &[u8],
+        url: &str,
+        conn_id: u64,
+    ) -> Result<()> {
+        let limit = self.config.challenge_settings.max_upstream_requests_per_connection.max(1);
+        let mut upstream_requests = 1;
+        let mut request = original_request.to_vec();
+        let mut response = first_response.to_vec();
+        let mut followed: Option<TcpStream> = None;
+        // Set-Cookie of responses followed without the client: without them it won't get clearance
+        let mut passed_cookies = Vec::new();
+
+        loop {
+            let text = String::from_utf8_lossy(&response).into_owned();
+            let status = parse_status_code(&text).unwrap_or(200);
+            let Some(location) = header_value(&text, "location").filter(|_| is_redirect_status(status)) else {
+                break;
+            };
+            let Some(path) = same_origin_path(&location, url) else {
+                log::debug!("Redirect from {} to another origin ({}), returning it to client", url, location);
+                break;
+            };
+            if upstream_requests >= limit {
+                log::warn!("Connection {} reached {} upstream requests following redirects for {}, returning last response",
+                    conn_id, limit, url);
+                break;
+            }
+
+            let set_cookies: Vec<String> = text.lines()
+                .take_while(|line| !line.is_empty())
+                .filter_map(|line| line.split_once(':'))
+                .filter(|(name, _)| name.trim().eq_ignore_ascii_case("set-cookie"))
+                .map(|(_, value)| value.trim().to_string())
+                .collect();
+            let pairs: Vec<String> = set_cookies.iter()
+                .filter_map(|value| value.split(';').next().map(|pair| pair.trim().to_string()))
+                .collect();
+            request = redirect_request(&request, status, &path, &pairs);
+            passed_cookies.extend(set_cookies);
+
+            let mut next = self.connect_to_target(url, conn_id).await?;
+            self.apply_upstream_tcp_options(&next, conn_id)?;
+            next.write_all(&request).await?;
+            upstream_requests += 1;
+
+            let mut exchange = Http1Exchange::new();
+            exchange.on_request(&request);
+            let mut buffer = vec![0u8; BUFFER_SIZE];
+            let n = next.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            let mut next_response = self.relay_http1_response(&mut exchange, &buffer[..n]).into_owned();
+            self.read_full_response(&mut next, &mut exchange, &mut next_response).await?;
+
+            log::debug!("Followed {} redirect to {} for {}", status, path, url);
+            response = next_response;
+            if let Some(code) = parse_status_code(&String::from_utf8_lossy(&response)) {
+                self.challenge_handler.complete_response(url, code);
+            }
+            followed = Some(next);
+        }
+
+        client_stream.write_all(&with_set_cookies(&response, &passed_cookies)).await?;
+        match followed.as_mut() {
+            Some(stream) => self.proxy_bidirectional(client_stream, stream, conn_id, None).await,
+            None => self.proxy_bidirectional(client_stream, server_stream, conn_id, None).await,
+        }
+    }
+
+    fn rewrite_http_request(&self, request: &str) -> Vec<u8> {
+        let parts: Vec<&str> = request.split("\r\n\r\n").collect();
+        let headers_part = parts[0];
+        let body = if parts.len() > 1 { parts[1] } else { "" };
+        
+        let lines: Vec<&str> = headers_part.split("\r\n").collect();
+        
+        if lines.is_empty() {
+            return request.as_bytes().to_vec();
+        }
+
+        let first_line = lines[0];
+        let parts: Vec<&str> = first_line.split_whitespace().collect();
+        
+        if parts.len() >= 2 {
+            let method = parts[0];
+            let url = parts[1];
+            let version = if parts.len() >= 3 { parts[2] } else { "HTTP/1.1" };
+            
+            let path = if url.starts_with("http://") {
+                if let Some(host_end) = url[7..].find('/') {
+                    &url[7 + host_end..]
+                } else {
+                    "/"
+                }
+            } else {
+                url
+            };
+            
+            let new_first_line = format!("{} {} {}", method, path, version);
+            let mut new_lines = vec![new_first_line];
+            let stripped = self.hop_by_hop_headers(headers_part);
+            
+            for line in &lines[1..] {
+                let name = line.split(':').next().unwrap_or("").trim().to_lowercase();
+                if !line.is_empty() && !stripped.contains(&name) {
+                    new_lines.push(line.to_string());
+                }
+            }
+            
+            let rewritten = if body.is_empty() {
+                format!("{}\r\n\r\n", new_lines.join("\r\n"))
+            } else {
+                format!("{}\r\n\r\n{}", new_lines.join("\r\n"), body)
+            };
+            
+            return rewritten.as_bytes().to_vec();
+        }
+        
+        request.as_bytes().to_vec()
+    }
+
+    /// Names (lowercase) of headers that don't go to the origin:
+    /// from proxy_settings.hop_by_hop_headers plus those listed in Connection.
+    /// An Upgrade request (WebSocket) keeps Upgrade and Connection, without them
+    /// the origin won't switch protocols
+    fn hop_by_hop_headers(&self, head: &str) -> std::collections::HashSet<String> {
+        let mut names: std::collections::HashSet<String> = self.config.proxy_settings.hop_by_hop_headers
+            .iter()
+            .map(|name| name.to_lowercase())
+            .collect();
+
+        if names.contains("connection") {
+            if let Some(options) = header_value(head, "Connection") {
+                names.extend(options.split(',')
+                    .map(|token| token.trim().to_lowercase())
+                    .filter(|token| !token.is_empty()));
+            }
+        }
+
+        let upgrade = header_value(head, "Upgrade").is_some()
+            && header_value(head, "Connection").is_some_and(|value| {
+                value.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+            });
+        if upgrade {
+            names.remove("upgrade");
+            names.remove("connection");
+        }
+        names
+    }
+
+    async fn handle_http2_connection(
+        &self,
+        client_stream: &mut TcpStream,
+        server_stream: &mut TcpStream,
+        initial_data: &[u8],
+        conn_id: u64,
+    ) -> Result<()> {
+        let mut http2_handler = Http2Handler::with_settings(self.http2_settings(conn_id));
+        let h2 = &self.config.http2_settings;
+        if h2.strip_alt_svc {
+            http2_handler.strip_headers(&["alt-svc"]);
+        }
+        if h2.keepalive_interval_ms > 0 {
+            http2_handler.set_keepalive(
+                Duration::from_millis(h2.keepalive_interval_ms),
+                Duration::from_millis(h2.keepalive_timeout_ms),
+            );
+        }
+
+        let preface = http2_handler.build_connection_preface();
+        server_stream.write_all(&preface).await?;
+        self.timers.apply_http2_delay().await;
+
+        http2_handler.observe_outgoing(initial_data)?;
+        server_stream.write_all(initial_data).await?;
+
+        self.proxy_http2_bidirectional(
+            client_stream,
+            server_stream,
+            &mut http2_handler,
+            conn_id,
+        ).await
+    }
+
+    /// h2 from the client -> HTTP/1.1 to the upstream. Requests are sent one at a time
+    /// over one keep-alive connection, responses are re-encoded back to h2.
+    async fn handle_h2_downgrade(
+        &self,
+        client_stream: &mut TcpStream,
+        server_stream: &mut TcpStream,
+        initial_data: &[u8],
+        conn_id: u64,
+    ) -> Result<()> {
+        let mut http2_handler = Http2Handler::with_settings(self.http2_settings(conn_id));
+        http2_handler.enable_h1_downgrade();
+        client_stream.write_all(&http2_handler.build_server_preface()).await?;
+
+        let mut client_buffer = vec![0u8; BUFFER_SIZE];
+        let mut incoming = initial_data.to_vec();
+        // Upstream bytes read but not parsed yet
+        let mut upstream_pending = Vec::new();
+
+        loop {
+            let mut output = http2_handler.handle_incoming_frame(&incoming)?;
+
+            for (stream_id, request) in http2_handler.take_h1_requests() {
+                let head_request = request.starts_with(b"HEAD ");
+                server_stream.write_all(&request).await?;
+                let response = self.read_h1_response(server_stream, &mut upstream_pending, head_request).await?;
+                output.extend_from_slice(&http2_handler.encode_h1_response(stream_id, response));
+            }
+
+            output.extend_from_slice(&http2_handler.drain_pending_data());
+            for frame in http2_handler.check_and_send_window_updates() {
+                output.extend_from_slice(&frame);
+            }
+            if !output.is_empty() {
+                client_stream.write_all(&output).await?;
+            }
+            self.graceful_shutdown.mark_activity(conn_id).await;
+
+            if self.graceful_shutdown.is_shutting_down() {
+                break;
+            }
+
+            let n = client_stream.read(&mut client_buffer).await?;
+            if n == 0 {
+                break;
+            }
+            incoming = client_buffer[..n].to_vec();
+        }
+
+        Ok(())
+    }
+
+    /// Reads the next final HTTP/1.1 response; interim 1xx are skipped.
+    /// Bytes beyond the response stay in pending - they start the next one
+    async fn read_h1_response(
+        &self,
+        server_stream: &mut TcpStream,
+        pending: &mut Vec<u8>,
+        head_request: bool,
+    ) -> Result<H1Response> {
+        let max_response = self.config.http2_settings.h2_downgrade_max_response;
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        let mut eof = false;
+
+        loop {
+            if let Some((response, consumed)) = H1Response::parse(pending, eof, head_request)? {
+                if consumed > max_response {
+                    return Err(anyhow::anyhow!("Upstream response exceeds {} bytes", max_response));
+                }
+                pending.drain(..consumed);
+                match response.status {
+                    101 => return Err(anyhow::anyhow!("Upstream switched protocols on a downgraded h2 request")),
+                    100..=199 => continue,
+                    _ => return Ok(response),
+                }
+            }
+            if eof {
+                return Err(anyhow::anyhow!("Upstream closed before a complete HTTP/1.1 response"));
+            }
+            if pending.len() > max_response {
+                return Err(anyhow::anyhow!("Upstream response exceeds {} bytes", max_response));
+            }
+
+            let n = server_stream.read(&mut buffer).await?;
+            pending.extend_from_slice(&buffer[..n]);
+            eof = n == 0;
+        }
+    }
+
+    async fn proxy_http2_bidirectional(
+        &self,
+        client_stream: &mut TcpStream,
+        server_stream: &mut TcpStream,
+        http2_handler: &mut Http2Handler,
+        conn_id: u64,
+    ) -> Result<()> {
+        let (mut client_buffer, mut server_buffer) = self.relay_buffers();
+        let mut timing = self.timing_preserver();
+        let mut last_activity = Instant::now();
+        // Our own PING ACK must not reach the client, so with keepalive frames are re-encoded
+        let relay = self.config.http2_settings.strip_alt_svc
+            || self.config.http2_settings.keepalive_interval_ms > 0;
+
+        // Deadline to finish responses on already open streams after GOAWAY
+        let mut drain_deadline: Option<Instant> = None;
+
+        loop {
+            if drain_deadline.is_none() && self.graceful_shutdown.is_shutting_down() {
+                // The client stops opening new streams and moves to a new connection
+                let last_stream_id = http2_handler.last_client_stream_id();
+                log::debug!("Connection {}: sending GOAWAY (last stream {}) on shutdown", conn_id, last_stream_id);
+                let goaway = http2_handler.build_goaway_frame(last_stream_id, ERROR_NO_ERROR);
+                if let Err(e) = client_stream.write_all(&goaway).await {
+                    log::debug!("Connection {}: failed to send GOAWAY: {}", conn_id, e);
+                    break;
+                }
+                drain_deadline = Some(Instant::now() + self.graceful_shutdown.shutdown_timeout());
+            }
+            if let Some(deadline) = drain_deadline {
+                if !http2_handler.has_open_client_streams() {
+                    log::debug!("Connection {}: all streams drained after GOAWAY", conn_id);
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    log::debug!("Connection {}: drain timeout with streams still open", conn_id);
+                    break;
+                }
+            }
+
+            let keepalive_deadline = http2_handler.keepalive_deadline(last_activity);
+
+            tokio::select! {
+                _ = self.graceful_shutdown.wait_for_shutdown(), if drain_deadline.is_none() => {}
+                _ = tokio::time::sleep_until(drain_deadline.unwrap_or_else(Instant::now).into()),
+                    if drain_deadline.is_some() => {}
+                result = client_stream.read(&mut client_buffer) => {
                     let n = result?;
                     if n == 0 {
                         break;
                     }
 
+                    http2_handler.observe_outgoing(&client_buffer[..n])?;
+
+                    timing.wait_natural_delay().await;
+                    server_stream.write_all(&client_buffer[..n]).await?;
+                    timing.record_send();
+                    last_activity = Instant::now();
+                    self.graceful_shutdown.mark_activity(conn_id).await;
+                }
+                result = server_stream.read(&mut server_buffer) => {
+                    let n = result?;
+                    if n == 0 {
+                        break;
+                    }
+                    last_activity = Instant::now();
+
                     // Process HTTP/2 frame and get response frames
-                    let response_frames = http2_handler.handle_incoming_frame(&server_buffer[..n])?;
+                    let (response_frames, forward) = if relay {
+                        let (forward, replies) = http2_handler.relay_frames(&server_buffer[..n])?;
+                        (replies, Some(forward))
+                    } else {
+                        (http2_handler.handle_incoming_frame(&server_buffer[..n])?, None)
+                    };
                     if !response_frames.is_empty() {
                         server_stream.write_all(&response_frames).await?;
                     }
@@ -438,10 +1570,17 @@ impl ProxyHandler {
                     }
 
                     timing.wait_natural_delay().await;
-                    client_stream.write_all(&server_buffer[..n]).await?;
+                    client_stream.write_all(forward.as_deref().unwrap_or(&server_buffer[..n])).await?;
                     timing.record_send();
                     self.graceful_shutdown.mark_activity(conn_id).await;
                 }
+                _ = tokio::time::sleep_until(keepalive_deadline.unwrap_or_else(Instant::now).into()),
+                    if keepalive_deadline.is_some() => {
+                    if let Some(ping) = http2_handler.poll_keepalive(Instant::now(), last_activity)? {
+                        log::debug!("HTTP/2 keepalive PING on connection {} (last RTT {:?})", conn_id, http2_handler.ping_rtt());
+                        server_stream.write_all(&ping).await?;
+                    }
+                }
             }
         }
 
@@ -454,12 +1593,12 @@ impl ProxyHandler {
         initial_data: &[u8],
         conn_id: u64,
     ) -> Result<()> {
-        let mut server_stream = self.connect_to_upstream().await?;
-        apply_tcp_options(&server_stream, false)?;
+        let mut server_stream = self.connect_to_upstream(conn_id).await?;
+        self.apply_upstream_tcp_options(&server_stream, conn_id)?;
 
         server_stream.write_all(initial_data).await?;
 
-        self.proxy_bidirectional(client_stream, &mut server_stream, conn_id).await
+        self.proxy_bidirectional(client_stream, &mut server_stream, conn_id, None).await
     }
 
     async fn proxy_bidirectional(
@@ -467,19 +1606,68 @@ impl ProxyHandler {
         client_stream: &mut TcpStream,
         server_stream: &mut TcpStream,
         conn_id: u64,
+        fingerprinted: Option<&str>,
     ) -> Result<()> {
         log::debug!("Starting bidirectional proxy for connection {}", conn_id);
         
-        let mut client_buffer = vec![0u8; BUFFER_SIZE];
-        let mut server_buffer = vec![0u8; BUFFER_SIZE];
-        let mut timing = TimingPreserver::new(0.05);
+        let (mut client_buffer, server_buffer) = self.relay_buffers();
+        let mut server_buffer = RelayBuffer::Heap(server_buffer);
+        let mut mmap_threshold = self.config.tcp_settings.mmap_buffer_threshold;
+        let mut server_bytes = 0u64;
+        let mut timing = self.timing_preserver();
+        // An alert or reset instead of the first reply to a rewritten ClientHello - the target rejects it
+        let mut awaiting_response = fingerprinted;
+        let window_interval = Duration::from_millis(self.config.tcp_settings.adaptive_window_interval_ms);
+        let mut window = TcpWindowManager::new(INITIAL_UPSTREAM_WINDOW);
+        let mut window_bytes = 0u64;
+        let mut window_started = Instant::now();
+        // Without delays and traffic inspection bytes can go through splice, bypassing userspace
+        let mut splice_allowed = self.config.tcp_settings.splice_relay && !self.config.timing_settings.enabled;
+        // An urgent byte is visible to neither read nor splice - it's caught separately via EPOLLPRI
+        let (mut client_urgent, mut server_urgent) = if self.config.tcp_settings.forward_urgent_data {
+            splice_allowed = false;
+            (urgent_watcher(client_stream, conn_id), urgent_watcher(server_stream, conn_id))
+        } else {
+            (None, None)
+        };
 
         loop {
-            if self.graceful_shutdown.is_shutting_down().await {
+            if self.graceful_shutdown.is_shutting_down() {
                 log::debug!("Shutdown detected for connection {}", conn_id);
                 break;
             }
 
+            // Reading stops at the urgent mark; the byte goes out before the next read
+            match forward_urgent_at_mark(&client_urgent, client_stream, server_stream, &mut client_buffer).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    log::debug!("Client closed connection {}", conn_id);
+                    break;
+                }
+                Err(e) => {
+                    log::error!("Failed to forward urgent data to server: {}", e);
+                    break;
+                }
+            }
+            match forward_urgent_at_mark(&server_urgent, server_stream, client_stream, &mut server_buffer).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    log::debug!("Server closed connection {}", conn_id);
+                    break;
+                }
+                Err(e) => {
+                    log::error!("Failed to forward urgent data to client: {}", e);
+                    break;
+                }
+            }
+
+            if splice_allowed && awaiting_response.is_none() {
+                if self.splice_tunnel(client_stream, server_stream, conn_id).await? {
+                    break;
+                }
+                splice_allowed = false;
+            }
+
             tokio::select! {
                 result = client_stream.read(&mut client_buffer) => {
                     match result {
@@ -511,6 +1699,21 @@ impl ProxyHandler {
                             break;
                         }
                         Ok(n) => {
+                            if let Some(sni) = awaiting_response.take() {
+                                if is_handshake_rejection(&server_buffer[..n]) {
+                                    self.fingerprint_failures.record_failure(sni);
+                                } else {
+                                    self.fingerprint_failures.record_success(sni);
+                                }
+                            }
+
+                            window_bytes += n as u64;
+                            if !window_interval.is_zero() && window_started.elapsed() >= window_interval {
+                                adapt_upstream_window(&mut window, server_stream, window_bytes, window_started.elapsed(), conn_id);
+                                window_bytes = 0;
+                                window_started = Instant::now();
+                            }
+
                             timing.wait_natural_delay().await;
                             
                             if let Err(e) = client_stream.write_all(&server_buffer[..n]).await {
@@ -518,15 +1721,45 @@ impl ProxyHandler {
                                 break;
                             }
 
+                            // The buffer was already sent to the client, it can be replaced
+                            server_bytes += n as u64;
+                            self.promote_download_buffer(&mut server_buffer, server_bytes, &mut mmap_threshold, conn_id);
+
                             timing.record_send();
                             self.graceful_shutdown.mark_activity(conn_id).await;
                         }
                         Err(e) => {
                             log::error!("Server read error: {}", e);
+                            if let (Some(sni), std::io::ErrorKind::ConnectionReset) = (awaiting_response, e.kind()) {
+                                self.fingerprint_failures.record_failure(sni);
+                            }
                             break;
                         }
                     }
                 }
+                // The signal only wakes the loop, the byte is forwarded at the mark at the top of the iteration
+                signalled = urgent_signal(&client_urgent), if client_urgent.is_some() => {
+                    match signalled {
+                        Ok(true) => {}
+                        Ok(false) => client_urgent = None,
+                        Err(e) => {
+                            log::debug!("Connection {}: client urgent data unavailable: {}", conn_id, e);
+                            client_urgent = None;
+                        }
+                    }
+                }
+                signalled = urgent_signal(&server_urgent), if server_urgent.is_some() => {
+                    match signalled {
+                        Ok(true) => {}
+                        Ok(false) => server_urgent = None,
+                        Err(e) => {
+                            log::debug!("Connection {}: server urgent data unavailable: {}", conn_id, e);
+                            server_urgent = None;
+                        }
+                    }
+                }
+                // An idle tunnel would otherwise sit there until shutdown_timeout
+                _ = self.graceful_shutdown.wait_for_shutdown() => continue,
             }
         }
 
@@ -534,35 +1767,298 @@ impl ProxyHandler {
         Ok(())
     }
 
-    async fn connect_to_upstream(&self) -> Result<TcpStream> {
+    /// Tunnel through two pipes with splice in both directions. false - splice is not
+    /// supported, the data is untouched and a buffered relay can continue
+    async fn splice_tunnel(
+        &self,
+        client_stream: &mut TcpStream,
+        server_stream: &mut TcpStream,
+        conn_id: u64,
+    ) -> Result<bool> {
+        use tokio::io::Interest;
+
+        let (upstream_pipe, downstream_pipe) = match (SplicePipe::new(), SplicePipe::new()) {
+            (Ok(upstream), Ok(downstream)) => (upstream, downstream),
+            (Err(e), _) | (_, Err(e)) => {
+                log::debug!("Failed to create splice pipes ({}), buffered relay", e);
+                return Ok(false);
+            }
+        };
+        log::debug!("Connection {}: relaying through splice", conn_id);
+
+        let (client_fd, server_fd) = (client_stream.as_raw_fd(), server_stream.as_raw_fd());
+        // Bytes sitting in a pipe and not yet delivered to the receiver
+        let (mut upstream_pending, mut downstream_pending) = (0usize, 0usize);
+        let mut moved_any = false;
+
+        loop {
+            if self.graceful_shutdown.is_shutting_down() {
+                log::debug!("Shutdown detected for connection {}", conn_id);
+                return Ok(true);
+            }
+
+            // Each branch is a single async_io operation, cancelling the select loses nothing
+            let (moved, into_pipe, upstream) = tokio::select! {
+                result = client_stream.async_io(Interest::READABLE, || {
+                    upstream_pipe.splice_in(client_fd, BUFFER_SIZE)
+                }), if upstream_pending == 0 => (result, true, true),
+                result = server_stream.async_io(Interest::WRITABLE, || {
+                    upstream_pipe.splice_out(server_fd, upstream_pending)
+                }), if upstream_pending > 0 => (result, false, true),
+                result = server_stream.async_io(Interest::READABLE, || {
+                    downstream_pipe.splice_in(server_fd, BUFFER_SIZE)
+                }), if downstream_pending == 0 => (result, true, false),
+                result = client_stream.async_io(Interest::WRITABLE, || {
+                    downstream_pipe.splice_out(client_fd, downstream_pending)
+                }), if downstream_pending > 0 => (result, false, false),
+                _ = self.graceful_shutdown.wait_for_shutdown() => continue,
+            };
+
+            let n = match moved {
+                Ok(n) => n,
+                Err(e) if is_splice_unsupported(&e) && !moved_any => {
+                    log::debug!("splice unsupported ({}), buffered relay", e);
+                    return Ok(false);
+                }
+                Err(e) => {
+                    log::debug!("Connection {}: splice relay error: {}", conn_id, e);
+                    return Ok(true);
+                }
+            };
+            moved_any = true;
+
+            if into_pipe && n == 0 {
+                log::debug!("{} closed connection {}", if upstream { "Client" } else { "Server" }, conn_id);
+                // Bytes of the opposite direction already in the pipe are delivered to the receiver
+                let (pipe, stream, rest) = if upstream {
+                    (&downstream_pipe, &*client_stream, downstream_pending)
+                } else {
+                    (&upstream_pipe, &*server_stream, upstream_pending)
+                };
+                match drain_pipe(pipe, stream, rest).await {
+                    Ok(()) => {
+                        self.zero_copy_bytes.fetch_add(rest as u64, Ordering::Relaxed);
+                    }
+                    Err(e) => log::debug!("Connection {}: failed to flush splice pipe: {}", conn_id, e),
+                }
+                return Ok(true);
+            }
+
+            let pending = if upstream { &mut upstream_pending } else { &mut downstream_pending };
+            if into_pipe {
+                *pending = n;
+            } else {
+                *pending -= n;
+                self.zero_copy_bytes.fetch_add(n as u64, Ordering::Relaxed);
+                self.graceful_shutdown.mark_activity(conn_id).await;
+            }
+        }
+    }
+
+    async fn connect_to_upstream(&self, conn_id: u64) -> Result<TcpStream> {
         let proxy = &self.config.proxy_settings;
         let addr = format!("{}:{}", proxy.proxy_host, proxy.proxy_port);
         
         let recovery = ConnectionRecovery::new();
+        let started = Instant::now();
         
-        recovery.retry_with_backoff(|| async {
+        let stream = recovery.retry_with_backoff(|| async {
             TcpStream::connect(&addr).await.map_err(|e| e.into())
-        }).await
+        }).await?;
+
+        self.record_rtt(conn_id, &stream, started);
+        self.record_upstream(conn_id, &addr);
+        Ok(stream)
+    }
+
+    async fn connect_to_target(&self, target: &str, conn_id: u64) -> Result<TcpStream> {
+        let started = Instant::now();
+        let stream = self.open_target_stream(target, conn_id).await?;
+
+        self.record_rtt(conn_id, &stream, started);
+        self.record_upstream(conn_id, target);
+        Ok(stream)
+    }
+
+    /// Records the path to the upstream (direct/socks5/http/https) and the endpoint
+    /// for the connection summary
+    fn record_upstream(&self, conn_id: u64, target: &str) {
+        let proxy = &self.config.proxy_settings;
+
+        if proxy.is_direct() {
+            self.state_manager.set_upstream(conn_id, "direct", target);
+        } else {
+            let endpoint = format!("{}:{}", proxy.proxy_host, proxy.proxy_port);
+            self.state_manager.set_upstream(conn_id, &proxy.proxy_type.to_lowercase(), &endpoint);
+        }
+    }
+
+    fn record_rtt(&self, conn_id: u64, stream: &TcpStream, connect_started: Instant) {
+        if !self.config.tcp_settings.measure_rtt {
+            return;
+        }
+
+        let handshake_rtt = connect_started.elapsed();
+        let tcp_rtt = match read_tcp_rtt(stream) {
+            Ok(rtt) => Some(rtt),
+            Err(e) => {
+                log::debug!("TCP_INFO unavailable for connection {}: {}", conn_id, e);
+                None
+            }
+        };
+
+        log::info!("Connection {} RTT: handshake={:?}, tcp_info={:?}", conn_id, handshake_rtt, tcp_rtt);
+        self.state_manager.set_rtt(conn_id, handshake_rtt, tcp_rtt);
+    }
+
+    /// Relay buffers: client -> server and server -> client
+    /// Moves the download buffer to mmap once mmap_buffer_threshold bytes
+    /// have passed through it; after an error the threshold is reset
+    fn promote_download_buffer(&self, buffer: &mut RelayBuffer, server_bytes: u64, mmap_threshold: &mut u64, conn_id: u64) {
+        if *mmap_threshold == 0 || server_bytes < *mmap_threshold || buffer.is_mmap() {
+            return;
+        }
+
+        match buffer.promote_to_mmap() {
+            Ok(()) => {
+                log::debug!("Connection {}: download buffer moved to mmap after {} bytes", conn_id, server_bytes);
+                self.mmap_relays.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                log::debug!("Connection {}: mmap buffer unavailable: {}", conn_id, e);
+                *mmap_threshold = 0;
+            }
+        }
+    }
+
+    fn relay_buffers(&self) -> (Vec<u8>, Vec<u8>) {
+        let tcp = &self.config.tcp_settings;
+        (vec![0u8; tcp.client_buffer_size.max(1)], vec![0u8; tcp.server_buffer_size.max(1)])
+    }
+
+    /// Relay delays from timing_settings; disabled ones don't sleep at all
+    fn timing_preserver(&self) -> TimingPreserver {
+        let timing = &self.config.timing_settings;
+        if timing.enabled {
+            TimingPreserver::new(timing.jitter_percent / 100.0)
+        } else {
+            TimingPreserver::disabled()
+        }
+    }
+
+    /// Keepalive from tcp_settings if enabled; an error is only logged
+    fn apply_keepalive(&self, stream: &TcpStream) {
+        let tcp = &self.config.tcp_settings;
+        if !tcp.keepalive {
+            return;
+        }
+
+        if let Err(e) = configure_keepalive(
+            stream,
+            Duration::from_secs(tcp.keepalive_idle_secs),
+            Duration::from_secs(tcp.keepalive_interval_secs),
+            tcp.keepalive_count,
+        ) {
+            log::warn!("Failed to configure TCP keepalive: {}", e);
+        }
+    }
+
+    /// The connection's profile: the one recorded for it in the state, else the default
+    /// profile. The TLS fingerprint and socket TTL/MSS come from the same profile
+    fn connection_profile(&self, conn_id: u64) -> Option<&FingerprintProfile> {
+        self.state_manager.get_connection(conn_id)
+            .and_then(|info| info.profile)
+            .and_then(|name| self.config.get_profile(&name))
+            .or_else(|| self.config.get_default_profile())
+    }
+
+    /// The profile also sets TTL/MSS/nodelay of plain HTTP and passthrough
+    /// connections, so it goes into the summary before any fingerprinting
+    fn record_connection_profile(&self, conn_id: u64) {
+        if let Some(profile) = self.connection_profile(conn_id) {
+            self.state_manager.set_profile(conn_id, &profile.name);
+        }
+    }
+
+    /// The connection's TCP_NODELAY: from the profile, else the global tcp_settings.tcp_nodelay
+    fn tcp_nodelay(&self, conn_id: u64) -> bool {
+        self.connection_profile(conn_id)
+            .and_then(|profile| profile.tcp_nodelay)
+            .unwrap_or(self.config.tcp_settings.tcp_nodelay)
+    }
+
+    /// TTL and MSS of upstream connections from the connection's profile
+    fn upstream_ip_params(&self, conn_id: u64) -> (Option<u8>, Option<u16>) {
+        self.connection_profile(conn_id)
+            .map(|profile| (profile.ttl, profile.mss))
+            .unwrap_or_default()
+    }
+
+    /// iOS TCP options of the upstream socket plus the profile TTL/MSS, so L3/L4
+    /// match the TLS fingerprint
+    fn apply_upstream_tcp_options(&self, stream: &TcpStream, conn_id: u64) -> Result<()> {
+        stream.set_nodelay(self.tcp_nodelay(conn_id))?;
+        apply_tcp_options(stream, false, &self.config.tcp_settings.congestion_control)?;
+        self.apply_keepalive(stream);
+        let (ttl, mss) = self.upstream_ip_params(conn_id);
+        apply_ip_fingerprint(stream, ttl, mss)
     }
 
-    async fn connect_to_target(&self, target: &str) -> Result<TcpStream> {
+    /// Direct connection to the target: the profile TTL/MSS are set before connect
+    /// so the SYN already carries them; with tcp_settings.tcp_fastopen the first data
+    /// goes in the SYN (Linux only, otherwise a regular connect)
+    async fn connect_direct(&self, target: &str, conn_id: u64) -> Result<TcpStream> {
+        let (ttl, mss) = self.upstream_ip_params(conn_id);
+
+        let mut last_error = None;
+        for addr in tokio::net::lookup_host(target).await? {
+            let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+            if let Err(e) = apply_ip_fingerprint(&socket, ttl, mss) {
+                log::debug!("Failed to apply IP fingerprint for {}: {}", target, e);
+            }
+            if self.config.tcp_settings.tcp_fastopen {
+                if let Err(e) = enable_fastopen_connect(&socket) {
+                    log::debug!("TCP Fast Open unavailable for {}: {}", target, e);
+                }
+            }
+
+            match socket.connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(match last_error {
+            Some(e) => e.into(),
+            None => anyhow::anyhow!("No addresses resolved for {}", target),
+        })
+    }
+
+    async fn open_target_stream(&self, target: &str, conn_id: u64) -> Result<TcpStream> {
         let proxy = &self.config.proxy_settings;
         
         if proxy.is_direct() {
             log::debug!("Direct mode: connecting to {}", target);
             
             let recovery = ConnectionRecovery::new();
+            let timeout = proxy.connect_timeout();
             return recovery.retry_with_backoff(|| async {
-                TcpStream::connect(target).await.map_err(|e| e.into())
+                match timeout {
+                    Some(limit) => tokio::time::timeout(limit, self.connect_direct(target, conn_id)).await
+                        .map_err(|_| anyhow::anyhow!("Connect to {} timed out after {:?}", target, limit))?,
+                    None => self.connect_direct(target, conn_id).await,
+                }
             }).await;
         }
         
         // Parse target
-        let (host, port) = if let Some(pos) = target.rfind(':') {
-            (&target[..pos], target[pos + 1..].parse::<u16>().unwrap_or(443))
+        let (host, port) = split_host_port(target, 443);
+        let host = if proxy.remote_dns {
+            host.to_string()
         } else {
-            (target, 443)
+            self.resolve_locally(host, port).await?
         };
+        let host = host.as_str();
 
         match proxy.proxy_type.to_lowercase().as_str() {
             "socks5" => {
@@ -570,8 +2066,19 @@ impl ProxyHandler {
                     proxy.proxy_host.clone(),
                     proxy.proxy_port,
                     proxy.username.clone(),
-                    proxy.password.clone(),
-                );
+                    proxy.password.clone(),
+                ).with_connect_timeout(proxy.connect_timeout())
+                    .with_require_auth(proxy.require_auth);
+                ConnectionRecovery::new()
+                    .retry_with_backoff_if(|| connector.connect(host, port), is_retryable_connect_error)
+                    .await
+            }
+            "socks4" => {
+                let connector = Socks4Connector::new(
+                    proxy.proxy_host.clone(),
+                    proxy.proxy_port,
+                    proxy.username.clone(),
+                ).with_connect_timeout(proxy.connect_timeout());
                 connector.connect(host, port).await
             }
             "http" | "https" => {
@@ -580,7 +2087,7 @@ impl ProxyHandler {
                     proxy.proxy_port,
                     proxy.username.clone(),
                     proxy.password.clone(),
-                );
+                ).with_connect_timeout(proxy.connect_timeout());
                 connector.connect(host, port).await
             }
             _ => {
@@ -589,6 +2096,19 @@ impl ProxyHandler {
         }
     }
 
+    async fn resolve_locally(&self, host: &str, port: u16) -> Result<String> {
+        if host.parse::<std::net::IpAddr>().is_ok() {
+            return Ok(host.to_string());
+        }
+
+        let addr = tokio::net::lookup_host((host, port)).await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("No addresses for {}", host))?;
+
+        log::debug!("Resolved {} locally to {}", host, addr.ip());
+        Ok(addr.ip().to_string())
+    }
+
     fn extract_http_host(&self, request: &str) -> String {
         for line in request.lines() {
             if line.to_lowercase().starts_with("host:") {
@@ -629,6 +2149,12 @@ impl ProxyHandler {
         "httpbin.org:80".to_string()
     }
 
+    /// Key for tracking target rejections: SNI from the ClientHello, else the destination host.
+    /// The same one is used for checking and for recording the result
+    fn fingerprint_target(&self, client_hello: &[u8], host: &str) -> String {
+        self.extract_sni(client_hello).unwrap_or_else(|| host.to_string())
+    }
+
     fn extract_sni(&self, data: &[u8]) -> Option<String> {
         if data.len() < 43 {
             return None;
@@ -688,20 +2214,1680 @@ impl ProxyHandler {
         None
     }
 
+    pub async fn shutdown(&self) {
+        self.graceful_shutdown.initiate_shutdown().await;
+        if let Err(e) = self.graceful_shutdown.graceful_close_all().await {
+            log::warn!("Graceful close failed: {}", e);
+        }
+
+    }
+
     pub async fn cleanup_task(&self) {
         let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
         
         loop {
             interval.tick().await;
             
-            self.session_cache.cleanup_expired();
-            self.challenge_handler.write().cleanup_expired();
+            self.challenge_handler.cleanup_expired();
             self.state_manager.cleanup();
+            self.upstream_pool.evict_idle();
             self.graceful_shutdown.cleanup_idle_connections(
                 tokio::time::Duration::from_secs(300)
             ).await;
             
-            log::debug!("Cleanup completed");
+            log::debug!("Cleanup completed ({} empty connections so far)", self.empty_connection_count());
+        }
+    }
+}
+
+/// Headers body parsing depends on: rewriting the response never touches them
+const FRAMING_HEADERS: &[&str] = &["content-length", "transfer-encoding", "content-encoding"];
+
+/// Rewrites only the headers of an HTTP/1.1 response: `keep` decides for each
+/// header line, but framing headers always stay and the body is copied
+/// byte for byte - a compressed or chunked body isn't corrupted, Content-Length isn't recalculated
+fn rewrite_response_headers(response: &[u8], keep: impl Fn(&str) -> bool) -> Vec<u8> {
+    let Some(header_end) = response.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return response.to_vec();
+    };
+
+    let head = String::from_utf8_lossy(&response[..header_end]);
+
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().unwrap_or_default();
+    let kept: Vec<&str> = std::iter::once(status_line)
+        .chain(lines.filter(|line| {
+            let name = line.split(':').next().unwrap_or_default().trim().to_lowercase();
+            FRAMING_HEADERS.contains(&name.as_str()) || keep(line)
+        }))
+        .collect();
+
+    let mut result = kept.join("\r\n").into_bytes();
+    result.extend_from_slice(&response[header_end..]);
+    result
+}
+
+/// Removes Alt-Svc headers from HTTP/1.1 response headers
+fn strip_alt_svc(response: &[u8]) -> Vec<u8> {
+    rewrite_response_headers(response, |line| !line.to_lowercase().starts_with("alt-svc:"))
+}
+
+/// Adds cookies to the request's Cookie header (or creates it);
+/// a cookie with the same name is replaced with the new value
+fn inject_cookies(request: &[u8], cookies: &[String]) -> Vec<u8> {
+    let header_end = request
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .unwrap_or(request.len());
+    let head = String::from_utf8_lossy(&request[..header_end]);
+    let cookie_name = |pair: &str| pair.split('=').next().unwrap_or_default().trim().to_string();
+
+    let mut lines: Vec<String> = head.split("\r\n").map(|l| l.to_string()).collect();
+    match lines.iter_mut().skip(1).find(|l| l.to_lowercase().starts_with("cookie:")) {
+        Some(line) => {
+            let mut pairs: Vec<String> = line[7..].split(';').map(|pair| pair.trim().to_string()).collect();
+            for cookie in cookies {
+                match pairs.iter_mut().find(|pair| cookie_name(pair) == cookie_name(cookie)) {
+                    Some(pair) => *pair = cookie.clone(),
+                    None => pairs.push(cookie.clone()),
+                }
+            }
+            *line = format!("Cookie: {}", pairs.join("; "));
+        }
+        None => lines.push(format!("Cookie: {}", cookies.join("; "))),
+    }
+
+    let mut result = lines.join("\r\n").into_bytes();
+    result.extend_from_slice(b"\r\n\r\n");
+    if header_end + 4 < request.len() {
+        result.extend_from_slice(&request[header_end + 4..]);
+    }
+    result
+}
+
+/// Parses "host:port", "[v6]:port", bare IPv6 and a host without a port.
+/// IPv6 is returned without brackets
+fn split_host_port(target: &str, default_port: u16) -> (&str, u16) {
+    if let Some(rest) = target.strip_prefix('[') {
+        if let Some(end) = rest.find(']') {
+            let port = rest[end + 1..].strip_prefix(':')
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(default_port);
+            return (&rest[..end], port);
+        }
+    }
+
+    if target.parse::<std::net::Ipv6Addr>().is_ok() {
+        return (target, default_port);
+    }
+
+    match target.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().unwrap_or(default_port)),
+        None => (target, default_port),
+    }
+}
+
+/// Tunes upstream socket buffers to the BDP: RTT from TCP_INFO and throughput
+/// from the bytes received over the interval
+fn adapt_upstream_window(
+    window: &mut TcpWindowManager,
+    stream: &TcpStream,
+    bytes: u64,
+    elapsed: Duration,
+    conn_id: u64,
+) {
+    if let Ok(rtt) = read_tcp_rtt(stream) {
+        window.update_rtt(rtt);
+    }
+    window.update_bandwidth(bytes, elapsed);
+
+    // How much data arrives in one RTT - an estimate of data "in flight"
+    let per_rtt = bytes as f64 * window.get_average_rtt().as_secs_f64() / elapsed.as_secs_f64();
+    window.update_window(per_rtt.min(u32::MAX as f64) as u32);
+
+    match window.apply_to_socket(stream) {
+        Ok(Some(size)) => log::trace!("Connection {} upstream window raised to {} bytes", conn_id, size),
+        Ok(None) => {}
+        Err(e) => log::debug!("Connection {} upstream window not applied: {}", conn_id, e),
+    }
+}
+
+/// Writes the `pending` bytes left in the pipe to the receiver
+async fn drain_pipe(pipe: &SplicePipe, stream: &TcpStream, mut pending: usize) -> std::io::Result<()> {
+    let fd = stream.as_raw_fd();
+    while pending > 0 {
+        pending -= stream.async_io(tokio::io::Interest::WRITABLE, || pipe.splice_out(fd, pending)).await?;
+    }
+    Ok(())
+}
+
+/// Maximum length of a plaintext TLS record (RFC 8446, 5.1)
+const MAX_TLS_RECORD_LEN: usize = 16384;
+
+/// TLS record body length from its 5-byte header
+fn tls_record_len(data: &[u8]) -> usize {
+    u16::from_be_bytes([data[3], data[4]]) as usize
+}
+
+/// Records that arrived with the ClientHello (ChangeCipherSpec, early data)
+/// go to the server unchanged
+fn after_first_record(data: &[u8]) -> &[u8] {
+    if data.len() < 5 {
+        return &[];
+    }
+    &data[(5 + tls_record_len(data)).min(data.len())..]
+}
+
+/// Watcher for a socket's urgent data; without it urgent bytes are simply lost
+fn urgent_watcher(stream: &TcpStream, conn_id: u64) -> Option<UrgentWatcher> {
+    UrgentWatcher::new(stream)
+        .map_err(|e| log::debug!("Connection {}: urgent data not forwarded: {}", conn_id, e))
+        .ok()
+}
+
+async fn urgent_signal(watcher: &Option<UrgentWatcher>) -> std::io::Result<bool> {
+    match watcher {
+        Some(watcher) => watcher.signalled().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Forwards the urgent byte once all regular bytes up to the mark are read and
+/// therefore written to the receiver: the receiver's mark lands at the same stream position.
+/// false - the source closed the connection
+async fn forward_urgent_at_mark(
+    watcher: &Option<UrgentWatcher>,
+    from: &TcpStream,
+    to: &mut TcpStream,
+    buf: &mut [u8],
+) -> std::io::Result<bool> {
+    let Some(watcher) = watcher else {
+        return Ok(true);
+    };
+    let Some(byte) = watcher.take_at_mark()? else {
+        return Ok(true);
+    };
+    send_urgent(to, byte).await?;
+
+    // A short read stopped by the mark cleared tokio readiness, although data
+    // past the mark may already be there; read it directly until EAGAIN
+    loop {
+        let n = unsafe {
+            libc::recv(from.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), libc::MSG_DONTWAIT)
+        };
+        if n == 0 {
+            return Ok(false);
+        }
+        if n < 0 {
+            let err = std::io::Error::last_os_error();
+            match err.kind() {
+                std::io::ErrorKind::WouldBlock => return Ok(true),
+                std::io::ErrorKind::Interrupted => continue,
+                _ => return Err(err),
+            }
+        }
+        to.write_all(&buf[..n as usize]).await?;
+        if let Some(byte) = watcher.take_at_mark()? {
+            send_urgent(to, byte).await?;
+        }
+    }
+}
+
+fn header_value(response: &str, name: &str) -> Option<String> {
+    response.lines()
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+/// Redirect path if Location points to the same origin: http scheme (the connection
+/// here is plaintext), same host and port. A relative path is always the same origin
+fn same_origin_path(location: &str, target: &str) -> Option<String> {
+    let rest = if let Some(rest) = location.strip_prefix("//") {
+        rest
+    } else if location.starts_with('/') {
+        return Some(location.to_string());
+    } else if location.get(..7).is_some_and(|scheme| scheme.eq_ignore_ascii_case("http://")) {
+        &location[7..]
+    } else {
+        return None;
+    };
+
+    let (authority, path) = match rest.find('/') {
+        Some(pos) => (&rest[..pos], &rest[pos..]),
+        None => (rest, "/"),
+    };
+
+    let (location_host, location_port) = split_host_port(authority, 80);
+    let (target_host, target_port) = split_host_port(target, 80);
+    (location_host.eq_ignore_ascii_case(target_host) && location_port == target_port).then(|| path.to_string())
+}
+
+/// Adds to the client the Set-Cookie of interim responses it didn't see
+fn with_set_cookies(response: &[u8], set_cookies: &[String]) -> Vec<u8> {
+    let Some(header_end) = response.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return response.to_vec();
+    };
+
+    let mut result = response[..header_end + 2].to_vec();
+    for value in set_cookies {
+        result.extend_from_slice(format!("Set-Cookie: {}\r\n", value).as_bytes());
+    }
+    result.extend_from_slice(&response[header_end + 2..]);
+    result
+}
+
+/// Request for Location: 307/308 keep the method and body, the rest become GET
+fn redirect_request(request: &[u8], status: u16, path: &str, cookies: &[String]) -> Vec<u8> {
+    let header_end = request.windows(4).position(|w| w == b"\r\n\r\n").unwrap_or(request.len());
+    let head = String::from_utf8_lossy(&request[..header_end]);
+    let mut lines = head.split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("GET");
+    let version = parts.nth(1).unwrap_or("HTTP/1.1");
+    let keep_body = matches!(status, 307 | 308);
+    let method = if keep_body || method == "HEAD" { method } else { "GET" };
+
+    let mut result = format!("{} {} {}", method, path, version);
+    for line in lines {
+        let name = line.split(':').next().unwrap_or_default().trim().to_lowercase();
+        if !keep_body && matches!(name.as_str(), "content-length" | "content-type" | "transfer-encoding") {
+            continue;
+        }
+        result.push_str("\r\n");
+        result.push_str(line);
+    }
+    result.push_str("\r\n\r\n");
+
+    let mut result = result.into_bytes();
+    if keep_body && header_end + 4 < request.len() {
+        result.extend_from_slice(&request[header_end + 4..]);
+    }
+
+    if cookies.is_empty() {
+        result
+    } else {
+        inject_cookies(&result, cookies)
+    }
+}
+
+/// Requests can be resent only if they are fully received
+/// headers of idempotent methods (RFC 9110, 9.2.2) without a body
+fn is_replayable(requests: &[u8]) -> bool {
+    if !requests.ends_with(b"\r\n\r\n") {
+        return false;
+    }
+
+    String::from_utf8_lossy(requests).split_terminator("\r\n\r\n").all(|head| {
+        let method = head.split(' ').next().unwrap_or_default();
+        matches!(method, "GET" | "HEAD" | "OPTIONS" | "TRACE" | "PUT" | "DELETE")
+            && header_value(head, "Transfer-Encoding").is_none()
+            && header_value(head, "Content-Length").is_none_or(|len| len == "0")
+    })
+}
+
+/// Some(true) for an interim 1xx response (101 Switching Protocols is final),
+/// None while the status isn't fully read
+fn is_interim_response(response: &[u8]) -> Option<bool> {
+    let status = response.get(9..12)?;
+    Some(status[0] == b'1' && status != b"101")
+}
+
+fn parse_status_code(response: &str) -> Option<u16> {
+    response.lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    /// One read may return only part of the request, so read up to the blank line
+    async fn read_request_head(stream: &mut TcpStream) -> Vec<u8> {
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).await.unwrap();
+            head.push(byte[0]);
+        }
+        head
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_denied_client_rejected_with_rst() {
+        use nix::sys::socket::{getsockopt, sockopt};
+
+        let mut config = Config::default();
+        config.acl_settings.deny_clients = vec!["127.0.0.0/8".to_string()];
+        config.acl_settings.reject_with_rst = true;
+        let handler = ProxyHandler::new(config);
+
+        let (_client, proxy_side) = connected_pair().await;
+        handler.reject_connection(&proxy_side);
+        let linger = getsockopt(&proxy_side, sockopt::Linger).unwrap();
+        assert_eq!((linger.l_onoff, linger.l_linger), (1, 0));
+
+        // The whole path: a client from a denied network gets a reset, not EOF
+        let (mut client, proxy_side) = connected_pair().await;
+        handler.handle_connection(proxy_side).await.unwrap();
+        let mut buf = [0u8; 16];
+        let err = client.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        assert_eq!(handler.empty_connection_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_connection_over_limit_refused() {
+        let mut config = Config::default();
+        config.tcp_settings.max_connections = 2;
+        let handler = Arc::new(ProxyHandler::new(config));
+        assert_eq!(handler.max_connections(), 2);
+
+        // Two clients hold connections without sending anything
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let (client, proxy_side) = connected_pair().await;
+            let handler = handler.clone();
+            tokio::spawn(async move { handler.handle_connection(proxy_side).await });
+            clients.push(client);
+        }
+        while handler.active_connection_count() < 2 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let (mut client, proxy_side) = connected_pair().await;
+        handler.handle_connection(proxy_side).await.unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(handler.rejected_connection_count(), 1);
+        assert_eq!(handler.active_connection_count(), 2);
+
+        // A slot is freed only once connection handling has finished
+        drop(clients.pop());
+        while handler.active_connection_count() > 1 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let (_client, proxy_side) = connected_pair().await;
+        let handler_task = handler.clone();
+        tokio::spawn(async move { handler_task.handle_connection(proxy_side).await });
+        while handler.active_connection_count() < 2 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(handler.rejected_connection_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_empty_connection_timeout() {
+        let mut config = Config::default();
+        config.tcp_settings.empty_connection_timeout_ms = 50;
+        let handler = ProxyHandler::new(config);
+
+        let (_client, mut server) = connected_pair().await;
+        let mut buffer = vec![0u8; 16];
+
+        let started = Instant::now();
+        let n = handler.read_first_packet(&mut server, &mut buffer, 1).await.unwrap();
+
+        assert_eq!(n, 0);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(handler.empty_connection_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_first_packet_within_timeout() {
+        let mut config = Config::default();
+        config.tcp_settings.empty_connection_timeout_ms = 1000;
+        let handler = ProxyHandler::new(config);
+
+        let (mut client, mut server) = connected_pair().await;
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut buffer = vec![0u8; 64];
+
+        let n = handler.read_first_packet(&mut server, &mut buffer, 1).await.unwrap();
+
+        assert!(n > 0);
+        assert_eq!(handler.empty_connection_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_resetting_target_switches_to_passthrough() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        config.tls_settings.fingerprint_failure_threshold = 2;
+        let handler = ProxyHandler::new(config);
+        let hello = crate::tls::tests::sample_client_hello();
+
+        let upstream_task = tokio::spawn(async move {
+            let mut received = Vec::new();
+            for _ in 0..3 {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let mut buf = vec![0u8; 4096];
+                let n = stream.read(&mut buf).await.unwrap();
+                received.push(buf[..n].to_vec());
+                set_linger_zero(&stream).unwrap();
+            }
+            received
+        });
+
+        let request = format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream_addr);
+        for conn_id in 1..=3 {
+            let (mut client, mut proxy_side) = connected_pair().await;
+            let hello = hello.clone();
+            let client_side = async move {
+                let mut established = [0u8; 39];
+                client.read_exact(&mut established).await.unwrap();
+                client.write_all(&hello).await.unwrap();
+                client
+            };
+            let (result, _client) = tokio::join!(
+                handler.handle_connect_method(&mut proxy_side, request.as_bytes(), conn_id),
+                client_side,
+            );
+            result.unwrap();
+        }
+
+        assert!(handler.fingerprint_failures.is_passthrough("example.com"));
+        let received = upstream_task.await.unwrap();
+        assert_ne!(received[0], hello);
+        assert_ne!(received[1], hello);
+        assert_eq!(received[2], hello);
+    }
+
+    #[tokio::test]
+    async fn test_early_data_passed_through_unchanged() {
+        use crate::tls::TlsExtension;
+
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        let handler = ProxyHandler::new(config);
+
+        // early_data + pre_shared_key (identity, binder), then CCS and 0-RTT application data
+        let psk = [0x00, 0x07, 0x00, 0x01, 0xAA, 0x00, 0x00, 0x00, 0x01,
+            0x00, 0x05, 0x04, 0xBB, 0xBB, 0xBB, 0xBB];
+        let mut flight = crate::tls::tests::client_hello_with_extensions(&[
+            TlsExtension { extension_type: 42, data: Vec::new() },
+            TlsExtension { extension_type: 41, data: psk.to_vec() },
+        ]);
+        flight.extend_from_slice(&[0x14, 0x03, 0x03, 0x00, 0x01, 0x01]);
+        flight.extend_from_slice(&[0x17, 0x03, 0x03, 0x00, 0x04, 0xDE, 0xAD, 0xBE, 0xEF]);
+        let expected_len = flight.len();
+
+        let upstream_task = tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut received = vec![0u8; expected_len];
+            stream.read_exact(&mut received).await.unwrap();
+            received
+        });
+
+        let request = format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream_addr);
+        let (mut client, mut proxy_side) = connected_pair().await;
+        let sent = flight.clone();
+        let client_side = async move {
+            let mut established = [0u8; 39];
+            client.read_exact(&mut established).await.unwrap();
+            client.write_all(&sent).await.unwrap();
+            client
+        };
+        let (result, _client) = tokio::join!(
+            handler.handle_connect_method(&mut proxy_side, request.as_bytes(), 1),
+            client_side,
+        );
+
+        result.unwrap();
+        assert_eq!(upstream_task.await.unwrap(), flight);
+        assert!(handler.fingerprint_failures.should_fingerprint("example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_fingerprint_failure_counts_only_rejections() {
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        config.tls_settings.fingerprint_failure_threshold = 1;
+        let handler = ProxyHandler::new(config);
+
+        // The upstream answers the ClientHello with reply and closes the connection
+        async fn exchange(handler: &ProxyHandler, reply: &'static [u8]) {
+            let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let request = format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream.local_addr().unwrap());
+            let upstream_task = tokio::spawn(async move {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let mut header = [0u8; 5];
+                stream.read_exact(&mut header).await.unwrap();
+                let mut hello = vec![0u8; u16::from_be_bytes([header[3], header[4]]) as usize];
+                stream.read_exact(&mut hello).await.unwrap();
+                stream.write_all(reply).await.unwrap();
+            });
+
+            let (mut client, mut proxy_side) = connected_pair().await;
+            let client_task = tokio::spawn(async move {
+                let mut established = [0u8; 39];
+                client.read_exact(&mut established).await.unwrap();
+                client.write_all(&crate::tls::tests::sample_client_hello()).await.unwrap();
+                let mut rest = Vec::new();
+                let _ = client.read_to_end(&mut rest).await;
+            });
+            handler.handle_connect_method(&mut proxy_side, request.as_bytes(), 1).await.unwrap();
+            drop(proxy_side);
+            client_task.await.unwrap();
+            upstream_task.await.unwrap();
+        }
+
+        // A close without a reply and a non-TLS reply are not a handshake rejection
+        exchange(&handler, b"").await;
+        exchange(&handler, b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        assert!(handler.fingerprint_failures.should_fingerprint("example.com"));
+
+        exchange(&handler, &[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28]).await;
+        assert!(!handler.fingerprint_failures.should_fingerprint("example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_client_hello_header_only_times_out() {
+        let mut config = Config::default();
+        config.tls_settings.client_hello_timeout_ms = 50;
+        let handler = ProxyHandler::new(config);
+
+        let (mut client, mut server) = connected_pair().await;
+        let header = vec![0x16, 0x03, 0x01, 0x02, 0x00];
+        client.write_all(&header).await.unwrap();
+
+        let started = Instant::now();
+        let result = tokio::time::timeout(
+            Duration::from_secs(2),
+            handler.read_client_hello(&mut server, header, 1),
+        ).await.expect("ClientHello read must not hang");
+
+        assert!(result.unwrap().is_none());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(handler.incomplete_client_hello_count(), 1);
+    }
+
+    #[test]
+    fn test_challenge_status_codes() {
+        let mut config = Config::default();
+        config.challenge_settings.challenge_status_codes = vec![403];
+        config.challenge_settings.challenge_server_markers = vec!["Cloudflare".to_string()];
+        let handler = ProxyHandler::new(config);
+
+        let challenged = "HTTP/1.1 403 Forbidden\r\nServer: cloudflare\r\n\r\n";
+        let normal = "HTTP/1.1 403 Forbidden\r\nServer: nginx\r\n\r\n";
+        assert!(handler.detect_challenge_in_response(challenged));
+        assert!(!handler.detect_challenge_in_response(normal));
+
+        let plain = ProxyHandler::new(Config::default());
+        assert!(!plain.detect_challenge_in_response(challenged));
+    }
+
+    #[tokio::test]
+    async fn test_challenge_handling_disabled_relays_directly() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        config.challenge_settings.enable_challenge_handling = false;
+        config.challenge_settings.challenge_status_codes = vec![403];
+        let handler = ProxyHandler::new(config);
+
+        let challenge: &[u8] = b"HTTP/1.1 403 Forbidden\r\nServer: cloudflare\r\n\
+            Set-Cookie: cf_clearance=token123; Path=/\r\nContent-Length: 35\r\n\r\n\
+            <form id=\"cf-challenge-form\"></form>";
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            read_request_head(&mut stream).await;
+            stream.write_all(challenge).await.unwrap();
+            // There must be no repeated request with clearance
+            tokio::time::timeout(Duration::from_millis(200), upstream.accept()).await.is_err()
+        });
+
+        let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", upstream_addr);
+        let (mut client, mut proxy_side) = connected_pair().await;
+        let (_, response) = tokio::join!(
+            handler.handle_http_connection(&mut proxy_side, request.as_bytes(), 1),
+            async move {
+                let mut response = vec![0u8; challenge.len()];
+                client.read_exact(&mut response).await.unwrap();
+                response
+            },
+        );
+
+        assert_eq!(response, challenge);
+        assert!(server.await.unwrap());
+        let key = upstream_addr.to_string();
+        assert!(handler.challenge_handler.shard(&key).read().get_challenge_cookies(&key).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_challenged_post_replayed_with_clearance() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        let handler = ProxyHandler::new(config);
+
+        let server = tokio::spawn(async move {
+            let (mut first, _) = upstream.accept().await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            read_request_head(&mut first).await;
+            // Cookies and the challenge marker arrive after the status line
+            first.write_all(b"HTTP/1.1 403 Forbidden\r\n").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            first.write_all(
+                b"Set-Cookie: cf_clearance=token123; Path=/\r\nSet-Cookie: session=abc; HttpOnly\r\n\
+                  Content-Length: 36\r\n\r\n<form id=\"cf-challenge-form\"></form>"
+            ).await.unwrap();
+
+            let (mut second, _) = upstream.accept().await.unwrap();
+            let n = second.read(&mut buf).await.unwrap();
+            second.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        });
+
+        let request = format!(
+            "POST /login HTTP/1.1\r\nHost: {}\r\nContent-Length: 9\r\n\r\nuser=test",
+            upstream_addr
+        );
+        let (mut client, mut proxy_side) = connected_pair().await;
+        handler.handle_http_connection(&mut proxy_side, request.as_bytes(), 1).await.unwrap();
+
+        let replayed = server.await.unwrap();
+        assert!(replayed.starts_with("POST /login HTTP/1.1\r\n"));
+        assert!(replayed.contains("Cookie: cf_clearance=token123; session=abc\r\n"));
+        assert!(replayed.ends_with("\r\n\r\nuser=test"));
+
+        drop(proxy_side);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    }
+
+    #[tokio::test]
+    async fn test_redirect_following_capped() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        config.challenge_settings.challenge_status_codes = vec![302];
+        config.challenge_settings.follow_redirects = true;
+        config.challenge_settings.max_upstream_requests_per_connection = 3;
+        let handler = ProxyHandler::new(config);
+
+        let server = tokio::spawn(async move {
+            let mut paths = Vec::new();
+            let mut cookies = Vec::new();
+            for hop in 1..=3 {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let mut buf = vec![0u8; 4096];
+                let n = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+                paths.push(request.split_whitespace().nth(1).unwrap().to_string());
+                cookies.push(header_value(&request, "Cookie"));
+                let response = format!(
+                    "HTTP/1.1 302 Found\r\nLocation: /hop{}\r\nSet-Cookie: step={}; Path=/\r\nContent-Length: 0\r\n\r\n",
+                    hop, hop
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            let extra = tokio::time::timeout(Duration::from_millis(100), upstream.accept()).await;
+            (paths, cookies, extra.is_err())
+        });
+
+        let request = format!("GET /start HTTP/1.1\r\nHost: {}\r\n\r\n", upstream_addr);
+        let (mut client, mut proxy_side) = connected_pair().await;
+        handler.handle_http_connection(&mut proxy_side, request.as_bytes(), 1).await.unwrap();
+
+        let (paths, cookies, no_fourth_request) = server.await.unwrap();
+        assert_eq!(paths, vec!["/start", "/hop1", "/hop2"]);
+        // A cookie with the same name is updated, not duplicated
+        assert_eq!(cookies, vec![None, Some("step=1".to_string()), Some("step=2".to_string())]);
+        assert!(no_fourth_request);
+
+        drop(proxy_side);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 302 Found\r\nLocation: /hop3\r\n"));
+        // The client also gets the Set-Cookie of responses followed without it
+        assert!(response.contains("Set-Cookie: step=1; Path=/\r\nSet-Cookie: step=2; Path=/\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_hop_by_hop_headers_stripped() {
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        let handler = ProxyHandler::new(config.clone());
+
+        let request = "GET http://a.com/page HTTP/1.1\r\nHost: a.com\r\nProxy-Connection: keep-alive\r\n\
+            Proxy-Authorization: Basic dTpw\r\nConnection: keep-alive, X-Trace\r\nKeep-Alive: timeout=5\r\n\
+            X-Trace: 1\r\nTE: trailers\r\nAccept: */*\r\nCookie: sid=1\r\n\r\n";
+        let rewritten = String::from_utf8(handler.rewrite_http_request(request)).unwrap();
+        assert_eq!(rewritten, "GET /page HTTP/1.1\r\nHost: a.com\r\nAccept: */*\r\nCookie: sid=1\r\n\r\n");
+
+        // A configured list replaces the defaults: Proxy-Connection stays
+        config.proxy_settings.hop_by_hop_headers = vec!["keep-alive".to_string()];
+        let handler = ProxyHandler::new(config);
+        let rewritten = String::from_utf8(handler.rewrite_http_request(request)).unwrap();
+        assert!(rewritten.contains("Proxy-Connection: keep-alive\r\n"));
+        assert!(rewritten.contains("X-Trace: 1\r\n"));
+        assert!(!rewritten.contains("Keep-Alive:"));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrade_relayed() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        let handler = ProxyHandler::new(config);
+
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            let n = stream.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..n]).to_string();
+            assert!(request.starts_with("GET /chat HTTP/1.1\r\n"));
+            assert!(request.contains("\r\nUpgrade: websocket\r\n"));
+            assert!(request.contains("\r\nConnection: Upgrade\r\n"));
+            assert!(!request.contains("Proxy-Connection"));
+
+            stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n").await.unwrap();
+            let mut frame = [0u8; 4];
+            stream.read_exact(&mut frame).await.unwrap();
+            assert_eq!(&frame, b"ping");
+            stream.write_all(b"pong").await.unwrap();
+        });
+
+        let request = format!(
+            "GET http://{}/chat HTTP/1.1\r\nHost: {}\r\nProxy-Connection: keep-alive\r\n\
+             Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            upstream_addr, upstream_addr,
+        );
+        let switching = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+        let (mut client, mut proxy_side) = connected_pair().await;
+        let client_side = async move {
+            let mut response = vec![0u8; switching.len()];
+            client.read_exact(&mut response).await.unwrap();
+            assert_eq!(response, switching);
+
+            // After 101 the connection is just a tunnel
+            client.write_all(b"ping").await.unwrap();
+            let mut frame = [0u8; 4];
+            client.read_exact(&mut frame).await.unwrap();
+            frame
+        };
+        let (_, frame) = tokio::join!(
+            handler.handle_http_connection(&mut proxy_side, request.as_bytes(), 1),
+            client_side,
+        );
+
+        assert_eq!(&frame, b"pong");
+    }
+
+    #[test]
+    fn test_redirect_request_rewrite() {
+        let post = b"POST /login HTTP/1.1\r\nHost: a.com\r\nContent-Length: 4\r\n\r\nuser";
+        let get = String::from_utf8(redirect_request(post, 302, "/home", &["sid=1".to_string()])).unwrap();
+        assert_eq!(get, "GET /home HTTP/1.1\r\nHost: a.com\r\nCookie: sid=1\r\n\r\n");
+
+        let kept = String::from_utf8(redirect_request(post, 307, "/retry", &[])).unwrap();
+        assert!(kept.starts_with("POST /retry HTTP/1.1\r\n") && kept.ends_with("\r\n\r\nuser"));
+
+        assert_eq!(same_origin_path("http://a.com/next", "a.com:80").as_deref(), Some("/next"));
+        assert_eq!(same_origin_path("//A.com:80", "a.com:80").as_deref(), Some("/"));
+        assert_eq!(same_origin_path("https://a.com/next", "a.com:80"), None);
+        assert_eq!(same_origin_path("http://a.com:8080/next", "a.com:80"), None);
+        assert_eq!(same_origin_path("http://b.com/next", "a.com:80"), None);
+
+        let merged = String::from_utf8(inject_cookies(&get.into_bytes(), &["sid=2".to_string(), "step=1".to_string()])).unwrap();
+        assert!(merged.contains("\r\nCookie: sid=2; step=1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_gzip_response_untouched_by_header_rewrite() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        config.http2_settings.strip_alt_svc = true;
+        let handler = ProxyHandler::new(config);
+
+        // gzip body with "\r\n\r\n" and non-UTF-8 bytes inside
+        let body: &[u8] = b"\x1f\x8b\x08\x00\xff\xfe\r\n\r\n\x00\x03";
+        let mut upstream_response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nAlt-Svc: h3=\":443\"\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        ).into_bytes();
+        upstream_response.extend_from_slice(body);
+
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            read_request_head(&mut stream).await;
+            stream.write_all(&upstream_response).await.unwrap();
+        });
+
+        let mut expected = format!(
+            "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        ).into_bytes();
+        expected.extend_from_slice(body);
+
+        let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", upstream_addr);
+        let (mut client, mut proxy_side) = connected_pair().await;
+        let expected_len = expected.len();
+        let client_side = async move {
+            let mut response = vec![0u8; expected_len];
+            client.read_exact(&mut response).await.unwrap();
+            response
+        };
+        let (result, response) = tokio::join!(
+            handler.handle_http_connection(&mut proxy_side, request.as_bytes(), 1),
+            client_side,
+        );
+
+        result.unwrap();
+        assert_eq!(response, expected);
+
+        // Chunked brotli body: the same rule keeps the framing headers and the body bytes
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Encoding: br\r\nAlt-Svc: h3=\":443\"\r\n\r\n6\r\n\x1f\x8b\r\n\r\n\r\n0\r\n\r\n";
+        let expected = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Encoding: br\r\n\r\n6\r\n\x1f\x8b\r\n\r\n\r\n0\r\n\r\n";
+        assert_eq!(strip_alt_svc(chunked), expected.to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_goaway_sent_to_client_on_shutdown() {
+        let handler = ProxyHandler::new(Config::default());
+        let mut http2_handler = Http2Handler::new_ios_safari();
+        let (mut client, mut proxy_client) = connected_pair().await;
+        let (mut proxy_server, mut upstream) = connected_pair().await;
+
+        let client_side = async {
+            // Preface + HEADERS (END_HEADERS, stream 3, ":method: GET")
+            let mut request = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+            request.extend_from_slice(&[0, 0, 1, 0x01, 0x04, 0, 0, 0, 3, 0x82]);
+            client.write_all(&request).await.unwrap();
+
+            let mut forwarded = vec![0u8; request.len()];
+            upstream.read_exact(&mut forwarded).await.unwrap();
+            handler.graceful_shutdown.initiate_shutdown().await;
+
+            let mut goaway = [0u8; 17];
+            client.read_exact(&mut goaway).await.unwrap();
+
+            // The response on stream 3 (HEADERS, END_STREAM) still reaches the client after GOAWAY
+            upstream.write_all(&[0, 0, 1, 0x01, 0x05, 0, 0, 0, 3, 0x88]).await.unwrap();
+            let mut response = [0u8; 9];
+            client.read_exact(&mut response).await.unwrap();
+            assert_eq!(response[3], 0x01);
+            assert_eq!(&response[5..9], &3u32.to_be_bytes());
+            goaway
+        };
+        let (result, goaway) = tokio::join!(
+            handler.proxy_http2_bidirectional(&mut proxy_client, &mut proxy_server, &mut http2_handler, 1),
+            client_side,
+        );
+
+        result.unwrap();
+        assert_eq!(goaway[3], 0x07);
+        assert_eq!(&goaway[9..13], &3u32.to_be_bytes());
+        assert_eq!(&goaway[13..17], &ERROR_NO_ERROR.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_idle_tunnel_closes_on_shutdown() {
+        let mut config = Config::default();
+        config.tcp_settings.splice_relay = false;
+        let handler = ProxyHandler::new(config);
+        let (_client, mut proxy_client) = connected_pair().await;
+        let (mut proxy_server, _server) = connected_pair().await;
+
+        let shutdown = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            handler.graceful_shutdown.initiate_shutdown().await;
+        };
+        let relay = tokio::time::timeout(
+            Duration::from_secs(1),
+            handler.proxy_bidirectional(&mut proxy_client, &mut proxy_server, 1, None),
+        );
+        let (result, _) = tokio::join!(relay, shutdown);
+
+        result.expect("idle tunnel outlived shutdown").unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_silent_upstream_gets_504() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        config.tcp_settings.first_response_timeout_ms = 100;
+        let handler = ProxyHandler::new(config);
+
+        // Accepts the connection and stays silent
+        tokio::spawn(async move {
+            let (stream, _) = upstream.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            drop(stream);
+        });
+
+        let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", upstream_addr);
+        let (mut client, mut proxy_side) = connected_pair().await;
+        let started = Instant::now();
+        let (result, response) = tokio::join!(
+            handler.handle_http_connection(&mut proxy_side, request.as_bytes(), 1),
+            async move {
+                let mut response = vec![0u8; GATEWAY_TIMEOUT_RESPONSE.len()];
+                client.read_exact(&mut response).await.unwrap();
+                response
+            },
+        );
+
+        result.unwrap();
+        assert_eq!(response, GATEWAY_TIMEOUT_RESPONSE);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_alt_svc_stripped_from_http1_response() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        config.http2_settings.strip_alt_svc = true;
+        let handler = ProxyHandler::new(config);
+
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            read_request_head(&mut stream).await;
+            stream.write_all(
+                b"HTTP/1.1 200 OK\r\nAlt-Svc: h3=\":443\"; ma=86400\r\nContent-Length: 2\r\n\r\nok"
+            ).await.unwrap();
+        });
+
+        let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", upstream_addr);
+        let (mut client, mut proxy_side) = connected_pair().await;
+        handler.handle_http_connection(&mut proxy_side, request.as_bytes(), 1).await.unwrap();
+
+        drop(proxy_side);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+
+        // Next responses of a keep-alive connection, headers split across reads
+        let mut exchange = Http1Exchange::new();
+        exchange.on_request(b"GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n");
+        let mut forwarded = exchange.relay_response(b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\naHTTP/1.1 200 OK\r\nAlt-S", strip_alt_svc);
+        forwarded.extend(exchange.relay_response(b"vc: h3=\":443\"\r\nContent-Length: 1\r\n\r\nb", strip_alt_svc));
+        assert_eq!(forwarded, b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\naHTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb");
+    }
+
+    #[tokio::test]
+    async fn test_large_response_body_spliced() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        config.tcp_settings.zero_copy_threshold = 1024;
+        let handler = ProxyHandler::new(config);
+
+        let body: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut expected = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        expected.extend_from_slice(&body);
+
+        let upstream_response = expected.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            read_request_head(&mut stream).await;
+            stream.write_all(&upstream_response).await.unwrap();
+        });
+
+        let request = format!("GET /large HTTP/1.1\r\nHost: {}\r\n\r\n", upstream_addr);
+        let (mut client, mut proxy_side) = connected_pair().await;
+        let expected_len = expected.len();
+        let client_side = async move {
+            let mut response = vec![0u8; expected_len];
+            client.read_exact(&mut response).await.unwrap();
+            response
+        };
+        let (result, response) = tokio::join!(
+            handler.handle_http_connection(&mut proxy_side, request.as_bytes(), 1),
+            client_side,
+        );
+
+        result.unwrap();
+        assert!(response == expected);
+        assert!(handler.zero_copy_bytes() > 0);
+        assert!(handler.zero_copy_bytes() <= body.len() as u64);
+
+        // Content-Length next to chunked doesn't set the body length
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Length: 4096\r\n\r\n";
+        assert_eq!(handler.zero_copy_body_len(b"GET / HTTP/1.1\r\n\r\n", chunked), None);
+        assert_eq!(Config::default().tcp_settings.zero_copy_threshold, 0);
+    }
+
+    #[tokio::test]
+    async fn test_http1_upstream_reused_from_pool() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        let handler = ProxyHandler::new(config);
+
+        let upstream_task = tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            for _ in 0..2 {
+                read_request_head(&mut stream).await;
+                stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+            }
+            // There must be no second connection
+            tokio::time::timeout(Duration::from_millis(100), upstream.accept()).await.is_err()
+        });
+
+        let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", upstream_addr);
+        let expected = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+        for conn_id in 1..=2 {
+            let (mut client, mut proxy_side) = connected_pair().await;
+            let client_side = async move {
+                let mut response = vec![0u8; expected.len()];
+                client.read_exact(&mut response).await.unwrap();
+                response
+            };
+            let (result, response) = tokio::join!(
+                handler.handle_http_connection(&mut proxy_side, request.as_bytes(), conn_id),
+                client_side,
+            );
+            result.unwrap();
+            assert_eq!(response, expected);
+        }
+
+        assert!(upstream_task.await.unwrap());
+        assert_eq!(handler.upstream_pool.idle_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_100_continue_forwarded_before_final_response() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        let handler = ProxyHandler::new(config);
+
+        let upstream_task = tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            read_request_head(&mut stream).await;
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await.unwrap();
+            let mut body = [0u8; 4];
+            stream.read_exact(&mut body).await.unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+            body
+        });
+
+        let request = format!(
+            "POST / HTTP/1.1\r\nHost: {}\r\nContent-Length: 4\r\nExpect: 100-continue\r\n\r\n",
+            upstream_addr
+        );
+        let (mut client, mut proxy_side) = connected_pair().await;
+        let client_side = async move {
+            let mut interim = vec![0u8; 25];
+            client.read_exact(&mut interim).await.unwrap();
+            client.write_all(b"data").await.unwrap();
+            let mut response = vec![0u8; 40];
+            client.read_exact(&mut response).await.unwrap();
+            (interim, response)
+        };
+        let (result, (interim, response)) = tokio::join!(
+            handler.handle_http_connection(&mut proxy_side, request.as_bytes(), 1),
+            client_side,
+        );
+        result.unwrap();
+
+        assert_eq!(interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        assert_eq!(&upstream_task.await.unwrap(), b"data");
+    }
+
+    #[tokio::test]
+    async fn test_direct_connect_with_fastopen() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = upstream.local_addr().unwrap().to_string();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        config.tcp_settings.tcp_fastopen = true;
+        let handler = ProxyHandler::new(config);
+
+        let mut stream = handler.open_target_stream(&target, 1).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+
+        let (mut accepted, _) = upstream.accept().await.unwrap();
+        let mut received = [0u8; 5];
+        accepted.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_profile_ttl_applied_to_upstream() {
+        use nix::sys::socket::{getsockopt, sockopt};
+
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = upstream.local_addr().unwrap().to_string();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        let mut profile = Config::builtin_profile(crate::config::LATEST_IOS_PROFILE).unwrap().clone();
+        profile.ttl = Some(128);
+        profile.mss = Some(1200);
+        let mut other = profile.clone();
+        other.name = "other".to_string();
+        other.ttl = Some(100);
+        config.profiles = vec![profile, other];
+        let handler = ProxyHandler::new(config);
+
+        let stream = handler.open_target_stream(&target, 1).await.unwrap();
+        assert_eq!(getsockopt(&stream, sockopt::Ipv4Ttl).unwrap(), 128);
+
+        handler.apply_upstream_tcp_options(&stream, 1).unwrap();
+        assert_eq!(getsockopt(&stream, sockopt::Ipv4Ttl).unwrap(), 128);
+
+        // TTL comes from the profile chosen for the connection
+        let conn_id = handler.state_manager.create_connection();
+        handler.state_manager.set_profile(conn_id, "other");
+        let stream = handler.open_target_stream(&target, conn_id).await.unwrap();
+        assert_eq!(getsockopt(&stream, sockopt::Ipv4Ttl).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_h2_settings_and_fragmentation_follow_connection_profile() {
+        use crate::config::Http2SettingEntry;
+
+        let mut config = Config::default();
+        let mut other = config.get_default_profile().unwrap().clone();
+        other.name = "other".to_string();
+        other.record_fragment_size = Some(100);
+        other.http2_settings = vec![Http2SettingEntry { name: "MAX_FRAME_SIZE".to_string(), value: 32768 }];
+        config.profiles.push(other);
+        let handler = ProxyHandler::new(config);
+
+        let default_conn = handler.state_manager.create_connection();
+        let other_conn = handler.state_manager.create_connection();
+        handler.state_manager.set_profile(other_conn, "other");
+
+        assert_ne!(handler.http2_settings(default_conn).frame_entries, vec![(0x5, 32768)]);
+        assert_eq!(handler.http2_settings(other_conn).frame_entries, vec![(0x5, 32768)]);
+
+        let mut hello = vec![0x16, 0x03, 0x01, 0x01, 0x2c];
+        hello.extend_from_slice(&[0x01; 300]);
+        assert_eq!(handler.fragment_client_hello(hello.clone(), default_conn), hello);
+        assert_ne!(handler.fragment_client_hello(hello.clone(), other_conn), hello);
+    }
+
+    #[tokio::test]
+    async fn test_profile_nodelay_overrides_global() {
+        use nix::sys::socket::{getsockopt, sockopt};
+
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = upstream.local_addr().unwrap().to_string();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        config.tcp_settings.tcp_nodelay = true;
+        let handler = ProxyHandler::new(config.clone());
+
+        let stream = handler.open_target_stream(&target, 1).await.unwrap();
+        handler.apply_upstream_tcp_options(&stream, 1).unwrap();
+        assert!(getsockopt(&stream, sockopt::TcpNoDelay).unwrap());
+
+        for profile in &mut config.profiles {
+            profile.tcp_nodelay = Some(false);
+        }
+        let handler = ProxyHandler::new(config);
+        let stream = handler.open_target_stream(&target, 1).await.unwrap();
+        handler.apply_upstream_tcp_options(&stream, 1).unwrap();
+        assert!(!getsockopt(&stream, sockopt::TcpNoDelay).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_applied_when_enabled() {
+        use nix::sys::socket::{getsockopt, sockopt};
+
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = upstream.local_addr().unwrap().to_string();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        config.tcp_settings.keepalive = true;
+        config.tcp_settings.keepalive_idle_secs = 45;
+        config.tcp_settings.keepalive_interval_secs = 7;
+        config.tcp_settings.keepalive_count = 3;
+        let handler = ProxyHandler::new(config);
+
+        let stream = handler.open_target_stream(&target, 1).await.unwrap();
+        handler.apply_upstream_tcp_options(&stream, 1).unwrap();
+
+        assert!(getsockopt(&stream, sockopt::KeepAlive).unwrap());
+        assert_eq!(getsockopt(&stream, sockopt::TcpKeepIdle).unwrap(), 45);
+        assert_eq!(getsockopt(&stream, sockopt::TcpKeepInterval).unwrap(), 7);
+        assert_eq!(getsockopt(&stream, sockopt::TcpKeepCount).unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_asymmetric_relay_buffers() {
+        let mut config = Config::default();
+        config.tcp_settings.client_buffer_size = 16;
+        config.tcp_settings.server_buffer_size = 4096;
+        config.tcp_settings.splice_relay = false;
+        config.timing_settings.enabled = false;
+        let handler = ProxyHandler::new(config);
+
+        let (mut client, mut proxy_client) = connected_pair().await;
+        let (mut proxy_server, mut server) = connected_pair().await;
+        let upload: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let download: Vec<u8> = (0..100_000u32).map(|i| (i * 7) as u8).collect();
+
+        let client_side = {
+            let (upload, download) = (upload.clone(), download.clone());
+            async move {
+                client.write_all(&upload).await.unwrap();
+                let mut received = vec![0u8; download.len()];
+                client.read_exact(&mut received).await.unwrap();
+                assert_eq!(received, download);
+            }
+        };
+        let server_side = async move {
+            let mut received = vec![0u8; upload.len()];
+            server.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, upload);
+            server.write_all(&download).await.unwrap();
+            server
+        };
+
+        let (result, _, _server) = tokio::join!(
+            handler.proxy_bidirectional(&mut proxy_client, &mut proxy_server, 1, None),
+            client_side,
+            server_side,
+        );
+        result.unwrap();
+    }
+
+    /// Pushes `len` bytes client -> server, then the same amount back
+    async fn relay_round_trip(handler: &ProxyHandler, len: usize) -> Duration {
+        let (mut client, mut proxy_client) = connected_pair().await;
+        let (mut proxy_server, mut server) = connected_pair().await;
+        let payload: Vec<u8> = (0..len).map(|i| (i * 31) as u8).collect();
+
+        let client_side = {
+            let payload = payload.clone();
+            async move {
+                client.write_all(&payload).await.unwrap();
+                let mut received = vec![0u8; payload.len()];
+                client.read_exact(&mut received).await.unwrap();
+                assert!(received == payload);
+            }
+        };
+        let server_side = async move {
+            let mut received = vec![0u8; len];
+            server.read_exact(&mut received).await.unwrap();
+            server.write_all(&received).await.unwrap();
+            server
+        };
+
+        let started = Instant::now();
+        let (result, _, _server) = tokio::join!(
+            handler.proxy_bidirectional(&mut proxy_client, &mut proxy_server, 1, None),
+            client_side,
+            server_side,
+        );
+        result.unwrap();
+        started.elapsed()
+    }
+
+    #[tokio::test]
+    #[ignore = "benchmark, relays 128 MiB"]
+    async fn test_splice_relay_throughput() {
+        const LEN: usize = 32 << 20;
+        let mut config = Config::default();
+        config.timing_settings.enabled = false;
+
+        let spliced = ProxyHandler::new(config.clone());
+        let splice_time = relay_round_trip(&spliced, LEN).await;
+        assert_eq!(spliced.zero_copy_bytes(), 2 * LEN as u64);
+
+        config.tcp_settings.splice_relay = false;
+        let buffered = ProxyHandler::new(config);
+        let buffered_time = relay_round_trip(&buffered, LEN).await;
+        assert_eq!(buffered.zero_copy_bytes(), 0);
+
+        // Rough bound: splice must not be noticeably slower than the buffered relay
+        assert!(splice_time <= buffered_time * 2, "splice {:?}, buffered {:?}", splice_time, buffered_time);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_urgent_data_forwarded_as_urgent() {
+        let mut config = Config::default();
+        config.tcp_settings.forward_urgent_data = true;
+        config.timing_settings.enabled = false;
+        let handler = ProxyHandler::new(config);
+
+        let (client, mut proxy_client) = connected_pair().await;
+        let (mut proxy_server, mut server) = connected_pair().await;
+
+        let relay = async {
+            handler.proxy_bidirectional(&mut proxy_client, &mut proxy_server, 1, None).await.unwrap();
+        };
+        let peers = async {
+            let mut client = client;
+            client.write_all(b"ab").await.unwrap();
+            let sent = unsafe {
+                libc::send(client.as_raw_fd(), b"!".as_ptr() as *const libc::c_void, 1, libc::MSG_OOB)
+            };
+            assert_eq!(sent, 1);
+            client.write_all(b"cd").await.unwrap();
+
+            let mut normal = [0u8; 2];
+            server.read_exact(&mut normal).await.unwrap();
+            assert_eq!(&normal, b"ab");
+
+            // The urgent byte arrives separately from the regular stream, via MSG_OOB,
+            // and the mark sits right after "ab", not after "cd"
+            let fd = server.as_raw_fd();
+            let mut urgent = None;
+            for _ in 0..200 {
+                let mut byte = 0u8;
+                let ret = unsafe {
+                    libc::recv(fd, &mut byte as *mut u8 as *mut libc::c_void, 1, libc::MSG_OOB | libc::MSG_DONTWAIT)
+                };
+                if ret == 1 {
+                    urgent = Some(byte);
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert_eq!(urgent, Some(b'!'));
+            let mut at_mark: libc::c_int = 0;
+            assert_eq!(unsafe { libc::ioctl(fd, 0x8905, &mut at_mark) }, 0);
+            assert_eq!(at_mark, 1, "urgent mark is not right after the bytes sent before it");
+
+            server.read_exact(&mut normal).await.unwrap();
+            assert_eq!(&normal, b"cd");
+            drop(client);
+        };
+
+        tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(relay, peers) })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_large_download_relayed_through_mmap_buffer() {
+        let mut config = Config::default();
+        config.tcp_settings.splice_relay = false;
+        config.tcp_settings.mmap_buffer_threshold = 64 * 1024;
+        config.timing_settings.enabled = false;
+
+        let handler = ProxyHandler::new(config.clone());
+        relay_round_trip(&handler, 1 << 20).await;
+        assert_eq!(handler.mmap_relay_count(), 1);
+
+        config.tcp_settings.mmap_buffer_threshold = 0;
+        let handler = ProxyHandler::new(config);
+        relay_round_trip(&handler, 1 << 20).await;
+        assert_eq!(handler.mmap_relay_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_connection_summary_records_profile_and_upstream() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = upstream.local_addr().unwrap().to_string();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        let handler = ProxyHandler::new(config);
+
+        let conn_id = handler.state_manager.create_connection();
+        handler.record_connection_profile(conn_id);
+        let _stream = handler.connect_to_target(&target, conn_id).await.unwrap();
+
+        let info = handler.state_manager.get_connection(conn_id).unwrap();
+        assert_eq!(info.profile.as_deref(), Some(crate::config::LATEST_IOS_PROFILE));
+        assert_eq!(info.upstream_path.as_deref(), Some("direct"));
+        assert_eq!(info.upstream_endpoint.as_deref(), Some(target.as_str()));
+
+        let summary = info.summary();
+        assert!(summary.contains("profile=ios_17_safari"));
+        assert!(summary.contains(&format!("upstream=direct endpoint={}", target)));
+        // Without RTT measurement (tcp_settings.measure_rtt off) - a dash
+        assert!(summary.contains(" rtt=- "), "{}", summary);
+
+        handler.state_manager.set_rtt(conn_id, Duration::from_millis(42), None);
+        let summary = handler.state_manager.get_connection(conn_id).unwrap().summary();
+        assert!(summary.contains(" rtt=42ms "), "{}", summary);
+    }
+
+    #[tokio::test]
+    async fn test_pooled_upstream_closed_before_response_retried() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        let handler = ProxyHandler::new(config);
+
+        // The pooled connection closes after receiving the second client's request
+        tokio::spawn(async move {
+            let (mut first, _) = upstream.accept().await.unwrap();
+            read_request_head(&mut first).await;
+            first.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+            read_request_head(&mut first).await;
+            drop(first);
+
+            let (mut second, _) = upstream.accept().await.unwrap();
+            read_request_head(&mut second).await;
+            second.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+        });
+
+        let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", upstream_addr);
+        let expected = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+        for conn_id in 1..=2 {
+            let (mut client, mut proxy_side) = connected_pair().await;
+            let client_side = async move {
+                let mut response = vec![0u8; expected.len()];
+                client.read_exact(&mut response).await.unwrap();
+                response
+            };
+            let (result, response) = tokio::join!(
+                handler.handle_http_connection(&mut proxy_side, request.as_bytes(), conn_id),
+                client_side,
+            );
+            result.unwrap();
+            assert_eq!(response, expected);
         }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_unframed_response_not_pooled() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        let handler = ProxyHandler::new(config);
+
+        // A body without a length ends only when the connection closes
+        let (close_tx, close_rx) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            read_request_head(&mut stream).await;
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\npartial").await.unwrap();
+            let _ = close_rx.await;
+        });
+
+        let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", upstream_addr);
+        let expected = b"HTTP/1.1 200 OK\r\n\r\npartial";
+        let (mut client, mut proxy_side) = connected_pair().await;
+        let client_side = async move {
+            let mut response = vec![0u8; expected.len()];
+            client.read_exact(&mut response).await.unwrap();
+            response
+        };
+        let (result, response) = tokio::join!(
+            handler.handle_http_connection(&mut proxy_side, request.as_bytes(), 1),
+            client_side,
+        );
+
+        result.unwrap();
+        assert_eq!(response, expected);
+        assert_eq!(handler.upstream_pool.idle_count(), 0);
+        drop(close_tx);
+    }
+
+    #[tokio::test]
+    async fn test_h1_responses_read_past_interim_and_capped() {
+        let mut config = Config::default();
+        config.http2_settings.h2_downgrade_max_response = 64;
+        let handler = ProxyHandler::new(config);
+
+        let (mut upstream, mut server_stream) = connected_pair().await;
+        upstream.write_all(
+            b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na\
+              HTTP/1.1 204 No Content\r\n\r\n"
+        ).await.unwrap();
+
+        let mut pending = Vec::new();
+        let first = handler.read_h1_response(&mut server_stream, &mut pending, false).await.unwrap();
+        assert_eq!((first.status, first.body.as_slice()), (200, &b"a"[..]));
+        // The second response came together with the first and must not be lost
+        let second = handler.read_h1_response(&mut server_stream, &mut pending, false).await.unwrap();
+        assert_eq!(second.status, 204);
+
+        upstream.write_all(format!("HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n{}", "x".repeat(100)).as_bytes()).await.unwrap();
+        assert!(handler.read_h1_response(&mut server_stream, &mut pending, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_reconnects_after_upstream_close() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_type = "direct".to_string();
+        let handler = Arc::new(ProxyHandler::new(config));
+
+        // The first connection closes after getting the second request without answering it
+        tokio::spawn(async move {
+            let (mut first, _) = upstream.accept().await.unwrap();
+            read_request_head(&mut first).await;
+            first.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfirst").await.unwrap();
+            read_request_head(&mut first).await;
+            drop(first);
+
+            let (mut second, _) = upstream.accept().await.unwrap();
+            read_request_head(&mut second).await;
+            second.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nsecond").await.unwrap();
+        });
+
+        let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", upstream_addr);
+        let (mut client, mut proxy_side) = connected_pair().await;
+        let proxy = {
+            let handler = handler.clone();
+            let request = request.clone();
+            tokio::spawn(async move {
+                handler.handle_http_connection(&mut proxy_side, request.as_bytes(), 1).await
+            })
+        };
+
+        let mut buf = vec![0u8; 4096];
+        let n = client.read(&mut buf).await.unwrap();
+        assert!(buf[..n].ends_with(b"first"));
+
+        client.write_all(request.as_bytes()).await.unwrap();
+        let n = client.read(&mut buf).await.unwrap();
+        assert!(buf[..n].ends_with(b"second"));
+
+        drop(client);
+        proxy.await.unwrap().unwrap();
+
+        // Only idempotent requests without a body can be retried
+        assert!(is_replayable(b"GET / HTTP/1.1\r\n\r\nHEAD /a HTTP/1.1\r\n\r\n"));
+        assert!(!is_replayable(b"GET / HTTP/1.1\r\n\r\nPOST /a HTTP/1.1\r\nContent-Length: 0\r\n\r\n"));
+        assert!(!is_replayable(b"PUT /a HTTP/1.1\r\nContent-Length: 2\r\n\r\nok"));
+        assert!(!is_replayable(b"GET / HTTP/1.1\r\nHost: a"));
+    }
+
+    /// Starts a fake SOCKS5 server and returns the address from the CONNECT request
+    async fn socks5_connect_address(remote_dns: bool, target: &str) -> (u8, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default();
+        config.proxy_settings.proxy_port = listener.local_addr().unwrap().port();
+        config.proxy_settings.remote_dns = remote_dns;
+        let handler = ProxyHandler::new(config);
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[0x05, 0x00]).await.unwrap();
+
+            let mut header = [0u8; 4];
+            stream.read_exact(&mut header).await.unwrap();
+            let addr_len = match header[3] {
+                0x01 => 4,
+                0x04 => 16,
+                _ => {
+                    let mut len = [0u8; 1];
+                    stream.read_exact(&mut len).await.unwrap();
+                    len[0] as usize
+                }
+            };
+            let mut addr = vec![0u8; addr_len + 2];
+            stream.read_exact(&mut addr).await.unwrap();
+            addr.truncate(addr_len);
+
+            stream.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            (header[3], addr)
+        });
+
+        handler.open_target_stream(target, 1).await.unwrap();
+        server.await.unwrap()
+    }
+
+    #[test]
+    fn test_split_ipv6_target() {
+        assert_eq!(split_host_port("[::1]:443", 80), ("::1", 443));
+        assert_eq!(split_host_port("[2001:db8::1]", 443), ("2001:db8::1", 443));
+        assert_eq!(split_host_port("2001:db8::1", 443), ("2001:db8::1", 443));
+        assert_eq!(split_host_port("example.com:8443", 443), ("example.com", 8443));
+        assert_eq!(split_host_port("example.com", 443), ("example.com", 443));
+
+        let handler = ProxyHandler::new(Config::default());
+        let target = handler.extract_connect_target("CONNECT [::1]:443 HTTP/1.1\r\nHost: [::1]:443\r\n\r\n").unwrap();
+        assert_eq!(target, "[::1]:443");
+    }
+
+    #[tokio::test]
+    async fn test_socks5_ipv6_target() {
+        let (atyp, addr) = socks5_connect_address(true, "[::1]:443").await;
+        assert_eq!(atyp, 0x04);
+        assert_eq!(addr, std::net::Ipv6Addr::LOCALHOST.octets());
+    }
+
+    #[tokio::test]
+    async fn test_socks5_remote_dns() {
+        let (atyp, addr) = socks5_connect_address(true, "localhost:443").await;
+        assert_eq!(atyp, 0x03);
+        assert_eq!(addr, b"localhost");
+
+        let (atyp, _) = socks5_connect_address(false, "localhost:443").await;
+        assert_ne!(atyp, 0x03);
+    }
+}
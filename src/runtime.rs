@@ -0,0 +1,81 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use anyhow::Result;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::config::RuntimeSettings;
+
+/// Builds a multi-thread runtime from the settings instead of the `#[tokio::main]` defaults
+pub fn build_runtime(settings: &RuntimeSettings) -> Result<Runtime> {
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all();
+
+    if let Some(workers) = settings.worker_threads {
+        if workers == 0 {
+            return Err(anyhow::anyhow!("worker_threads must be greater than 0"));
+        }
+        builder.worker_threads(workers);
+    }
+
+    if let Some(blocking) = settings.max_blocking_threads {
+        if blocking == 0 {
+            return Err(anyhow::anyhow!("max_blocking_threads must be greater than 0"));
+        }
+        builder.max_blocking_threads(blocking);
+    }
+
+    if !settings.cpu_affinity.is_empty() {
+        // Threads (blocking ones included) are spread over the CPU list round-robin
+        let cpus = Arc::new(settings.cpu_affinity.clone());
+        let next = Arc::new(AtomicUsize::new(0));
+        builder.on_thread_start(move || {
+            let cpu = cpus[next.fetch_add(1, Ordering::Relaxed) % cpus.len()];
+            if let Err(e) = pin_current_thread(cpu) {
+                log::warn!("Failed to pin runtime thread to CPU {}: {}", cpu, e);
+            }
+        });
+    }
+
+    Ok(builder.build()?)
+}
+
+fn pin_current_thread(cpu: usize) -> Result<()> {
+    // CPU_SET panics outside cpu_set_t
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(anyhow::anyhow!("CPU {} exceeds CPU_SETSIZE ({})", cpu, libc::CPU_SETSIZE));
+    }
+
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        libc::CPU_SET(cpu, &mut set);
+
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runtime_uses_configured_workers() {
+        let settings = RuntimeSettings {
+            worker_threads: Some(3),
+            max_blocking_threads: Some(4),
+            cpu_affinity: vec![0],
+            ..Default::default()
+        };
+
+        let runtime = build_runtime(&settings).unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 3);
+        assert_eq!(runtime.block_on(async { 2 + 2 }), 4);
+
+        let invalid = RuntimeSettings { worker_threads: Some(0), ..Default::default() };
+        assert!(build_runtime(&invalid).is_err());
+    }
+}
@@ -1,23 +1,175 @@
 use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use anyhow::{Result, Context};
 use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
 use base64::Engine;
 
 const SOCKS5_VERSION: u8 = 0x05;
 const SOCKS5_AUTH_NONE: u8 = 0x00;
 const SOCKS5_AUTH_PASSWORD: u8 = 0x02;
 const SOCKS5_CMD_CONNECT: u8 = 0x01;
+const SOCKS5_CMD_UDP_ASSOCIATE: u8 = 0x03;
 const SOCKS5_ATYP_IPV4: u8 = 0x01;
 const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
 const SOCKS5_ATYP_IPV6: u8 = 0x04;
 const SOCKS5_REP_SUCCESS: u8 = 0x00;
+/// Limit on connecting to the proxy, handshake included
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const SOCKS4_VERSION: u8 = 0x04;
+const SOCKS4_CMD_CONNECT: u8 = 0x01;
+const SOCKS4_REP_GRANTED: u8 = 0x5A;
+
+/// Connect and handshake limit shared by all proxy connectors
+pub trait ConnectTimeout: Sized {
+    fn connect_timeout_mut(&mut self) -> &mut Option<Duration>;
+
+    /// None - wait for the connection and handshake without a limit
+    fn with_connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        *self.connect_timeout_mut() = timeout;
+        self
+    }
+}
+
+/// Rejection code from a SOCKS5 proxy reply (RFC 1928, REP field)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Socks5ReplyError {
+    GeneralFailure,
+    NotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+    CommandNotSupported,
+    AddressTypeNotSupported,
+    Unknown(u8),
+}
+
+impl Socks5ReplyError {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x01 => Self::GeneralFailure,
+            0x02 => Self::NotAllowed,
+            0x03 => Self::NetworkUnreachable,
+            0x04 => Self::HostUnreachable,
+            0x05 => Self::ConnectionRefused,
+            0x06 => Self::TtlExpired,
+            0x07 => Self::CommandNotSupported,
+            0x08 => Self::AddressTypeNotSupported,
+            code => Self::Unknown(code),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::GeneralFailure => 0x01,
+            Self::NotAllowed => 0x02,
+            Self::NetworkUnreachable => 0x03,
+            Self::HostUnreachable => 0x04,
+            Self::ConnectionRefused => 0x05,
+            Self::TtlExpired => 0x06,
+            Self::CommandNotSupported => 0x07,
+            Self::AddressTypeNotSupported => 0x08,
+            Self::Unknown(code) => *code,
+        }
+    }
+
+    /// Transient failures are worth retrying; a ruleset rejection, an unreachable
+    /// target or an unsupported request won't be fixed by a retry
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::GeneralFailure | Self::TtlExpired)
+    }
+}
+
+impl std::fmt::Display for Socks5ReplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            Self::GeneralFailure => "general SOCKS server failure",
+            Self::NotAllowed => "connection not allowed by ruleset",
+            Self::NetworkUnreachable => "network unreachable",
+            Self::HostUnreachable => "host unreachable",
+            Self::ConnectionRefused => "connection refused",
+            Self::TtlExpired => "TTL expired",
+            Self::CommandNotSupported => "command not supported",
+            Self::AddressTypeNotSupported => "address type not supported",
+            Self::Unknown(_) => "unknown error",
+        };
+        write!(f, "SOCKS5 request failed: {} (code {:#04x})", reason, self.code())
+    }
+}
+
+impl std::error::Error for Socks5ReplyError {}
+
+/// SOCKS5 proxy rejection during method selection and authentication; a retry
+/// with the same settings ends the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Socks5AuthError {
+    /// The proxy accepted none of the offered methods (0xFF)
+    NoAcceptableMethod,
+    /// The proxy skipped authentication, but require_auth demands it
+    AuthNotRequested,
+    UnsupportedMethod(u8),
+    /// Username and password were rejected (RFC 1929)
+    Rejected,
+}
+
+impl std::fmt::Display for Socks5AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoAcceptableMethod => write!(f, "No acceptable authentication method"),
+            Self::AuthNotRequested => write!(f, "SOCKS5 proxy selected no authentication, but require_auth is set"),
+            Self::UnsupportedMethod(method) => write!(f, "SOCKS5 proxy selected unsupported auth method {}", method),
+            Self::Rejected => write!(f, "SOCKS5 authentication failed"),
+        }
+    }
+}
+
+impl std::error::Error for Socks5AuthError {}
+
+/// Whether to retry connecting through the proxy: everything except final
+/// SOCKS5 rejections and authentication refusals (network errors and timeouts are retried as before)
+pub fn is_retryable_connect_error(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<Socks5AuthError>().is_none()
+        && error.downcast_ref::<Socks5ReplyError>().is_none_or(|reply| reply.is_retryable())
+}
+
+/// UDP ASSOCIATE: the relay lives as long as the control TCP connection is open
+#[derive(Debug)]
+pub struct Socks5UdpAssociation {
+    pub control: Arc<TcpStream>,
+    pub relay_addr: SocketAddr,
+}
+
+/// BND.ADDR/BND.PORT from a SOCKS5 proxy reply
+#[derive(Debug, Clone, PartialEq)]
+pub enum BoundAddress {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl std::fmt::Display for BoundAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BoundAddress::Ip(addr) => write!(f, "{}", addr),
+            BoundAddress::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
 
 pub struct Socks5Connector {
     proxy_host: String,
     proxy_port: u16,
     username: Option<String>,
     password: Option<String>,
+    connect_timeout: Option<Duration>,
+    require_auth: bool,
+}
+
+impl ConnectTimeout for Socks5Connector {
+    fn connect_timeout_mut(&mut self) -> &mut Option<Duration> {
+        &mut self.connect_timeout
+    }
 }
 
 impl Socks5Connector {
@@ -32,27 +184,107 @@ impl Socks5Connector {
             proxy_port,
             username,
             password,
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+            require_auth: false,
         }
     }
 
+    /// Fail if the proxy picks NONE instead of password authentication:
+    /// otherwise the connection would run under a different account than configured
+    pub fn with_require_auth(mut self, require_auth: bool) -> Self {
+        self.require_auth = require_auth;
+        self
+    }
+
     pub async fn connect(&self, target_host: &str, target_port: u16) -> Result<TcpStream> {
+        let (stream, _) = self.connect_with_bound(target_host, target_port).await?;
+        Ok(stream)
+    }
+
+    /// CONNECT together with the BND address from the proxy reply
+    pub async fn connect_with_bound(
+        &self,
+        target_host: &str,
+        target_port: u16,
+    ) -> Result<(TcpStream, BoundAddress)> {
         let proxy_addr = format!("{}:{}", self.proxy_host, self.proxy_port);
-        let mut stream = TcpStream::connect(&proxy_addr).await
+        connect_within(
+            self.connect_timeout,
+            "SOCKS5",
+            &proxy_addr,
+            self.establish(&proxy_addr, target_host, target_port),
+        ).await
+    }
+
+    async fn establish(
+        &self,
+        proxy_addr: &str,
+        target_host: &str,
+        target_port: u16,
+    ) -> Result<(TcpStream, BoundAddress)> {
+        let mut stream = TcpStream::connect(proxy_addr).await
             .context("Failed to connect to SOCKS5 proxy")?;
 
         log::debug!("Connected to SOCKS5 proxy at {}", proxy_addr);
 
-        self.handshake(&mut stream).await?;
-        self.authenticate(&mut stream).await?;
-        self.send_connect_request(&mut stream, target_host, target_port).await?;
+        let method = self.handshake(&mut stream).await?;
+        self.authenticate(&mut stream, method).await?;
+        let bound = self.send_connect_request(&mut stream, target_host, target_port).await?;
 
-        log::info!("✓ SOCKS5 connection established to {}:{} via {}", 
-            target_host, target_port, proxy_addr);
+        log::info!("✓ SOCKS5 connection established to {}:{} via {} (bound {})", 
+            target_host, target_port, proxy_addr, bound);
 
-        Ok(stream)
+        Ok((stream, bound))
+    }
+
+    /// Performs UDP ASSOCIATE and returns the proxy's UDP relay address
+    pub async fn udp_associate(&self) -> Result<Socks5UdpAssociation> {
+        let proxy_addr = format!("{}:{}", self.proxy_host, self.proxy_port);
+        connect_within(
+            self.connect_timeout,
+            "SOCKS5",
+            &proxy_addr,
+            self.associate(&proxy_addr),
+        ).await
+    }
+
+    async fn associate(&self, proxy_addr: &str) -> Result<Socks5UdpAssociation> {
+        let mut stream = TcpStream::connect(proxy_addr).await
+            .context("Failed to connect to SOCKS5 proxy")?;
+
+        let method = self.handshake(&mut stream).await?;
+        self.authenticate(&mut stream, method).await?;
+
+        // The client address is not known in advance, hence 0.0.0.0:0
+        let mut request = vec![SOCKS5_VERSION, SOCKS5_CMD_UDP_ASSOCIATE, 0x00];
+        request.extend_from_slice(&encode_address("0.0.0.0", 0));
+        stream.write_all(&request).await
+            .context("Failed to send SOCKS5 UDP ASSOCIATE request")?;
+
+        let bound = match read_reply(&mut stream).await? {
+            BoundAddress::Ip(addr) => addr,
+            BoundAddress::Domain(..) => {
+                return Err(anyhow::anyhow!("SOCKS5 UDP relay returned a domain address"));
+            }
+        };
+
+        // 0.0.0.0 means "the same host as the proxy"
+        let relay_addr = if bound.ip().is_unspecified() {
+            SocketAddr::new(stream.peer_addr()?.ip(), bound.port())
+        } else {
+            bound
+        };
+
+        log::info!("✓ SOCKS5 UDP ASSOCIATE via {}, relay {}", proxy_addr, relay_addr);
+
+        Ok(Socks5UdpAssociation {
+            control: Arc::new(stream),
+            relay_addr,
+        })
     }
 
-    async fn handshake(&self, stream: &mut TcpStream) -> Result<()> {
+    /// Negotiates the authentication method and returns the one the proxy chose
+    async fn handshake(&self, stream: &mut TcpStream) -> Result<u8> {
         let mut auth_methods = vec![SOCKS5_AUTH_NONE];
         if self.username.is_some() && self.password.is_some() {
             auth_methods.push(SOCKS5_AUTH_PASSWORD);
@@ -73,14 +305,30 @@ impl Socks5Connector {
         }
 
         if response[1] == 0xFF {
-            return Err(anyhow::anyhow!("No acceptable authentication method"));
+            return Err(Socks5AuthError::NoAcceptableMethod.into());
         }
 
         log::debug!("SOCKS5 handshake complete, auth method: {}", response[1]);
-        Ok(())
+        Ok(response[1])
     }
 
-    async fn authenticate(&self, stream: &mut TcpStream) -> Result<()> {
+    async fn authenticate(&self, stream: &mut TcpStream, method: u8) -> Result<()> {
+        let has_credentials = self.username.is_some() && self.password.is_some();
+        if method == SOCKS5_AUTH_NONE {
+            if self.require_auth {
+                return Err(Socks5AuthError::AuthNotRequested.into());
+            }
+            if has_credentials {
+                log::warn!("SOCKS5 proxy {}:{} selected no authentication, configured credentials were not used",
+                    self.proxy_host, self.proxy_port);
+            }
+            return Ok(());
+        }
+
+        if method != SOCKS5_AUTH_PASSWORD {
+            return Err(Socks5AuthError::UnsupportedMethod(method).into());
+        }
+
         if let (Some(username), Some(password)) = (&self.username, &self.password) {
             let mut auth_request = vec![0x01]; // Auth version
             auth_request.push(username.len() as u8);
@@ -96,7 +344,7 @@ impl Socks5Connector {
                 .context("Failed to read SOCKS5 authentication response")?;
 
             if auth_response[1] != 0x00 {
-                return Err(anyhow::anyhow!("SOCKS5 authentication failed"));
+                return Err(Socks5AuthError::Rejected.into());
             }
 
             log::debug!("SOCKS5 authentication successful");
@@ -110,66 +358,224 @@ impl Socks5Connector {
         stream: &mut TcpStream,
         target_host: &str,
         target_port: u16,
-    ) -> Result<()> {
+    ) -> Result<BoundAddress> {
         let mut request = vec![
             SOCKS5_VERSION,
             SOCKS5_CMD_CONNECT,
             0x00, // Reserved
         ];
+        request.extend_from_slice(&encode_address(target_host, target_port));
+
+        stream.write_all(&request).await
+            .context("Failed to send SOCKS5 connect request")?;
+
+        let bound = read_reply(stream).await?;
+
+        log::debug!("SOCKS5 CONNECT successful to {}:{}", target_host, target_port);
+        Ok(bound)
+    }
+}
 
-        if let Ok(ip) = target_host.parse::<IpAddr>() {
-            match ip {
-                IpAddr::V4(ipv4) => {
-                    request.push(SOCKS5_ATYP_IPV4);
-                    request.extend_from_slice(&ipv4.octets());
-                }
-                IpAddr::V6(ipv6) => {
-                    request.push(SOCKS5_ATYP_IPV6);
-                    request.extend_from_slice(&ipv6.octets());
-                }
+/// ATYP + address + port in SOCKS5 format
+fn encode_address(host: &str, port: u16) -> Vec<u8> {
+    let mut encoded = Vec::new();
+
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        match ip {
+            IpAddr::V4(ipv4) => {
+                encoded.push(SOCKS5_ATYP_IPV4);
+                encoded.extend_from_slice(&ipv4.octets());
+            }
+            IpAddr::V6(ipv6) => {
+                encoded.push(SOCKS5_ATYP_IPV6);
+                encoded.extend_from_slice(&ipv6.octets());
             }
-        } else {
-            request.push(SOCKS5_ATYP_DOMAIN);
-            request.push(target_host.len() as u8);
-            request.extend_from_slice(target_host.as_bytes());
         }
+    } else {
+        encoded.push(SOCKS5_ATYP_DOMAIN);
+        encoded.push(host.len() as u8);
+        encoded.extend_from_slice(host.as_bytes());
+    }
 
-        request.extend_from_slice(&target_port.to_be_bytes());
+    encoded.extend_from_slice(&port.to_be_bytes());
+    encoded
+}
 
-        stream.write_all(&request).await
-            .context("Failed to send SOCKS5 connect request")?;
+/// Reads a command reply together with the BND address
+async fn read_reply<S: AsyncRead + Unpin>(stream: &mut S) -> Result<BoundAddress> {
+    let mut response = [0u8; 4];
+    stream.read_exact(&mut response).await
+        .context("Failed to read SOCKS5 reply")?;
 
-        let mut response = [0u8; 4];
-        stream.read_exact(&mut response).await
-            .context("Failed to read SOCKS5 connect response")?;
+    if response[0] != SOCKS5_VERSION {
+        return Err(anyhow::anyhow!("Invalid SOCKS5 version in reply"));
+    }
 
-        if response[0] != SOCKS5_VERSION {
-            return Err(anyhow::anyhow!("Invalid SOCKS5 version in connect response"));
+    if response[1] != SOCKS5_REP_SUCCESS {
+        return Err(Socks5ReplyError::from_code(response[1]).into());
+    }
+
+    if response[2] != 0x00 {
+        return Err(anyhow::anyhow!("Invalid SOCKS5 reply: reserved byte is {:#04x}", response[2]));
+    }
+
+    let atyp = response[3];
+    let addr_len = match atyp {
+        SOCKS5_ATYP_IPV4 => 4,
+        SOCKS5_ATYP_IPV6 => 16,
+        SOCKS5_ATYP_DOMAIN => {
+            let mut len_buf = [0u8; 1];
+            stream.read_exact(&mut len_buf).await
+                .context("Failed to read SOCKS5 bind address length")?;
+            len_buf[0] as usize
+        }
+        _ => return Err(anyhow::anyhow!("Invalid address type: {}", atyp)),
+    };
+
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await
+        .context("Failed to read SOCKS5 bind address")?;
+
+    let port = u16::from_be_bytes([bound[addr_len], bound[addr_len + 1]]);
+    let ip = match atyp {
+        SOCKS5_ATYP_IPV4 => IpAddr::V4(Ipv4Addr::new(bound[0], bound[1], bound[2], bound[3])),
+        SOCKS5_ATYP_IPV6 => {
+            let octets: [u8; 16] = bound[..16].try_into()?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        // An empty name means the proxy did not report an address, same as 0.0.0.0
+        _ if addr_len == 0 => {
+            log::debug!("SOCKS5 reply has an empty domain bind address");
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        }
+        _ => {
+            let host = String::from_utf8(bound[..addr_len].to_vec())
+                .context("SOCKS5 bind address is not valid UTF-8")?;
+            return Ok(BoundAddress::Domain(host, port));
         }
+    };
+
+    Ok(BoundAddress::Ip(SocketAddr::new(ip, port)))
+}
+
+/// UDP datagram header for the relay: RSV(2) FRAG(1) ATYP ADDR PORT
+pub fn wrap_udp_datagram(target: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0x00, 0x00, 0x00];
+    datagram.extend_from_slice(&encode_address(&target.ip().to_string(), target.port()));
+    datagram.extend_from_slice(data);
+    datagram
+}
+
+/// Strips the header from a relay datagram. Fragments are not supported
+pub fn unwrap_udp_datagram(datagram: &[u8]) -> Result<(SocketAddr, &[u8])> {
+    if datagram.len() < 4 {
+        return Err(anyhow::anyhow!("SOCKS5 UDP datagram too short"));
+    }
+    if datagram[2] != 0x00 {
+        return Err(anyhow::anyhow!("Fragmented SOCKS5 UDP datagrams are not supported"));
+    }
 
-        if response[1] != SOCKS5_REP_SUCCESS {
-            return Err(anyhow::anyhow!("SOCKS5 connect failed with code: {}", response[1]));
+    let (ip, offset) = match datagram[3] {
+        SOCKS5_ATYP_IPV4 if datagram.len() >= 10 => {
+            (IpAddr::V4(Ipv4Addr::new(datagram[4], datagram[5], datagram[6], datagram[7])), 8)
+        }
+        SOCKS5_ATYP_IPV6 if datagram.len() >= 22 => {
+            let octets: [u8; 16] = datagram[4..20].try_into()?;
+            (IpAddr::V6(Ipv6Addr::from(octets)), 20)
         }
+        atyp => return Err(anyhow::anyhow!("Unsupported SOCKS5 UDP address type: {}", atyp)),
+    };
 
-        let atyp = response[3];
-        let skip_bytes = match atyp {
-            SOCKS5_ATYP_IPV4 => 4 + 2,
-            SOCKS5_ATYP_IPV6 => 16 + 2,
-            SOCKS5_ATYP_DOMAIN => {
-                let mut len_buf = [0u8; 1];
-                stream.read_exact(&mut len_buf).await?;
-                len_buf[0] as usize + 2
-            }
-            _ => return Err(anyhow::anyhow!("Invalid address type: {}", atyp)),
-        };
+    let port = u16::from_be_bytes([datagram[offset], datagram[offset + 1]]);
+    Ok((SocketAddr::new(ip, port), &datagram[offset + 2..]))
+}
 
-        let mut skip_buffer = vec![0u8; skip_bytes];
-        stream.read_exact(&mut skip_buffer).await
-            .context("Failed to read SOCKS5 bind address")?;
+pub struct Socks4Connector {
+    proxy_host: String,
+    proxy_port: u16,
+    user_id: Option<String>,
+    connect_timeout: Option<Duration>,
+}
 
-        log::debug!("SOCKS5 CONNECT successful to {}:{}", target_host, target_port);
-        Ok(())
+impl ConnectTimeout for Socks4Connector {
+    fn connect_timeout_mut(&mut self) -> &mut Option<Duration> {
+        &mut self.connect_timeout
+    }
+}
+
+impl Socks4Connector {
+    pub fn new(proxy_host: String, proxy_port: u16, user_id: Option<String>) -> Self {
+        Self {
+            proxy_host,
+            proxy_port,
+            user_id,
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+        }
+    }
+
+    pub async fn connect(&self, target_host: &str, target_port: u16) -> Result<TcpStream> {
+        let proxy_addr = format!("{}:{}", self.proxy_host, self.proxy_port);
+        connect_within(
+            self.connect_timeout,
+            "SOCKS4",
+            &proxy_addr,
+            self.establish(&proxy_addr, target_host, target_port),
+        ).await
+    }
+
+    async fn establish(&self, proxy_addr: &str, target_host: &str, target_port: u16) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(proxy_addr).await
+            .context("Failed to connect to SOCKS4 proxy")?;
+
+        log::debug!("Connected to SOCKS4 proxy at {}", proxy_addr);
+
+        let request = build_socks4_request(target_host, target_port, self.user_id.as_deref())?;
+        stream.write_all(&request).await
+            .context("Failed to send SOCKS4 CONNECT request")?;
+
+        let mut reply = [0u8; 8];
+        stream.read_exact(&mut reply).await
+            .context("Failed to read SOCKS4 reply")?;
+
+        if reply[0] != 0x00 {
+            return Err(anyhow::anyhow!("Invalid SOCKS4 reply version: {}", reply[0]));
+        }
+        if reply[1] != SOCKS4_REP_GRANTED {
+            return Err(anyhow::anyhow!("SOCKS4 CONNECT rejected: 0x{:02x}", reply[1]));
+        }
+
+        log::info!("✓ SOCKS4 connection established to {}:{} via {}",
+            target_host, target_port, proxy_addr);
+
+        Ok(stream)
+    }
+}
+
+/// SOCKS4 CONNECT request; a non-IPv4 target uses the 4a form with a host name
+fn build_socks4_request(host: &str, port: u16, user_id: Option<&str>) -> Result<Vec<u8>> {
+    let mut request = vec![SOCKS4_VERSION, SOCKS4_CMD_CONNECT];
+    request.extend_from_slice(&port.to_be_bytes());
+
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ipv4)) => {
+            request.extend_from_slice(&ipv4.octets());
+            request.extend_from_slice(user_id.unwrap_or("").as_bytes());
+            request.push(0);
+        }
+        Ok(IpAddr::V6(_)) => {
+            return Err(anyhow::anyhow!("SOCKS4 does not support IPv6 target {}", host));
+        }
+        Err(_) => {
+            // 0.0.0.x with non-zero x tells the proxy a host name follows the user id
+            request.extend_from_slice(&[0, 0, 0, 1]);
+            request.extend_from_slice(user_id.unwrap_or("").as_bytes());
+            request.push(0);
+            request.extend_from_slice(host.as_bytes());
+            request.push(0);
+        }
     }
+
+    Ok(request)
 }
 
 pub struct HttpsProxyConnector {
@@ -177,6 +583,13 @@ pub struct HttpsProxyConnector {
     proxy_port: u16,
     username: Option<String>,
     password: Option<String>,
+    connect_timeout: Option<Duration>,
+}
+
+impl ConnectTimeout for HttpsProxyConnector {
+    fn connect_timeout_mut(&mut self) -> &mut Option<Duration> {
+        &mut self.connect_timeout
+    }
 }
 
 impl HttpsProxyConnector {
@@ -191,19 +604,30 @@ impl HttpsProxyConnector {
             proxy_port,
             username,
             password,
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
         }
     }
 
     pub async fn connect(&self, target_host: &str, target_port: u16) -> Result<TcpStream> {
         let proxy_addr = format!("{}:{}", self.proxy_host, self.proxy_port);
-        let mut stream = TcpStream::connect(&proxy_addr).await
+        connect_within(
+            self.connect_timeout,
+            "HTTPS",
+            &proxy_addr,
+            self.establish(&proxy_addr, target_host, target_port),
+        ).await
+    }
+
+    async fn establish(&self, proxy_addr: &str, target_host: &str, target_port: u16) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(proxy_addr).await
             .context("Failed to connect to HTTPS proxy")?;
 
         log::debug!("Connected to HTTPS proxy at {}", proxy_addr);
 
+        let authority = connect_authority(target_host, target_port);
         let mut connect_request = format!(
-            "CONNECT {}:{} HTTP/1.1\r\nHost: {}:{}\r\n",
-            target_host, target_port, target_host, target_port
+            "CONNECT {} HTTP/1.1\r\nHost: {}\r\n",
+            authority, authority
         );
 
         if let (Some(username), Some(password)) = (&self.username, &self.password) {
@@ -250,6 +674,31 @@ impl HttpsProxyConnector {
     }
 }
 
+async fn connect_within<T, F>(
+    limit: Option<Duration>,
+    kind: &str,
+    proxy_addr: &str,
+    connect: F,
+) -> Result<T>
+where
+    F: std::future::Future<Output = Result<T>>,
+{
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, connect).await
+            .map_err(|_| anyhow::anyhow!("{} proxy {} connect timed out after {:?}", kind, proxy_addr, limit))?,
+        None => connect.await,
+    }
+}
+
+/// Authority for CONNECT and Host: an IPv6 literal is bracketed
+fn connect_authority(host: &str, port: u16) -> String {
+    if host.parse::<Ipv6Addr>().is_ok() {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -266,6 +715,187 @@ mod tests {
         assert_eq!(connector.proxy_port, 1080);
     }
 
+    #[test]
+    fn test_udp_datagram_header() {
+        let target: SocketAddr = "93.184.216.34:443".parse().unwrap();
+        let wrapped = wrap_udp_datagram(target, b"quic");
+        assert_eq!(&wrapped[..10], &[0, 0, 0, SOCKS5_ATYP_IPV4, 93, 184, 216, 34, 0x01, 0xbb]);
+
+        let (from, payload) = unwrap_udp_datagram(&wrapped).unwrap();
+        assert_eq!(from, target);
+        assert_eq!(payload, b"quic");
+
+        let v6: SocketAddr = "[2001:db8::1]:3478".parse().unwrap();
+        let wrapped_v6 = wrap_udp_datagram(v6, b"stun");
+        let (from, payload) = unwrap_udp_datagram(&wrapped_v6).unwrap();
+        assert_eq!((from, payload), (v6, &b"stun"[..]));
+
+        let mut fragmented = wrapped.clone();
+        fragmented[2] = 1;
+        assert!(unwrap_udp_datagram(&fragmented).is_err());
+    }
+
+    #[test]
+    fn test_socks4_request_encoding() {
+        let request = build_socks4_request("93.184.216.34", 443, Some("bob")).unwrap();
+        assert_eq!(request, vec![0x04, 0x01, 0x01, 0xbb, 93, 184, 216, 34, b'b', b'o', b'b', 0]);
+
+        let request = build_socks4_request("example.com", 80, None).unwrap();
+        let mut expected = vec![0x04, 0x01, 0x00, 0x50, 0, 0, 0, 1, 0];
+        expected.extend_from_slice(b"example.com\0");
+        assert_eq!(request, expected);
+
+        assert!(build_socks4_request("2001:db8::1", 443, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reply_bound_address() {
+        let mut ipv4: &[u8] = &[0x05, 0x00, 0x00, SOCKS5_ATYP_IPV4, 10, 0, 0, 1, 0x1f, 0x90];
+        assert_eq!(read_reply(&mut ipv4).await.unwrap(),
+            BoundAddress::Ip("10.0.0.1:8080".parse().unwrap()));
+
+        let mut reply = vec![0x05, 0x00, 0x00, SOCKS5_ATYP_IPV6];
+        reply.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        reply.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(read_reply(&mut reply.as_slice()).await.unwrap(),
+            BoundAddress::Ip("[2001:db8::1]:443".parse().unwrap()));
+
+        let mut reply = vec![0x05, 0x00, 0x00, SOCKS5_ATYP_DOMAIN, 9];
+        reply.extend_from_slice(b"relay.lan");
+        reply.extend_from_slice(&1080u16.to_be_bytes());
+        let bound = read_reply(&mut reply.as_slice()).await.unwrap();
+        assert_eq!(bound, BoundAddress::Domain("relay.lan".to_string(), 1080));
+        assert_eq!(bound.to_string(), "relay.lan:1080");
+
+        let mut failed: &[u8] = &[0x05, 0x05, 0x00, SOCKS5_ATYP_IPV4, 0, 0, 0, 0, 0, 0];
+        assert!(read_reply(&mut failed).await.is_err());
+
+        let mut reserved: &[u8] = &[0x05, 0x00, 0x01, SOCKS5_ATYP_IPV4, 0, 0, 0, 0, 0, 0];
+        assert!(read_reply(&mut reserved).await.unwrap_err().to_string().contains("reserved"));
+
+        let mut empty_domain: &[u8] = &[0x05, 0x00, 0x00, SOCKS5_ATYP_DOMAIN, 0, 0x04, 0x38];
+        assert_eq!(read_reply(&mut empty_domain).await.unwrap(),
+            BoundAddress::Ip("0.0.0.0:1080".parse().unwrap()));
+
+        let mut truncated: &[u8] = &[0x05, 0x00, 0x00, SOCKS5_ATYP_DOMAIN, 9, b'r', b'e'];
+        assert!(read_reply(&mut truncated).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_reply() {
+        let mut reply = vec![0x05, 0x00, 0x00, SOCKS5_ATYP_DOMAIN, 9];
+        reply.extend_from_slice(b"relay.lan");
+        reply.extend_from_slice(&1080u16.to_be_bytes());
+
+        // The reply arrives byte by byte, including splits at ATYP and the name length
+        let (mut reader, mut writer) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            for byte in reply {
+                writer.write_all(&[byte]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+        });
+
+        assert_eq!(read_reply(&mut reader).await.unwrap(),
+            BoundAddress::Domain("relay.lan".to_string(), 1080));
+    }
+
+    #[tokio::test]
+    async fn test_reply_error_codes() {
+        let expected = [
+            (0x01, Socks5ReplyError::GeneralFailure, "general SOCKS server failure", true),
+            (0x02, Socks5ReplyError::NotAllowed, "not allowed", false),
+            (0x03, Socks5ReplyError::NetworkUnreachable, "network unreachable", false),
+            (0x04, Socks5ReplyError::HostUnreachable, "host unreachable", false),
+            (0x05, Socks5ReplyError::ConnectionRefused, "connection refused", false),
+            (0x06, Socks5ReplyError::TtlExpired, "TTL expired", true),
+            (0x07, Socks5ReplyError::CommandNotSupported, "command not supported", false),
+            (0x08, Socks5ReplyError::AddressTypeNotSupported, "address type not supported", false),
+            (0x2A, Socks5ReplyError::Unknown(0x2A), "unknown error", false),
+        ];
+
+        for (code, variant, reason, retryable) in expected {
+            let mut reply: &[u8] = &[0x05, code, 0x00, SOCKS5_ATYP_IPV4, 0, 0, 0, 0, 0, 0];
+            let err = read_reply(&mut reply).await.unwrap_err();
+            assert_eq!(err.downcast_ref::<Socks5ReplyError>(), Some(&variant));
+            assert_eq!(variant.code(), code);
+            assert!(err.to_string().contains(reason), "{}", err);
+            assert_eq!(is_retryable_connect_error(&err), retryable, "code {:#04x}", code);
+        }
+
+        // A proxy connection failure is not classified and is retried
+        assert!(is_retryable_connect_error(&anyhow::anyhow!("Failed to connect to SOCKS5 proxy")));
+    }
+
+    #[test]
+    fn test_connect_authority_ipv6() {
+        assert_eq!(connect_authority("::1", 443), "[::1]:443");
+        assert_eq!(connect_authority("93.184.216.34", 443), "93.184.216.34:443");
+        assert_eq!(connect_authority("example.com", 8443), "example.com:8443");
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout_on_silent_proxy() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Accepts the connection but never answers the handshake
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let connector = Socks5Connector::new("127.0.0.1".to_string(), port, None, None)
+            .with_connect_timeout(Some(Duration::from_millis(100)));
+        let started = std::time::Instant::now();
+        let err = connector.connect("example.com", 443).await.unwrap_err();
+
+        assert!(err.to_string().contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    /// Fake SOCKS5 proxy: picks `method` on handshake, then closes the connection
+    async fn proxy_selecting(method: u8) -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 4];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [SOCKS5_VERSION, 2, SOCKS5_AUTH_NONE, SOCKS5_AUTH_PASSWORD]);
+            stream.write_all(&[SOCKS5_VERSION, method]).await.unwrap();
+            let _ = stream.read(&mut [0u8; 64]).await;
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_require_auth_rejects_no_auth_proxy() {
+        let credentials = || (Some("user".to_string()), Some("pass".to_string()));
+
+        let port = proxy_selecting(SOCKS5_AUTH_NONE).await;
+        let (username, password) = credentials();
+        let connector = Socks5Connector::new("127.0.0.1".to_string(), port, username, password)
+            .with_require_auth(true);
+        let err = connector.connect("example.com", 443).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Socks5AuthError>(), Some(&Socks5AuthError::AuthNotRequested));
+        assert!(!is_retryable_connect_error(&err));
+
+        // An unsupported method is final too
+        let port = proxy_selecting(0x03).await;
+        let (username, password) = credentials();
+        let connector = Socks5Connector::new("127.0.0.1".to_string(), port, username, password);
+        let err = connector.connect("example.com", 443).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Socks5AuthError>(), Some(&Socks5AuthError::UnsupportedMethod(0x03)));
+        assert!(!is_retryable_connect_error(&err));
+
+        // Without require_auth authentication is skipped and it gets to CONNECT
+        let port = proxy_selecting(SOCKS5_AUTH_NONE).await;
+        let (username, password) = credentials();
+        let connector = Socks5Connector::new("127.0.0.1".to_string(), port, username, password);
+        let err = connector.connect("example.com", 443).await.unwrap_err();
+        assert!(!err.to_string().contains("require_auth"));
+    }
+
     #[test]
     fn test_https_connector_creation() {
         let connector = HttpsProxyConnector::new(
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 use parking_lot::RwLock;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use cookie::Cookie;
 
 #[derive(Debug, Clone)]
@@ -198,6 +198,11 @@ pub struct ConnectionInfo {
     pub last_activity: u64,
     pub bytes_sent: u64,
     pub bytes_received: u64,
+    pub handshake_rtt: Option<Duration>,
+    pub tcp_rtt: Option<Duration>,
+    pub profile: Option<String>,
+    pub upstream_path: Option<String>,
+    pub upstream_endpoint: Option<String>,
 }
 
 impl ConnectionInfo {
@@ -213,9 +218,37 @@ impl ConnectionInfo {
             last_activity: now,
             bytes_sent: 0,
             bytes_received: 0,
+            handshake_rtt: None,
+            tcp_rtt: None,
+            profile: None,
+            upstream_path: None,
+            upstream_endpoint: None,
         }
     }
 
+    /// One-line connection summary for the access log
+    pub fn summary(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let rtt = self.handshake_rtt
+            .map_or_else(|| "-".to_string(), |rtt| format!("{}ms", rtt.as_millis()));
+
+        format!(
+            "conn={} profile={} upstream={} endpoint={} sent={} received={} rtt={} duration={}s",
+            self.id,
+            self.profile.as_deref().unwrap_or("-"),
+            self.upstream_path.as_deref().unwrap_or("-"),
+            self.upstream_endpoint.as_deref().unwrap_or("-"),
+            self.bytes_sent,
+            self.bytes_received,
+            rtt,
+            now.saturating_sub(self.created_at),
+        )
+    }
+
     pub fn update_activity(&mut self) {
         self.last_activity = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -253,6 +286,26 @@ impl ConnectionStateManager {
         }
     }
 
+    pub fn set_rtt(&self, id: u64, handshake_rtt: Duration, tcp_rtt: Option<Duration>) {
+        if let Some(info) = self.connections.write().get_mut(&id) {
+            info.handshake_rtt = Some(handshake_rtt);
+            info.tcp_rtt = tcp_rtt;
+        }
+    }
+
+    pub fn set_profile(&self, id: u64, profile: &str) {
+        if let Some(info) = self.connections.write().get_mut(&id) {
+            info.profile = Some(profile.to_string());
+        }
+    }
+
+    pub fn set_upstream(&self, id: u64, path: &str, endpoint: &str) {
+        if let Some(info) = self.connections.write().get_mut(&id) {
+            info.upstream_path = Some(path.to_string());
+            info.upstream_endpoint = Some(endpoint.to_string());
+        }
+    }
+
     pub fn get_connection(&self, id: u64) -> Option<ConnectionInfo> {
         self.connections.read().get(&id).cloned()
     }
@@ -320,6 +373,11 @@ mod tests {
         assert_ne!(id1, id2);
         assert_eq!(manager.get_active_count(), 2);
         
+        manager.set_rtt(id2, Duration::from_millis(20), Some(Duration::from_millis(18)));
+        let info = manager.get_connection(id2).unwrap();
+        assert_eq!(info.handshake_rtt, Some(Duration::from_millis(20)));
+        assert_eq!(info.tcp_rtt, Some(Duration::from_millis(18)));
+        
         manager.remove_connection(id1);
         assert_eq!(manager.get_active_count(), 1);
     }
@@ -1,10 +1,18 @@
 // src/tcp.rs
 use std::net::Ipv4Addr;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use pnet::packet::tcp::TcpPacket;
 use pnet::packet::ipv4::Ipv4Packet;
 use pnet::packet::Packet;
 
+const TLS_HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const TLS_RECORD_HEADER_LEN: usize = 5;
+/// Maximum TLS record (2^14) with its header; a ClientHello is never larger
+const MAX_HELLO_RECORD_LEN: usize = TLS_RECORD_HEADER_LEN + 16384;
+/// How long to wait for missing ClientHello segments before freeing the buffer
+const HELLO_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub struct ConnectionId {
     pub src_ip: Ipv4Addr,
@@ -110,6 +118,19 @@ impl IpParametersExact {
     }
 }
 
+/// Result of adding a segment to the ClientHello being reassembled
+#[derive(Debug, PartialEq)]
+pub enum HelloReassembly {
+    /// The stream doesn't start with a TLS handshake or reassembly is already done
+    NotHello,
+    /// The record isn't complete yet, the segment is buffered
+    Incomplete,
+    /// A complete TLS record with the ClientHello (and possibly bytes after it)
+    Complete(Vec<u8>),
+    /// Reassembly abandoned (sequence gap, overflow, timeout), buffer freed
+    Abandoned,
+}
+
 #[derive(Debug)]
 pub struct ConnectionState {
     pub id: ConnectionId,
@@ -119,6 +140,11 @@ pub struct ConnectionState {
     pub client_ip: IpParametersExact,
     pub tls_modified: bool,
     pub created_at: Instant,
+    hello_buffer: Vec<u8>,
+    /// Sequence of the next expected ClientHello byte
+    hello_next_seq: Option<u32>,
+    hello_started: Option<Instant>,
+    hello_done: bool,
 }
 
 impl ConnectionState {
@@ -131,6 +157,158 @@ impl ConnectionState {
             client_ip: IpParametersExact::from_packet(ip),
             tls_modified: false,
             created_at: Instant::now(),
+            hello_buffer: Vec::new(),
+            hello_next_seq: None,
+            hello_started: None,
+            hello_done: false,
+        }
+    }
+
+    /// Adds a client segment's payload to a ClientHello split across
+    /// several TCP segments. Repeats of already accepted segments are ignored;
+    /// a sequence gap, record overflow and timeout abandon reassembly
+    pub fn push_client_payload(&mut self, seq: u32, payload: &[u8]) -> HelloReassembly {
+        if self.hello_done || self.tls_modified {
+            return HelloReassembly::NotHello;
         }
+        if payload.is_empty() {
+            return HelloReassembly::Incomplete;
+        }
+        if self.expire_hello(Instant::now()) {
+            return HelloReassembly::Abandoned;
+        }
+
+        let next_seq = match self.hello_next_seq {
+            None => {
+                if payload[0] != TLS_HANDSHAKE {
+                    self.hello_done = true;
+                    return HelloReassembly::NotHello;
+                }
+                self.hello_started = Some(Instant::now());
+                seq
+            }
+            Some(next_seq) => next_seq,
+        };
+
+        // Signed difference accounts for sequence wraparound
+        let offset = seq.wrapping_sub(next_seq) as i32;
+        if offset > 0 {
+            return self.abandon_hello();
+        }
+        let skip = offset.unsigned_abs() as usize;
+        if skip >= payload.len() {
+            return HelloReassembly::Incomplete;
+        }
+
+        self.hello_buffer.extend_from_slice(&payload[skip..]);
+        self.hello_next_seq = Some(next_seq.wrapping_add((payload.len() - skip) as u32));
+
+        if self.hello_buffer.len() < TLS_RECORD_HEADER_LEN {
+            return HelloReassembly::Incomplete;
+        }
+        // A handshake record with another message is not rewritten
+        if self.hello_buffer.get(TLS_RECORD_HEADER_LEN).is_some_and(|&msg_type| msg_type != CLIENT_HELLO) {
+            self.hello_buffer = Vec::new();
+            self.hello_started = None;
+            self.hello_done = true;
+            return HelloReassembly::NotHello;
+        }
+        let record_len = TLS_RECORD_HEADER_LEN
+            + u16::from_be_bytes([self.hello_buffer[3], self.hello_buffer[4]]) as usize;
+        if record_len > MAX_HELLO_RECORD_LEN {
+            return self.abandon_hello();
+        }
+        if self.hello_buffer.len() < record_len {
+            return HelloReassembly::Incomplete;
+        }
+
+        self.hello_done = true;
+        self.hello_started = None;
+        HelloReassembly::Complete(std::mem::take(&mut self.hello_buffer))
+    }
+
+    /// Frees a ClientHello buffer that didn't complete in the allotted time;
+    /// the connection table owner also calls this during periodic cleanup
+    pub fn expire_hello(&mut self, now: Instant) -> bool {
+        let stale = self.hello_started
+            .is_some_and(|started| now.duration_since(started) >= HELLO_REASSEMBLY_TIMEOUT);
+        if stale {
+            self.abandon_hello();
+        }
+        stale
+    }
+
+    pub fn buffered_hello_len(&self) -> usize {
+        self.hello_buffer.len()
+    }
+
+    fn abandon_hello(&mut self) -> HelloReassembly {
+        log::debug!("Abandoning ClientHello reassembly for {:?} ({} bytes buffered)",
+            self.id, self.hello_buffer.len());
+        self.hello_buffer = Vec::new();
+        self.hello_started = None;
+        self.hello_done = true;
+        HelloReassembly::Abandoned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection_state() -> ConnectionState {
+        let (ip, tcp) = ([0x45u8; 20], [0x50u8; 20]);
+        let ip = Ipv4Packet::new(&ip).unwrap();
+        let tcp = TcpPacket::new(&tcp).unwrap();
+        ConnectionState::new(ConnectionId::from_packets(&ip, &tcp), &ip, &tcp)
+    }
+
+    #[test]
+    fn test_client_hello_reassembled_across_segments() {
+        let mut record = vec![TLS_HANDSHAKE, 0x03, 0x01, 0x00, 0x08];
+        record.extend_from_slice(&[0x01, 0, 0, 4, 0x03, 0x03, 0xAA, 0xBB]);
+
+        let mut state = connection_state();
+        let seq = u32::MAX - 2;
+        assert_eq!(state.push_client_payload(seq, &record[..3]), HelloReassembly::Incomplete);
+        // A repeat of the first segment doesn't duplicate bytes
+        assert_eq!(state.push_client_payload(seq, &record[..3]), HelloReassembly::Incomplete);
+        assert_eq!(state.push_client_payload(seq.wrapping_add(3), &record[3..9]), HelloReassembly::Incomplete);
+        assert_eq!(state.buffered_hello_len(), 9);
+        assert_eq!(state.push_client_payload(seq.wrapping_add(9), &record[9..]),
+            HelloReassembly::Complete(record.clone()));
+        assert_eq!(state.push_client_payload(seq.wrapping_add(13), b"next"), HelloReassembly::NotHello);
+
+        let mut plain = connection_state();
+        assert_eq!(plain.push_client_payload(1, b"GET / HTTP/1.1"), HelloReassembly::NotHello);
+
+        // A handshake record, but a ServerHello rather than a ClientHello
+        let mut other = connection_state();
+        assert_eq!(other.push_client_payload(1, &[TLS_HANDSHAKE, 0x03, 0x03, 0x00, 0x08]), HelloReassembly::Incomplete);
+        assert_eq!(other.push_client_payload(6, &[0x02, 0, 0, 4]), HelloReassembly::NotHello);
+        assert_eq!(other.buffered_hello_len(), 0);
+    }
+
+    #[test]
+    fn test_incomplete_client_hello_released() {
+        // Sequence gap
+        let mut state = connection_state();
+        state.push_client_payload(100, &[TLS_HANDSHAKE, 0x03, 0x01, 0x02, 0x00]);
+        assert_eq!(state.push_client_payload(200, &[0u8; 10]), HelloReassembly::Abandoned);
+        assert_eq!(state.buffered_hello_len(), 0);
+
+        // Record longer than allowed
+        let mut state = connection_state();
+        assert_eq!(state.push_client_payload(1, &[TLS_HANDSHAKE, 0x03, 0x01, 0xFF, 0xFF]),
+            HelloReassembly::Abandoned);
+
+        // The rest never arrived
+        let mut state = connection_state();
+        state.push_client_payload(1, &[TLS_HANDSHAKE, 0x03, 0x01, 0x02, 0x00, 0x01]);
+        assert_eq!(state.buffered_hello_len(), 6);
+        assert!(!state.expire_hello(Instant::now()));
+        assert!(state.expire_hello(Instant::now() + HELLO_REASSEMBLY_TIMEOUT));
+        assert_eq!(state.buffered_hello_len(), 0);
+        assert_eq!(state.push_client_payload(7, &[0u8; 4]), HelloReassembly::NotHello);
     }
 }
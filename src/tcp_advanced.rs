@@ -2,8 +2,10 @@ use std::collections::{BTreeMap, VecDeque};
 use std::time::{Duration, Instant};
 use std::os::unix::io::AsRawFd;
 use std::os::fd::AsFd;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use anyhow::Result;
-use nix::sys::socket::{setsockopt, sockopt};
+use nix::sys::socket::{getsockname, setsockopt, sockopt, AddressFamily, SockaddrLike, SockaddrStorage};
 
 const MAX_WINDOW_SIZE: u32 = 1048576;
 const MIN_WINDOW_SIZE: u32 = 8192;
@@ -113,6 +115,57 @@ impl TcpWindowManager {
     pub fn get_advertised_window(&self) -> u32 {
         self.advertised_window
     }
+
+    /// Apply the current window to the socket as SO_RCVBUF and SO_SNDBUF.
+    /// The advertised window is the scaled on-wire value, so the buffers take
+    /// the unscaled byte count; the kernel doubles it and clamps to rmem_max/wmem_max.
+    /// Setting a buffer turns off the kernel's autotuning for it, so a buffer
+    /// that is already at least this large is left alone. Returns the window
+    /// if any buffer was raised
+    pub fn apply_to_socket<F: AsRawFd>(&self, socket: &F) -> Result<Option<u32>> {
+        let fd = socket.as_raw_fd();
+        let window = self.current_window as libc::c_int;
+        let mut raised = false;
+
+        for (name, option) in [("SO_RCVBUF", libc::SO_RCVBUF), ("SO_SNDBUF", libc::SO_SNDBUF)] {
+            let mut current: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let ret = unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    option,
+                    &mut current as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if ret < 0 {
+                return Err(anyhow::anyhow!("Failed to read {}: {}", name,
+                    std::io::Error::last_os_error()));
+            }
+            // getsockopt reports the doubled size
+            if current >= window.saturating_mul(2) {
+                continue;
+            }
+
+            let ret = unsafe {
+                libc::setsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    option,
+                    &window as *const _ as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            if ret < 0 {
+                return Err(anyhow::anyhow!("Failed to set {}: {}", name,
+                    std::io::Error::last_os_error()));
+            }
+            raised = true;
+        }
+
+        Ok(raised.then_some(self.current_window))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -143,10 +196,54 @@ impl TcpSegment {
     }
 }
 
+/// Byte budget shared by the buffers of all connections, so that many
+/// slow peers together cannot hold more than `limit` bytes.
+pub struct BufferBudget {
+    used: AtomicUsize,
+    limit: usize,
+}
+
+impl BufferBudget {
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            used: AtomicUsize::new(0),
+            limit,
+        })
+    }
+
+    /// Reserves `bytes`, or returns false if that would exceed the limit
+    pub fn try_reserve(&self, bytes: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|&total| total <= self.limit)
+            })
+            .is_ok()
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::AcqRel);
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+fn release_budget(budget: &Option<Arc<BufferBudget>>, bytes: usize) {
+    if let Some(budget) = budget {
+        budget.release(bytes);
+    }
+}
+
 pub struct OutOfOrderBuffer {
     segments: BTreeMap<u32, Vec<u8>>,
     expected_seq: u32,
     max_size: usize,
+    budget: Option<Arc<BufferBudget>>,
 }
 
 impl OutOfOrderBuffer {
@@ -155,9 +252,16 @@ impl OutOfOrderBuffer {
             segments: BTreeMap::new(),
             expected_seq: initial_seq,
             max_size,
+            budget: None,
         }
     }
 
+    /// Account buffered bytes against a budget shared with other connections
+    pub fn with_budget(mut self, budget: Arc<BufferBudget>) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
     pub fn insert(&mut self, seq: u32, data: Vec<u8>) -> bool {
         if self.segments.len() >= self.max_size {
             return false;
@@ -167,7 +271,15 @@ impl OutOfOrderBuffer {
             return false;
         }
 
-        self.segments.insert(seq, data);
+        if let Some(budget) = &self.budget {
+            if !budget.try_reserve(data.len()) {
+                return false;
+            }
+        }
+
+        if let Some(old) = self.segments.insert(seq, data) {
+            release_budget(&self.budget, old.len());
+        }
         true
     }
 
@@ -183,6 +295,7 @@ impl OutOfOrderBuffer {
                 break;
             }
         }
+        release_budget(&self.budget, result.len());
 
         if result.is_empty() {
             None
@@ -201,9 +314,17 @@ impl OutOfOrderBuffer {
     }
 }
 
+impl Drop for OutOfOrderBuffer {
+    fn drop(&mut self) {
+        let buffered: usize = self.segments.values().map(Vec::len).sum();
+        release_budget(&self.budget, buffered);
+    }
+}
+
 pub struct RetransmissionQueue {
     segments: VecDeque<TcpSegment>,
     timeout: Duration,
+    budget: Option<Arc<BufferBudget>>,
 }
 
 impl RetransmissionQueue {
@@ -211,19 +332,40 @@ impl RetransmissionQueue {
         Self {
             segments: VecDeque::new(),
             timeout: Duration::from_millis(RETRANSMIT_TIMEOUT_MS),
+            budget: None,
         }
     }
 
-    pub fn add(&mut self, seq: u32, data: Vec<u8>) {
+    /// Account queued bytes against a budget shared with other connections
+    pub fn with_budget(mut self, budget: Arc<BufferBudget>) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Returns false if the shared budget is exhausted and the segment was not queued
+    pub fn add(&mut self, seq: u32, data: Vec<u8>) -> bool {
+        if let Some(budget) = &self.budget {
+            if !budget.try_reserve(data.len()) {
+                return false;
+            }
+        }
+
         let segment = TcpSegment::new(seq, data);
         self.segments.push_back(segment);
+        true
     }
 
     pub fn acknowledge(&mut self, ack_seq: u32) {
+        let mut released = 0;
         self.segments.retain(|seg| {
             let end_seq = seg.seq.wrapping_add(seg.data.len() as u32);
-            ack_seq < end_seq
+            let keep = ack_seq < end_seq;
+            if !keep {
+                released += seg.data.len();
+            }
+            keep
         });
+        release_budget(&self.budget, released);
     }
 
     pub fn get_retransmits(&mut self) -> Vec<TcpSegment> {
@@ -253,6 +395,13 @@ impl RetransmissionQueue {
     }
 }
 
+impl Drop for RetransmissionQueue {
+    fn drop(&mut self) {
+        let queued: usize = self.segments.iter().map(|seg| seg.data.len()).sum();
+        release_budget(&self.budget, queued);
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SackBlock {
     pub left_edge: u32,
@@ -330,17 +479,84 @@ impl SackManager {
     }
 }
 
-/// Configure basic TCP socket options
-pub fn configure_tcp_socket<F: AsRawFd + AsFd>(socket: &F) -> Result<()> {
-    setsockopt(socket, sockopt::TcpNoDelay, &true)?;
+/// Configure basic TCP socket options. `nodelay` disables Nagle: right for
+/// interactive and fingerprinted flows, while bulk transfers may prefer fewer,
+/// fuller segments
+pub fn configure_tcp_socket<F: AsRawFd + AsFd>(socket: &F, nodelay: bool) -> Result<()> {
+    setsockopt(socket, sockopt::TcpNoDelay, &nodelay)?;
     setsockopt(socket, sockopt::ReuseAddr, &true)?;
     setsockopt(socket, sockopt::KeepAlive, &true)?;
     
     Ok(())
 }
 
-/// Apply iOS Safari TCP fingerprint to socket
-pub fn apply_tcp_options<F: AsRawFd + AsFd>(socket: &F, is_client: bool) -> Result<()> {
+/// Enable SO_KEEPALIVE with explicit TCP_KEEPIDLE, TCP_KEEPINTVL and TCP_KEEPCNT
+/// so idle tunnels keep their NAT mappings alive. Values are in whole seconds,
+/// at least 1
+pub fn configure_keepalive<F: AsFd>(socket: &F, idle: Duration, interval: Duration, count: u32) -> Result<()> {
+    setsockopt(socket, sockopt::KeepAlive, &true)?;
+    setsockopt(socket, sockopt::TcpKeepIdle, &(idle.as_secs().max(1) as u32))?;
+    setsockopt(socket, sockopt::TcpKeepInterval, &(interval.as_secs().max(1) as u32))?;
+    setsockopt(socket, sockopt::TcpKeepCount, &count.max(1))?;
+
+    Ok(())
+}
+
+/// Set SO_LINGER to zero so that closing the socket sends RST instead of FIN
+pub fn set_linger_zero<F: AsFd>(socket: &F) -> Result<()> {
+    let linger = libc::linger { l_onoff: 1, l_linger: 0 };
+    setsockopt(socket, sockopt::Linger, &linger)?;
+
+    Ok(())
+}
+
+/// Enable TCP_FASTOPEN_CONNECT on a socket that is about to connect.
+/// Linux only (4.11+): connect() returns without a handshake when a TFO cookie
+/// for the peer is cached, and the first write goes out in the SYN. Without a
+/// cookie, or when the peer ignores SYN data, the kernel falls back to a normal
+/// handshake on its own
+#[cfg(target_os = "linux")]
+pub fn enable_fastopen_connect<F: AsRawFd>(socket: &F) -> Result<()> {
+    let fd = socket.as_raw_fd();
+
+    unsafe {
+        let enable = 1 as libc::c_int;
+        let ret = libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+            &enable as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+
+        if ret < 0 {
+            return Err(anyhow::anyhow!("Failed to enable TCP_FASTOPEN_CONNECT: {}",
+                std::io::Error::last_os_error()));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_fastopen_connect<F: AsRawFd>(_socket: &F) -> Result<()> {
+    Err(anyhow::anyhow!("TCP Fast Open is only supported on Linux"))
+}
+
+/// Apply iOS Safari TCP fingerprint to socket. `is_client` is true for the
+/// accepted client-side socket and false for the upstream one.
+///
+/// setsockopt calls, in order:
+/// - `IP_TTL` = 64 (best effort)
+/// - `TCP_MAXSEG` = 1460, the MSS hint (best effort)
+/// - client side only: `SO_RCVBUF` = 65535, the iOS initial window; the kernel
+///   derives the window scale from the receive buffer, so this also carries the
+///   window scale intent (best effort)
+/// - client side only, Linux: `TCP_QUICKACK` = 1 (best effort)
+/// - `TCP_TIMESTAMP` (27) = 1 (best effort, absent on most kernels)
+/// - Linux: `TCP_CONGESTION` = `congestion`, e.g. "cubic" or "bbr" (best effort)
+/// - `TCP_KEEPIDLE` = 120s; this is the only call whose failure is returned
+pub fn apply_tcp_options<F: AsRawFd + AsFd>(socket: &F, is_client: bool, congestion: &str) -> Result<()> {
     let fd = socket.as_raw_fd();
     
     // Set TTL to iOS default (64)
@@ -388,6 +604,22 @@ pub fn apply_tcp_options<F: AsRawFd + AsFd>(socket: &F, is_client: bool) -> Resu
                 log::warn!("Failed to set receive buffer: {}", std::io::Error::last_os_error());
             }
         }
+
+        // Ack the request immediately instead of waiting for delayed ACK
+        #[cfg(target_os = "linux")]
+        unsafe {
+            let enable = 1 as libc::c_int;
+            let ret = libc::setsockopt(
+                fd,
+                libc::IPPROTO_TCP,
+                libc::TCP_QUICKACK,
+                &enable as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            );
+            if ret < 0 {
+                log::debug!("Failed to set TCP_QUICKACK: {}", std::io::Error::last_os_error());
+            }
+        }
     }
     
     // Enable TCP timestamps (important for iOS fingerprint)
@@ -401,34 +633,18 @@ pub fn apply_tcp_options<F: AsRawFd + AsFd>(socket: &F, is_client: bool) -> Resu
             std::mem::size_of::<libc::c_int>() as libc::socklen_t,
         );
         if ret < 0 {
-            // Не критично, продолжаем
+            // Not critical, continue
             log::debug!("TCP_TIMESTAMP not supported or failed");
         }
     }
     
-    // Set congestion control to cubic (iOS default)
-    #[cfg(target_os = "linux")]
-    {
-        use std::ffi::CString;
-        
-        unsafe {
-            let cubic = CString::new("cubic").unwrap();
-            let ret = libc::setsockopt(
-                fd,
-                libc::IPPROTO_TCP,
-                13, // TCP_CONGESTION
-                cubic.as_ptr() as *const libc::c_void,
-                cubic.as_bytes_with_nul().len() as libc::socklen_t,
-            );
-            if ret < 0 {
-                log::warn!("Failed to set TCP congestion control: {}", std::io::Error::last_os_error());
-            } else {
-                log::debug!("✓ TCP congestion control set to cubic");
-            }
-        }
+    // Congestion control from config (cubic is the iOS default)
+    match set_congestion_control(socket, congestion) {
+        Ok(()) => log::debug!("✓ TCP congestion control set to {}", congestion),
+        Err(e) => log::warn!("{}", e),
     }
     
-    // Enable SACK (Selective Acknowledgment)
+    // Keepalive probes after 2 minutes idle
     setsockopt(socket, sockopt::TcpKeepIdle, &120)?;
     
     log::debug!("✓ iOS Safari TCP options applied (TTL={}, MSS={}, Window={})", 
@@ -437,6 +653,209 @@ pub fn apply_tcp_options<F: AsRawFd + AsFd>(socket: &F, is_client: bool) -> Resu
     Ok(())
 }
 
+/// Set the TCP congestion control algorithm by name ("cubic", "bbr", ...).
+/// Fails if the algorithm is not available in the kernel (or not allowed for
+/// unprivileged users, see net.ipv4.tcp_allowed_congestion_control)
+#[cfg(target_os = "linux")]
+pub fn set_congestion_control<F: AsRawFd>(socket: &F, name: &str) -> Result<()> {
+    let algorithm = std::ffi::CString::new(name)?;
+
+    unsafe {
+        let ret = libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_CONGESTION,
+            algorithm.as_ptr() as *const libc::c_void,
+            algorithm.as_bytes_with_nul().len() as libc::socklen_t,
+        );
+        if ret < 0 {
+            return Err(anyhow::anyhow!("Failed to set TCP congestion control {}: {}", name,
+                std::io::Error::last_os_error()));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_congestion_control<F: AsRawFd>(_socket: &F, name: &str) -> Result<()> {
+    Err(anyhow::anyhow!("TCP congestion control {} is only supported on Linux", name))
+}
+
+/// Set IP_TTL (IPV6_UNICAST_HOPS on IPv6) and TCP_MAXSEG so L3/L4 matches the
+/// spoofed TLS fingerprint; `None` keeps the iOS defaults (64, 1460). Call before
+/// connect so the SYN already carries them: afterwards the TTL only applies to
+/// later packets and the MSS only caps outgoing segments
+pub fn apply_ip_fingerprint<F: AsRawFd + AsFd>(socket: &F, ttl: Option<u8>, mss: Option<u16>) -> Result<()> {
+    let ttl = ttl.unwrap_or(IOS_TTL) as libc::c_int;
+    let mss = mss.unwrap_or(IOS_MSS) as libc::c_int;
+
+    let local: SockaddrStorage = getsockname(socket.as_raw_fd())?;
+    if local.family() == Some(AddressFamily::Inet6) {
+        setsockopt(socket, sockopt::Ipv6Ttl, &ttl)?;
+    } else {
+        setsockopt(socket, sockopt::Ipv4Ttl, &ttl)?;
+    }
+
+    unsafe {
+        let ret = libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_MAXSEG,
+            &mss as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+        if ret < 0 {
+            return Err(anyhow::anyhow!("Failed to set TCP_MAXSEG: {}",
+                std::io::Error::last_os_error()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the kernel's smoothed RTT estimate (`tcpi_rtt`) from TCP_INFO
+#[cfg(target_os = "linux")]
+pub fn read_tcp_rtt<F: AsRawFd>(socket: &F) -> Result<Duration> {
+    let fd = socket.as_raw_fd();
+    
+    unsafe {
+        let mut info: libc::tcp_info = std::mem::zeroed();
+        let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        let ret = libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut _ as *mut libc::c_void,
+            &mut len,
+        );
+        
+        if ret < 0 {
+            return Err(anyhow::anyhow!("Failed to read TCP_INFO: {}", 
+                std::io::Error::last_os_error()));
+        }
+        
+        Ok(Duration::from_micros(info.tcpi_rtt as u64))
+    }
+}
+
+/// Check whether the peer has closed or reset an idle connection, without
+/// consuming any pending data (MSG_PEEK | MSG_DONTWAIT)
+pub fn peer_closed<F: AsRawFd>(socket: &F) -> bool {
+    let fd = socket.as_raw_fd();
+    let mut byte = 0u8;
+
+    let ret = unsafe {
+        libc::recv(
+            fd,
+            &mut byte as *mut u8 as *mut libc::c_void,
+            1,
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+
+    if ret == 0 {
+        return true;
+    }
+    if ret < 0 {
+        let err = std::io::Error::last_os_error();
+        return !matches!(err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted);
+    }
+
+    false
+}
+
+/// Waits for TCP urgent (out-of-band) data on a socket. Tokio streams are
+/// only registered for read/write readiness, so this watches a dup of the fd
+/// for EPOLLPRI without touching the stream's own registration
+pub struct UrgentWatcher {
+    fd: tokio::io::unix::AsyncFd<std::os::fd::OwnedFd>,
+}
+
+impl UrgentWatcher {
+    pub fn new<F: AsFd>(socket: &F) -> std::io::Result<Self> {
+        let dup = socket.as_fd().try_clone_to_owned()?;
+        let fd = tokio::io::unix::AsyncFd::with_interest(dup, tokio::io::Interest::PRIORITY)?;
+        Ok(Self { fd })
+    }
+
+    /// Waits until the peer signals urgent data; false once it has closed its side
+    pub async fn signalled(&self) -> std::io::Result<bool> {
+        let mut guard = self.fd.ready(tokio::io::Interest::PRIORITY).await?;
+        let ready = guard.ready();
+        // Closed readiness is never cleared, so the next call returns false right away
+        guard.clear_ready();
+        Ok(ready.is_priority())
+    }
+
+    /// The urgent byte, but only once every byte sent before it has been read
+    /// from the stream (the read pointer is at the urgent mark). None while the
+    /// mark is still ahead or no urgent data is pending
+    pub fn take_at_mark(&self) -> std::io::Result<Option<u8>> {
+        let fd = self.fd.get_ref().as_raw_fd();
+        if !at_urgent_mark(fd)? {
+            return Ok(None);
+        }
+        match recv_urgent(fd) {
+            Ok(byte) => Ok(Some(byte)),
+            // EINVAL: already read; WouldBlock: the mark is known but the byte hasn't arrived
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) || e.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// SIOCATMARK: whether the read pointer sits at the urgent mark
+fn at_urgent_mark(fd: std::os::unix::io::RawFd) -> std::io::Result<bool> {
+    const SIOCATMARK: u64 = 0x8905;
+    let mut at_mark: libc::c_int = 0;
+    let ret = unsafe { libc::ioctl(fd, SIOCATMARK as _, &mut at_mark) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(at_mark != 0)
+}
+
+fn recv_urgent(fd: std::os::unix::io::RawFd) -> std::io::Result<u8> {
+    let mut byte = 0u8;
+    let ret = unsafe {
+        libc::recv(
+            fd,
+            &mut byte as *mut u8 as *mut libc::c_void,
+            1,
+            libc::MSG_OOB | libc::MSG_DONTWAIT,
+        )
+    };
+
+    if ret == 1 {
+        Ok(byte)
+    } else if ret == 0 {
+        Err(std::io::Error::from_raw_os_error(libc::EINVAL))
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Send one byte as TCP urgent data, setting the urgent pointer on the peer's side
+pub async fn send_urgent(stream: &tokio::net::TcpStream, byte: u8) -> std::io::Result<()> {
+    let fd = stream.as_raw_fd();
+    stream.async_io(tokio::io::Interest::WRITABLE, || {
+        let ret = unsafe {
+            libc::send(
+                fd,
+                &byte as *const u8 as *const libc::c_void,
+                1,
+                libc::MSG_OOB | libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+            )
+        };
+        if ret < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }).await
+}
+
 /// Preserve original TTL from packet (for TPROXY mode)
 pub fn preserve_ttl<F: AsRawFd>(socket: &F, ttl: u8) -> Result<()> {
     let fd = socket.as_raw_fd();
@@ -460,54 +879,86 @@ pub fn preserve_ttl<F: AsRawFd>(socket: &F, ttl: u8) -> Result<()> {
     Ok(())
 }
 
-/// Enable IP_TRANSPARENT for TPROXY mode
+/// Set an integer IP-level option, picking the IPv6 variant on IPv6 sockets
 #[cfg(target_os = "linux")]
-pub fn enable_transparent_proxy<F: AsRawFd>(socket: &F) -> Result<()> {
+fn set_ip_option<F: AsRawFd>(socket: &F, name: &str, v4: libc::c_int, v6: libc::c_int) -> Result<()> {
     let fd = socket.as_raw_fd();
-    
+    let local: SockaddrStorage = getsockname(fd)?;
+    let (level, option) = if local.family() == Some(AddressFamily::Inet6) {
+        (libc::IPPROTO_IPV6, v6)
+    } else {
+        (libc::IPPROTO_IP, v4)
+    };
+
     unsafe {
         let enable = 1 as libc::c_int;
         let ret = libc::setsockopt(
             fd,
-            libc::IPPROTO_IP,
-            19, // IP_TRANSPARENT
+            level,
+            option,
             &enable as *const _ as *const libc::c_void,
             std::mem::size_of::<libc::c_int>() as libc::socklen_t,
         );
         
         if ret < 0 {
-            return Err(anyhow::anyhow!("Failed to enable IP_TRANSPARENT: {}", 
+            return Err(anyhow::anyhow!("Failed to enable {}: {}", name,
                 std::io::Error::last_os_error()));
         }
     }
     
-    log::debug!("✓ IP_TRANSPARENT enabled");
+    log::debug!("✓ {} enabled", name);
     Ok(())
 }
 
-/// Enable IP_RECVORIGDSTADDR to get original destination
+/// Enable IP_TRANSPARENT (IPV6_TRANSPARENT on IPv6) for TPROXY mode
+#[cfg(target_os = "linux")]
+pub fn enable_transparent_proxy<F: AsRawFd>(socket: &F) -> Result<()> {
+    set_ip_option(socket, "IP_TRANSPARENT", libc::IP_TRANSPARENT, libc::IPV6_TRANSPARENT)
+}
+
+/// Enable IP_RECVORIGDSTADDR (IPV6_RECVORIGDSTADDR on IPv6) to get original destination
 #[cfg(target_os = "linux")]
 pub fn enable_recvorigdstaddr<F: AsRawFd>(socket: &F) -> Result<()> {
-    let fd = socket.as_raw_fd();
-    
-    unsafe {
-        let enable = 1 as libc::c_int;
-        let ret = libc::setsockopt(
+    set_ip_option(socket, "IP_RECVORIGDSTADDR", libc::IP_RECVORIGDSTADDR, libc::IPV6_RECVORIGDSTADDR)
+}
+
+/// Bind a UDP socket that may own a non-local address: IP_TRANSPARENT is set
+/// before bind, SO_REUSEADDR lets several sockets share one original destination
+/// (TPROXY replies must leave from the address the client sent to)
+#[cfg(target_os = "linux")]
+pub fn bind_transparent_udp(addr: std::net::SocketAddr) -> Result<std::net::UdpSocket> {
+    use std::os::fd::FromRawFd;
+
+    let domain = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK, 0) };
+    if fd < 0 {
+        return Err(anyhow::anyhow!("Failed to create UDP socket: {}", std::io::Error::last_os_error()));
+    }
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+
+    setsockopt(&socket, sockopt::ReuseAddr, &true)?;
+    let (level, option) = if addr.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_TRANSPARENT)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_TRANSPARENT)
+    };
+    let enable = 1 as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
             fd,
-            libc::IPPROTO_IP,
-            20, // IP_RECVORIGDSTADDR
+            level,
+            option,
             &enable as *const _ as *const libc::c_void,
             std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-        );
-        
-        if ret < 0 {
-            return Err(anyhow::anyhow!("Failed to enable IP_RECVORIGDSTADDR: {}", 
-                std::io::Error::last_os_error()));
-        }
+        )
+    };
+    if ret < 0 {
+        return Err(anyhow::anyhow!("Failed to enable IP_TRANSPARENT: {}", std::io::Error::last_os_error()));
     }
-    
-    log::debug!("✓ IP_RECVORIGDSTADDR enabled");
-    Ok(())
+
+    nix::sys::socket::bind(fd, &SockaddrStorage::from(addr))
+        .map_err(|e| anyhow::anyhow!("Failed to bind transparent UDP socket to {}: {}", addr, e))?;
+    Ok(socket)
 }
 
 #[cfg(test)]
@@ -551,6 +1002,121 @@ mod tests {
         assert_eq!(queue.len(), 1);
     }
 
+    #[test]
+    fn test_buffer_budget_shared_across_connections() {
+        let budget = BufferBudget::new(10 * 1024);
+        let mut reorder: Vec<OutOfOrderBuffer> = (0..50)
+            .map(|_| OutOfOrderBuffer::new(0, 100).with_budget(budget.clone()))
+            .collect();
+        let mut retransmit: Vec<RetransmissionQueue> = (0..50)
+            .map(|_| RetransmissionQueue::new().with_budget(budget.clone()))
+            .collect();
+
+        let mut accepted = 0;
+        for (buffer, queue) in reorder.iter_mut().zip(retransmit.iter_mut()) {
+            accepted += buffer.insert(1000, vec![0; 128]) as usize;
+            accepted += queue.add(0, vec![0; 128]) as usize;
+        }
+        assert_eq!(accepted, 80);
+        assert_eq!(budget.used(), budget.limit());
+
+        // Acked and delivered bytes go back to the pool
+        retransmit[0].acknowledge(128);
+        assert!(reorder[49].insert(2000, vec![0; 128]));
+
+        drop(reorder);
+        drop(retransmit);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_read_tcp_rtt_loopback() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let _server = listener.accept().unwrap();
+        
+        let rtt = read_tcp_rtt(&client).unwrap();
+        assert!(rtt < Duration::from_secs(1));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_set_congestion_control() {
+        use nix::sys::socket::getsockopt;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        set_congestion_control(&stream, "cubic").unwrap();
+        let algorithm = getsockopt(&stream, sockopt::TcpCongestion).unwrap();
+        assert_eq!(algorithm.to_string_lossy().trim_end_matches('\0'), "cubic");
+        assert!(set_congestion_control(&stream, "no-such-algorithm").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_configure_tcp_socket_nodelay() {
+        use nix::sys::socket::getsockopt;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        configure_tcp_socket(&stream, false).unwrap();
+        assert!(!getsockopt(&stream, sockopt::TcpNoDelay).unwrap());
+
+        configure_tcp_socket(&stream, true).unwrap();
+        assert!(getsockopt(&stream, sockopt::TcpNoDelay).unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_window_applied_to_socket() {
+        use nix::sys::socket::{getsockopt, setsockopt};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let mut wm = TcpWindowManager::new(32768);
+        wm.update_rtt(Duration::from_millis(20));
+        wm.update_bandwidth(1_000_000, Duration::from_secs(1));
+        let window = wm.update_window(0);
+        assert!(window < 32768);
+
+        // Never below what the kernel already picked
+        let autotuned = getsockopt(&stream, sockopt::RcvBuf).unwrap();
+        setsockopt(&stream, sockopt::SndBuf, &(window as usize)).unwrap();
+        assert_eq!(wm.apply_to_socket(&stream).unwrap(), None);
+        assert_eq!(getsockopt(&stream, sockopt::RcvBuf).unwrap(), autotuned);
+
+        setsockopt(&stream, sockopt::RcvBuf, &4096).unwrap();
+        setsockopt(&stream, sockopt::SndBuf, &4096).unwrap();
+        assert_eq!(wm.apply_to_socket(&stream).unwrap(), Some(window));
+        // The kernel doubles the requested size for bookkeeping overhead
+        assert_eq!(getsockopt(&stream, sockopt::RcvBuf).unwrap(), 2 * window as usize);
+        assert_eq!(getsockopt(&stream, sockopt::SndBuf).unwrap(), 2 * window as usize);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_apply_tcp_options_connected_socket() {
+        use nix::sys::socket::getsockopt;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (client, _) = listener.accept().unwrap();
+
+        apply_tcp_options(&client, true, "cubic").unwrap();
+        apply_tcp_options(&upstream, false, "cubic").unwrap();
+
+        for socket in [&client, &upstream] {
+            assert_eq!(getsockopt(socket, sockopt::Ipv4Ttl).unwrap(), IOS_TTL as i32);
+            assert_eq!(getsockopt(socket, sockopt::TcpKeepIdle).unwrap(), 120);
+        }
+        // The kernel doubles SO_RCVBUF for bookkeeping overhead
+        assert!(getsockopt(&client, sockopt::RcvBuf).unwrap() >= IOS_INITIAL_WINDOW as usize);
+    }
+
     #[test]
     fn test_sack_manager() {
         let mut sack = SackManager::new(4);
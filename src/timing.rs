@@ -7,41 +7,92 @@ use rand_distr::{Distribution, Normal};
 const HISTORY_SIZE: usize = 100;
 const MIN_DELAY_MS: u64 = 1;
 const MAX_DELAY_MS: u64 = 5000;
+const DEFAULT_JITTER_STDDEV: f64 = 0.1;
+const MAX_JITTER_STDDEV: f64 = 1.0;
+
+/// How the natural delay is derived from recent send intervals
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DelayMode {
+    Average,
+    Median,
+    /// Exponentially weighted; higher alpha follows bursts faster
+    Ewma { alpha: f64 },
+}
 
 pub struct TimingPreserver {
     last_send: Option<Instant>,
     intervals: VecDeque<Duration>,
     jitter_dist: Normal<f64>,
+    delay_mode: DelayMode,
+    ewma_ms: Option<f64>,
+    enabled: bool,
 }
 
 impl TimingPreserver {
     pub fn new(jitter_stddev: f64) -> Self {
-        let jitter_dist = Normal::new(0.0, jitter_stddev).unwrap_or_else(|_| {
-            Normal::new(0.0, 1.0).unwrap()
-        });
+        let jitter_stddev = sanitize_jitter(jitter_stddev);
+        let jitter_dist = Normal::new(0.0, jitter_stddev)
+            .unwrap_or_else(|_| Normal::new(0.0, DEFAULT_JITTER_STDDEV).expect("constant jitter is valid"));
 
         Self {
             last_send: None,
             intervals: VecDeque::with_capacity(HISTORY_SIZE),
             jitter_dist,
+            delay_mode: DelayMode::Average,
+            ewma_ms: None,
+            enabled: true,
         }
     }
 
+    /// A preserver that never delays, for deployments that only care about
+    /// the TLS fingerprint
+    pub fn disabled() -> Self {
+        let mut preserver = Self::new(0.0);
+        preserver.enabled = false;
+        preserver
+    }
+
+    pub fn with_delay_mode(mut self, mode: DelayMode) -> Self {
+        self.delay_mode = match mode {
+            DelayMode::Ewma { alpha } if !(alpha > 0.0 && alpha <= 1.0) => {
+                log::warn!("Invalid EWMA alpha {}, using average delay", alpha);
+                DelayMode::Average
+            }
+            mode => mode,
+        };
+        self
+    }
+
     pub fn record_send(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
         let now = Instant::now();
         
         if let Some(last) = self.last_send {
-            let interval = now.duration_since(last);
-            self.intervals.push_back(interval);
-            
-            if self.intervals.len() > HISTORY_SIZE {
-                self.intervals.pop_front();
-            }
+            self.push_interval(now.duration_since(last));
         }
         
         self.last_send = Some(now);
     }
 
+    fn push_interval(&mut self, interval: Duration) {
+        self.intervals.push_back(interval);
+
+        if self.intervals.len() > HISTORY_SIZE {
+            self.intervals.pop_front();
+        }
+
+        if let DelayMode::Ewma { alpha } = self.delay_mode {
+            let sample = interval.as_secs_f64() * 1000.0;
+            self.ewma_ms = Some(match self.ewma_ms {
+                Some(prev) => alpha * sample + (1.0 - alpha) * prev,
+                None => sample,
+            });
+        }
+    }
+
     pub fn get_average_interval(&self) -> Duration {
         if self.intervals.is_empty() {
             return Duration::from_millis(10);
@@ -51,16 +102,47 @@ impl TimingPreserver {
         sum / self.intervals.len() as u32
     }
 
+    /// Base delay according to the configured mode
+    pub fn get_natural_interval(&self) -> Duration {
+        if self.intervals.is_empty() {
+            return Duration::from_millis(10);
+        }
+
+        match self.delay_mode {
+            DelayMode::Average => self.get_average_interval(),
+            DelayMode::Median => {
+                let mut sorted: Vec<Duration> = self.intervals.iter().copied().collect();
+                sorted.sort();
+                sorted[sorted.len() / 2]
+            }
+            DelayMode::Ewma { .. } => {
+                Duration::from_secs_f64(self.ewma_ms.unwrap_or(10.0) / 1000.0)
+            }
+        }
+    }
+
     pub async fn wait_natural_delay(&mut self) {
-        let base_delay = self.get_average_interval();
-        let delay = self.apply_jitter(base_delay);
-        
-        if delay > Duration::from_millis(MIN_DELAY_MS) 
-            && delay < Duration::from_millis(MAX_DELAY_MS) {
+        let delay = self.next_delay();
+        if !delay.is_zero() {
             sleep(delay).await;
         }
     }
 
+    /// Jittered natural delay, zero when disabled or outside the sane range
+    pub fn next_delay(&mut self) -> Duration {
+        if !self.enabled {
+            return Duration::ZERO;
+        }
+
+        let delay = self.apply_jitter(self.get_natural_interval());
+        if delay > Duration::from_millis(MIN_DELAY_MS)
+            && delay < Duration::from_millis(MAX_DELAY_MS) {
+            delay
+        } else {
+            Duration::ZERO
+        }
+    }
+
     fn apply_jitter(&mut self, base: Duration) -> Duration {
         let mut rng = rng();
         let jitter: f64 = self.jitter_dist.sample(&mut rng);
@@ -81,6 +163,44 @@ impl TimingPreserver {
     pub fn reset(&mut self) {
         self.last_send = None;
         self.intervals.clear();
+        self.ewma_ms = None;
+    }
+}
+
+/// Negative or NaN jitter falls back to the default, huge values are capped
+fn sanitize_jitter(jitter_stddev: f64) -> f64 {
+    if jitter_stddev.is_nan() || jitter_stddev < 0.0 {
+        log::warn!("Invalid timing jitter {}, using {}", jitter_stddev, DEFAULT_JITTER_STDDEV);
+        return DEFAULT_JITTER_STDDEV;
+    }
+
+    jitter_stddev.min(MAX_JITTER_STDDEV)
+}
+
+/// Pauses a real client makes between handshake phases: before sending the
+/// ClientHello and between the HTTP/2 preface and the first request.
+/// Zero durations skip the sleep entirely
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpecializedTimers {
+    pub tls_handshake: Duration,
+    pub http2_settings: Duration,
+}
+
+impl SpecializedTimers {
+    pub fn new(tls_handshake: Duration, http2_settings: Duration) -> Self {
+        Self { tls_handshake, http2_settings }
+    }
+
+    pub async fn apply_tls_delay(&self) {
+        if !self.tls_handshake.is_zero() {
+            sleep(self.tls_handshake).await;
+        }
+    }
+
+    pub async fn apply_http2_delay(&self) {
+        if !self.http2_settings.is_zero() {
+            sleep(self.http2_settings).await;
+        }
     }
 }
 
@@ -144,6 +264,113 @@ mod tests {
         assert!(avg <= Duration::from_millis(11));
     }
 
+    #[test]
+    fn test_learns_send_intervals() {
+        let mut tp = TimingPreserver::new(0.0).with_delay_mode(DelayMode::Median);
+        assert_eq!(tp.get_natural_interval(), Duration::from_millis(10));
+
+        for ms in [20, 30, 500, 30, 25] {
+            tp.push_interval(Duration::from_millis(ms));
+        }
+        // One outlier doesn't move the median
+        assert_eq!(tp.get_natural_interval(), Duration::from_millis(30));
+
+        for _ in 0..HISTORY_SIZE {
+            tp.push_interval(Duration::from_millis(40));
+        }
+        assert_eq!(tp.intervals.len(), HISTORY_SIZE);
+        assert_eq!(tp.get_natural_interval(), Duration::from_millis(40));
+
+        tp.reset();
+        assert_eq!(tp.get_natural_interval(), Duration::from_millis(10));
+    }
+
+    #[tokio::test]
+    async fn test_wait_natural_delay_sleeps_learned_interval() {
+        let mut tp = TimingPreserver::new(0.0).with_delay_mode(DelayMode::Median);
+        for _ in 0..5 {
+            tp.push_interval(Duration::from_millis(30));
+        }
+
+        let started = Instant::now();
+        tp.wait_natural_delay().await;
+        assert!(started.elapsed() >= Duration::from_millis(30));
+
+        // Beyond MAX_DELAY_MS the delay is skipped instead of stalling the stream
+        tp.reset();
+        tp.push_interval(Duration::from_millis(MAX_DELAY_MS + 1000));
+        let started = Instant::now();
+        tp.wait_natural_delay().await;
+        assert!(started.elapsed() < Duration::from_millis(MAX_DELAY_MS));
+    }
+
+    #[tokio::test]
+    async fn test_disabled_preserver_never_delays() {
+        let mut tp = TimingPreserver::disabled();
+        tp.push_interval(Duration::from_millis(500));
+        assert_eq!(tp.next_delay(), Duration::ZERO);
+
+        tp.record_send();
+        tp.record_send();
+        assert_eq!(tp.intervals.len(), 1);
+
+        let started = Instant::now();
+        tp.wait_natural_delay().await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_invalid_jitter_does_not_panic() {
+        for jitter in [-0.5, f64::NAN, f64::INFINITY, 1e300] {
+            let mut tp = TimingPreserver::new(jitter);
+            for _ in 0..100 {
+                let delay = tp.apply_jitter(Duration::from_millis(100));
+                assert!(delay <= Duration::from_millis(100 * 20));
+            }
+        }
+
+        assert_eq!(sanitize_jitter(-0.5), DEFAULT_JITTER_STDDEV);
+        assert_eq!(sanitize_jitter(f64::NAN), DEFAULT_JITTER_STDDEV);
+        assert_eq!(sanitize_jitter(1e300), MAX_JITTER_STDDEV);
+        assert_eq!(sanitize_jitter(0.2), 0.2);
+    }
+
+    #[test]
+    fn test_ewma_reacts_to_step_faster_than_median() {
+        let mut median = TimingPreserver::new(0.0).with_delay_mode(DelayMode::Median);
+        let mut ewma = TimingPreserver::new(0.0).with_delay_mode(DelayMode::Ewma { alpha: 0.5 });
+
+        for tp in [&mut median, &mut ewma] {
+            for _ in 0..20 {
+                tp.push_interval(Duration::from_millis(10));
+            }
+            for _ in 0..5 {
+                tp.push_interval(Duration::from_millis(100));
+            }
+        }
+
+        // Five slow samples out of 25 don't move the median at all
+        assert_eq!(median.get_natural_interval(), Duration::from_millis(10));
+        assert!(ewma.get_natural_interval() > Duration::from_millis(90));
+
+        let fallback = TimingPreserver::new(0.0).with_delay_mode(DelayMode::Ewma { alpha: 1.5 });
+        assert_eq!(fallback.delay_mode, DelayMode::Average);
+    }
+
+    #[tokio::test]
+    async fn test_specialized_timers() {
+        let timers = SpecializedTimers::new(Duration::from_millis(30), Duration::ZERO);
+
+        let started = Instant::now();
+        timers.apply_tls_delay().await;
+        assert!(started.elapsed() >= Duration::from_millis(30));
+
+        let started = Instant::now();
+        timers.apply_http2_delay().await;
+        SpecializedTimers::default().apply_tls_delay().await;
+        assert!(started.elapsed() < Duration::from_millis(20));
+    }
+
     #[test]
     fn test_packet_timing_analyzer() {
         let mut analyzer = PacketTimingAnalyzer::new(10);
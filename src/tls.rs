@@ -1,19 +1,57 @@
 use bytes::{BytesMut, BufMut};
 use anyhow::Result;
-use rand::Rng;
-use std::collections::HashMap;
-use std::sync::Arc;
-use parking_lot::RwLock;
-use std::time::{SystemTime, UNIX_EPOCH};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::collections::{HashMap, HashSet};
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+use sha2::{Digest, Sha256};
+
+use crate::config::FingerprintProfile;
 
 const TLS_HANDSHAKE: u8 = 0x16;
 const TLS_VERSION_1_0: [u8; 2] = [0x03, 0x01];
 const TLS_VERSION_1_2: [u8; 2] = [0x03, 0x03];
 const CLIENT_HELLO: u8 = 0x01;
-const SESSION_TICKET_LIFETIME: u64 = 7200;
+const TLS_ALERT: u8 = 0x15;
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_EC_POINT_FORMATS: u16 = 11;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXT_STATUS_REQUEST: u16 = 5;
+const EXT_ALPN: u16 = 16;
+const EXT_PADDING: u16 = 21;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+const EXT_PSK_KEY_EXCHANGE_MODES: u16 = 45;
+const EXT_KEY_SHARE: u16 = 51;
+const EXT_COMPRESS_CERTIFICATE: u16 = 27;
+const EXT_SESSION_TICKET: u16 = 35;
+const EXT_PRE_SHARED_KEY: u16 = 41;
+const EXT_EARLY_DATA: u16 = 42;
+const EXT_ENCRYPTED_CLIENT_HELLO: u16 = 0xfe0d;
+const ECH_GREASE_PAYLOAD_LENGTHS: [usize; 4] = [144, 176, 208, 240];
+const TLS_1_3: u16 = 0x0304;
+const TLS_1_2: u16 = 0x0303;
+const IOS_SUPPORTED_VERSIONS: [u16; 2] = [TLS_1_3, TLS_1_2];
+const TLS13_CIPHERS: [u16; 3] = [0x1301, 0x1302, 0x1303];
+/// Без них handshake клиента не состоится, поэтому они остаются, даже если
+/// профиль их не перечисляет
+const REQUIRED_EXTENSIONS: [u16; 9] = [
+    EXT_SERVER_NAME,
+    EXT_SUPPORTED_GROUPS,
+    EXT_SIGNATURE_ALGORITHMS,
+    EXT_SUPPORTED_VERSIONS,
+    EXT_KEY_SHARE,
+    EXT_PSK_KEY_EXCHANGE_MODES,
+    EXT_PRE_SHARED_KEY,
+    EXT_EARLY_DATA,
+    EXT_ENCRYPTED_CLIENT_HELLO,
+];
+const MAX_RECORD_PAYLOAD: usize = 16384;
 
 #[derive(Debug, Clone)]
 pub struct TlsClientHello {
+    pub record_version: [u8; 2],
     pub version: [u8; 2],
     pub random: [u8; 32],
     pub session_id: Vec<u8>,
@@ -28,73 +66,342 @@ pub struct TlsExtension {
     pub data: Vec<u8>,
 }
 
-#[derive(Debug, Clone)]
-pub struct SessionTicket {
-    pub ticket: Vec<u8>,
-    pub timestamp: u64,
-    pub domain: String,
-}
-
-impl SessionTicket {
-    pub fn new(ticket: Vec<u8>, domain: String) -> Self {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        
+fn hex_list(values: &[u16]) -> String {
+    values.iter().map(|v| format!("{:04x}", v)).collect::<Vec<_>>().join(",")
+}
+
+/// Первые 12 hex символов SHA-256; пустой вход - нули, как в JA4
+fn ja4_hash(input: &str) -> String {
+    if input.is_empty() {
+        return "000000000000".to_string();
+    }
+
+    Sha256::digest(input.as_bytes())
+        .iter()
+        .take(6)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Источник GREASE значений
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum GreaseMode {
+    /// Случайные значения, выбранные самим клиентом; без них GREASE не добавляется
+    #[default]
+    Random,
+    /// Стабильные значения в пределах соединения, разные между соединениями
+    PerConnection(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GreaseValues {
+    pub cipher: u16,
+    pub first_extension: u16,
+    pub last_extension: u16,
+}
+
+impl GreaseMode {
+    /// Один источник для всех GREASE значений ClientHello, включая GREASE ECH
+    fn rng(self) -> StdRng {
+        match self {
+            GreaseMode::Random => StdRng::from_rng(&mut rand::rng()),
+            GreaseMode::PerConnection(conn_id) => StdRng::seed_from_u64(conn_id),
+        }
+    }
+}
+
+impl GreaseValues {
+    pub fn generate(mode: GreaseMode) -> Self {
+        Self::from_rng(&mut mode.rng())
+    }
+
+    fn from_rng<R: Rng>(rng: &mut R) -> Self {
+        let cipher = grease_value(rng.random_range(0..16));
+        let first = rng.random_range(0..16);
+        // Safari никогда не повторяет одно и то же GREASE extension
+        let last = (first + rng.random_range(1..16)) % 16;
+
         Self {
-            ticket,
-            timestamp,
-            domain,
+            cipher,
+            first_extension: grease_value(first),
+            last_extension: grease_value(last),
         }
     }
+}
 
-    pub fn is_expired(&self) -> bool {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        
-        now - self.timestamp > SESSION_TICKET_LIFETIME
+fn grease_value(index: u16) -> u16 {
+    0x0A0A + 0x1010 * index
+}
+
+/// "TLS 1.3" -> 0x0304 и т.д. (формат supported_versions в профиле)
+pub fn parse_version_name(name: &str) -> Option<u16> {
+    match name.trim().to_uppercase().replace(' ', "").as_str() {
+        "TLS1.3" => Some(0x0304),
+        "TLS1.2" => Some(0x0303),
+        "TLS1.1" => Some(0x0302),
+        "TLS1.0" => Some(0x0301),
+        _ => None,
     }
 }
 
-pub struct SessionTicketCache {
-    tickets: Arc<RwLock<HashMap<String, SessionTicket>>>,
+/// Имена cipher suites из профиля (IANA)
+pub fn cipher_suite_id(name: &str) -> Option<u16> {
+    match name.trim() {
+        "TLS_AES_128_GCM_SHA256" => Some(0x1301),
+        "TLS_AES_256_GCM_SHA384" => Some(0x1302),
+        "TLS_CHACHA20_POLY1305_SHA256" => Some(0x1303),
+        "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256" => Some(0xc02b),
+        "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384" => Some(0xc02c),
+        "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256" => Some(0xc02f),
+        "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384" => Some(0xc030),
+        "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256" => Some(0xcca9),
+        "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256" => Some(0xcca8),
+        "TLS_ECDHE_ECDSA_WITH_AES_256_CBC_SHA" => Some(0xc00a),
+        "TLS_ECDHE_ECDSA_WITH_AES_128_CBC_SHA" => Some(0xc009),
+        "TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA" => Some(0xc014),
+        "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA" => Some(0xc013),
+        "TLS_RSA_WITH_AES_256_GCM_SHA384" => Some(0x009d),
+        "TLS_RSA_WITH_AES_128_GCM_SHA256" => Some(0x009c),
+        "TLS_RSA_WITH_AES_256_CBC_SHA" => Some(0x0035),
+        "TLS_RSA_WITH_AES_128_CBC_SHA" => Some(0x002f),
+        _ => None,
+    }
+}
+
+pub fn extension_id(name: &str) -> Option<u16> {
+    match name.trim() {
+        "server_name" => Some(EXT_SERVER_NAME),
+        "status_request" => Some(EXT_STATUS_REQUEST),
+        "supported_groups" => Some(EXT_SUPPORTED_GROUPS),
+        "ec_point_formats" => Some(EXT_EC_POINT_FORMATS),
+        "signature_algorithms" => Some(EXT_SIGNATURE_ALGORITHMS),
+        "application_layer_protocol_negotiation" => Some(EXT_ALPN),
+        "signed_certificate_timestamp" => Some(18),
+        "padding" => Some(EXT_PADDING),
+        "extended_master_secret" => Some(23),
+        "compress_certificate" => Some(EXT_COMPRESS_CERTIFICATE),
+        "session_ticket" => Some(EXT_SESSION_TICKET),
+        "supported_versions" => Some(EXT_SUPPORTED_VERSIONS),
+        "psk_key_exchange_modes" => Some(EXT_PSK_KEY_EXCHANGE_MODES),
+        "key_share" => Some(EXT_KEY_SHARE),
+        "renegotiation_info" => Some(0xff01),
+        _ => None,
+    }
+}
+
+pub fn signature_scheme_id(name: &str) -> Option<u16> {
+    match name.trim() {
+        "ecdsa_secp256r1_sha256" => Some(0x0403),
+        "ecdsa_secp384r1_sha384" => Some(0x0503),
+        "ecdsa_secp521r1_sha512" => Some(0x0603),
+        "rsa_pss_rsae_sha256" => Some(0x0804),
+        "rsa_pss_rsae_sha384" => Some(0x0805),
+        "rsa_pss_rsae_sha512" => Some(0x0806),
+        "ed25519" => Some(0x0807),
+        "rsa_pkcs1_sha256" => Some(0x0401),
+        "rsa_pkcs1_sha384" => Some(0x0501),
+        "rsa_pkcs1_sha512" => Some(0x0601),
+        "ecdsa_sha1" => Some(0x0203),
+        "rsa_pkcs1_sha1" => Some(0x0201),
+        _ => None,
+    }
+}
+
+pub fn named_group_id(name: &str) -> Option<u16> {
+    match name.trim() {
+        "x25519" => Some(0x001d),
+        "secp256r1" => Some(0x0017),
+        "secp384r1" => Some(0x0018),
+        "secp521r1" => Some(0x0019),
+        "x448" => Some(0x001e),
+        _ => None,
+    }
+}
+
+/// Длина публичного ключа в key_share (несжатая точка для NIST кривых)
+fn key_share_len(group: u16) -> usize {
+    match group {
+        0x001d => 32,
+        0x001e => 56,
+        0x0017 => 65,
+        0x0018 => 97,
+        _ => 133,
+    }
+}
+
+pub fn psk_mode_id(name: &str) -> Option<u8> {
+    match name.trim() {
+        "psk_ke" => Some(0),
+        "psk_dhe_ke" => Some(1),
+        _ => None,
+    }
+}
+
+pub fn cert_compression_id(name: &str) -> Option<u16> {
+    match name.trim() {
+        "zlib" => Some(1),
+        "brotli" => Some(2),
+        "zstd" => Some(3),
+        _ => None,
+    }
+}
+
+/// Все имена списка обязаны быть известны: молча пропущенное значение
+/// дало бы не тот отпечаток
+fn lookup_names<T>(names: &[String], kind: &str, lookup: fn(&str) -> Option<T>) -> Result<Vec<T>> {
+    names.iter()
+        .map(|name| lookup(name).ok_or_else(|| anyhow::anyhow!("Unknown {} in profile: {}", kind, name)))
+        .collect()
 }
 
-impl SessionTicketCache {
-    pub fn new() -> Self {
+pub fn is_grease(value: u16) -> bool {
+    (value & 0x0F0F) == 0x0A0A && (value >> 8) == (value & 0xFF)
+}
+
+/// Где в ClientHello стоят GREASE значения; у iOS Safari - все три позиции
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GreasePositions {
+    pub cipher_first: bool,
+    pub extension_first: bool,
+    /// Последним extension (перед pre_shared_key)
+    pub extension_last: bool,
+}
+
+impl Default for GreasePositions {
+    fn default() -> Self {
+        Self {
+            cipher_first: true,
+            extension_first: true,
+            extension_last: true,
+        }
+    }
+}
+
+/// Параметры генерации ClientHello
+#[derive(Debug, Clone)]
+pub struct ClientHelloOptions {
+    pub grease: GreaseMode,
+    pub grease_positions: GreasePositions,
+    /// Handshake дополняется extension 21 до кратного этому значению размера (0 - без padding)
+    pub padding_boundary: usize,
+    /// Добавлять GREASE ECH (как Chrome), если клиент не прислал свой encrypted_client_hello
+    pub ech_grease: bool,
+    /// Порядок версий в supported_versions из профиля
+    pub version_order: Vec<u16>,
+    /// Шифры профиля в его порядке (пусто - клиентские)
+    pub cipher_suites: Vec<u16>,
+    /// Extensions профиля в его порядке (пусто - клиентские как есть)
+    pub extension_order: Vec<u16>,
+}
+
+impl Default for ClientHelloOptions {
+    fn default() -> Self {
         Self {
-            tickets: Arc::new(RwLock::new(HashMap::new())),
+            grease: GreaseMode::default(),
+            grease_positions: GreasePositions::default(),
+            padding_boundary: 0,
+            ech_grease: false,
+            version_order: IOS_SUPPORTED_VERSIONS.to_vec(),
+            cipher_suites: Vec::new(),
+            extension_order: Vec::new(),
         }
     }
+}
+
+/// Цели, которые сбрасывают соединения с переписанным ClientHello. После
+/// `threshold` неудач подряд отпечаток для SNI отключается (чистый passthrough)
+/// на `cooldown`, затем переписывание пробуется снова
+pub struct FingerprintFailures {
+    threshold: u32,
+    cooldown: Duration,
+    targets: Mutex<HashMap<String, FailureState>>,
+}
+
+/// Предел отслеживаемых SNI, чтобы поток случайных имен не раздувал таблицу
+const MAX_FAILURE_TARGETS: usize = 4096;
+
+#[derive(Default)]
+struct FailureState {
+    failures: u32,
+    disabled_until: Option<Instant>,
+}
 
-    pub fn store(&self, domain: String, ticket: Vec<u8>) {
-        let session_ticket = SessionTicket::new(ticket, domain.clone());
-        self.tickets.write().insert(domain, session_ticket);
+impl FingerprintFailures {
+    /// threshold == 0 - адаптивное отключение выключено
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            targets: Mutex::new(HashMap::new()),
+        }
     }
 
-    pub fn get(&self, domain: &str) -> Option<Vec<u8>> {
-        let tickets = self.tickets.read();
-        if let Some(ticket) = tickets.get(domain) {
-            if !ticket.is_expired() {
-                return Some(ticket.ticket.clone());
+    pub fn should_fingerprint(&self, sni: &str) -> bool {
+        let mut targets = self.targets.lock();
+        let Some(state) = targets.get_mut(sni) else {
+            return true;
+        };
+
+        match state.disabled_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                log::info!("Fingerprint cooldown for {} expired, retrying rewrite", sni);
+                targets.remove(sni);
+                true
             }
+            None => true,
         }
-        None
     }
 
-    pub fn cleanup_expired(&self) {
-        let mut tickets = self.tickets.write();
-        tickets.retain(|_, ticket| !ticket.is_expired());
+    /// Upstream ответил на переписанный ClientHello alert-ом или сбросом
+    pub fn record_failure(&self, sni: &str) {
+        if self.threshold == 0 || sni.is_empty() {
+            return;
+        }
+
+        let mut targets = self.targets.lock();
+        if !targets.contains_key(sni) && targets.len() >= MAX_FAILURE_TARGETS {
+            // Сначала вытесняются одиночные неудачи и истекшие отключения
+            let now = Instant::now();
+            targets.retain(|_, state| state.disabled_until.is_some_and(|until| now < until));
+            if targets.len() >= MAX_FAILURE_TARGETS {
+                return;
+            }
+        }
+        let state = targets.entry(sni.to_string()).or_default();
+        state.failures += 1;
+
+        if state.failures >= self.threshold && state.disabled_until.is_none() {
+            log::warn!("{} reset {} fingerprinted connections, passthrough for {:?}",
+                sni, state.failures, self.cooldown);
+            state.disabled_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+
+    pub fn record_success(&self, sni: &str) {
+        let mut targets = self.targets.lock();
+        if targets.get(sni).is_some_and(|state| state.disabled_until.is_none()) {
+            targets.remove(sni);
+        }
     }
 
-    pub fn clear(&self) {
-        self.tickets.write().clear();
+    pub fn is_passthrough(&self, sni: &str) -> bool {
+        self.targets.lock().get(sni)
+            .and_then(|state| state.disabled_until)
+            .is_some_and(|until| Instant::now() < until)
     }
 }
 
+/// Годится ли имя для SNI: IP литералы там запрещены (RFC 6066, 3)
+pub fn is_sni_hostname(domain: &str) -> bool {
+    !domain.is_empty() && domain.parse::<std::net::IpAddr>().is_err()
+}
+
+/// Первые байты upstream после ClientHello - TLS alert, т.е. отказ в рукопожатии
+pub fn is_handshake_rejection(response: &[u8]) -> bool {
+    response.first() == Some(&TLS_ALERT)
+}
+
 impl TlsClientHello {
     pub fn parse(data: &[u8]) -> Result<Self> {
         if data.len() < 43 {
@@ -111,6 +418,9 @@ impl TlsClientHello {
             return Err(anyhow::anyhow!("Not a ClientHello"));
         }
 
+        let record_version = [data[1], data[2]];
+        let version = [handshake_data[4], handshake_data[5]];
+
         let mut offset = 6;
         
         let mut random = [0u8; 32];
@@ -179,7 +489,8 @@ impl TlsClientHello {
         }
 
         Ok(Self {
-            version: TLS_VERSION_1_2,
+            record_version,
+            version,
             random,
             session_id,
             cipher_suites,
@@ -188,19 +499,368 @@ impl TlsClientHello {
         })
     }
 
+    /// Строгая проверка сгенерированного record: parse() снисходителен к
+    /// обрезанным данным, здесь же все длины обязаны сходиться точно
+    pub fn verify_generated(data: &[u8], domain: &str) -> Result<Self> {
+        fn read_u16(data: &[u8], offset: usize) -> Result<usize> {
+            data.get(offset..offset + 2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+                .ok_or_else(|| anyhow::anyhow!("Truncated at offset {}", offset))
+        }
+
+        if data.len() < 9 || data[0] != TLS_HANDSHAKE || data[5] != CLIENT_HELLO {
+            return Err(anyhow::anyhow!("Not a TLS ClientHello record"));
+        }
+        let record_len = read_u16(data, 3)?;
+        if record_len != data.len() - 5 {
+            return Err(anyhow::anyhow!("Record length {} != payload {}", record_len, data.len() - 5));
+        }
+        let body = &data[9..];
+        let handshake_len = u32::from_be_bytes([0, data[6], data[7], data[8]]) as usize;
+        if handshake_len != body.len() {
+            return Err(anyhow::anyhow!("Handshake length {} != body {}", handshake_len, body.len()));
+        }
+
+        // version(2) + random(32)
+        let mut offset = 34;
+        let session_id_len = *body.get(offset).ok_or_else(|| anyhow::anyhow!("Truncated session id"))? as usize;
+        offset += 1 + session_id_len;
+        let cipher_suites_len = read_u16(body, offset)?;
+        if cipher_suites_len == 0 || cipher_suites_len % 2 != 0 {
+            return Err(anyhow::anyhow!("Invalid cipher suites length {}", cipher_suites_len));
+        }
+        offset += 2 + cipher_suites_len;
+        let compression_len = *body.get(offset).ok_or_else(|| anyhow::anyhow!("Truncated compression methods"))? as usize;
+        offset += 1 + compression_len;
+        let extensions_len = read_u16(body, offset)?;
+        offset += 2;
+        if offset + extensions_len != body.len() {
+            return Err(anyhow::anyhow!("Extensions length {} != remaining {}",
+                extensions_len, body.len().saturating_sub(offset)));
+        }
+
+        let mut seen = HashSet::new();
+        while offset < body.len() {
+            let ext_type = read_u16(body, offset)?;
+            let ext_len = read_u16(body, offset + 2)?;
+            offset += 4 + ext_len;
+            if offset > body.len() {
+                return Err(anyhow::anyhow!("Extension {} overruns ClientHello", ext_type));
+            }
+            if !seen.insert(ext_type) {
+                return Err(anyhow::anyhow!("Duplicate extension {}", ext_type));
+            }
+        }
+
+        let hello = Self::parse(data)?;
+        if is_sni_hostname(domain) && hello.server_name().as_deref() != Some(domain) {
+            return Err(anyhow::anyhow!("SNI {:?} != {}", hello.server_name(), domain));
+        }
+        Ok(hello)
+    }
+
+    /// PSK binder - HMAC по всему ClientHello: любая правка делает его (и 0-RTT
+    /// данные за ним) недействительным, такой ClientHello передается как есть
+    pub fn offers_psk(&self) -> bool {
+        self.find_extension(EXT_PRE_SHARED_KEY).is_some()
+            || self.find_extension(EXT_EARLY_DATA).is_some()
+    }
+
+    /// host_name из SNI extension
+    pub fn server_name(&self) -> Option<String> {
+        let data = &self.find_extension(EXT_SERVER_NAME)?.data;
+        // list length(2) + name type(1) + name length(2)
+        if data.len() < 5 || data[2] != 0 {
+            return None;
+        }
+        let len = u16::from_be_bytes([data[3], data[4]]) as usize;
+        let name = data.get(5..5 + len)?;
+        String::from_utf8(name.to_vec()).ok()
+    }
+
+    /// ClientHello целиком из профиля, без клиентского: random, session id и
+    /// key_share синтезируются из `rng`. Extensions идут в порядке профиля
+    pub fn from_profile<R: Rng>(profile: &FingerprintProfile, sni: &str, rng: &mut R) -> Result<Self> {
+        let groups = lookup_names(&profile.key_share_groups, "group", named_group_id)?;
+        let versions = lookup_names(&profile.supported_versions, "TLS version", parse_version_name)?;
+
+        let mut extensions = Vec::new();
+        for name in &profile.extensions {
+            let extension_type = extension_id(name)
+                .ok_or_else(|| anyhow::anyhow!("Unknown extension in profile: {}", name))?;
+            let mut data = BytesMut::new();
+
+            match extension_type {
+                EXT_SERVER_NAME if !is_sni_hostname(sni) => continue,
+                EXT_SERVER_NAME => {
+                    data.put_u16(u16_len(sni.len() + 3, "SNI")?);
+                    data.put_u8(0);
+                    data.put_u16(sni.len() as u16);
+                    data.put_slice(sni.as_bytes());
+                }
+                // OCSP, без responder_id и extensions
+                EXT_STATUS_REQUEST => data.put_slice(&[0x01, 0x00, 0x00, 0x00, 0x00]),
+                EXT_SUPPORTED_GROUPS => Self::put_u16_list(&mut data, &groups)?,
+                EXT_EC_POINT_FORMATS => data.put_slice(&[0x01, 0x00]),
+                EXT_SIGNATURE_ALGORITHMS => {
+                    let schemes = lookup_names(&profile.signature_algorithms, "signature algorithm", signature_scheme_id)?;
+                    Self::put_u16_list(&mut data, &schemes)?;
+                }
+                EXT_ALPN => {
+                    let mut protocols = BytesMut::new();
+                    for protocol in &profile.alpn {
+                        let len = u8::try_from(protocol.len())
+                            .map_err(|_| anyhow::anyhow!("ALPN protocol too long: {}", protocol))?;
+                        protocols.put_u8(len);
+                        protocols.put_slice(protocol.as_bytes());
+                    }
+                    data.put_u16(u16_len(protocols.len(), "ALPN")?);
+                    data.put_slice(&protocols);
+                }
+                EXT_KEY_SHARE => {
+                    let mut shares = BytesMut::new();
+                    for &group in &groups {
+                        let mut key = vec![0u8; key_share_len(group)];
+                        rng.fill(&mut key[..]);
+                        if group != 0x001d && group != 0x001e {
+                            key[0] = 0x04;
+                        }
+                        shares.put_u16(group);
+                        shares.put_u16(key.len() as u16);
+                        shares.put_slice(&key);
+                    }
+                    data.put_u16(u16_len(shares.len(), "key_share")?);
+                    data.put_slice(&shares);
+                }
+                EXT_PSK_KEY_EXCHANGE_MODES => {
+                    let modes = lookup_names(&profile.psk_key_exchange_modes, "PSK mode", psk_mode_id)?;
+                    data.put_u8(modes.len() as u8);
+                    data.put_slice(&modes);
+                }
+                EXT_SUPPORTED_VERSIONS => data.put_slice(&Self::encode_supported_versions(&versions)),
+                EXT_COMPRESS_CERTIFICATE => {
+                    let algorithms = lookup_names(&profile.compress_certificate, "certificate compression", cert_compression_id)?;
+                    data.put_u8((algorithms.len() * 2) as u8);
+                    for algorithm in algorithms {
+                        data.put_u16(algorithm);
+                    }
+                }
+                0xff01 => data.put_u8(0),
+                _ => {}
+            }
+
+            extensions.push(TlsExtension { extension_type, data: data.to_vec() });
+        }
+
+        let mut random = [0u8; 32];
+        rng.fill(&mut random);
+        let mut session_id = vec![0u8; 32];
+        rng.fill(&mut session_id[..]);
+
+        Ok(Self {
+            record_version: TLS_VERSION_1_0,
+            version: TLS_VERSION_1_2,
+            random,
+            session_id,
+            cipher_suites: lookup_names(&profile.cipher_suites, "cipher suite", cipher_suite_id)?,
+            compression_methods: vec![0],
+            extensions,
+        })
+    }
+
+    fn put_u16_list(data: &mut BytesMut, values: &[u16]) -> Result<()> {
+        data.put_u16(u16_len(values.len() * 2, "List")?);
+        for value in values {
+            data.put_u16(*value);
+        }
+        Ok(())
+    }
+
     /// Совместимая версия - минимальные изменения оригинального ClientHello
-    pub fn to_ios_safari(&self, _ticket_cache: Option<&SessionTicketCache>, domain: &str) -> Result<Vec<u8>> {
+    pub fn to_ios_safari(
+        &self,
+        domain: &str,
+        options: &ClientHelloOptions,
+    ) -> Result<Vec<u8>> {
+        let mut grease_rng = options.grease.rng();
+        let (grease, positions) = match options.grease {
+            GreaseMode::Random => self.client_grease(options.grease_positions),
+            _ => (GreaseValues::from_rng(&mut grease_rng), options.grease_positions),
+        };
+
+        // Cipher Suites - GREASE первым, затем ОРИГИНАЛЬНЫЕ + TLS 1.3 в начало
+        let mut ciphers = Vec::new();
+        if positions.cipher_first {
+            ciphers.push(grease.cipher);
+        }
+        
+        ciphers.extend(self.ios_cipher_suites(&options.cipher_suites));
+
+        // Extensions - ОРИГИНАЛЬНЫЕ в порядке профиля, обновляем SNI и GREASE.
+        // Session ticket идет только клиентский: к чужому у клиента нет секрета
+        let mut extensions = self.build_ios_extensions(domain, &grease, positions, options);
+
+        if options.ech_grease {
+            Self::apply_ech_grease(&mut extensions, &mut grease_rng);
+        }
+
+        if options.padding_boundary > 0 {
+            self.apply_padding(&mut extensions, &ciphers, options.padding_boundary)?;
+        }
+
+        self.encode_record(TLS_VERSION_1_0, TLS_VERSION_1_2, &ciphers, &extensions)
+    }
+
+    /// GREASE в режиме Random: случайные значения уже выбрал сам клиент, они и
+    /// переносятся. Позиция, для которой у клиента GREASE не было, остается пустой
+    fn client_grease(&self, positions: GreasePositions) -> (GreaseValues, GreasePositions) {
+        let cipher = self.cipher_suites.iter().copied().find(|c| is_grease(*c));
+        let extensions: Vec<u16> = self.extensions.iter()
+            .map(|e| e.extension_type)
+            .filter(|t| is_grease(*t))
+            .collect();
+        let first_extension = extensions.first().copied();
+        let last_extension = extensions.get(1..).and_then(|rest| rest.last()).copied();
+
+        let values = GreaseValues {
+            cipher: cipher.unwrap_or(grease_value(0)),
+            first_extension: first_extension.unwrap_or(grease_value(0)),
+            last_extension: last_extension.unwrap_or(grease_value(0)),
+        };
+        let positions = GreasePositions {
+            cipher_first: positions.cipher_first && cipher.is_some(),
+            extension_first: positions.extension_first && first_extension.is_some(),
+            extension_last: positions.extension_last && last_extension.is_some(),
+        };
+        (values, positions)
+    }
+
+    /// Шифры в порядке профиля. Шифр, которого клиент не предлагал, сервер
+    /// выбрать не должен, поэтому из профиля берутся только предложенные клиентом
+    /// и TLS 1.3 наборы (если клиент не ограничен TLS 1.2). Без профиля или без
+    /// общих шифров - все клиентские
+    fn ios_cipher_suites(&self, profile_ciphers: &[u16]) -> Vec<u16> {
+        let offers_tls13 = self.supported_versions()
+            .map(|versions| versions.contains(&TLS_1_3))
+            .unwrap_or(true);
+        let client_ciphers: Vec<u16> = self.cipher_suites.iter()
+            .copied()
+            .filter(|c| !is_grease(*c))
+            .collect();
+
+        let ciphers: Vec<u16> = profile_ciphers.iter()
+            .copied()
+            .filter(|c| client_ciphers.contains(c) || (offers_tls13 && TLS13_CIPHERS.contains(c)))
+            .collect();
+        if ciphers.iter().any(|c| client_ciphers.contains(c)) {
+            return ciphers;
+        }
+
+        // Добавляем TLS 1.3 ciphers в начало (если их нет и клиент не ограничен TLS 1.2)
+        let mut ciphers: Vec<u16> = TLS13_CIPHERS.iter()
+            .copied()
+            .filter(|c| offers_tls13 && !client_ciphers.contains(c))
+            .collect();
+        ciphers.extend(client_ciphers);
+        ciphers
+    }
+
+    /// Дополняет handshake до следующей границы `boundary`, как это делает Safari
+    fn apply_padding(&self, extensions: &mut Vec<TlsExtension>, ciphers: &[u16], boundary: usize) -> Result<()> {
+        let position = match extensions.iter().position(|e| e.extension_type == EXT_PADDING) {
+            Some(index) => {
+                extensions.remove(index);
+                index
+            }
+            None => Self::tail_insert_index(extensions),
+        };
+
+        let unpadded_len = self.encode_record(TLS_VERSION_1_0, TLS_VERSION_1_2, ciphers, extensions)?.len() - 5;
+        if unpadded_len % boundary == 0 {
+            return Ok(());
+        }
+
+        // Заголовок extension занимает 4 байта
+        let mut needed = boundary - unpadded_len % boundary;
+        if needed < 4 {
+            needed += boundary;
+        }
+
+        extensions.insert(position, TlsExtension {
+            extension_type: EXT_PADDING,
+            data: vec![0u8; needed - 4],
+        });
+        Ok(())
+    }
+
+    /// ECH мы не расшифровываем: клиентский encrypted_client_hello переносится как есть
+    /// (см. build_ios_extensions). Если его нет - добавляем GREASE ECH в формате Chrome:
+    /// outer, HKDF-SHA256/AES-128-GCM, случайные config_id, enc (X25519) и payload.
+    /// Значения берутся из того же источника, что и остальные GREASE (`GreaseMode`)
+    fn apply_ech_grease<R: Rng>(extensions: &mut Vec<TlsExtension>, rng: &mut R) {
+        if extensions.iter().any(|e| e.extension_type == EXT_ENCRYPTED_CLIENT_HELLO) {
+            return;
+        }
+
+        let payload_len = ECH_GREASE_PAYLOAD_LENGTHS[rng.random_range(0..ECH_GREASE_PAYLOAD_LENGTHS.len())];
+
+        let mut data = BytesMut::new();
+        data.put_u8(0x00);
+        data.put_u16(0x0001);
+        data.put_u16(0x0001);
+        data.put_u8(rng.random());
+
+        let mut enc = [0u8; 32];
+        rng.fill(&mut enc[..]);
+        data.put_u16(enc.len() as u16);
+        data.put_slice(&enc);
+
+        let mut payload = vec![0u8; payload_len];
+        rng.fill(&mut payload[..]);
+        data.put_u16(payload_len as u16);
+        data.put_slice(&payload);
+
+        // Перед завершающим GREASE extension (первый GREASE завершающим не считается)
+        let position = extensions.iter()
+            .rposition(|e| is_grease(e.extension_type))
+            .filter(|&index| index > 0)
+            .unwrap_or_else(|| Self::tail_insert_index(extensions));
+
+        extensions.insert(position, TlsExtension {
+            extension_type: EXT_ENCRYPTED_CLIENT_HELLO,
+            data: data.to_vec(),
+        });
+    }
+
+    /// pre_shared_key обязан быть последним extension
+    fn tail_insert_index(extensions: &[TlsExtension]) -> usize {
+        match extensions.last() {
+            Some(ext) if ext.extension_type == EXT_PRE_SHARED_KEY => extensions.len() - 1,
+            _ => extensions.len(),
+        }
+    }
+
+    /// Восстанавливает исходный record из распарсенных полей (для диагностики)
+    pub fn reserialize_original(&self) -> Result<Vec<u8>> {
+        self.encode_record(self.record_version, self.version, &self.cipher_suites, &self.extensions)
+    }
+
+    fn encode_record(
+        &self,
+        record_version: [u8; 2],
+        version: [u8; 2],
+        ciphers: &[u16],
+        extensions: &[TlsExtension],
+    ) -> Result<Vec<u8>> {
         let mut result = BytesMut::new();
         result.put_u8(TLS_HANDSHAKE);
-        result.put_slice(&TLS_VERSION_1_0);
+        result.put_slice(&record_version);
         
         let mut handshake = BytesMut::new();
         handshake.put_u8(CLIENT_HELLO);
         
         let mut client_hello = BytesMut::new();
-        
-        // Используем оригинальную версию
-        client_hello.put_slice(&TLS_VERSION_1_2);
+        client_hello.put_slice(&version);
         
         // Сохраняем оригинальный random (ВАЖНО для session resumption)
         client_hello.put_slice(&self.random);
@@ -211,33 +871,17 @@ impl TlsClientHello {
             client_hello.put_slice(&self.session_id);
         }
         
-        // Cipher Suites - используем ОРИГИНАЛЬНЫЕ + добавляем TLS 1.3 в начало
-        let mut ciphers = Vec::new();
-        
-        // Добавляем TLS 1.3 ciphers в начало (если их нет)
-        let tls13_ciphers = vec![0x1301, 0x1302, 0x1303];
-        for cipher in &tls13_ciphers {
-            if !self.cipher_suites.contains(cipher) {
-                ciphers.push(*cipher);
-            }
-        }
-        
-        // Добавляем все оригинальные cipher suites
-        ciphers.extend_from_slice(&self.cipher_suites);
-        
         client_hello.put_u16(ciphers.len() as u16 * 2);
         for cipher in ciphers {
-            client_hello.put_u16(cipher);
+            client_hello.put_u16(*cipher);
         }
         
         // Compression - оригинальный
         client_hello.put_u8(self.compression_methods.len() as u8);
         client_hello.put_slice(&self.compression_methods);
         
-        // Extensions - ИСПОЛЬЗУЕМ ОРИГИНАЛЬНЫЕ, только обновляем SNI
-        let extensions = self.update_sni_in_extensions(domain);
-        let extensions_bytes = Self::serialize_extensions(&extensions);
-        client_hello.put_u16(extensions_bytes.len() as u16);
+        let extensions_bytes = Self::serialize_extensions(extensions)?;
+        client_hello.put_u16(u16_len(extensions_bytes.len(), "Extensions")?);
         client_hello.put_slice(&extensions_bytes);
         
         let ch_len = client_hello.len();
@@ -246,14 +890,234 @@ impl TlsClientHello {
         handshake.put_u8(ch_len as u8);
         handshake.put_slice(&client_hello);
         
-        result.put_u16(handshake.len() as u16);
+        // Больше 16 КБ допустимо только с последующей fragment_record
+        result.put_u16(u16_len(handshake.len(), "ClientHello handshake")?);
         result.put_slice(&handshake);
         
         Ok(result.to_vec())
     }
 
+    /// Оригинальные extensions с обновлённым SNI и GREASE в первой и последней позиции.
+    /// С профилем - в его порядке; чего нет в профиле, отбрасывается, кроме
+    /// REQUIRED_EXTENSIONS. Недостающих у клиента extensions не добавляем: сервер
+    /// ответил бы на возможность, которой у клиента нет
+    fn build_ios_extensions(
+        &self,
+        domain: &str,
+        grease: &GreaseValues,
+        positions: GreasePositions,
+        options: &ClientHelloOptions,
+    ) -> Vec<TlsExtension> {
+        let order = &options.extension_order;
+        let rank = |extension_type: u16| match extension_type {
+            EXT_PRE_SHARED_KEY => order.len() + 1,
+            _ => order.iter().position(|t| *t == extension_type).unwrap_or(order.len()),
+        };
+
+        let mut extensions = Vec::new();
+        if positions.extension_first {
+            extensions.push(TlsExtension {
+                extension_type: grease.first_extension,
+                data: Vec::new(),
+            });
+        }
+
+        let mut client_extensions: Vec<TlsExtension> = self.update_sni_in_extensions(domain)
+            .into_iter()
+            .filter(|ext| !is_grease(ext.extension_type))
+            .filter(|ext| {
+                order.is_empty()
+                    || order.contains(&ext.extension_type)
+                    || REQUIRED_EXTENSIONS.contains(&ext.extension_type)
+            })
+            .map(|mut ext| {
+                if ext.extension_type == EXT_SUPPORTED_VERSIONS {
+                    ext.data = Self::encode_supported_versions(&self.ordered_versions(&options.version_order));
+                }
+                ext
+            })
+            .collect();
+        if !order.is_empty() {
+            client_extensions.sort_by_key(|ext| rank(ext.extension_type));
+        }
+        extensions.extend(client_extensions);
+
+        if positions.extension_last {
+            let tail = Self::tail_insert_index(&extensions);
+            extensions.insert(tail, TlsExtension {
+                extension_type: grease.last_extension,
+                data: vec![0x00],
+            });
+        }
+
+        extensions
+    }
+
+    /// Версии из клиентского supported_versions (None, если extension нет или он битый)
+    pub fn supported_versions(&self) -> Option<Vec<u16>> {
+        let ext = self.extensions.iter().find(|e| e.extension_type == EXT_SUPPORTED_VERSIONS)?;
+        let len = *ext.data.first()? as usize;
+
+        if len == 0 || len % 2 != 0 || ext.data.len() < 1 + len {
+            return None;
+        }
+
+        Some(
+            ext.data[1..1 + len]
+                .chunks(2)
+                .map(|v| u16::from_be_bytes([v[0], v[1]]))
+                .collect(),
+        )
+    }
+
+    fn find_extension(&self, extension_type: u16) -> Option<&TlsExtension> {
+        self.extensions.iter().find(|e| e.extension_type == extension_type)
+    }
+
+    /// Список u16 с 2-байтовой длиной (supported_groups, signature_algorithms)
+    fn extension_u16_list(&self, extension_type: u16) -> Vec<u16> {
+        let Some(ext) = self.find_extension(extension_type) else {
+            return Vec::new();
+        };
+        if ext.data.len() < 2 {
+            return Vec::new();
+        }
+
+        let len = (u16::from_be_bytes([ext.data[0], ext.data[1]]) as usize).min(ext.data.len() - 2);
+        ext.data[2..2 + len]
+            .chunks_exact(2)
+            .map(|v| u16::from_be_bytes([v[0], v[1]]))
+            .collect()
+    }
+
+    /// Первый протокол из ALPN
+    fn first_alpn(&self) -> Option<&[u8]> {
+        let data = &self.find_extension(EXT_ALPN)?.data;
+        let len = *data.get(2)? as usize;
+        data.get(3..3 + len)
+    }
+
+    /// JA3 строка (до MD5): версия,шифры,extensions,группы,форматы точек, без GREASE
+    pub fn ja3_string(&self) -> String {
+        fn join(values: impl Iterator<Item = u16>) -> String {
+            values.map(|v| v.to_string()).collect::<Vec<_>>().join("-")
+        }
+
+        let formats = self.find_extension(EXT_EC_POINT_FORMATS)
+            .and_then(|ext| ext.data.get(1..))
+            .unwrap_or_default();
+
+        format!(
+            "{},{},{},{},{}",
+            u16::from_be_bytes(self.version),
+            join(self.cipher_suites.iter().copied().filter(|c| !is_grease(*c))),
+            join(self.extensions.iter().map(|e| e.extension_type).filter(|t| !is_grease(*t))),
+            join(self.extension_u16_list(EXT_SUPPORTED_GROUPS).into_iter().filter(|g| !is_grease(*g))),
+            join(formats.iter().map(|f| *f as u16)),
+        )
+    }
+
+    /// JA4 (TCP): `a_b_c`, где a - протокол, версия, SNI, число шифров и
+    /// extensions, ALPN; b - хэш отсортированных шифров; c - хэш отсортированных
+    /// extensions (без SNI и ALPN) и алгоритмов подписи в исходном порядке
+    pub fn ja4_string(&self) -> String {
+        let version = self.supported_versions()
+            .and_then(|versions| versions.into_iter().filter(|v| !is_grease(*v)).max())
+            .unwrap_or_else(|| u16::from_be_bytes(self.version));
+        let version = match version {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            0x0002 => "s2",
+            _ => "00",
+        };
+
+        let sni = if self.find_extension(EXT_SERVER_NAME).is_some() { 'd' } else { 'i' };
+
+        let mut ciphers: Vec<u16> = self.cipher_suites.iter().copied().filter(|c| !is_grease(*c)).collect();
+        let extension_types: Vec<u16> = self.extensions.iter()
+            .map(|e| e.extension_type)
+            .filter(|t| !is_grease(*t))
+            .collect();
+
+        let alpn = match self.first_alpn() {
+            Some(alpn) if !alpn.is_empty() => {
+                let (first, last) = (alpn[0], alpn[alpn.len() - 1]);
+                if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                    format!("{}{}", first as char, last as char)
+                } else {
+                    let first_hex = format!("{:02x}", first);
+                    let last_hex = format!("{:02x}", last);
+                    format!("{}{}", &first_hex[..1], &last_hex[1..])
+                }
+            }
+            _ => "00".to_string(),
+        };
+
+        let ja4_a = format!(
+            "t{}{}{:02}{:02}{}",
+            version,
+            sni,
+            ciphers.len().min(99),
+            extension_types.len().min(99),
+            alpn,
+        );
+
+        ciphers.sort_unstable();
+        let ja4_b = ja4_hash(&hex_list(&ciphers));
+
+        let mut sorted_extensions: Vec<u16> = extension_types.into_iter()
+            .filter(|t| *t != EXT_SERVER_NAME && *t != EXT_ALPN)
+            .collect();
+        sorted_extensions.sort_unstable();
+        let signature_algorithms = hex_list(&self.extension_u16_list(EXT_SIGNATURE_ALGORITHMS));
+        let ja4_c = if sorted_extensions.is_empty() {
+            ja4_hash("")
+        } else if signature_algorithms.is_empty() {
+            ja4_hash(&hex_list(&sorted_extensions))
+        } else {
+            ja4_hash(&format!("{}_{}", hex_list(&sorted_extensions), signature_algorithms))
+        };
+
+        format!("{}_{}_{}", ja4_a, ja4_b, ja4_c)
+    }
+
+    /// Клиентские версии в порядке профиля: GREASE первым, неизвестные профилю - в конце
+    fn ordered_versions(&self, version_order: &[u16]) -> Vec<u16> {
+        let mut versions = self.supported_versions()
+            .unwrap_or_else(|| IOS_SUPPORTED_VERSIONS.to_vec());
+
+        versions.sort_by_key(|v| {
+            if is_grease(*v) {
+                0
+            } else {
+                version_order.iter()
+                    .position(|o| o == v)
+                    .map(|pos| pos + 1)
+                    .unwrap_or(version_order.len() + 1)
+            }
+        });
+
+        versions
+    }
+
+    fn encode_supported_versions(versions: &[u16]) -> Vec<u8> {
+        let mut data = vec![(versions.len() * 2) as u8];
+        for version in versions {
+            data.extend_from_slice(&version.to_be_bytes());
+        }
+        data
+    }
+
     /// Обновляет только SNI extension, остальные сохраняет
     fn update_sni_in_extensions(&self, domain: &str) -> Vec<TlsExtension> {
+        // Без имени хоста SNI остается таким, каким его прислал клиент
+        if !is_sni_hostname(domain) {
+            return self.extensions.clone();
+        }
+
         let mut extensions = Vec::new();
         let mut sni_found = false;
         
@@ -294,21 +1158,26 @@ impl TlsClientHello {
         extensions
     }
 
-    fn serialize_extensions(extensions: &[TlsExtension]) -> Vec<u8> {
+    /// Длины пишутся в u16: extension или список больше 65535 байт - ошибка,
+    /// а не молча обрезанная длина
+    fn serialize_extensions(extensions: &[TlsExtension]) -> Result<Vec<u8>> {
         let mut result = Vec::new();
         
         for ext in extensions {
+            let len = u16_len(ext.data.len(), "Extension")
+                .map_err(|e| anyhow::anyhow!("{} (type 0x{:04x})", e, ext.extension_type))?;
             result.extend_from_slice(&ext.extension_type.to_be_bytes());
-            result.extend_from_slice(&(ext.data.len() as u16).to_be_bytes());
+            result.extend_from_slice(&len.to_be_bytes());
             result.extend_from_slice(&ext.data);
         }
         
-        result
+        u16_len(result.len(), "Extensions")?;
+        Ok(result)
     }
 
     pub fn extract_session_ticket(&self) -> Option<Vec<u8>> {
         for ext in &self.extensions {
-            if ext.extension_type == 35 && !ext.data.is_empty() {
+            if ext.extension_type == EXT_SESSION_TICKET && !ext.data.is_empty() {
                 return Some(ext.data.clone());
             }
         }
@@ -316,18 +1185,562 @@ impl TlsClientHello {
     }
 }
 
+/// Длина для u16 поля или ошибка вместо молчаливого переполнения
+fn u16_len(len: usize, what: &str) -> Result<u16> {
+    u16::try_from(len).map_err(|_| anyhow::anyhow!("{} length {} exceeds 65535 bytes", what, len))
+}
+
+/// Разбивает TLS record на несколько: первый размером `first_record_size`, остаток по MAX_RECORD_PAYLOAD
+pub fn fragment_record(record: &[u8], first_record_size: usize) -> Vec<u8> {
+    if record.len() < 5 || first_record_size == 0 {
+        return record.to_vec();
+    }
+
+    let payload = &record[5..];
+    if first_record_size >= payload.len() {
+        return record.to_vec();
+    }
+
+    let (first, rest) = payload.split_at(first_record_size);
+    let mut result = Vec::with_capacity(record.len() + 5 * (1 + rest.len() / MAX_RECORD_PAYLOAD));
+
+    for chunk in std::iter::once(first).chain(rest.chunks(MAX_RECORD_PAYLOAD)) {
+        result.extend_from_slice(&record[..3]);
+        result.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+        result.extend_from_slice(chunk);
+    }
+
+    result
+}
+
+/// Переписывает ClientHello, перехваченный на уровне пакетов (NFQUEUE), в
+/// отпечаток профиля; HTTP/TCP уровень прокси для этого не нужен
+pub struct TlsModifier {
+    profile: FingerprintProfile,
+}
+
+impl TlsModifier {
+    pub fn new(profile: FingerprintProfile) -> Self {
+        Self { profile }
+    }
+
+    /// Начало TLS record с handshake сообщением ClientHello
+    pub fn is_client_hello(data: &[u8]) -> bool {
+        data.len() > 5 && data[0] == TLS_HANDSHAKE && data[1] == 0x03 && data[5] == CLIENT_HELLO
+    }
+
+    /// Заменяет первый record в `data` на ClientHello профиля с тем же SNI;
+    /// байты после record сохраняются. При ошибке `data` не меняется
+    pub fn modify_client_hello(&self, data: &mut Vec<u8>) -> Result<()> {
+        if !Self::is_client_hello(data) {
+            return Err(anyhow::anyhow!("Not a TLS ClientHello"));
+        }
+        let record_len = 5 + u16::from_be_bytes([data[3], data[4]]) as usize;
+        if data.len() < record_len {
+            return Err(anyhow::anyhow!(
+                "Incomplete ClientHello record: {} of {} bytes", data.len(), record_len
+            ));
+        }
+
+        let hello = TlsClientHello::parse(&data[..record_len])?;
+        let sni = hello.server_name().unwrap_or_default();
+        let options = self.profile.client_hello_options(GreaseMode::default(), 0);
+        let mut modified = hello.to_ios_safari(&sni, &options)?;
+
+        modified.extend_from_slice(&data[record_len..]);
+        *data = modified;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
+    fn wrap_record(content_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut record = vec![content_type, 0x03, 0x03];
+        record.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        record.extend_from_slice(payload);
+        record
+    }
+
+    fn wrap_handshake(msg_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut msg = vec![msg_type];
+        msg.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..4]);
+        msg.extend_from_slice(body);
+        msg
+    }
+
+    /// ClientHello для example.com; используется и в тестах proxy
+    pub(crate) fn sample_client_hello() -> Vec<u8> {
+        client_hello_with_extensions(&[])
+    }
+
+    pub(crate) fn client_hello_with_extensions(extra: &[TlsExtension]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&TLS_VERSION_1_2);
+        body.extend_from_slice(&[0x11; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0x00, 0x04, 0x13, 0x01, 0xc0, 0x2b]);
+        body.extend_from_slice(&[0x01, 0x00]);
+
+        let domain = b"example.com";
+        let mut extensions = Vec::new();
+        extensions.extend_from_slice(&[0x00, 0x00]);
+        extensions.extend_from_slice(&((domain.len() + 5) as u16).to_be_bytes());
+        extensions.extend_from_slice(&((domain.len() + 3) as u16).to_be_bytes());
+        extensions.push(0);
+        extensions.extend_from_slice(&(domain.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(domain);
+        extensions.extend_from_slice(&[0x00, 0x23, 0x00, 0x00]);
+        extensions.extend(TlsClientHello::serialize_extensions(extra).unwrap());
+
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut record = wrap_record(TLS_HANDSHAKE, &wrap_handshake(CLIENT_HELLO, &body));
+        record[1..3].copy_from_slice(&TLS_VERSION_1_0);
+        record
+    }
+
     #[test]
-    fn test_session_ticket_cache() {
-        let cache = SessionTicketCache::new();
-        
-        cache.store("example.com".to_string(), vec![1, 2, 3, 4]);
-        
-        let ticket = cache.get("example.com");
-        assert!(ticket.is_some());
-        assert_eq!(ticket.unwrap(), vec![1, 2, 3, 4]);
+    fn test_only_client_ticket_sent() {
+        let ticket = vec![0xAB; 48];
+
+        // Без своего тикета клиент делает полное рукопожатие
+        let hello = TlsClientHello::parse(&sample_client_hello()).unwrap();
+        let modified = hello.to_ios_safari("example.com", &ClientHelloOptions::default()).unwrap();
+        assert_eq!(TlsClientHello::parse(&modified).unwrap().extract_session_ticket(), None);
+
+        let resuming = TlsClientHello::parse(&client_hello_with_extensions(&[
+            TlsExtension { extension_type: EXT_SESSION_TICKET, data: ticket.clone() },
+        ])).unwrap();
+        let modified = resuming.to_ios_safari("example.com", &ClientHelloOptions::default()).unwrap();
+        assert_eq!(TlsClientHello::parse(&modified).unwrap().extract_session_ticket(), Some(ticket));
+    }
+
+    #[test]
+    fn test_tls_modifier_rewrites_client_hello() {
+        let original = sample_client_hello();
+        assert!(TlsModifier::is_client_hello(&original));
+        assert!(!TlsModifier::is_client_hello(b"GET / HTTP/1.1\r\n"));
+        assert!(!TlsModifier::is_client_hello(&wrap_record(TLS_HANDSHAKE, &wrap_handshake(0x02, &[0; 4]))));
+
+        let profile = crate::config::Config::default().get_default_profile().unwrap().clone();
+        let modifier = TlsModifier::new(profile);
+
+        let mut data = original.clone();
+        data.extend_from_slice(b"trailing");
+        modifier.modify_client_hello(&mut data).unwrap();
+        assert!(data.ends_with(b"trailing"));
+
+        let record = &data[..data.len() - b"trailing".len()];
+        assert_ne!(record, &original[..]);
+        TlsClientHello::verify_generated(record, "example.com").unwrap();
+
+        // Неполный record не трогается
+        let mut partial = original[..original.len() - 1].to_vec();
+        assert!(modifier.modify_client_hello(&mut partial).is_err());
+        assert_eq!(partial, &original[..original.len() - 1]);
+    }
+
+    #[test]
+    fn test_profile_drives_ciphers_and_extensions() {
+        let mut hello = TlsClientHello::parse(&sample_client_hello()).unwrap();
+        hello.cipher_suites = vec![0xc030, 0x1301, 0xc02b, 0xc02c];
+        hello.extensions.push(TlsExtension { extension_type: EXT_COMPRESS_CERTIFICATE, data: vec![0x02, 0x00, 0x02] });
+        hello.extensions.push(TlsExtension { extension_type: 23, data: Vec::new() });
+        hello.extensions.push(TlsExtension { extension_type: EXT_KEY_SHARE, data: vec![0x00, 0x00] });
+
+        let generate = |name: &str| {
+            let profile = crate::config::Config::builtin_profile(name).unwrap();
+            let options = ClientHelloOptions {
+                padding_boundary: 0,
+                ..profile.client_hello_options(GreaseMode::PerConnection(1), 1)
+            };
+            TlsClientHello::parse(&hello.to_ios_safari("example.com", &options).unwrap()).unwrap()
+        };
+        let types = |hello: &TlsClientHello| -> Vec<u16> {
+            hello.extensions.iter().map(|e| e.extension_type).filter(|t| !is_grease(*t)).collect()
+        };
+
+        // Порядок профиля; шифры, которых клиент не предлагал, не добавляются
+        let ios16 = generate("ios_16_safari");
+        assert_eq!(&ios16.cipher_suites[1..], &[0x1301, 0x1302, 0x1303, 0xc02c, 0xc02b, 0xc030]);
+        assert_eq!(types(&ios16), vec![EXT_SERVER_NAME, EXT_KEY_SHARE, EXT_SESSION_TICKET]);
+
+        let ios17 = generate("ios_17_safari");
+        assert_eq!(&ios17.cipher_suites[1..], &[0x1301, 0x1302, 0x1303, 0xc02c, 0xc02b]);
+        assert_eq!(types(&ios17), vec![EXT_SERVER_NAME, EXT_KEY_SHARE, EXT_COMPRESS_CERTIFICATE, EXT_SESSION_TICKET]);
+        assert_ne!(ios16.ja3_string(), ios17.ja3_string());
+    }
+
+    #[test]
+    fn test_random_grease_carries_client_values() {
+        let plain = TlsClientHello::parse(&sample_client_hello()).unwrap();
+        let record = plain.to_ios_safari("example.com", &ClientHelloOptions::default()).unwrap();
+        let generated = TlsClientHello::parse(&record).unwrap();
+        assert!(!generated.cipher_suites.iter().any(|c| is_grease(*c)));
+        assert!(!generated.extensions.iter().any(|e| is_grease(e.extension_type)));
+
+        let mut hello = plain.clone();
+        hello.cipher_suites.insert(0, 0x3a3a);
+        hello.extensions.insert(0, TlsExtension { extension_type: 0x5a5a, data: Vec::new() });
+        hello.extensions.push(TlsExtension { extension_type: 0x9a9a, data: vec![0x00] });
+        let record = hello.to_ios_safari("example.com", &ClientHelloOptions::default()).unwrap();
+        let generated = TlsClientHello::parse(&record).unwrap();
+        assert_eq!(generated.cipher_suites[0], 0x3a3a);
+        assert_eq!(generated.extensions[0].extension_type, 0x5a5a);
+        let grease: Vec<u16> = generated.extensions.iter()
+            .map(|e| e.extension_type)
+            .filter(|t| is_grease(*t))
+            .collect();
+        assert_eq!(grease, vec![0x5a5a, 0x9a9a]);
+    }
+
+    #[test]
+    fn test_ip_literal_never_sent_as_sni() {
+        assert!(is_sni_hostname("example.com"));
+        assert!(!is_sni_hostname("2001:db8::1"));
+        assert!(!is_sni_hostname("93.184.216.34"));
+        assert!(!is_sni_hostname(""));
+
+        // CONNECT на IP: остается имя, которое прислал клиент
+        let hello = TlsClientHello::parse(&sample_client_hello()).unwrap();
+        let record = hello.to_ios_safari("2001:db8::1", &ClientHelloOptions::default()).unwrap();
+        let generated = TlsClientHello::verify_generated(&record, "2001:db8::1").unwrap();
+        assert_eq!(generated.server_name().as_deref(), Some("example.com"));
+
+        let profile = crate::config::Config::default().get_default_profile().unwrap().clone();
+        let synthesized = TlsClientHello::from_profile(&profile, "93.184.216.34", &mut rand::rng()).unwrap();
+        assert!(synthesized.find_extension(EXT_SERVER_NAME).is_none());
+    }
+
+    #[test]
+    fn test_generated_hello_passes_self_check() {
+        let hello = TlsClientHello::parse(&sample_client_hello()).unwrap();
+        let options = ClientHelloOptions {
+            grease: GreaseMode::PerConnection(3),
+            ..ClientHelloOptions::default()
+        };
+        let record = hello.to_ios_safari("example.org", &options).unwrap();
+
+        let generated = TlsClientHello::verify_generated(&record, "example.org").unwrap();
+        assert_eq!(generated.server_name().as_deref(), Some("example.org"));
+        assert_eq!(generated.cipher_suites[0], GreaseValues::generate(GreaseMode::PerConnection(3)).cipher);
+        for cipher in hello.cipher_suites.iter().filter(|c| !is_grease(**c)) {
+            assert!(generated.cipher_suites.contains(cipher));
+        }
+        for ext in hello.extensions.iter().filter(|e| !is_grease(e.extension_type) && e.extension_type != EXT_PADDING) {
+            assert!(generated.find_extension(ext.extension_type).is_some(), "missing {}", ext.extension_type);
+        }
+
+        assert!(TlsClientHello::verify_generated(&record, "other.org").is_err());
+        assert!(TlsClientHello::verify_generated(&record[..record.len() - 1], "example.org").is_err());
+
+        let mut bad_extensions = record.clone();
+        let ext_len_offset = 9 + 34 + 1 + generated.session_id.len()
+            + 2 + generated.cipher_suites.len() * 2 + 1 + generated.compression_methods.len();
+        bad_extensions[ext_len_offset + 1] = bad_extensions[ext_len_offset + 1].wrapping_add(1);
+        assert!(TlsClientHello::verify_generated(&bad_extensions, "example.org").is_err());
+    }
+
+    #[test]
+    fn test_grease_per_connection() {
+        let hello = TlsClientHello::parse(&sample_client_hello()).unwrap();
+        let options = |conn_id| ClientHelloOptions {
+            grease: GreaseMode::PerConnection(conn_id),
+            padding_boundary: 0,
+            ..ClientHelloOptions::default()
+        };
+
+        let first = hello.to_ios_safari("example.com", &options(7)).unwrap();
+        let second = hello.to_ios_safari("example.com", &options(7)).unwrap();
+        assert_eq!(first, second);
+
+        let values: Vec<GreaseValues> = (0..8)
+            .map(|id| GreaseValues::generate(GreaseMode::PerConnection(id)))
+            .collect();
+        assert!(values.iter().any(|v| *v != values[0]));
+
+        let reparsed = TlsClientHello::parse(&first).unwrap();
+        let expected = GreaseValues::generate(GreaseMode::PerConnection(7));
+        assert_eq!(reparsed.cipher_suites[0], expected.cipher);
+        assert_eq!(reparsed.extensions.first().unwrap().extension_type, expected.first_extension);
+        assert_eq!(reparsed.extensions.last().unwrap().extension_type, expected.last_extension);
+        assert!(is_grease(expected.cipher));
+    }
+
+    #[test]
+    fn test_padding_to_boundary() {
+        let hello = TlsClientHello::parse(&sample_client_hello()).unwrap();
+
+        // Padding только по явной настройке
+        let record = hello.to_ios_safari("example.com", &ClientHelloOptions::default()).unwrap();
+        let reparsed = TlsClientHello::parse(&record).unwrap();
+        assert!(!reparsed.extensions.iter().any(|e| e.extension_type == EXT_PADDING));
+
+        for boundary in [512, 256] {
+            let options = ClientHelloOptions {
+                padding_boundary: boundary,
+                ..ClientHelloOptions::default()
+            };
+            let record = hello.to_ios_safari("example.com", &options).unwrap();
+            let record_len = u16::from_be_bytes([record[3], record[4]]) as usize;
+
+            assert_eq!(record_len, boundary);
+            assert_eq!(record.len(), 5 + boundary);
+
+            let reparsed = TlsClientHello::parse(&record).unwrap();
+            let padding = reparsed.extensions.iter().find(|e| e.extension_type == EXT_PADDING).unwrap();
+            assert!(padding.data.iter().all(|b| *b == 0));
+        }
+    }
+
+    #[test]
+    fn test_ech_passthrough_and_grease() {
+        let client_ech = TlsExtension {
+            extension_type: EXT_ENCRYPTED_CLIENT_HELLO,
+            data: vec![0x00, 0x00, 0x01, 0x00, 0x01, 0x2a, 0x00, 0x02, 0xde, 0xad, 0x00, 0x01, 0xff],
+        };
+        let options = ClientHelloOptions {
+            ech_grease: true,
+            ..ClientHelloOptions::default()
+        };
+
+        let with_ech = TlsClientHello::parse(&client_hello_with_extensions(&[client_ech.clone()])).unwrap();
+        let record = with_ech.to_ios_safari("example.com", &options).unwrap();
+        let echs: Vec<TlsExtension> = TlsClientHello::parse(&record).unwrap().extensions.into_iter()
+            .filter(|e| e.extension_type == EXT_ENCRYPTED_CLIENT_HELLO)
+            .collect();
+        assert_eq!(echs.len(), 1);
+        assert_eq!(echs[0].data, client_ech.data);
+
+        let without_ech = TlsClientHello::parse(&sample_client_hello()).unwrap();
+        let record = without_ech.to_ios_safari("example.com", &options).unwrap();
+        let extensions = TlsClientHello::parse(&record).unwrap().extensions;
+        let grease_ech = extensions.iter().find(|e| e.extension_type == EXT_ENCRYPTED_CLIENT_HELLO).unwrap();
+        assert_eq!(grease_ech.data[0], 0x00);
+        assert!(ECH_GREASE_PAYLOAD_LENGTHS.iter().any(|len| grease_ech.data.len() == 10 + 32 + len));
+
+        let record = without_ech.to_ios_safari("example.com", &ClientHelloOptions::default()).unwrap();
+        assert!(TlsClientHello::parse(&record).unwrap().extensions.iter()
+            .all(|e| e.extension_type != EXT_ENCRYPTED_CLIENT_HELLO));
+
+        // GREASE ECH следует GreaseMode, как и остальные GREASE
+        let grease_ech = |conn_id| {
+            let options = ClientHelloOptions {
+                grease: GreaseMode::PerConnection(conn_id),
+                ech_grease: true,
+                ..ClientHelloOptions::default()
+            };
+            let record = without_ech.to_ios_safari("example.com", &options).unwrap();
+            TlsClientHello::parse(&record).unwrap().extensions.into_iter()
+                .find(|e| e.extension_type == EXT_ENCRYPTED_CLIENT_HELLO)
+                .unwrap()
+                .data
+        };
+        assert_eq!(grease_ech(5), grease_ech(5));
+        assert_ne!(grease_ech(5), grease_ech(6));
+    }
+
+    #[test]
+    fn test_grease_positions_configurable() {
+        let hello = TlsClientHello::parse(&sample_client_hello()).unwrap();
+
+        for bits in 0..8u8 {
+            let positions = GreasePositions {
+                cipher_first: bits & 1 != 0,
+                extension_first: bits & 2 != 0,
+                extension_last: bits & 4 != 0,
+            };
+            let options = ClientHelloOptions {
+                grease: GreaseMode::PerConnection(1),
+                grease_positions: positions,
+                padding_boundary: 0,
+                ech_grease: true,
+                ..ClientHelloOptions::default()
+            };
+
+            let record = hello.to_ios_safari("example.com", &options).unwrap();
+            let reparsed = TlsClientHello::parse(&record).unwrap();
+            let ciphers = &reparsed.cipher_suites;
+            let extensions: Vec<u16> = reparsed.extensions.iter().map(|e| e.extension_type).collect();
+
+            assert_eq!(is_grease(ciphers[0]), positions.cipher_first, "{:?}", positions);
+            assert_eq!(ciphers.iter().filter(|c| is_grease(**c)).count(), positions.cipher_first as usize);
+            assert_eq!(is_grease(extensions[0]), positions.extension_first, "{:?}", positions);
+            assert_eq!(is_grease(*extensions.last().unwrap()), positions.extension_last, "{:?}", positions);
+            assert_eq!(
+                extensions.iter().filter(|e| is_grease(**e)).count(),
+                positions.extension_first as usize + positions.extension_last as usize,
+            );
+            // GREASE ECH встает перед завершающим GREASE или в конец, но не в начало
+            assert_ne!(extensions[0], EXT_ENCRYPTED_CLIENT_HELLO);
+        }
+    }
+
+    #[test]
+    fn test_supported_versions_preserved() {
+        let tls12_only = TlsExtension {
+            extension_type: EXT_SUPPORTED_VERSIONS,
+            data: vec![0x02, 0x03, 0x03],
+        };
+        let hello = TlsClientHello::parse(&client_hello_with_extensions(&[tls12_only])).unwrap();
+        assert_eq!(hello.supported_versions(), Some(vec![TLS_1_2]));
+
+        let record = hello.to_ios_safari("example.com", &ClientHelloOptions::default()).unwrap();
+        let reparsed = TlsClientHello::parse(&record).unwrap();
+        assert_eq!(reparsed.supported_versions(), Some(vec![TLS_1_2]));
+        assert!(!reparsed.cipher_suites.contains(&0x1302));
+
+        let reordered = TlsExtension {
+            extension_type: EXT_SUPPORTED_VERSIONS,
+            data: vec![0x06, 0x03, 0x03, 0x3a, 0x3a, 0x03, 0x04],
+        };
+        let hello = TlsClientHello::parse(&client_hello_with_extensions(&[reordered])).unwrap();
+        let record = hello.to_ios_safari("example.com", &ClientHelloOptions::default()).unwrap();
+        assert_eq!(
+            TlsClientHello::parse(&record).unwrap().supported_versions(),
+            Some(vec![0x3a3a, TLS_1_3, TLS_1_2])
+        );
+
+        assert_eq!(parse_version_name("TLS 1.3"), Some(TLS_1_3));
+        assert_eq!(parse_version_name("tls1.2"), Some(TLS_1_2));
+    }
+
+    #[test]
+    fn test_reserialize_original_round_trip() {
+        let record = sample_client_hello();
+        let hello = TlsClientHello::parse(&record).unwrap();
+
+        assert_eq!(hello.record_version, TLS_VERSION_1_0);
+        assert_eq!(hello.version, TLS_VERSION_1_2);
+        assert_eq!(hello.reserialize_original().unwrap(), record);
+    }
+
+    #[test]
+    fn test_oversized_extensions_rejected() {
+        let record = sample_client_hello();
+        let hello = TlsClientHello::parse(&record).unwrap();
+
+        // Один ticket больше u16
+        let mut huge_ticket = hello.clone();
+        huge_ticket.extensions.push(TlsExtension { extension_type: EXT_SESSION_TICKET, data: vec![0xab; 70000] });
+        let err = huge_ticket
+            .to_ios_safari("example.com", &ClientHelloOptions::default())
+            .unwrap_err();
+        assert!(err.to_string().contains("exceeds 65535"));
+
+        // Каждый extension в пределах, но вместе больше u16
+        let mut oversized = hello.clone();
+        for _ in 0..2 {
+            oversized.extensions.push(TlsExtension { extension_type: 0xff01, data: vec![0; 40000] });
+        }
+        assert!(oversized.reserialize_original().is_err());
+    }
+
+    /// Бенчмарк, зависит от нагрузки машины: cargo test -- --ignored
+    #[test]
+    #[ignore = "benchmark, timing-dependent"]
+    fn test_fingerprint_generation_time() {
+        let hello = TlsClientHello::parse(&sample_client_hello()).unwrap();
+        let options = ClientHelloOptions::default();
+        let iterations = 200;
+
+        let started = std::time::Instant::now();
+        for _ in 0..iterations {
+            hello.to_ios_safari("example.com", &options).unwrap();
+        }
+        let average = started.elapsed() / iterations;
+
+        // С запасом даже для debug сборки; обычно десятки микросекунд
+        assert!(average < std::time::Duration::from_millis(2), "average {:?}", average);
+    }
+
+    #[test]
+    fn test_fragment_record() {
+        let record = sample_client_hello();
+        let fragmented = fragment_record(&record, 32);
+
+        let mut payload = Vec::new();
+        let mut records = 0;
+        let mut offset = 0;
+        while offset < fragmented.len() {
+            assert_eq!(&fragmented[offset..offset + 3], &record[..3]);
+            let len = u16::from_be_bytes([fragmented[offset + 3], fragmented[offset + 4]]) as usize;
+            payload.extend_from_slice(&fragmented[offset + 5..offset + 5 + len]);
+            offset += 5 + len;
+            records += 1;
+        }
+
+        assert_eq!(records, 2);
+        assert_eq!(payload, &record[5..]);
+        assert_eq!(fragment_record(&record, record.len()), record);
+    }
+
+    #[test]
+    fn test_fingerprint_failures_cooldown() {
+        let failures = FingerprintFailures::new(2, Duration::from_millis(50));
+
+        failures.record_failure("broken.example");
+        assert!(failures.should_fingerprint("broken.example"));
+        failures.record_failure("broken.example");
+        assert!(failures.is_passthrough("broken.example"));
+        assert!(!failures.should_fingerprint("broken.example"));
+        assert!(failures.should_fingerprint("other.example"));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(failures.should_fingerprint("broken.example"));
+        assert!(!failures.is_passthrough("broken.example"));
+
+        // Успешный ответ обнуляет счетчик
+        failures.record_failure("flaky.example");
+        failures.record_success("flaky.example");
+        failures.record_failure("flaky.example");
+        assert!(failures.should_fingerprint("flaky.example"));
+
+        assert!(is_handshake_rejection(&[TLS_ALERT, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28]));
+        assert!(!is_handshake_rejection(&[TLS_HANDSHAKE, 0x03, 0x03]));
+        assert!(!is_handshake_rejection(b"HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn test_fingerprint_failures_bounded() {
+        let failures = FingerprintFailures::new(1, Duration::from_secs(60));
+        for i in 0..MAX_FAILURE_TARGETS {
+            failures.record_failure(&format!("{}.example", i));
+        }
+        assert_eq!(failures.targets.lock().len(), MAX_FAILURE_TARGETS);
+
+        // Все записи - действующие отключения, новое имя не вытесняет их
+        failures.record_failure("new.example");
+        assert_eq!(failures.targets.lock().len(), MAX_FAILURE_TARGETS);
+        assert!(failures.should_fingerprint("new.example"));
+        assert!(failures.is_passthrough("0.example"));
+
+        let failures = FingerprintFailures::new(2, Duration::from_secs(60));
+        for i in 0..MAX_FAILURE_TARGETS {
+            failures.record_failure(&format!("{}.example", i));
+        }
+        failures.record_failure("new.example");
+        assert_eq!(failures.targets.lock().len(), 1);
+    }
+
+    #[test]
+    fn test_ja4_known_client_hello() {
+        let hello = TlsClientHello::parse(&client_hello_with_extensions(&[
+            TlsExtension { extension_type: EXT_SUPPORTED_VERSIONS, data: vec![0x04, 0x03, 0x04, 0x03, 0x03] },
+            TlsExtension {
+                extension_type: EXT_ALPN,
+                data: vec![0x00, 0x0c, 0x02, b'h', b'2', 0x08, b'h', b't', b't', b'p', b'/', b'1', b'.', b'1'],
+            },
+            TlsExtension { extension_type: EXT_SIGNATURE_ALGORITHMS, data: vec![0x00, 0x04, 0x04, 0x03, 0x08, 0x04] },
+            TlsExtension { extension_type: EXT_SUPPORTED_GROUPS, data: vec![0x00, 0x04, 0x00, 0x1d, 0x00, 0x17] },
+            TlsExtension { extension_type: 0x0a0a, data: vec![] },
+        ])).unwrap();
+
+        assert_eq!(hello.ja4_string(), "t13d0206h2_777cda164f4b_4df8e29e9210");
+        assert_eq!(hello.ja3_string(), "771,4865-49195,0-35-43-16-13-10,29-23,");
     }
 }
\ No newline at end of file
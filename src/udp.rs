@@ -1,29 +1,63 @@
 use anyhow::Result;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
 use std::collections::HashMap;
+use std::io::IoSliceMut;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::Arc;
-use tokio::net::UdpSocket;
+use tokio::io::Interest;
+use tokio::net::{TcpStream, UdpSocket};
+use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags, SockaddrStorage};
 use tokio::sync::RwLock;
 use std::time::{Duration, Instant};
 
+use crate::config::Config;
+use crate::tcp_advanced::{bind_transparent_udp, enable_recvorigdstaddr};
+use crate::socks5::{Socks5Connector, wrap_udp_datagram, unwrap_udp_datagram};
+
 const MAX_DATAGRAM_SIZE: usize = 65535;
 const SESSION_TIMEOUT: Duration = Duration::from_secs(120);
 const CLEANUP_INTERVAL: Duration = Duration::from_secs(30);
+const DNS_PORT: u16 = 53;
+const DNS_HEADER_LEN: usize = 12;
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_AAAA: u16 = 28;
+const DNS_CACHE_CAPACITY: usize = 4096;
+const MAX_CID_LEN: usize = 20;
+const MAX_PENDING_DATAGRAMS: usize = 32;
+
+/// QUIC sessions are keyed by Destination Connection ID and survive a client
+/// address change (connection migration); everything else by client and target address
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum SessionKey {
+    Addr(SocketAddr, SocketAddr),
+    QuicCid(Vec<u8>),
+}
 
 #[derive(Debug, Clone)]
 struct UdpSession {
     client_addr: SocketAddr,
+    /// Connection IDs that map QUIC packets to this session
+    quic_cids: Vec<Vec<u8>>,
     target_addr: SocketAddr,
+    upstream: Arc<UdpSocket>,
+    /// UDP ASSOCIATE control connection, if datagrams go through SOCKS5
+    socks_control: Option<Arc<TcpStream>>,
+    created_at: Instant,
     last_activity: Instant,
     bytes_sent: u64,
     bytes_received: u64,
 }
 
 impl UdpSession {
-    fn new(client_addr: SocketAddr, target_addr: SocketAddr) -> Self {
+    fn new(client_addr: SocketAddr, target_addr: SocketAddr, upstream: Arc<UdpSocket>) -> Self {
         Self {
             client_addr,
+            quic_cids: Vec::new(),
             target_addr,
+            upstream,
+            socks_control: None,
+            created_at: Instant::now(),
             last_activity: Instant::now(),
             bytes_sent: 0,
             bytes_received: 0,
@@ -37,24 +71,252 @@ impl UdpSession {
     fn update_activity(&mut self) {
         self.last_activity = Instant::now();
     }
+
+    fn duration(&self) -> Duration {
+        self.created_at.elapsed()
+    }
+
+    fn stats(&self) -> UdpSessionStats {
+        UdpSessionStats {
+            client_addr: self.client_addr,
+            target_addr: self.target_addr,
+            duration: self.duration(),
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+        }
+    }
+
+    fn log_closed(&self, reason: &str) {
+        log::debug!("UDP session {} -> {} closed ({}): {:?}, sent {} B, received {} B",
+            self.client_addr, self.target_addr, reason, self.duration(),
+            self.bytes_sent, self.bytes_received);
+    }
+}
+
+/// Sessions and the QUIC Connection ID -> session key index
+#[derive(Default)]
+struct SessionTable {
+    sessions: HashMap<SessionKey, UdpSession>,
+    cids: HashMap<Vec<u8>, SessionKey>,
+}
+
+impl SessionTable {
+    fn get_mut(&mut self, key: &SessionKey) -> Option<&mut UdpSession> {
+        self.sessions.get_mut(key)
+    }
+
+    fn get(&self, key: &SessionKey) -> Option<&UdpSession> {
+        self.sessions.get(key)
+    }
+
+    fn insert(&mut self, key: SessionKey, session: UdpSession) {
+        self.remove(&key);
+        for cid in &session.quic_cids {
+            self.cids.insert(cid.clone(), key.clone());
+        }
+        self.sessions.insert(key, session);
+    }
+
+    fn remove(&mut self, key: &SessionKey) -> Option<UdpSession> {
+        let session = self.sessions.remove(key)?;
+        for cid in &session.quic_cids {
+            if self.cids.get(cid) == Some(key) {
+                self.cids.remove(cid);
+            }
+        }
+        Some(session)
+    }
+
+    /// A server-issued Connection ID maps the client's next packets to the session
+    fn add_cid(&mut self, key: &SessionKey, cid: &[u8]) {
+        let Some(session) = self.sessions.get_mut(key) else {
+            return;
+        };
+        if !session.quic_cids.iter().any(|known| known == cid) {
+            session.quic_cids.push(cid.to_vec());
+            self.cids.insert(cid.to_vec(), key.clone());
+        }
+    }
+
+    fn find_cid(&self, cid: &[u8]) -> Option<SessionKey> {
+        self.cids.get(cid).cloned()
+    }
+
+    /// A short header doesn't carry the DCID length - try every valid length
+    fn find_short_header(&self, data: &[u8]) -> Option<SessionKey> {
+        (1..=MAX_CID_LEN)
+            .filter_map(|len| data.get(1..1 + len))
+            .find_map(|cid| self.find_cid(cid))
+    }
+
+    /// Removes expired sessions, returns how many were removed
+    fn remove_expired(&mut self) -> usize {
+        let expired: Vec<SessionKey> = self.sessions.iter()
+            .filter(|(_, session)| session.is_expired())
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            if let Some(session) = self.remove(key) {
+                session.log_closed("expired");
+            }
+        }
+        expired.len()
+    }
+
+    fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    fn values(&self) -> impl Iterator<Item = &UdpSession> {
+        self.sessions.values()
+    }
+}
+
+type Sessions = Arc<RwLock<SessionTable>>;
+
+/// Datagrams of sessions that are still opening (UDP ASSOCIATE)
+type PendingSessions = Arc<parking_lot::Mutex<HashMap<SessionKey, Vec<Vec<u8>>>>>;
+
+/// Where a new session's datagram came from and where it goes
+struct SessionRoute {
+    key: SessionKey,
+    src: SocketAddr,
+    reply_from: Option<SocketAddr>,
+    target: SocketAddr,
 }
 
 pub struct UdpForwarder {
     listen_addr: SocketAddr,
-    sessions: Arc<RwLock<HashMap<SocketAddr, UdpSession>>>,
+    target: Option<SocketAddr>,
+    port_targets: HashMap<u16, SocketAddr>,
+    transparent: bool,
+    socks5: Option<Arc<Socks5Connector>>,
+    dns_upstream: Option<SocketAddr>,
+    dns_cache: Arc<DnsCache>,
+    sessions: Sessions,
+    pending: PendingSessions,
 }
 
 impl UdpForwarder {
     pub fn new(listen_addr: SocketAddr) -> Self {
         Self {
             listen_addr,
-            sessions: Arc::new(RwLock::new(HashMap::new())),
+            target: None,
+            port_targets: HashMap::new(),
+            transparent: false,
+            socks5: None,
+            dns_upstream: None,
+            dns_cache: Arc::new(DnsCache::default()),
+            sessions: Arc::new(RwLock::new(SessionTable::default())),
+            pending: Arc::new(parking_lot::Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Forwarder from `udp_settings`; None if UDP is not configured.
+    /// With a socks5 upstream datagrams go through UDP ASSOCIATE
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let settings = &config.udp_settings;
+        let Some(listen_addr) = &settings.listen_addr else {
+            return Ok(None);
+        };
+
+        let mut forwarder = Self::new(listen_addr.parse()
+            .map_err(|e| anyhow::anyhow!("Invalid UDP listen address {}: {}", listen_addr, e))?);
+
+        if let Some(target) = &settings.target {
+            forwarder = forwarder.with_target(target.parse()
+                .map_err(|e| anyhow::anyhow!("Invalid UDP target {}: {}", target, e))?);
+        }
+
+        if settings.transparent {
+            forwarder = forwarder.with_transparent();
+        } else if settings.target.is_none() {
+            // Without TPROXY the original destination is the forwarder itself
+            return Err(anyhow::anyhow!("udp_settings.target is required unless udp_settings.transparent is set"));
+        }
+
+        for (port, target) in &settings.port_targets {
+            forwarder = forwarder.with_port_target(*port, target.parse()
+                .map_err(|e| anyhow::anyhow!("Invalid UDP target {} for port {}: {}", target, port, e))?);
+        }
+
+        if let Some(resolver) = &settings.dns_upstream {
+            forwarder = forwarder.with_dns_upstream(resolver.parse()
+                .map_err(|e| anyhow::anyhow!("Invalid DNS upstream {}: {}", resolver, e))?);
+        }
+
+        let proxy = &config.proxy_settings;
+        if proxy.proxy_type.eq_ignore_ascii_case("socks5") {
+            forwarder = forwarder.with_socks5(Socks5Connector::new(
+                proxy.proxy_host.clone(),
+                proxy.proxy_port,
+                proxy.username.clone(),
+                proxy.password.clone(),
+            ).with_require_auth(proxy.require_auth));
         }
+
+        Ok(Some(forwarder))
+    }
+
+    /// All datagrams go to a fixed address instead of the original destination (TPROXY)
+    pub fn with_target(mut self, target: SocketAddr) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    /// Datagrams to port `port` go to `target`; takes precedence over `with_target`
+    pub fn with_port_target(mut self, port: u16, target: SocketAddr) -> Self {
+        self.port_targets.insert(port, target);
+        self
+    }
+
+    /// Receive via TPROXY: IP_TRANSPARENT on the receiving socket, replies to the client
+    /// are sent from the datagram's original destination address
+    pub fn with_transparent(mut self) -> Self {
+        self.transparent = true;
+        self
+    }
+
+    /// Datagram target: first by destination port (original address from TPROXY,
+    /// else the forwarder's own port), then the fixed target, then the original address
+    fn resolve_target(&self, orig_dst: Option<SocketAddr>, local_port: u16) -> Option<SocketAddr> {
+        let dst_port = orig_dst.map_or(local_port, |dst| dst.port());
+        self.port_targets.get(&dst_port).copied()
+            .or(self.target)
+            .or(orig_dst)
+    }
+
+    /// Datagrams are tunneled through UDP ASSOCIATE of the upstream SOCKS5 proxy
+    pub fn with_socks5(mut self, connector: Socks5Connector) -> Self {
+        self.socks5 = Some(Arc::new(connector));
+        self
+    }
+
+    /// DNS queries (port 53) go to this resolver, A/AAAA answers are cached by TTL
+    pub fn with_dns_upstream(mut self, resolver: SocketAddr) -> Self {
+        self.dns_upstream = Some(resolver);
+        self
     }
 
-    pub async fn run(self) -> Result<()> {
-        let socket = Arc::new(UdpSocket::bind(self.listen_addr).await?);
-        log::info!("UDP forwarder listening on {}", self.listen_addr);
+    pub async fn run(&self) -> Result<()> {
+        let socket = if self.transparent {
+            UdpSocket::from_std(bind_transparent_udp(self.listen_addr)?)?
+        } else {
+            UdpSocket::bind(self.listen_addr).await?
+        };
+        self.run_on(socket).await
+    }
+
+    pub async fn run_on(&self, socket: UdpSocket) -> Result<()> {
+        if self.transparent || self.target.is_none() || !self.port_targets.is_empty() {
+            // Without a fixed target the destination comes from IP_RECVORIGDSTADDR,
+            // and the port_targets entry is picked by it too
+            enable_recvorigdstaddr(&socket)?;
+        }
+        let socket = Arc::new(socket);
+        let local_addr = socket.local_addr()?;
+        let local_port = local_addr.port();
+        log::info!("UDP forwarder listening on {}", local_addr);
 
         // Cleanup task
         let sessions_cleanup = self.sessions.clone();
@@ -69,23 +331,39 @@ impl UdpForwarder {
         let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
 
         loop {
-            match socket.recv_from(&mut buf).await {
-                Ok((len, src)) => {
+            match recv_with_orig_dst(&socket, &mut buf).await {
+                Ok((len, src, orig_dst)) => {
                     let data = &buf[..len];
-                    
-                    // Detect protocol
-                    if self.is_quic_packet(data) {
+                    let Some(target) = self.resolve_target(orig_dst, local_port) else {
+                        log::debug!("No target for UDP packet from {}, dropping", src);
+                        continue;
+                    };
+                    if target == self.listen_addr || is_local_endpoint(target, local_addr) {
+                        log::debug!("UDP target {} is the forwarder itself, dropping packet from {}", target, src);
+                        continue;
+                    }
+                    let reply_from = orig_dst
+                        .filter(|dst| self.transparent && !is_local_endpoint(*dst, local_addr));
+
+                    // Detect protocol; DNS goes first since a query ID can look like a QUIC header
+                    let dns_resolver = self.dns_upstream
+                        .filter(|_| target.port() == DNS_PORT && self.is_dns_packet(data));
+
+                    if let Some(resolver) = dns_resolver {
+                        log::debug!("DNS query from {}, {} bytes", src, len);
+                        self.handle_dns_packet(&socket, data, src, reply_from, resolver).await;
+                    } else if self.is_quic_packet(data) {
                         log::debug!("QUIC packet from {}, {} bytes", src, len);
-                        self.handle_quic_packet(&socket, data, src).await;
+                        self.handle_quic_packet(&socket, data, src, reply_from, target).await;
                     } else if self.is_stun_packet(data) {
                         log::debug!("STUN packet from {}, {} bytes", src, len);
-                        self.handle_stun_packet(&socket, data, src).await;
+                        self.handle_stun_packet(&socket, data, src, reply_from, target).await;
                     } else if self.is_dtls_packet(data) {
                         log::debug!("DTLS packet from {}, {} bytes", src, len);
-                        self.handle_dtls_packet(&socket, data, src).await;
+                        self.handle_dtls_packet(&socket, data, src, reply_from, target).await;
                     } else {
                         log::debug!("Generic UDP packet from {}, {} bytes", src, len);
-                        self.handle_generic_udp(&socket, data, src).await;
+                        self.handle_generic_udp(&socket, data, src, reply_from, target).await;
                     }
                 }
                 Err(e) => {
@@ -133,94 +411,354 @@ impl UdpForwarder {
         content_type >= 20 && content_type <= 23 && data[1] == 254
     }
 
-    /// Handle QUIC: прозрачная передача без модификаций
-    async fn handle_quic_packet(&self, socket: &UdpSocket, data: &[u8], src: SocketAddr) {
-        // QUIC - полностью прозрачная передача
-        // Не модифицируем пакеты, только форвардим
-        
-        if let Err(e) = socket.send_to(data, src).await {
-            log::error!("Failed to forward QUIC packet: {}", e);
+    /// Session key: a known Connection ID, the DCID of a new QUIC connection or the address
+    fn session_key(sessions: &SessionTable, data: &[u8], src: SocketAddr, target: SocketAddr) -> SessionKey {
+        if let Some(dcid) = quic_long_header_cids(data).map(|(dcid, _)| dcid) {
+            return sessions.find_cid(dcid)
+                .unwrap_or_else(|| SessionKey::QuicCid(dcid.to_vec()));
         }
-        
-        // Update session stats
-        let mut sessions = self.sessions.write().await;
-        if let Some(session) = sessions.get_mut(&src) {
-            session.bytes_sent += data.len() as u64;
-            session.update_activity();
-        } else {
-            let mut session = UdpSession::new(src, src);
-            session.bytes_sent = data.len() as u64;
-            sessions.insert(src, session);
+
+        if data.len() > 1 && (data[0] & 0xC0) == 0x40 {
+            if let Some(key) = sessions.find_short_header(data) {
+                return key;
+            }
         }
+
+        SessionKey::Addr(src, target)
     }
 
-    /// Handle STUN: прозрачная передача
-    async fn handle_stun_packet(&self, socket: &UdpSocket, data: &[u8], src: SocketAddr) {
-        // STUN/TURN - прозрачная передача для WebRTC
-        
-        if let Err(e) = socket.send_to(data, src).await {
-            log::error!("Failed to forward STUN packet: {}", e);
+    /// DNS query detection: standard query (QR=0, OPCODE=0) with one question
+    fn is_dns_packet(&self, data: &[u8]) -> bool {
+        if data.len() < DNS_HEADER_LEN + 5 {
+            return false;
         }
-        
-        let mut sessions = self.sessions.write().await;
-        if let Some(session) = sessions.get_mut(&src) {
-            session.bytes_sent += data.len() as u64;
-            session.update_activity();
-        } else {
-            let mut session = UdpSession::new(src, src);
-            session.bytes_sent = data.len() as u64;
-            sessions.insert(src, session);
+
+        let is_query = (data[2] & 0x80) == 0;
+        let opcode = (data[2] >> 3) & 0x0F;
+        let qdcount = u16::from_be_bytes([data[4], data[5]]);
+        let ancount = u16::from_be_bytes([data[6], data[7]]);
+        let nscount = u16::from_be_bytes([data[8], data[9]]);
+
+        is_query && opcode == 0 && qdcount == 1 && ancount == 0 && nscount == 0
+            && dns_question_end(data).is_some()
+    }
+
+    /// Handle DNS: answer from the cache or query the resolver and reply to the client
+    async fn handle_dns_packet(
+        &self,
+        socket: &Arc<UdpSocket>,
+        data: &[u8],
+        src: SocketAddr,
+        reply_from: Option<SocketAddr>,
+        resolver: SocketAddr,
+    ) {
+        if let Some(response) = self.dns_cache.lookup(data) {
+            log::debug!("DNS cache hit for {}", src);
+            let sent = match reply_socket(socket, reply_from) {
+                Ok(reply) => reply.send_to(&response, src).await.map_err(anyhow::Error::from),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = sent {
+                log::error!("Failed to send cached DNS response: {}", e);
+            }
+            return;
+        }
+
+        let socket = socket.clone();
+        let cache = self.dns_cache.clone();
+        let query = data.to_vec();
+
+        // Don't block the receive loop while waiting for the resolver
+        tokio::spawn(async move {
+            match resolve_dns(&query, resolver).await {
+                Ok(response) => {
+                    cache.store(&query, &response);
+                    let sent = match reply_socket(&socket, reply_from) {
+                        Ok(reply) => reply.send_to(&response, src).await.map_err(anyhow::Error::from),
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = sent {
+                        log::error!("Failed to relay DNS response to {}: {}", src, e);
+                    }
+                }
+                Err(e) => log::warn!("DNS query from {} via {} failed: {}", src, resolver, e),
+            }
+        });
+    }
+
+    /// Handle QUIC: transparent forwarding without modification
+    async fn handle_quic_packet(
+        &self,
+        socket: &Arc<UdpSocket>,
+        data: &[u8],
+        src: SocketAddr,
+        reply_from: Option<SocketAddr>,
+        target: SocketAddr,
+    ) {
+        // QUIC - fully transparent forwarding
+        // Packets are not modified, only forwarded
+        if let Err(e) = self.forward_to_target(socket, data, src, reply_from, target).await {
+            log::error!("Failed to forward QUIC packet: {}", e);
         }
     }
 
-    /// Handle DTLS: прозрачная передача
-    async fn handle_dtls_packet(&self, socket: &UdpSocket, data: &[u8], src: SocketAddr) {
-        // DTLS - прозрачная передача для WebRTC
-        
-        if let Err(e) = socket.send_to(data, src).await {
-            log::error!("Failed to forward DTLS packet: {}", e);
+    /// Handle STUN: transparent forwarding
+    async fn handle_stun_packet(
+        &self,
+        socket: &Arc<UdpSocket>,
+        data: &[u8],
+        src: SocketAddr,
+        reply_from: Option<SocketAddr>,
+        target: SocketAddr,
+    ) {
+        // STUN/TURN - transparent forwarding for WebRTC
+        if let Err(e) = self.forward_to_target(socket, data, src, reply_from, target).await {
+            log::error!("Failed to forward STUN packet: {}", e);
         }
-        
-        let mut sessions = self.sessions.write().await;
-        if let Some(session) = sessions.get_mut(&src) {
-            session.bytes_sent += data.len() as u64;
-            session.update_activity();
-        } else {
-            let mut session = UdpSession::new(src, src);
-            session.bytes_sent = data.len() as u64;
-            sessions.insert(src, session);
+    }
+
+    /// Handle DTLS: transparent forwarding
+    async fn handle_dtls_packet(
+        &self,
+        socket: &Arc<UdpSocket>,
+        data: &[u8],
+        src: SocketAddr,
+        reply_from: Option<SocketAddr>,
+        target: SocketAddr,
+    ) {
+        // DTLS - transparent forwarding for WebRTC
+        if let Err(e) = self.forward_to_target(socket, data, src, reply_from, target).await {
+            log::error!("Failed to forward DTLS packet: {}", e);
         }
     }
 
     /// Handle generic UDP
-    async fn handle_generic_udp(&self, socket: &UdpSocket, data: &[u8], src: SocketAddr) {
-        // Generic UDP - прозрачная передача
-        
-        if let Err(e) = socket.send_to(data, src).await {
+    async fn handle_generic_udp(
+        &self,
+        socket: &Arc<UdpSocket>,
+        data: &[u8],
+        src: SocketAddr,
+        reply_from: Option<SocketAddr>,
+        target: SocketAddr,
+    ) {
+        // Generic UDP - transparent forwarding
+        if let Err(e) = self.forward_to_target(socket, data, src, reply_from, target).await {
             log::error!("Failed to forward UDP packet: {}", e);
         }
-        
-        let mut sessions = self.sessions.write().await;
-        if let Some(session) = sessions.get_mut(&src) {
-            session.bytes_sent += data.len() as u64;
-            session.update_activity();
+    }
+
+    /// Sends a client datagram to its session's upstream socket.
+    /// The session and its return task are created on the first packet.
+    async fn forward_to_target(
+        &self,
+        socket: &Arc<UdpSocket>,
+        data: &[u8],
+        src: SocketAddr,
+        reply_from: Option<SocketAddr>,
+        target: SocketAddr,
+    ) -> Result<()> {
+        let (key, existing) = {
+            let mut sessions = self.sessions.write().await;
+            let key = Self::session_key(&sessions, data, src, target);
+            let existing = match sessions.get_mut(&key) {
+                Some(session) if session.target_addr == target => {
+                    // A Connection ID is visible to anyone on the path, so the return path only moves
+                    // within the client's IP (NAT rebinding); packets from another IP
+                    // reach the server, but their replies are not redirected
+                    if session.client_addr != src {
+                        if session.client_addr.ip() == src.ip() {
+                            log::debug!("UDP session migrated {} -> {}", session.client_addr, src);
+                            session.client_addr = src;
+                        } else {
+                            log::debug!("UDP session {} not migrated to {}: different IP", session.client_addr, src);
+                        }
+                    }
+                    session.bytes_sent += data.len() as u64;
+                    session.update_activity();
+                    Some(session.upstream.clone())
+                }
+                _ => None,
+            };
+            (key, existing)
+        };
+
+        let route = SessionRoute { key, src, reply_from, target };
+        let upstream = match existing {
+            Some(upstream) => upstream,
+            // UDP ASSOCIATE can take up to the connect timeout - don't hold the receive loop
+            None if self.socks5.is_some() => {
+                self.open_session_in_background(socket, data, route);
+                return Ok(());
+            }
+            None => Self::open_session(None, self.sessions.clone(), socket, data.len(), route).await?,
+        };
+
+        if self.socks5.is_some() {
+            upstream.send(&wrap_udp_datagram(target, data)).await?;
+        } else {
+            upstream.send(data).await?;
+        }
+        Ok(())
+    }
+
+    /// Opens the session in a separate task; datagrams that arrive before it opens
+    /// wait in a queue and follow
+    fn open_session_in_background(&self, socket: &Arc<UdpSocket>, data: &[u8], route: SessionRoute) {
+        {
+            let mut pending = self.pending.lock();
+            if let Some(queue) = pending.get_mut(&route.key) {
+                if queue.len() < MAX_PENDING_DATAGRAMS {
+                    queue.push(data.to_vec());
+                }
+                return;
+            }
+            pending.insert(route.key.clone(), vec![data.to_vec()]);
+        }
+
+        let socks5 = self.socks5.clone();
+        let sessions = self.sessions.clone();
+        let pending = self.pending.clone();
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            let (key, src, target) = (route.key.clone(), route.src, route.target);
+            let opened = Self::open_session(socks5, sessions.clone(), &socket, 0, route).await;
+            let queued = pending.lock().remove(&key).unwrap_or_default();
+
+            let upstream = match opened {
+                Ok(upstream) => upstream,
+                Err(e) => {
+                    log::error!("Failed to open UDP session {} -> {}: {}", src, target, e);
+                    return;
+                }
+            };
+            let mut sent = 0;
+            for datagram in &queued {
+                match upstream.send(&wrap_udp_datagram(target, datagram)).await {
+                    Ok(_) => sent += datagram.len() as u64,
+                    Err(e) => log::error!("Failed to forward UDP packet: {}", e),
+                }
+            }
+            if let Some(session) = sessions.write().await.get_mut(&key) {
+                session.bytes_sent += sent;
+            }
+        });
+    }
+
+    async fn open_session(
+        socks5: Option<Arc<Socks5Connector>>,
+        sessions: Sessions,
+        socket: &Arc<UdpSocket>,
+        first_len: usize,
+        route: SessionRoute,
+    ) -> Result<Arc<UdpSocket>> {
+        let SessionRoute { key, src, reply_from, target } = route;
+        let (peer, socks_control) = match &socks5 {
+            Some(connector) => {
+                let association = connector.udp_associate().await?;
+                (association.relay_addr, Some(association.control))
+            }
+            None => (target, None),
+        };
+
+        let bind_addr: SocketAddr = if peer.is_ipv4() {
+            "0.0.0.0:0".parse()?
         } else {
-            let mut session = UdpSession::new(src, src);
-            session.bytes_sent = data.len() as u64;
-            sessions.insert(src, session);
+            "[::]:0".parse()?
+        };
+        let upstream = Arc::new(UdpSocket::bind(bind_addr).await?);
+        upstream.connect(peer).await?;
+
+        let mut session = UdpSession::new(src, target, upstream.clone());
+        if let SessionKey::QuicCid(cid) = &key {
+            session.quic_cids.push(cid.clone());
+        }
+        session.socks_control = socks_control;
+        session.bytes_sent = first_len as u64;
+        sessions.write().await.insert(key.clone(), session);
+
+        tokio::spawn(Self::relay_replies(
+            reply_socket(socket, reply_from)?,
+            upstream.clone(),
+            sessions,
+            key,
+            socks5.is_some(),
+        ));
+        log::debug!("New UDP session {} -> {} via {}", src, target, peer);
+
+        Ok(upstream)
+    }
+
+    /// target -> client while the session lives. Replies go to the session's current
+    /// client address, so QUIC migration doesn't lose return traffic
+    async fn relay_replies(
+        socket: Arc<UdpSocket>,
+        upstream: Arc<UdpSocket>,
+        sessions: Sessions,
+        key: SessionKey,
+        socks: bool,
+    ) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let len = match tokio::time::timeout(SESSION_TIMEOUT, upstream.recv(&mut buf)).await {
+                Ok(Ok(len)) => len,
+                Ok(Err(e)) => {
+                    log::debug!("UDP upstream for {:?} failed: {}", key, e);
+                    break;
+                }
+                Err(_) => break,
+            };
+
+            let payload = if socks {
+                match unwrap_udp_datagram(&buf[..len]) {
+                    Ok((_, payload)) => payload,
+                    Err(e) => {
+                        log::debug!("Dropping SOCKS5 UDP reply for {:?}: {}", key, e);
+                        continue;
+                    }
+                }
+            } else {
+                &buf[..len]
+            };
+
+            let client_addr = {
+                let mut sessions = sessions.write().await;
+                // The server's SCID becomes the DCID of the client's next packets
+                if let Some((_, scid)) = quic_long_header_cids(payload) {
+                    if matches!(sessions.get(&key), Some(session) if Arc::ptr_eq(&session.upstream, &upstream)) {
+                        sessions.add_cid(&key, scid);
+                    }
+                }
+                match sessions.get_mut(&key) {
+                    // The session moved to another upstream or was removed
+                    Some(session) if Arc::ptr_eq(&session.upstream, &upstream) => {
+                        session.bytes_received += payload.len() as u64;
+                        session.update_activity();
+                        session.client_addr
+                    }
+                    _ => break,
+                }
+            };
+
+            if let Err(e) = socket.send_to(payload, client_addr).await {
+                log::error!("Failed to relay UDP reply to {}: {}", client_addr, e);
+            }
+        }
+
+        let mut sessions = sessions.write().await;
+        if matches!(sessions.get(&key), Some(session) if Arc::ptr_eq(&session.upstream, &upstream)) {
+            if let Some(session) = sessions.remove(&key) {
+                session.log_closed("upstream idle");
+            }
         }
     }
 
-    async fn cleanup_sessions(sessions: &Arc<RwLock<HashMap<SocketAddr, UdpSession>>>) {
+    async fn cleanup_sessions(sessions: &Sessions) {
         let mut sessions = sessions.write().await;
-        let before = sessions.len();
-        
-        sessions.retain(|_, session| !session.is_expired());
-        
-        let after = sessions.len();
-        if before != after {
+        let removed = sessions.remove_expired();
+        if removed > 0 {
             log::debug!("Cleaned up {} expired UDP sessions ({} active)", 
-                before - after, after);
+                removed, sessions.len());
         }
     }
 
@@ -235,15 +773,261 @@ impl UdpForwarder {
             active_sessions: total_sessions,
             total_bytes_sent,
             total_bytes_received,
+            sessions: sessions.values().map(UdpSession::stats).collect(),
+        }
+    }
+}
+
+/// Replies to an intercepted datagram are sent from its original destination,
+/// otherwise the client rejects them; without TPROXY - from the receiving socket
+fn reply_socket(listener: &Arc<UdpSocket>, reply_from: Option<SocketAddr>) -> Result<Arc<UdpSocket>> {
+    match reply_from {
+        Some(addr) => Ok(Arc::new(UdpSocket::from_std(bind_transparent_udp(addr)?)?)),
+        None => Ok(listener.clone()),
+    }
+}
+
+/// Address the socket itself listens on (accounting for 0.0.0.0 / [::])
+fn is_local_endpoint(addr: SocketAddr, local_addr: SocketAddr) -> bool {
+    addr == local_addr || (local_addr.ip().is_unspecified() && addr.port() == local_addr.port())
+}
+
+async fn resolve_dns(query: &[u8], resolver: SocketAddr) -> Result<Vec<u8>> {
+    let bind_addr: SocketAddr = if resolver.is_ipv4() { "0.0.0.0:0".parse()? } else { "[::]:0".parse()? };
+    let upstream = UdpSocket::bind(bind_addr).await?;
+    upstream.connect(resolver).await?;
+    upstream.send(query).await?;
+
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let len = tokio::time::timeout(DNS_TIMEOUT, upstream.recv(&mut buf)).await
+            .map_err(|_| anyhow::anyhow!("resolver timeout"))??;
+        // The reply must match by ID, foreign datagrams are ignored
+        if len >= DNS_HEADER_LEN && buf[..2] == query[..2] {
+            return Ok(buf[..len].to_vec());
+        }
+    }
+}
+
+/// Offset past a name (following compression pointers)
+fn skip_dns_name(data: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *data.get(offset)? as usize;
+        if len == 0 {
+            return Some(offset + 1);
+        }
+        if len & 0xC0 == 0xC0 {
+            return Some(offset + 2).filter(|&end| end <= data.len());
+        }
+        offset += 1 + len;
+    }
+}
+
+/// End of the first (only) question
+fn dns_question_end(data: &[u8]) -> Option<usize> {
+    let end = skip_dns_name(data, DNS_HEADER_LEN)? + 4;
+    (end <= data.len()).then_some(end)
+}
+
+/// TTL offsets of all answer section records
+fn dns_answer_ttl_offsets(response: &[u8]) -> Option<Vec<usize>> {
+    let ancount = u16::from_be_bytes([*response.get(6)?, *response.get(7)?]);
+    let mut offset = dns_question_end(response)?;
+    let mut offsets = Vec::with_capacity(ancount as usize);
+
+    for _ in 0..ancount {
+        offset = skip_dns_name(response, offset)?;
+        let ttl_offset = offset + 4;
+        let rdlen = u16::from_be_bytes([*response.get(offset + 8)?, *response.get(offset + 9)?]) as usize;
+        offset += 10 + rdlen;
+        if offset > response.len() {
+            return None;
+        }
+        offsets.push(ttl_offset);
+    }
+
+    Some(offsets)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+struct CachedDnsResponse {
+    response: Vec<u8>,
+    stored: Instant,
+    ttl: Duration,
+}
+
+/// A/AAAA answer cache; the key is the question with a case-insensitive name
+struct DnsCache {
+    entries: parking_lot::Mutex<HashMap<Vec<u8>, CachedDnsResponse>>,
+    capacity: usize,
+}
+
+impl Default for DnsCache {
+    fn default() -> Self {
+        Self::with_capacity(DNS_CACHE_CAPACITY)
+    }
+}
+
+impl DnsCache {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            entries: parking_lot::Mutex::new(HashMap::new()),
+            capacity,
+        }
+    }
+
+    fn key(query: &[u8]) -> Option<(Vec<u8>, u16)> {
+        let end = dns_question_end(query)?;
+        let question = query[DNS_HEADER_LEN..end].to_ascii_lowercase();
+        let qtype = u16::from_be_bytes([question[question.len() - 4], question[question.len() - 3]]);
+        Some((question, qtype))
+    }
+
+    /// Cached answer with the query ID and decremented TTLs
+    fn lookup(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let (key, _) = Self::key(query)?;
+        let mut entries = self.entries.lock();
+
+        let elapsed = match entries.get(&key) {
+            Some(entry) if entry.stored.elapsed() < entry.ttl => entry.stored.elapsed(),
+            Some(_) => {
+                entries.remove(&key);
+                return None;
+            }
+            None => return None,
+        };
+
+        let mut response = entries.get(&key)?.response.clone();
+        response[..2].copy_from_slice(&query[..2]);
+        for offset in dns_answer_ttl_offsets(&response)? {
+            let ttl = read_u32(&response, offset).saturating_sub(elapsed.as_secs() as u32);
+            response[offset..offset + 4].copy_from_slice(&ttl.to_be_bytes());
+        }
+
+        Some(response)
+    }
+
+    fn store(&self, query: &[u8], response: &[u8]) {
+        let Some((key, qtype)) = Self::key(query) else {
+            return;
+        };
+        let truncated = response[2] & 0x02 != 0;
+        let rcode = response[3] & 0x0F;
+        if !matches!(qtype, DNS_TYPE_A | DNS_TYPE_AAAA) || truncated || rcode != 0 {
+            return;
+        }
+        // The answer must be for the same question
+        if Self::key(response).map_or(true, |(k, _)| k != key) {
+            return;
+        }
+
+        let Some(ttl) = dns_answer_ttl_offsets(response)
+            .and_then(|offsets| offsets.iter().map(|&o| read_u32(response, o)).min())
+            .filter(|&ttl| ttl > 0)
+        else {
+            return;
+        };
+
+        let mut entries = self.entries.lock();
+        entries.retain(|_, entry| entry.stored.elapsed() < entry.ttl);
+        // The cache is full of live entries - evict the one expiring soonest
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let soonest = entries.iter()
+                .min_by_key(|(_, entry)| entry.stored + entry.ttl)
+                .map(|(key, _)| key.clone());
+            if let Some(soonest) = soonest {
+                entries.remove(&soonest);
+            }
+        }
+        entries.insert(key, CachedDnsResponse {
+            response: response.to_vec(),
+            stored: Instant::now(),
+            ttl: Duration::from_secs(ttl as u64),
+        });
+    }
+}
+
+/// DCID and SCID from a QUIC long header; empty or longer than 20 bytes is rejected
+fn quic_long_header_cids(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    if data.len() < 7 || (data[0] & 0xC0) != 0xC0 {
+        return None;
+    }
+
+    let dcid_len = data[5] as usize;
+    let dcid = data.get(6..6 + dcid_len)?;
+    let scid_len = *data.get(6 + dcid_len)? as usize;
+    let scid = data.get(7 + dcid_len..7 + dcid_len + scid_len)?;
+
+    if dcid.is_empty() || dcid_len > MAX_CID_LEN || scid_len > MAX_CID_LEN {
+        return None;
+    }
+
+    Some((dcid, scid))
+}
+
+/// recv_from that also returns the original destination address (IP_ORIGDSTADDR)
+/// when it's present in a control message
+async fn recv_with_orig_dst(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    loop {
+        socket.readable().await?;
+        match socket.try_io(Interest::READABLE, || recvmsg_orig_dst(socket.as_raw_fd(), buf)) {
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            result => return result,
         }
     }
 }
 
+fn recvmsg_orig_dst(fd: RawFd, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg_space = nix::cmsg_space!(libc::sockaddr_in6);
+    let msg = recvmsg::<SockaddrStorage>(fd, &mut iov, Some(&mut cmsg_space), MsgFlags::empty())?;
+
+    let src = msg.address
+        .and_then(|addr| {
+            addr.as_sockaddr_in().map(|a| SocketAddr::from(SocketAddrV4::from(*a)))
+                .or_else(|| addr.as_sockaddr_in6().map(|a| SocketAddr::from(SocketAddrV6::from(*a))))
+        })
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "recvmsg without source address"))?;
+
+    let mut orig_dst = None;
+    for cmsg in msg.cmsgs()? {
+        match cmsg {
+            ControlMessageOwned::Ipv4OrigDstAddr(addr) => {
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                orig_dst = Some(SocketAddr::from((ip, u16::from_be(addr.sin_port))));
+            }
+            ControlMessageOwned::Ipv6OrigDstAddr(addr) => {
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                orig_dst = Some(SocketAddr::from((ip, u16::from_be(addr.sin6_port))));
+            }
+            _ => {}
+        }
+    }
+
+    Ok((msg.bytes, src, orig_dst))
+}
+
 #[derive(Debug, Clone)]
 pub struct UdpStats {
     pub active_sessions: usize,
     pub total_bytes_sent: u64,
     pub total_bytes_received: u64,
+    pub sessions: Vec<UdpSessionStats>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UdpSessionStats {
+    pub client_addr: SocketAddr,
+    pub target_addr: SocketAddr,
+    pub duration: Duration,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
 }
 
 #[cfg(test)]
@@ -305,12 +1089,491 @@ mod tests {
     }
 
     #[test]
-    fn test_session_expiry() {
+    fn test_forwarder_from_config() {
+        let mut config = Config::default();
+        assert!(UdpForwarder::from_config(&config).unwrap().is_none());
+
+        config.udp_settings.listen_addr = Some("127.0.0.1:8443".to_string());
+        assert!(UdpForwarder::from_config(&config).is_err());
+        config.udp_settings.transparent = true;
+        assert!(UdpForwarder::from_config(&config).unwrap().unwrap().transparent);
+        config.udp_settings.transparent = false;
+
+        config.udp_settings.target = Some("203.0.113.7:443".to_string());
+        let forwarder = UdpForwarder::from_config(&config).unwrap().unwrap();
+        assert_eq!(forwarder.listen_addr, "127.0.0.1:8443".parse().unwrap());
+        assert_eq!(forwarder.target, Some("203.0.113.7:443".parse().unwrap()));
+        assert!(!forwarder.transparent);
+        assert!(forwarder.socks5.is_some());
+
+        config.proxy_settings.proxy_type = "direct".to_string();
+        assert!(UdpForwarder::from_config(&config).unwrap().unwrap().socks5.is_none());
+
+        config.udp_settings.port_targets.insert(3478, "203.0.113.8:3478".to_string());
+        let forwarder = UdpForwarder::from_config(&config).unwrap().unwrap();
+        assert_eq!(forwarder.port_targets.get(&3478), Some(&"203.0.113.8:3478".parse().unwrap()));
+
+        config.udp_settings.port_targets.insert(443, "bad".to_string());
+        assert!(UdpForwarder::from_config(&config).is_err());
+        config.udp_settings.port_targets.clear();
+
+        config.udp_settings.listen_addr = Some("not-an-address".to_string());
+        assert!(UdpForwarder::from_config(&config).is_err());
+    }
+
+    /// A query for example.com with the given ID
+    fn dns_query(id: u16) -> Vec<u8> {
+        let mut query = id.to_be_bytes().to_vec();
+        query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        query.extend_from_slice(b"\x07example\x03com\x00");
+        query.extend_from_slice(&[0, 1, 0, 1]);
+        query
+    }
+
+    #[tokio::test]
+    async fn test_dns_forwarded_and_cached() {
+        let resolver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let resolver_addr = resolver.local_addr().unwrap();
+        let queries = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, from) = resolver.recv_from(&mut buf).await.unwrap();
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let mut response = buf[..len].to_vec();
+                response[2] = 0x81;
+                response[3] = 0x80;
+                response[7] = 1;
+                // example.com A 93.184.216.34, TTL 300, name via pointer
+                response.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0x01, 0x2C, 0, 4, 93, 184, 216, 34]);
+                resolver.send_to(&response, from).await.unwrap();
+            }
+        });
+
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let forwarder = Arc::new(UdpForwarder::new(listen_addr)
+            .with_target("127.0.0.1:53".parse().unwrap())
+            .with_dns_upstream(resolver_addr));
+        assert!(forwarder.is_dns_packet(&dns_query(1)));
+        let runner = forwarder.clone();
+        tokio::spawn(async move { runner.run_on(listener).await });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 512];
+        for id in [0x1234u16, 0x5678] {
+            client.send_to(&dns_query(id), listen_addr).await.unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..2], &id.to_be_bytes());
+            assert_eq!(&buf[len - 4..len], &[93, 184, 216, 34]);
+            assert!(read_u32(&buf, len - 10) <= 300);
+        }
+
+        // The second answer came from the cache; no UDP session was created
+        assert_eq!(queries.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(forwarder.get_stats().await.active_sessions, 0);
+    }
+
+    #[tokio::test]
+    async fn test_slow_udp_associate_does_not_block_receive_loop() {
+        // The SOCKS5 proxy accepts the connection and stays silent
+        let proxy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = proxy.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            loop {
+                let (control, _) = proxy.accept().await.unwrap();
+                held.push(control);
+            }
+        });
+
+        let resolver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let resolver_addr = resolver.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (len, from) = resolver.recv_from(&mut buf).await.unwrap();
+            let mut response = buf[..len].to_vec();
+            response[2] = 0x81;
+            resolver.send_to(&response, from).await.unwrap();
+        });
+
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let connector = Socks5Connector::new("127.0.0.1".to_string(), proxy_port, None, None);
+        let forwarder = Arc::new(UdpForwarder::new(listen_addr)
+            .with_target("127.0.0.1:53".parse().unwrap())
+            .with_dns_upstream(resolver_addr)
+            .with_socks5(connector));
+        let runner = forwarder.clone();
+        tokio::spawn(async move { runner.run_on(listener).await });
+
+        let stalled = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for _ in 0..3 {
+            stalled.send_to(b"hello", listen_addr).await.unwrap();
+        }
+
+        // While UDP ASSOCIATE hangs, other datagrams are processed
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&dns_query(0x4242), listen_addr).await.unwrap();
+        let mut buf = [0u8; 512];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..2], &[0x42, 0x42]);
+        assert!(len > DNS_HEADER_LEN);
+
+        assert_eq!(forwarder.pending.lock().values().map(Vec::len).sum::<usize>(), 3);
+        assert_eq!(forwarder.get_stats().await.active_sessions, 0);
+    }
+
+    #[test]
+    fn test_dns_cache_bounded() {
+        let cache = DnsCache::with_capacity(2);
+        let answer = |name: &[u8], ttl: u8| {
+            let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, name.len() as u8];
+            query.extend_from_slice(name);
+            query.extend_from_slice(&[0, 0, 1, 0, 1]);
+            let mut response = query.clone();
+            response[2] = 0x81;
+            response[3] = 0x80;
+            response[7] = 1;
+            response.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, ttl, 0, 4, 10, 0, 0, 1]);
+            (query, response)
+        };
+
+        let (short_query, short) = answer(b"short", 30);
+        let (long_query, long) = answer(b"long", 120);
+        let (new_query, new) = answer(b"new", 60);
+        cache.store(&short_query, &short);
+        cache.store(&long_query, &long);
+        cache.store(&new_query, &new);
+
+        // The entry expiring soonest was evicted
+        assert_eq!(cache.entries.lock().len(), 2);
+        assert!(cache.lookup(&short_query).is_none());
+        assert!(cache.lookup(&long_query).is_some());
+        assert!(cache.lookup(&new_query).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_session_expiry() {
+        let upstream = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
         let session = UdpSession::new(
             "127.0.0.1:8080".parse().unwrap(),
-            "127.0.0.1:9090".parse().unwrap()
+            "127.0.0.1:9090".parse().unwrap(),
+            upstream,
         );
         
         assert!(!session.is_expired());
     }
+
+    #[tokio::test]
+    async fn test_packets_forwarded_to_target() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (len, from) = target.recv_from(&mut buf).await.unwrap();
+                let mut reply = b"pong:".to_vec();
+                reply.extend_from_slice(&buf[..len]);
+                target.send_to(&reply, from).await.unwrap();
+            }
+        });
+
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let forwarder = Arc::new(UdpForwarder::new(listen_addr).with_target(target_addr));
+        let runner = forwarder.clone();
+        tokio::spawn(async move { runner.run_on(listener).await });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 1500];
+        for payload in [&[0xC0, 1, 2, 3][..], b"hello"] {
+            client.send_to(payload, listen_addr).await.unwrap();
+            let (len, from) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(from, listen_addr);
+            assert_eq!(&buf[..5], b"pong:");
+            assert_eq!(&buf[5..len], payload);
+        }
+
+        let stats = forwarder.get_stats().await;
+        assert_eq!(stats.active_sessions, 1);
+        assert_eq!(stats.total_bytes_sent, 9);
+        assert_eq!(stats.total_bytes_received, 19);
+
+        let session = &stats.sessions[0];
+        assert_eq!(session.client_addr, client.local_addr().unwrap());
+        assert_eq!(session.target_addr, target_addr);
+        assert_eq!((session.bytes_sent, session.bytes_received), (9, 19));
+        assert!(session.duration > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_datagrams_routed_by_destination_port() {
+        let mut upstreams = Vec::new();
+        for tag in [b'A', b'B'] {
+            let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            upstreams.push(upstream.local_addr().unwrap());
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1500];
+                loop {
+                    let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+                    let mut reply = vec![tag];
+                    reply.extend_from_slice(&buf[..len]);
+                    upstream.send_to(&reply, from).await.unwrap();
+                }
+            });
+        }
+
+        // One forwarder listens on two ports, each port has its own target
+        let listeners = [
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let ports: Vec<u16> = listeners.iter().map(|l| l.local_addr().unwrap().port()).collect();
+        let fallback: SocketAddr = "203.0.113.7:443".parse().unwrap();
+        let forwarder = Arc::new(UdpForwarder::new("127.0.0.1:0".parse().unwrap())
+            .with_target(fallback)
+            .with_port_target(ports[0], upstreams[0])
+            .with_port_target(ports[1], upstreams[1]));
+        for listener in listeners {
+            let runner = forwarder.clone();
+            tokio::spawn(async move { runner.run_on(listener).await });
+        }
+
+        let mut buf = [0u8; 1500];
+        for (port, tag) in ports.iter().zip([b'A', b'B']) {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.send_to(b"ping", ("127.0.0.1", *port)).await.unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..len], [&[tag][..], b"ping"].concat());
+        }
+
+        // The original address from TPROXY picks the target by its port
+        let orig_dst = |port: u16| Some(SocketAddr::from(([198, 51, 100, 1], port)));
+        assert_eq!(forwarder.resolve_target(orig_dst(ports[1]), ports[0]), Some(upstreams[1]));
+        assert_eq!(forwarder.resolve_target(orig_dst(9), ports[0]), Some(fallback));
+        assert_eq!(forwarder.resolve_target(None, 9), Some(fallback));
+    }
+
+    #[tokio::test]
+    async fn test_session_per_target_for_one_client() {
+        let mut upstreams = Vec::new();
+        for tag in [b'A', b'B'] {
+            let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            upstreams.push(upstream.local_addr().unwrap());
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1500];
+                loop {
+                    let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+                    let mut reply = vec![tag];
+                    reply.extend_from_slice(&buf[..len]);
+                    upstream.send_to(&reply, from).await.unwrap();
+                }
+            });
+        }
+
+        let listeners = [
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let ports: Vec<u16> = listeners.iter().map(|l| l.local_addr().unwrap().port()).collect();
+        let forwarder = Arc::new(UdpForwarder::new("127.0.0.1:0".parse().unwrap())
+            .with_port_target(ports[0], upstreams[0])
+            .with_port_target(ports[1], upstreams[1]));
+        for listener in listeners {
+            let runner = forwarder.clone();
+            tokio::spawn(async move { runner.run_on(listener).await });
+        }
+
+        // One client source port alternates between both targets
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 1500];
+        for (port, tag) in ports.iter().zip([b'A', b'B']).cycle().take(4) {
+            client.send_to(b"ping", ("127.0.0.1", *port)).await.unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..len], [&[tag][..], b"ping"].concat());
+        }
+
+        // Sessions don't evict each other: one per target, both survived the switches
+        let stats = forwarder.get_stats().await;
+        assert_eq!(stats.active_sessions, 2);
+        assert!(stats.sessions.iter().all(|session| session.bytes_sent == 8));
+    }
+
+    #[tokio::test]
+    async fn test_datagram_to_forwarder_itself_dropped() {
+        // Without a target and without TPROXY the original destination is the forwarder itself
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let forwarder = Arc::new(UdpForwarder::new(listen_addr));
+        let runner = forwarder.clone();
+        tokio::spawn(async move { runner.run_on(listener).await });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"loop", listen_addr).await.unwrap();
+        let mut buf = [0u8; 64];
+        assert!(tokio::time::timeout(Duration::from_millis(200), client.recv_from(&mut buf)).await.is_err());
+        assert_eq!(forwarder.get_stats().await.active_sessions, 0);
+
+        let wildcard: SocketAddr = "0.0.0.0:8443".parse().unwrap();
+        assert!(is_local_endpoint("127.0.0.1:8443".parse().unwrap(), wildcard));
+        assert!(!is_local_endpoint("203.0.113.7:443".parse().unwrap(), wildcard));
+    }
+
+    #[tokio::test]
+    async fn test_quic_session_keyed_by_connection_id() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (len, from) = target.recv_from(&mut buf).await.unwrap();
+                target.send_to(&buf[..len], from).await.unwrap();
+            }
+        });
+
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let forwarder = Arc::new(UdpForwarder::new(listen_addr).with_target(target_addr));
+        let runner = forwarder.clone();
+        tokio::spawn(async move { runner.run_on(listener).await });
+
+        // Initial: long header, version 1, 8-byte DCID, 4-byte SCID
+        let mut initial = vec![0xC3, 0x00, 0x00, 0x00, 0x01, 8];
+        initial.extend_from_slice(&[0xAB; 8]);
+        initial.push(4);
+        initial.extend_from_slice(&[0xCD; 4]);
+        initial.extend_from_slice(b"payload");
+        // Short header with the same DCID after the client port changed
+        let mut short = vec![0x41];
+        short.extend_from_slice(&[0xAB; 8]);
+        short.extend_from_slice(b"after-migration");
+
+        let mut buf = [0u8; 1500];
+        for datagram in [&initial, &short] {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.send_to(datagram, listen_addr).await.unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..len], &datagram[..]);
+        }
+
+        let stats = forwarder.get_stats().await;
+        assert_eq!(stats.active_sessions, 1);
+        assert_eq!(stats.total_bytes_sent, (initial.len() + short.len()) as u64);
+    }
+
+    #[tokio::test]
+    async fn test_quic_return_path_stays_on_client_ip() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (len, from) = target.recv_from(&mut buf).await.unwrap();
+                target.send_to(&buf[..len], from).await.unwrap();
+            }
+        });
+
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let forwarder = Arc::new(UdpForwarder::new(listen_addr).with_target(target_addr));
+        let runner = forwarder.clone();
+        tokio::spawn(async move { runner.run_on(listener).await });
+
+        let mut initial = vec![0xC3, 0x00, 0x00, 0x00, 0x01, 4];
+        initial.extend_from_slice(&[0xAB; 4]);
+        initial.push(0);
+        let mut short = vec![0x41];
+        short.extend_from_slice(&[0xAB; 4]);
+        short.extend_from_slice(b"spoofed");
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 1500];
+        client.send_to(&initial, listen_addr).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf)).await.unwrap().unwrap();
+
+        // A foreign IP with a known Connection ID doesn't take over the session's replies
+        let observer = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        observer.send_to(&short, listen_addr).await.unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..len], &short[..]);
+        assert!(tokio::time::timeout(Duration::from_millis(100), observer.recv_from(&mut buf)).await.is_err());
+
+        let stats = forwarder.get_stats().await;
+        assert_eq!(stats.active_sessions, 1);
+        assert_eq!(stats.sessions[0].client_addr, client.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_packets_tunneled_through_socks5_udp_associate() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_port = relay.local_addr().unwrap().port();
+        let target: SocketAddr = "203.0.113.7:443".parse().unwrap();
+
+        // Fake SOCKS5: no auth, UDP ASSOCIATE replies with 0.0.0.0:relay_port
+        let proxy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = proxy.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut control, _) = proxy.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            control.read_exact(&mut buf[..3]).await.unwrap();
+            control.write_all(&[0x05, 0x00]).await.unwrap();
+            control.read_exact(&mut buf[..10]).await.unwrap();
+            assert_eq!(buf[1], 0x03);
+            let mut reply = vec![0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0];
+            reply.extend_from_slice(&relay_port.to_be_bytes());
+            control.write_all(&reply).await.unwrap();
+            // Keep the control connection open
+            let _ = control.read(&mut buf).await;
+        });
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            let (len, from) = relay.recv_from(&mut buf).await.unwrap();
+            let (dst, payload) = unwrap_udp_datagram(&buf[..len]).unwrap();
+            assert_eq!(dst, "203.0.113.7:443".parse::<SocketAddr>().unwrap());
+            assert_eq!(payload, &[0xC0, 0xAA]);
+            relay.send_to(&wrap_udp_datagram(dst, b"reply"), from).await.unwrap();
+        });
+
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let connector = Socks5Connector::new("127.0.0.1".to_string(), proxy_port, None, None);
+        let forwarder = Arc::new(UdpForwarder::new(listen_addr).with_target(target).with_socks5(connector));
+        let runner = forwarder.clone();
+        tokio::spawn(async move { runner.run_on(listener).await });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&[0xC0, 0xAA], listen_addr).await.unwrap();
+        let mut buf = [0u8; 1500];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..len], b"reply");
+    }
 }
\ No newline at end of file
@@ -1,19 +1,40 @@
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::io::{self, Error, ErrorKind};
+use std::collections::HashMap;
 use libc::{c_void, off_t, size_t, ssize_t};
+use parking_lot::Mutex;
 
 const SPLICE_SIZE: usize = 65536;
 const SENDFILE_SIZE: usize = 65536;
+const DEFAULT_MAX_MMAP_SIZE: usize = 1 << 30;
+
+/// Per fd pair state: whether splice works for the pair, the intermediate
+/// pipe, and bytes already taken from `fd_in` but not yet delivered to `fd_out`
+#[derive(Default)]
+struct PairState {
+    splice_supported: Option<bool>,
+    pipe: Option<SplicePipe>,
+    /// Bytes sitting in `pipe`
+    pipe_pending: usize,
+    /// Bytes read by the buffered copy but not yet written
+    backlog: Vec<u8>,
+}
 
 pub struct ZeroCopyTransfer {
     buffer_size: usize,
+    pairs: Mutex<HashMap<(RawFd, RawFd), PairState>>,
 }
 
 impl ZeroCopyTransfer {
     pub fn new(buffer_size: usize) -> Self {
-        Self { buffer_size }
+        Self {
+            buffer_size,
+            pairs: Mutex::new(HashMap::new()),
+        }
     }
 
+    /// Runs until EOF on `read_fd` or an error, `WouldBlock` included, then
+    /// forgets the pair
     pub async fn splice_bidirectional<R, W>(
         &self,
         read_fd: RawFd,
@@ -25,40 +46,134 @@ impl ZeroCopyTransfer {
     {
         let mut total_transferred = 0u64;
         
-        loop {
-            let transferred = self.splice_once(read_fd, write_fd)?;
+        let result = loop {
+            let transferred = match self.transfer_once(read_fd, write_fd) {
+                Ok(transferred) => transferred,
+                Err(e) => break Err(e),
+            };
             
             if transferred == 0 {
-                break;
+                break Ok(total_transferred);
             }
             
             total_transferred += transferred as u64;
+        };
+
+        self.forget_fds(read_fd, write_fd);
+        result
+    }
+
+    /// Moves one chunk from `fd_in` to `fd_out` and returns the bytes delivered
+    /// to `fd_out`. Splice goes through a pipe kept per fd pair when the kernel
+    /// supports it, otherwise a buffered copy; the first attempt is cached per pair.
+    /// Bytes taken from `fd_in` but not yet delivered stay with the pair and go
+    /// out first on the next call. `WouldBlock` is returned as an error so the
+    /// caller can wait for readiness; `Ok(0)` means EOF with nothing pending.
+    pub fn transfer_once(&self, fd_in: RawFd, fd_out: RawFd) -> io::Result<ssize_t> {
+        let mut pairs = self.pairs.lock();
+        let state = pairs.entry((fd_in, fd_out)).or_default();
+
+        if state.splice_supported != Some(false) {
+            match self.splice_through_pipe(state, fd_in, fd_out) {
+                Err(e) if is_splice_unsupported(&e) && state.splice_supported.is_none() => {
+                    log::debug!("splice unsupported for fds {}->{} ({}), using buffered copy", fd_in, fd_out, e);
+                    state.splice_supported = Some(false);
+                    // Whatever already made it into the pipe goes out through the copy
+                    if let Some(pipe) = state.pipe.take() {
+                        state.backlog = pipe.drain(state.pipe_pending)?;
+                        state.pipe_pending = 0;
+                    }
+                }
+                result => {
+                    if result.is_ok() {
+                        state.splice_supported.get_or_insert(true);
+                    }
+                    return result;
+                }
+            }
         }
-        
-        Ok(total_transferred)
+
+        self.copy_once(state, fd_in, fd_out)
     }
 
-    fn splice_once(&self, fd_in: RawFd, fd_out: RawFd) -> io::Result<ssize_t> {
-        let result = unsafe {
-            libc::splice(
-                fd_in,
-                std::ptr::null_mut(),
-                fd_out,
-                std::ptr::null_mut(),
-                self.buffer_size,
-                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
-            )
-        };
+    pub fn is_splice_supported(&self, fd_in: RawFd, fd_out: RawFd) -> Option<bool> {
+        self.pairs.lock().get(&(fd_in, fd_out)).and_then(|state| state.splice_supported)
+    }
 
-        if result < 0 {
-            let err = Error::last_os_error();
-            if err.kind() == ErrorKind::WouldBlock {
+    /// Drops the state of a pair, pending bytes included. Call once either fd
+    /// is closed, so a reused fd number doesn't inherit a stale pipe
+    pub fn forget_fds(&self, fd_in: RawFd, fd_out: RawFd) {
+        self.pairs.lock().remove(&(fd_in, fd_out));
+    }
+
+    /// Drops the state of every pair `fd` takes part in
+    pub fn forget_fd(&self, fd: RawFd) {
+        self.pairs.lock().retain(|&(fd_in, fd_out), _| fd_in != fd && fd_out != fd);
+    }
+
+    fn splice_through_pipe(&self, state: &mut PairState, fd_in: RawFd, fd_out: RawFd) -> io::Result<ssize_t> {
+        if state.pipe.is_none() {
+            state.pipe = Some(SplicePipe::new()?);
+        }
+        let pipe = state.pipe.as_ref().expect("pipe created above");
+
+        if state.pipe_pending == 0 {
+            let n = pipe.splice_in(fd_in, self.buffer_size)?;
+            if n == 0 {
                 return Ok(0);
             }
-            return Err(err);
+            state.pipe_pending = n;
         }
 
-        Ok(result)
+        let n = pipe.splice_out(fd_out, state.pipe_pending)?;
+        state.pipe_pending -= n;
+        Ok(n as ssize_t)
+    }
+
+    fn copy_once(&self, state: &mut PairState, fd_in: RawFd, fd_out: RawFd) -> io::Result<ssize_t> {
+        if state.backlog.is_empty() {
+            let mut buffer = vec![0u8; self.buffer_size];
+            let read = loop {
+                let read = unsafe { libc::read(fd_in, buffer.as_mut_ptr() as *mut c_void, buffer.len()) };
+                if read >= 0 {
+                    break read as usize;
+                }
+                let err = Error::last_os_error();
+                if err.kind() != ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            };
+            if read == 0 {
+                return Ok(0);
+            }
+            buffer.truncate(read);
+            state.backlog = buffer;
+        }
+
+        let mut written = 0usize;
+        while written < state.backlog.len() {
+            let result = unsafe {
+                libc::write(
+                    fd_out,
+                    state.backlog[written..].as_ptr() as *const c_void,
+                    state.backlog.len() - written,
+                )
+            };
+            if result < 0 {
+                let err = Error::last_os_error();
+                if err.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                if written == 0 {
+                    return Err(err);
+                }
+                break;
+            }
+            written += result as usize;
+        }
+
+        state.backlog.drain(..written);
+        Ok(written as ssize_t)
     }
 
     pub fn sendfile(&self, out_fd: RawFd, in_fd: RawFd, offset: Option<off_t>, count: size_t) -> io::Result<ssize_t> {
@@ -80,6 +195,98 @@ impl ZeroCopyTransfer {
     }
 }
 
+/// Errors meaning splice can't work for this fd pair or kernel at all, as
+/// opposed to a transient failure; callers switch to a buffered copy
+pub fn is_splice_unsupported(err: &Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP)
+    )
+}
+
+/// Intermediate pipe for socket-to-socket splice: the kernel cannot splice
+/// two sockets directly, so data goes socket -> pipe -> socket without
+/// entering userspace. Both ends are non-blocking; `WouldBlock` is returned
+/// as an error so callers can wait on readiness.
+pub struct SplicePipe {
+    read_fd: RawFd,
+    write_fd: RawFd,
+}
+
+impl SplicePipe {
+    pub fn new() -> io::Result<Self> {
+        let mut fds = [0 as libc::c_int; 2];
+        let result = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) };
+        if result < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(Self { read_fd: fds[0], write_fd: fds[1] })
+    }
+
+    /// Moves up to `len` bytes from `fd_in` into the pipe; 0 means EOF on `fd_in`
+    pub fn splice_in(&self, fd_in: RawFd, len: usize) -> io::Result<usize> {
+        Self::splice(fd_in, self.write_fd, len)
+    }
+
+    /// Moves up to `len` bytes buffered in the pipe out to `fd_out`
+    pub fn splice_out(&self, fd_out: RawFd, len: usize) -> io::Result<usize> {
+        Self::splice(self.read_fd, fd_out, len)
+    }
+
+    /// Reads up to `len` bytes buffered in the pipe back into userspace
+    fn drain(&self, len: usize) -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0u8; len];
+        let mut filled = 0;
+        while filled < len {
+            let read = unsafe {
+                libc::read(self.read_fd, buffer[filled..].as_mut_ptr() as *mut c_void, len - filled)
+            };
+            if read < 0 {
+                let err = Error::last_os_error();
+                if err.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            if read == 0 {
+                break;
+            }
+            filled += read as usize;
+        }
+        buffer.truncate(filled);
+        Ok(buffer)
+    }
+
+    fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+        let result = unsafe {
+            libc::splice(
+                fd_in,
+                std::ptr::null_mut(),
+                fd_out,
+                std::ptr::null_mut(),
+                len.min(SPLICE_SIZE),
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+            )
+        };
+
+        if result < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(result as usize)
+    }
+}
+
+impl Drop for SplicePipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read_fd);
+            libc::close(self.write_fd);
+        }
+    }
+}
+
 pub struct RingBuffer {
     buffer: Vec<u8>,
     read_pos: usize,
@@ -110,40 +317,70 @@ impl RingBuffer {
     }
 
     pub fn write(&mut self, data: &[u8]) -> usize {
-        let available = self.available_write();
-        let to_write = data.len().min(available);
+        let (first, second) = self.write_slices();
+        let head = data.len().min(first.len());
+        first[..head].copy_from_slice(&data[..head]);
+        let tail = (data.len() - head).min(second.len());
+        second[..tail].copy_from_slice(&data[head..head + tail]);
+
+        self.commit_write(head + tail);
+        head + tail
+    }
 
-        for i in 0..to_write {
-            self.buffer[self.write_pos] = data[i];
-            self.write_pos = (self.write_pos + 1) % self.capacity;
-        }
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let to_read = self.peek(buf);
+        self.consume(to_read);
+        to_read
+    }
 
-        to_write
+    /// Readable data as up to two contiguous slices; the second one is
+    /// non-empty only when the data wraps around the end of the buffer.
+    /// Suitable for `IoSlice`s passed to `write_vectored`
+    pub fn read_slices(&self) -> (&[u8], &[u8]) {
+        if self.write_pos >= self.read_pos {
+            (&self.buffer[self.read_pos..self.write_pos], &[])
+        } else {
+            (&self.buffer[self.read_pos..], &self.buffer[..self.write_pos])
+        }
     }
 
-    pub fn read(&mut self, buf: &mut [u8]) -> usize {
-        let available = self.available_read();
-        let to_read = buf.len().min(available);
+    /// Free space as up to two contiguous slices, for `read_vectored`.
+    /// One slot always stays free to tell a full buffer from an empty one
+    pub fn write_slices(&mut self) -> (&mut [u8], &mut [u8]) {
+        let (read_pos, write_pos) = (self.read_pos, self.write_pos);
+        if write_pos < read_pos {
+            return (&mut self.buffer[write_pos..read_pos - 1], &mut []);
+        }
 
-        for i in 0..to_read {
-            buf[i] = self.buffer[self.read_pos];
-            self.read_pos = (self.read_pos + 1) % self.capacity;
+        let (head, tail) = self.buffer.split_at_mut(write_pos);
+        if read_pos == 0 {
+            let end = tail.len() - 1;
+            (&mut tail[..end], &mut [])
+        } else {
+            (tail, &mut head[..read_pos - 1])
         }
+    }
 
-        to_read
+    /// Drops `n` bytes already taken out through `read_slices`
+    pub fn consume(&mut self, n: usize) {
+        let n = n.min(self.available_read());
+        self.read_pos = (self.read_pos + n) % self.capacity;
     }
 
-    pub fn peek(&self, buf: &mut [u8]) -> usize {
-        let available = self.available_read();
-        let to_peek = buf.len().min(available);
-        let mut pos = self.read_pos;
+    /// Marks `n` bytes filled through `write_slices` as readable
+    pub fn commit_write(&mut self, n: usize) {
+        let n = n.min(self.available_write());
+        self.write_pos = (self.write_pos + n) % self.capacity;
+    }
 
-        for i in 0..to_peek {
-            buf[i] = self.buffer[pos];
-            pos = (pos + 1) % self.capacity;
-        }
+    pub fn peek(&self, buf: &mut [u8]) -> usize {
+        let (first, second) = self.read_slices();
+        let head = buf.len().min(first.len());
+        buf[..head].copy_from_slice(&first[..head]);
+        let tail = (buf.len() - head).min(second.len());
+        buf[head..head + tail].copy_from_slice(&second[..tail]);
 
-        to_peek
+        head + tail
     }
 
     pub fn clear(&mut self) {
@@ -162,11 +399,31 @@ impl RingBuffer {
 
 pub struct MmapBuffer {
     ptr: *mut c_void,
+    len: usize,
     size: usize,
 }
 
 impl MmapBuffer {
     pub fn new(size: usize) -> io::Result<Self> {
+        Self::with_limit(size, DEFAULT_MAX_MMAP_SIZE)
+    }
+
+    /// Maps `len` bytes rounded up to the page size, rejecting zero or over-limit sizes
+    pub fn with_limit(len: usize, max_size: usize) -> io::Result<Self> {
+        if len == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "mmap buffer size must be non-zero"));
+        }
+
+        let page_size = Self::page_size();
+        let size = len
+            .checked_add(page_size - 1)
+            .map(|n| n / page_size * page_size)
+            .filter(|size| *size <= max_size)
+            .ok_or_else(|| Error::new(
+                ErrorKind::InvalidInput,
+                format!("mmap buffer size {} exceeds limit of {} bytes", len, max_size),
+            ))?;
+
         let ptr = unsafe {
             libc::mmap(
                 std::ptr::null_mut(),
@@ -182,15 +439,24 @@ impl MmapBuffer {
             return Err(Error::last_os_error());
         }
 
-        Ok(Self { ptr, size })
+        Ok(Self { ptr, len, size })
+    }
+
+    fn page_size() -> usize {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        if page_size > 0 { page_size as usize } else { 4096 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
     }
 
     pub fn as_slice(&self) -> &[u8] {
-        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.size) }
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
     }
 
     pub fn as_mut_slice(&mut self) -> &mut [u8] {
-        unsafe { std::slice::from_raw_parts_mut(self.ptr as *mut u8, self.size) }
+        unsafe { std::slice::from_raw_parts_mut(self.ptr as *mut u8, self.len) }
     }
 
     pub fn advise_sequential(&self) -> io::Result<()> {
@@ -229,9 +495,54 @@ impl Drop for MmapBuffer {
 unsafe impl Send for MmapBuffer {}
 unsafe impl Sync for MmapBuffer {}
 
+/// Relay read buffer: a plain heap allocation, or an mmap region once a
+/// transfer is large enough for madvise hints to pay off
+pub enum RelayBuffer {
+    Heap(Vec<u8>),
+    Mmap(MmapBuffer),
+}
+
+impl RelayBuffer {
+    /// Moves to an mmap buffer of the same length advised for sequential
+    /// access. The current contents are not carried over
+    pub fn promote_to_mmap(&mut self) -> io::Result<()> {
+        if let RelayBuffer::Heap(buf) = self {
+            let mmap = MmapBuffer::new(buf.len())?;
+            mmap.advise_sequential()?;
+            *self = RelayBuffer::Mmap(mmap);
+        }
+        Ok(())
+    }
+
+    pub fn is_mmap(&self) -> bool {
+        matches!(self, RelayBuffer::Mmap(_))
+    }
+}
+
+impl std::ops::Deref for RelayBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            RelayBuffer::Heap(buf) => buf,
+            RelayBuffer::Mmap(buf) => buf.as_slice(),
+        }
+    }
+}
+
+impl std::ops::DerefMut for RelayBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            RelayBuffer::Heap(buf) => buf,
+            RelayBuffer::Mmap(buf) => buf.as_mut_slice(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::{IoSlice, Write};
 
     #[test]
     fn test_ring_buffer() {
@@ -260,6 +571,40 @@ mod tests {
         assert_eq!(rb.available_read(), 4);
     }
 
+    #[test]
+    fn test_ring_buffer_vectored_slices() {
+        let mut rb = RingBuffer::new(8);
+        rb.write(b"xxxxx");
+        rb.consume(5);
+
+        // Free space wraps: 3 bytes at the end, 4 at the start
+        let (first, second) = rb.write_slices();
+        assert_eq!((first.len(), second.len()), (3, 4));
+        first.copy_from_slice(b"abc");
+        second[..2].copy_from_slice(b"de");
+        rb.commit_write(5);
+
+        let (first, second) = rb.read_slices();
+        assert_eq!((first, second), (&b"abc"[..], &b"de"[..]));
+
+        let mut out = Vec::new();
+        let written = out
+            .write_vectored(&[IoSlice::new(first), IoSlice::new(second)])
+            .unwrap();
+        assert_eq!(written, 5);
+        assert_eq!(out, b"abcde");
+        rb.consume(written);
+        assert!(rb.is_empty());
+
+        // Byte-wise API sees the same data across the wrap
+        assert_eq!(rb.write(b"0123456789"), 7);
+        let mut buf = [0u8; 7];
+        assert_eq!(rb.peek(&mut buf), 7);
+        assert_eq!(&buf, b"0123456");
+        let (first, second) = rb.read_slices();
+        assert_eq!((first.len(), second.len()), (6, 1));
+    }
+
     #[test]
     fn test_mmap_buffer() {
         let mut mmap = MmapBuffer::new(4096).unwrap();
@@ -271,4 +616,142 @@ mod tests {
         assert_eq!(mmap.as_slice()[0], 42);
         assert_eq!(mmap.as_slice()[100], 99);
     }
+
+    #[test]
+    fn test_splice_fallback_copy() {
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixStream;
+
+        // The kernel refuses to splice into a file opened with O_APPEND
+        let (mut client, source) = UnixStream::pair().unwrap();
+        let path = std::env::temp_dir().join(format!("tproxy-fallback-{}", std::process::id()));
+        let sink = std::fs::OpenOptions::new().create(true).append(true).open(&path).unwrap();
+        let transfer = ZeroCopyTransfer::new(SPLICE_SIZE);
+
+        client.write_all(b"first").unwrap();
+        let n = transfer.transfer_once(source.as_raw_fd(), sink.as_raw_fd()).unwrap();
+        assert_eq!(n, 5);
+        assert_eq!(transfer.is_splice_supported(source.as_raw_fd(), sink.as_raw_fd()), Some(false));
+
+        client.write_all(b"second").unwrap();
+        let n = transfer.transfer_once(source.as_raw_fd(), sink.as_raw_fd()).unwrap();
+        assert_eq!(n, 6);
+
+        // Nothing to read is not EOF
+        source.set_nonblocking(true).unwrap();
+        let err = transfer.transfer_once(source.as_raw_fd(), sink.as_raw_fd()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        let mut received = Vec::new();
+        std::fs::File::open(&path).unwrap().read_to_end(&mut received).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(received, b"firstsecond");
+
+        transfer.forget_fd(source.as_raw_fd());
+        assert_eq!(transfer.is_splice_supported(source.as_raw_fd(), sink.as_raw_fd()), None);
+    }
+
+    #[test]
+    fn test_transfer_keeps_pending_bytes_when_sink_blocks() {
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixStream;
+
+        const LEN: usize = 1 << 20;
+        let (mut client, source) = UnixStream::pair().unwrap();
+        let (sink, mut server) = UnixStream::pair().unwrap();
+        source.set_nonblocking(true).unwrap();
+        sink.set_nonblocking(true).unwrap();
+        server.set_nonblocking(true).unwrap();
+
+        let writer = std::thread::spawn(move || {
+            let payload: Vec<u8> = (0..LEN).map(|i| (i * 7) as u8).collect();
+            client.write_all(&payload).unwrap();
+            payload
+        });
+
+        // The sink fills up long before 1 MiB; whatever was already taken
+        // from the source must survive the WouldBlock
+        let transfer = ZeroCopyTransfer::new(SPLICE_SIZE);
+        let mut received = Vec::with_capacity(LEN);
+        let mut buf = vec![0u8; SPLICE_SIZE];
+        while received.len() < LEN {
+            match transfer.transfer_once(source.as_raw_fd(), sink.as_raw_fd()) {
+                Ok(n) => assert!(n > 0),
+                Err(e) => assert_eq!(e.kind(), ErrorKind::WouldBlock),
+            }
+            match server.read(&mut buf) {
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(e) => assert_eq!(e.kind(), ErrorKind::WouldBlock),
+            }
+        }
+
+        assert!(received == writer.join().unwrap());
+        assert_eq!(transfer.is_splice_supported(source.as_raw_fd(), sink.as_raw_fd()), Some(true));
+    }
+
+    #[test]
+    fn test_splice_pipe_reports_unsupported_fds() {
+        use std::io::Write;
+        use std::os::unix::net::UnixStream;
+
+        // The kernel refuses to splice into a file opened with O_APPEND
+        let pipe = SplicePipe::new().unwrap();
+        let (mut client, source) = UnixStream::pair().unwrap();
+        client.write_all(b"data").unwrap();
+        let n = pipe.splice_in(source.as_raw_fd(), 4).unwrap();
+        assert_eq!(n, 4);
+
+        let path = std::env::temp_dir().join(format!("tproxy-splice-{}", std::process::id()));
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(&path).unwrap();
+        let err = pipe.splice_out(file.as_raw_fd(), 4).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(is_splice_unsupported(&err), "{:?}", err);
+
+        assert!(is_splice_unsupported(&Error::from_raw_os_error(libc::ENOSYS)));
+        assert!(!is_splice_unsupported(&Error::from_raw_os_error(libc::EPIPE)));
+        assert!(!is_splice_unsupported(&Error::from(ErrorKind::WouldBlock)));
+    }
+
+    #[test]
+    fn test_splice_pipe_between_sockets() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut upstream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (source, _) = listener.accept().unwrap();
+        let sink = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut client, _) = listener.accept().unwrap();
+
+        let pipe = SplicePipe::new().unwrap();
+        upstream.write_all(b"spliced body").unwrap();
+
+        let n = pipe.splice_in(source.as_raw_fd(), 1024).unwrap();
+        assert_eq!(n, 12);
+        assert_eq!(pipe.splice_out(sink.as_raw_fd(), n).unwrap(), 12);
+
+        let mut received = [0u8; 12];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"spliced body");
+
+        source.set_nonblocking(true).unwrap();
+        let err = pipe.splice_in(source.as_raw_fd(), 1024).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn test_mmap_buffer_size_validation() {
+        let zero = MmapBuffer::new(0);
+        assert_eq!(zero.err().unwrap().kind(), ErrorKind::InvalidInput);
+
+        let huge = MmapBuffer::with_limit(1 << 20, 1 << 16);
+        assert_eq!(huge.err().unwrap().kind(), ErrorKind::InvalidInput);
+
+        let overflow = MmapBuffer::new(usize::MAX);
+        assert!(overflow.is_err());
+
+        let valid = MmapBuffer::with_limit(1000, 1 << 16).unwrap();
+        assert_eq!(valid.len(), 1000);
+        assert_eq!(valid.as_slice().len(), 1000);
+    }
 }
\ No newline at end of file